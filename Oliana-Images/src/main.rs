@@ -20,7 +20,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
   Ok(())
 }
 
+#[cfg(feature = "python")]
 use pyo3::prelude::*;
+#[cfg(feature = "python")]
 use pyo3::ffi::c_str;
 
 async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
@@ -39,13 +41,40 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     return Ok(());
   }
 
+  // "diffusers" runs koala-lightning through python on the GPU; "mock" paints seeded procedural images through the same workdir protocol and needs no GPU.
+  let mut backend = "diffusers".to_string();
+  let mut seed: u64 = 0;
+  let mut mock_step_delay = oliana_lib::mock::DEFAULT_MOCK_STEP_DELAY;
+  if let Some(backend_i) = args.iter().position(|n| n == "--backend") {
+    if backend_i < args.len()-1 {
+      backend = args[backend_i+1].clone();
+    }
+  }
+  if let Some(seed_i) = args.iter().position(|n| n == "--seed") {
+    if seed_i < args.len()-1 {
+      seed = args[seed_i+1].parse::<u64>().map_err(oliana_lib::eloc!())?;
+    }
+  }
+  if let Some(delay_i) = args.iter().position(|n| n == "--mock-step-delay-ms") {
+    if delay_i < args.len()-1 {
+      mock_step_delay = std::time::Duration::from_millis(args[delay_i+1].parse::<u64>().map_err(oliana_lib::eloc!())?);
+    }
+  }
+
   println!("");
   println!("Using {env_var_work_dir} as a work directory.");
   println!("write files named 'NAME.json' containing objects like:");
   println!(r#" {{"prompt": "A cow jumps over the moon while fireworks explode in the air", "negative_prompt": "worst quality, low quality, ugly, duplicate, morbid, mutilated, extra fingers, mutated hands, extra limbs, cloned face, disfigured, malformed limbs, missing arms, missing legs", "guidance_scale": 3.5, "num_inference_steps": 10 }}"#);
-  println!("and wait for either 'NAME.png' or 'NAME.txt' to be written back from this process.");
+  println!("and wait for either 'NAME.png' or 'NAME.{}' (a JSON error report) to be written back from this process.", oliana_lib::launchers::FAILED_JOB_EXTENSION);
+  println!("Creating 'NAME.cancel' stops the pipeline after the current step (or skips 'NAME.json' if it has not started yet); no 'NAME.png' is written for cancelled runs.");
+  println!("'NAME.done' is written once 'NAME.json' has been handled, whether it succeeded, failed or was cancelled.");
+  println!("While the pipeline runs, 'NAME.preview-STEP.png' holds a low-resolution preview of the newest step and 'NAME.progress' holds {{\"step\": STEP, \"num_steps\": N}}.");
   println!("'NAME.json' will remain post-generation, and if the file's mtime becomes newer it will be processed again with the new contents.");
   println!("If 'NAME.json' has an mtime older than this process's start time, it will not be processed.");
+  println!("'{}' holds this process's PID once the model is loaded and 'NAME.json' files are being picked up.", oliana_lib::launchers::WORKER_READY_FILE_NAME);
+  println!("'{}' is re-written every {:?} while idle and on every step while the pipeline runs.", oliana_lib::launchers::WORKER_HEARTBEAT_FILE_NAME, oliana_lib::launchers::WORKER_HEARTBEAT_INTERVAL);
+  println!("The model and python packages come from the '{}' entry of the models manifest (${} or the one built into oliana_lib); with {}=1 neither is ever downloaded.", oliana_lib::models::IMAGE_MODEL_NAME, oliana_lib::models::MODELS_MANIFEST_ENV_VAR, oliana_lib::models::OFFLINE_ENV_VAR);
+  println!("Pass --backend mock to run without a GPU or python; images are seeded procedural patterns (--seed N, --mock-step-delay-ms N).");
   println!("");
 
   tokio::fs::create_dir_all(&env_var_work_dir[..]).await?;
@@ -54,9 +83,36 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     "WORK_DIR", env_var_work_dir.clone()
   );
 
-  let site_packages = oliana_lib::files::get_cache_file("Oliana-Images-site_packages").await.map_err(oliana_lib::eloc!())?;
+  if backend == "mock" {
+    println!("--backend mock: painting images seeded by --seed {seed} and the prompts, one step every ~{}ms (--mock-step-delay-ms)", mock_step_delay.as_millis());
+    return oliana_lib::mock::run_mock_image_worker(&env_var_work_dir, seed, mock_step_delay).await;
+  }
+  if backend != "diffusers" {
+    return Err(format!("Unknown --backend {:?}, expected \"diffusers\" or \"mock\"", backend).into());
+  }
+
+  run_diffusers_worker(env_var_work_dir).await
+}
+
+#[cfg(not(feature = "python"))]
+async fn run_diffusers_worker(env_var_work_dir: String) -> Result<(), Box<dyn std::error::Error>> {
+  Err("oliana_images was built without the \"python\" feature, so only --backend mock is available".into())
+}
+
+#[cfg(feature = "python")]
+async fn run_diffusers_worker(env_var_work_dir: String) -> Result<(), Box<dyn std::error::Error>> {
+  // The model and python packages come from the models manifest; see oliana_lib::models
+  let offline = oliana_lib::models::offline_mode();
+  let manifest = oliana_lib::models::ModelManifest::load_default().map_err(oliana_lib::eloc!())?;
+  let cache_root = oliana_lib::files::get_cache_dir().await.map_err(oliana_lib::eloc!())?;
+  // Leases are held until we exit, so nothing prunes what we load from; see oliana_lib::files::prune_cache_entry
+  let _model_lease = oliana_lib::files::lease_cache_entry(manifest.model(oliana_lib::models::IMAGE_MODEL_NAME)?.local_dir(&cache_root))?;
+  let model_dir = oliana_lib::models::ensure_model(&manifest, oliana_lib::models::IMAGE_MODEL_NAME, &cache_root, offline).await?;
+  eprintln!("Loading {} from {}", manifest.model(oliana_lib::models::IMAGE_MODEL_NAME)?.repo, model_dir.display());
+
+  let site_packages = oliana_lib::models::python_site_packages_dir(&manifest, &cache_root);
+  let _site_packages_lease = oliana_lib::files::lease_cache_entry(&site_packages)?;
   let site_packages = site_packages.to_string_lossy();
-  tokio::fs::create_dir_all(&site_packages[..]).await?;
 
   let pythonpath = std::env::join_paths(&[
     site_packages.to_string(),
@@ -91,22 +147,46 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     "PATH", os_path
   );
 
-  let hf_home = oliana_lib::files::get_cache_file("Oliana-Images-hf_home").await.map_err(oliana_lib::eloc!())?;
+  let hf_home = oliana_lib::files::get_cache_file(oliana_lib::models::IMAGE_HF_HOME_DIR).await.map_err(oliana_lib::eloc!())?;
+  let _hf_home_lease = oliana_lib::files::lease_cache_entry(&hf_home)?;
   let hf_home = hf_home.to_string_lossy();
-  tokio::fs::create_dir_all(&hf_home[..]).await?;
 
   eprintln!("Storing model data at {hf_home}");
 
   std::env::set_var(
     "HF_HOME", hf_home.to_string()
   );
+  if offline {
+    std::env::set_var("HF_HUB_OFFLINE", "1");
+  }
 
-  python_main(&site_packages, &env_var_work_dir).map_err(oliana_lib::eloc!())?;
+  let poll_workdir_once = python_main(&site_packages, &env_var_work_dir, &manifest.python.packages, offline, &model_dir.to_string_lossy()).map_err(oliana_lib::eloc!())?;
+
+  // Python does the work, but waiting for work happens out here so we sleep on file events instead of polling.
+  let workdir_watcher = oliana_lib::watch::DirWatcher::new(&env_var_work_dir).map_err(oliana_lib::eloc!())?;
+  let mut workdir_events = workdir_watcher.subscribe();
+  oliana_lib::launchers::write_worker_ready_file(&env_var_work_dir).map_err(oliana_lib::eloc!())?; // Lets oliana_server report the model as loaded
+  loop {
+    // Python beats for itself while it works on a job, see oliana_lib::launchers::WORKER_HEARTBEAT_FILE_NAME
+    oliana_lib::launchers::write_worker_heartbeat(&env_var_work_dir, oliana_lib::launchers::WorkerActivity::Idle, None).map_err(oliana_lib::eloc!())?;
+    let keep_going: bool = Python::with_gil(|py| -> PyResult<bool> {
+      poll_workdir_once.call0(py)?.extract(py)
+    }).map_err(oliana_lib::eloc!())?;
+    if !keep_going {
+      break;
+    }
+    // Sleep until a .json is written or it is time for the next heartbeat
+    workdir_events.wait_for_extension("json", oliana_lib::launchers::WORKER_HEARTBEAT_INTERVAL).await;
+  }
 
   Ok(())
 }
 
-fn python_main(site_packages: &str, env_var_work_dir: &str) -> PyResult<()> {
+#[cfg(feature = "python")]
+// Installs the python packages of the models manifest (unless offline), loads the diffusion pipeline from model_dir and returns a python
+// function which processes every new .json in env_var_work_dir once.
+// That function returns False when too many errors have happened (or on KeyboardInterrupt) and the process should exit.
+fn python_main(site_packages: &str, env_var_work_dir: &str, python_packages: &[oliana_lib::models::PythonPackage], offline: bool, model_dir: &str) -> PyResult<Py<PyAny>> {
   Python::with_gil(|py| {
       let sys = py.import("sys")?;
       let version: String = sys.getattr("version")?.extract()?;
@@ -116,76 +196,39 @@ fn python_main(site_packages: &str, env_var_work_dir: &str) -> PyResult<()> {
       let pip = py.import("pip")?;
       let pip_main: Py<PyAny> = pip.getattr("main")?.into();
 
-      if let Err(e) = py.import("torch") {
-        eprintln!("{:?}", e);
-        let arg_vals = vec![
-          "install".to_string(), format!("--target={site_packages}"), "torch".to_string(), "torchvision".to_string(), "torchaudio".to_string(),
-          "--index-url".to_string(), "https://download.pytorch.org/whl/cu124".to_string(),
-        ];
-        let args = (arg_vals, );
-        pip_main.call1(py, args)?;
-      }
-
-      let torch = py.import("torch")?;
-      eprintln!("torch = {:?}", torch);
-
-
-      if let Err(e) = py.import("transformers") {
-        eprintln!("{:?}", e);
-        let arg_vals = vec![
-          "install".to_string(), format!("--target={site_packages}"), "transformers".to_string(),
-        ];
-        let args = (arg_vals, );
-        pip_main.call1(py, args)?;
-      }
-
-      let transformers = py.import("transformers")?;
-      eprintln!("transformers = {:?}", transformers);
-
-
-      if let Err(e) = py.import("diffusers") {
-        eprintln!("{:?}", e);
-        let arg_vals = vec![
-          "install".to_string(), format!("--target={site_packages}"), "diffusers".to_string(),
-        ];
-        let args = (arg_vals, );
-        pip_main.call1(py, args)?;
-      }
-
-      let diffusers = py.import("diffusers")?;
-      eprintln!("diffusers = {:?}", diffusers);
-
-      /*if let Err(e) = py.import("accelerate") {
-        eprintln!("{:?}", e);
-        let arg_vals = vec![
-          "install".to_string(), format!("--target={site_packages}"), "accelerate".to_string(),
-        ];
-        let args = (arg_vals, );
-        pip_main.call1(py, args)?;
+      for package in python_packages.iter() {
+        if let Err(e) = py.import(package.import.as_str()) {
+          eprintln!("{:?}", e);
+          if offline {
+            let missing = oliana_lib::models::MissingArtifactsError { what: format!("python packages under {site_packages}"), missing: vec![package.import.clone()] };
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(missing.to_string()));
+          }
+          let mut arg_vals = vec![
+            "install".to_string(), format!("--target={site_packages}"),
+          ];
+          arg_vals.extend(package.requirements.iter().cloned());
+          if let Some(index_url) = &package.index_url {
+            arg_vals.push("--index-url".to_string());
+            arg_vals.push(index_url.clone());
+          }
+          let args = (arg_vals, );
+          pip_main.call1(py, args)?;
+        }
+
+        let module = py.import(package.import.as_str())?;
+        eprintln!("{} = {:?}", package.import, module);
       }
 
-      let accelerate = py.import("accelerate")?;
-      eprintln!("accelerate = {:?}", accelerate);*/ // ^^ accelerate is more trouble than its worth
-
-      if let Err(e) = py.import("json5") {
-        eprintln!("{:?}", e);
-        let arg_vals = vec![
-          "install".to_string(), format!("--target={site_packages}"), "json5".to_string(),
-        ];
-        let args = (arg_vals, );
-        pip_main.call1(py, args)?;
-      }
-
-      let json5 = py.import("json5")?;
-      eprintln!("json5 = {:?}", json5);
+      // accelerate is more trouble than its worth, so it is not in the manifest
 
       let python_module = PyModule::from_code(
           py,
           c_str!(r#"
-def main(env_var_work_dir):
+def main(env_var_work_dir, heartbeat_file_name, failed_job_extension, model_dir):
   import traceback
   import os
   import time
+  import json
   import json5
   try:
     if hasattr(os, 'add_dll_directory'):
@@ -210,10 +253,8 @@ def main(env_var_work_dir):
   except:
     traceback.print_exc()
 
-  # You can replace the checkpoint id with several koala models as below:
-  # "etri-vilab/koala-lightning-700m"
-
-  pipe = StableDiffusionXLPipeline.from_pretrained("etri-vilab/koala-lightning-1b", torch_dtype=torch.float16)
+  # model_dir holds the files of the models manifest's "images" entry; point that at eg "etri-vilab/koala-lightning-700m" to swap models
+  pipe = StableDiffusionXLPipeline.from_pretrained(model_dir, torch_dtype=torch.float16)
   pipe = pipe.to("cuda")
 
   # Ensure sampler uses "trailing" timesteps and "sample" prediction type.
@@ -221,11 +262,53 @@ def main(env_var_work_dir):
     pipe.scheduler.config, timestep_spacing="trailing"
   )
 
-  # Now we poll env_var_work_dir forever!
+  # SDXL latents -> approximate RGB, cheap enough to run on every step (decoding w/ the VAE would double the cost of a run).
+  # Previews come out at 1/8th the final resolution.
+  latent_rgb_factors = torch.tensor([
+    [ 0.3651,  0.4232,  0.4341],
+    [-0.2533, -0.0042,  0.1068],
+    [ 0.1076,  0.1111, -0.0362],
+    [-0.3165, -0.2492, -0.2188],
+  ])
+  latent_rgb_factors_bias = torch.tensor([0.1084, -0.0175, -0.0011])
+
+  def write_preview(env_var_work_dir, file_name_no_extension, latents, step, num_steps):
+    from PIL import Image
+    rgb = latents[0].permute(1, 2, 0).to(device='cpu', dtype=torch.float32) @ latent_rgb_factors + latent_rgb_factors_bias
+    rgb = ((rgb + 1.0) / 2.0).clamp(0.0, 1.0).mul(255.0).to(torch.uint8).numpy()
+    preview_png_file = os.path.join(env_var_work_dir, f'{file_name_no_extension}.preview-{step}.png')
+    # Write-then-rename so readers never see a half-written frame
+    Image.fromarray(rgb).save(preview_png_file + '.tmp', format='PNG')
+    os.replace(preview_png_file + '.tmp', preview_png_file)
+    progress_file = os.path.join(env_var_work_dir, f'{file_name_no_extension}.progress')
+    with open(progress_file + '.tmp', 'w') as fd:
+      fd.write(json.dumps({'step': step, 'num_steps': num_steps}))
+    os.replace(progress_file + '.tmp', progress_file)
+    # Only the newest frame is kept around
+    previous_preview_png_file = os.path.join(env_var_work_dir, f'{file_name_no_extension}.preview-{step-1}.png')
+    if os.path.exists(previous_preview_png_file):
+      os.remove(previous_preview_png_file)
+
+  # Same format as oliana_lib::launchers::write_worker_heartbeat(); the caller beats while idle, we beat on every step of a job
+  heartbeat_file = os.path.join(env_var_work_dir, heartbeat_file_name)
+  def write_busy_heartbeat(file_name_no_extension):
+    with open(heartbeat_file + '.tmp', 'w') as fd:
+      fd.write(json.dumps({'pid': os.getpid(), 'activity': 'busy', 'job': file_name_no_extension, 'beat_at_ms': int(time.time() * 1000)}))
+    os.replace(heartbeat_file + '.tmp', heartbeat_file)
+
+  # Same format as oliana_lib::launchers::write_job_failed() writes (an oliana_lib::err::ErrorReport); our finally: block writes NAME.done after it
+  def write_job_failed(file_name_no_extension, message, traceback_str):
+    out_failed_file = os.path.join(env_var_work_dir, f'{file_name_no_extension}.{failed_job_extension}')
+    with open(out_failed_file + '-tmp', 'w') as fd:
+      fd.write(json.dumps({'kind': 'worker_failed', 'message': message, 'contexts': [], 'backtrace': traceback_str}))
+    os.replace(out_failed_file + '-tmp', out_failed_file)
+
+  # The caller waits for new files and calls poll_once() whenever env_var_work_dir changes
   our_start_time = int(time.time())
   last_seen_mtime = dict()
   allowed_errors_remaining = 100
-  while allowed_errors_remaining > 0:
+  def poll_once():
+    nonlocal allowed_errors_remaining
     try:
       for file_name in os.listdir(env_var_work_dir):
         full_path = os.path.join(env_var_work_dir, file_name)
@@ -236,11 +319,18 @@ def main(env_var_work_dir):
               # We either have NOT seen this file yet or it has been updated, process it!
               file_name_no_extension, _unused_ext = os.path.splitext(file_name)
               print(f'Processing {full_path}')
-              out_txt_file = os.path.join(env_var_work_dir, f'{file_name_no_extension}.txt')
               out_png_file = os.path.join(env_var_work_dir, f'{file_name_no_extension}.png')
+              in_cancel_file = os.path.join(env_var_work_dir, f'{file_name_no_extension}.cancel')
+              out_done_file = os.path.join(env_var_work_dir, f'{file_name_no_extension}.done')
               try:
                 last_seen_mtime[full_path] = file_mtime + 1
 
+                if os.path.exists(in_cancel_file):
+                  print(f'Skipping {full_path} because it was cancelled before it began')
+                  continue
+
+                write_busy_heartbeat(file_name_no_extension)
+
                 input_data = dict()
                 with open(full_path, 'r') as fd:
                   input_data = json5.loads(fd.read())
@@ -252,18 +342,43 @@ def main(env_var_work_dir):
                 guidance_scale = input_data.get('guidance_scale', 3.5)
                 num_inference_steps = int(input_data.get('num_inference_steps', 10))
 
-                image = pipe(prompt=prompt, negative_prompt=negative_prompt, guidance_scale=guidance_scale, num_inference_steps=num_inference_steps).images[0]
+                def on_step_end(pipe, step_index, timestep, callback_kwargs):
+                  write_busy_heartbeat(file_name_no_extension)
+                  if os.path.exists(in_cancel_file):
+                    pipe._interrupt = True # diffusers skips every remaining step once this is set
+                    return callback_kwargs
+                  try:
+                    write_preview(env_var_work_dir, file_name_no_extension, callback_kwargs['latents'], step_index + 1, num_inference_steps)
+                  except:
+                    traceback.print_exc() # A broken preview should never fail the run
+                  return callback_kwargs
+
+                image = pipe(prompt=prompt, negative_prompt=negative_prompt, guidance_scale=guidance_scale, num_inference_steps=num_inference_steps,
+                             callback_on_step_end=on_step_end, callback_on_step_end_tensor_inputs=['latents']).images[0]
+
+                if os.path.exists(in_cancel_file):
+                  print(f'Cancelled {full_path}')
+                  del image
+                  torch.cuda.empty_cache() # Hand the memory back for the next request
+                  continue
 
                 print(f'Saving {out_png_file}')
-                image.save(out_png_file)
+                # Write-then-rename so the server never reads a half-written image
+                image.save(out_png_file + '.tmp', format='PNG')
+                os.replace(out_png_file + '.tmp', out_png_file)
 
-              except:
+              except BaseException as e:
                 allowed_errors_remaining -= 1
                 traceback.print_exc()
+                exception_str = traceback.format_exc()
                 if 'KeyboardInterrupt' in exception_str: # We actually do want these to be fatal!
                   allowed_errors_remaining -= 999
-                with open(out_txt_file, 'w') as fd:
-                  fd.write(traceback.format_exc())
+                write_job_failed(file_name_no_extension, f'{type(e).__name__}: {e}', exception_str)
+
+              finally:
+                # Written whatever the outcome (including cancelled runs, which have no .png); the server frees the job's queue slot once it exists
+                with open(out_done_file, 'w') as fd:
+                  fd.write(' ')
 
     except:
       allowed_errors_remaining -= 1
@@ -271,9 +386,11 @@ def main(env_var_work_dir):
       exception_str = traceback.format_exc()
 
       if 'KeyboardInterrupt' in exception_str: # We actually do want these to be fatal!
-        break
+        return False
+
+    return allowed_errors_remaining > 0
 
-    time.sleep(0.100) # Poll several times a second for new work
+  return poll_once
 
 "#),
           c_str!("in_memory.py"),
@@ -282,9 +399,9 @@ def main(env_var_work_dir):
 
       let python_entry_fn: Py<PyAny> = python_module.getattr("main")?.into();
 
-      python_entry_fn.call1(py, (env_var_work_dir, ) )?;
+      let poll_workdir_once = python_entry_fn.call1(py, (env_var_work_dir, oliana_lib::launchers::WORKER_HEARTBEAT_FILE_NAME, oliana_lib::launchers::FAILED_JOB_EXTENSION, model_dir) )?;
 
-      Ok(())
+      Ok(poll_workdir_once)
   })
 }
 
@@ -1,3 +1,95 @@
+// Errors are Box<dyn Error>s which pick up a LocatedError (through eloc!()) everywhere they are passed on, so nested LocatedErrors
+// record the path an error took. ErrorReport flattens that chain into plain data which serializes, so it survives a trip through a
+// worker's <job>.failed file (see launchers::write_job_failed) or an OlianaError sent to a client.
+
+/// What went wrong, for callers deciding what to do about an error. The serialized names (see code()) never change once released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Io,
+    NotFound,
+    InvalidInput,
+    Network,
+    /// A downloaded or cached file failed its sha256 / size check; see files::IntegrityError
+    Integrity,
+    /// Offline and a model file or python package is missing; see models::MissingArtifactsError
+    MissingArtifacts,
+    /// See files::CacheEntryInUse
+    InUse,
+    Timeout,
+    Cancelled,
+    /// A worker process crashed, hung or failed a job
+    WorkerFailed,
+    /// Nothing more specific is known; kinds a peer built later knows about also read as this. New kinds go above.
+    #[default]
+    #[serde(other)]
+    Other,
+}
+
+impl ErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::Io => "io",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::InvalidInput => "invalid_input",
+            ErrorKind::Network => "network",
+            ErrorKind::Integrity => "integrity",
+            ErrorKind::MissingArtifacts => "missing_artifacts",
+            ErrorKind::InUse => "in_use",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Cancelled => "cancelled",
+            ErrorKind::WorkerFailed => "worker_failed",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    /// The kind of the outermost error in `e`'s chain that has one; LocatedErrors and ErrorReports carry theirs, a few well-known error types are recognized
+    pub fn of(e: &(dyn std::error::Error + 'static)) -> ErrorKind {
+        let mut cur = Some(e);
+        while let Some(e) = cur {
+            if let Some(located) = e.downcast_ref::<LocatedError>() {
+                return located.kind;
+            }
+            if let Some(report) = e.downcast_ref::<ErrorReport>() {
+                return report.kind;
+            }
+            if let Some(io_error) = e.downcast_ref::<std::io::Error>() {
+                return match io_error.kind() {
+                    std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+                    std::io::ErrorKind::TimedOut => ErrorKind::Timeout,
+                    std::io::ErrorKind::InvalidInput | std::io::ErrorKind::InvalidData => ErrorKind::InvalidInput,
+                    _ => ErrorKind::Io,
+                };
+            }
+            if let Some(reqwest_error) = e.downcast_ref::<reqwest::Error>() {
+                return if reqwest_error.is_timeout() { ErrorKind::Timeout } else { ErrorKind::Network };
+            }
+            if e.is::<crate::files::IntegrityError>() {
+                return ErrorKind::Integrity;
+            }
+            if e.is::<crate::models::MissingArtifactsError>() {
+                return ErrorKind::MissingArtifacts;
+            }
+            if e.is::<crate::files::CacheEntryInUse>() {
+                return ErrorKind::InUse;
+            }
+            if e.is::<tokio::time::error::Elapsed>() {
+                return ErrorKind::Timeout;
+            }
+            if e.is::<serde_json::Error>() || e.is::<toml::de::Error>() {
+                return ErrorKind::InvalidInput;
+            }
+            cur = e.source();
+        }
+        ErrorKind::Other
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
 
 #[derive(Debug)]
 pub struct LocatedError {
@@ -6,6 +98,43 @@ pub struct LocatedError {
     pub line: u32,
     pub column: u32,
     pub addtl_msg: String,
+    /// Inferred from `inner` (see ErrorKind::of) unless set with with_kind()
+    pub kind: ErrorKind,
+    /// Captured where the error was first located, when RUST_BACKTRACE or RUST_LIB_BACKTRACE is set
+    pub backtrace: Option<String>,
+}
+
+impl LocatedError {
+    pub fn new(inner: Box<dyn std::error::Error>, file: &'static str, line: u32, column: u32, addtl_msg: String) -> Self {
+        let inner_has_backtrace = inner.downcast_ref::<LocatedError>().map(|located| located.backtrace.is_some()).unwrap_or(false);
+        let backtrace = if inner_has_backtrace { None } else { capture_backtrace() };
+        Self {
+            kind: ErrorKind::of(&*inner),
+            inner: inner,
+            file: file,
+            line: line,
+            column: column,
+            addtl_msg: addtl_msg,
+            backtrace: backtrace,
+        }
+    }
+
+    pub fn with_kind(mut self, kind: ErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn report(&self) -> ErrorReport {
+        ErrorReport::from_error(self)
+    }
+}
+
+fn capture_backtrace() -> Option<String> {
+    let backtrace = std::backtrace::Backtrace::capture();
+    match backtrace.status() {
+        std::backtrace::BacktraceStatus::Captured => Some(backtrace.to_string()),
+        _ => None,
+    }
 }
 
 impl std::error::Error for LocatedError {
@@ -25,24 +154,121 @@ impl std::fmt::Display for LocatedError {
     }
 }
 
-// The core idea: convenience macro to create the structure
+/// One eloc!() an error passed through
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ErrorContext {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+    #[serde(default)]
+    pub msg: String,
+}
+
+/// A LocatedError chain as plain data; Display prints the same text the chain did.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ErrorReport {
+    pub kind: ErrorKind,
+    /// The innermost error, ie "No such file or directory (os error 2)"
+    pub message: String,
+    /// Innermost first
+    #[serde(default)]
+    pub contexts: Vec<ErrorContext>,
+    #[serde(default)]
+    pub backtrace: Option<String>,
+}
+
+impl ErrorReport {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind: kind,
+            message: message.into(),
+            contexts: vec![],
+            backtrace: None,
+        }
+    }
+
+    /// Flattens `e` and every LocatedError (or ErrorReport, ie one read back from a worker) nested in it
+    pub fn from_error(e: &(dyn std::error::Error + 'static)) -> Self {
+        let mut contexts = vec![]; // Outermost first until the end
+        let mut backtrace = None;
+        let mut cur = e;
+        let mut message = None;
+        while message.is_none() {
+            if let Some(located) = cur.downcast_ref::<LocatedError>() {
+                contexts.push(ErrorContext {
+                    file: located.file.to_string(),
+                    line: located.line,
+                    column: located.column,
+                    msg: located.addtl_msg.clone(),
+                });
+                backtrace = located.backtrace.clone().or(backtrace);
+                cur = &*located.inner;
+            }
+            else if let Some(report) = cur.downcast_ref::<ErrorReport>() {
+                contexts.extend(report.contexts.iter().rev().cloned());
+                backtrace = report.backtrace.clone().or(backtrace);
+                message = Some(report.message.clone());
+            }
+            else {
+                message = Some(cur.to_string());
+            }
+        }
+        contexts.reverse();
+        Self {
+            kind: ErrorKind::of(e),
+            message: message.unwrap_or_default(),
+            contexts: contexts,
+            backtrace: backtrace,
+        }
+    }
+
+    /// file:line:column the error was first located at
+    pub fn location(&self) -> Option<String> {
+        self.contexts.first().map(|context| format!("{}:{}:{}", context.file, context.line, context.column))
+    }
+}
+
+impl std::error::Error for ErrorReport { }
+
+impl std::fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        for context in self.contexts.iter() {
+            if context.msg.len() > 0 {
+                write!(f, " from {}:{} ({})", context.file, context.line, context.msg)?;
+            }
+            else {
+                write!(f, " from {}:{}", context.file, context.line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The core idea: convenience macro to create the structure. eloc!(kind = ErrorKind::X) / eloc!(kind = ErrorKind::X, msg) also set its kind.
 #[macro_export]
 macro_rules! eloc {
     () => {
-        |e| oliana_lib::err::LocatedError { inner: e.into(), file: file!(), line: line!(), column: column!(), addtl_msg: String::new() }
+        |e| oliana_lib::err::LocatedError::new(e.into(), file!(), line!(), column!(), String::new())
+    };
+    (kind = $kind:expr) => {
+        |e| oliana_lib::err::LocatedError::new(e.into(), file!(), line!(), column!(), String::new()).with_kind($kind)
+    };
+    (kind = $kind:expr, $msg:expr) => {
+        |e| oliana_lib::err::LocatedError::new(e.into(), file!(), line!(), column!(), $msg).with_kind($kind)
     };
     ($msg:expr) => {
-        |e| oliana_lib::err::LocatedError { inner: e.into(), file: file!(), line: line!(), column: column!(), addtl_msg: $msg }
+        |e| oliana_lib::err::LocatedError::new(e.into(), file!(), line!(), column!(), $msg)
     };
 }
 
 #[macro_export]
 macro_rules! eloc_str {
     () => {
-        |e| oliana_lib::err::LocatedError { inner: format!("{:?}", e).into(), file: file!(), line: line!(), column: column!(), addtl_msg: String::new() }
+        |e| oliana_lib::err::LocatedError::new(format!("{:?}", e).into(), file!(), line!(), column!(), String::new())
     };
     ($msg:expr) => {
-        |e| oliana_lib::err::LocatedError { inner: format!("{:?}", e).into(), file: file!(), line: line!(), column: column!(), addtl_msg: $msg }
+        |e| oliana_lib::err::LocatedError::new(format!("{:?}", e).into(), file!(), line!(), column!(), $msg)
     };
 }
 
@@ -1,39 +1,327 @@
 
 use crate as oliana_lib; // This helps our crate::err::eloc!() leak state via a struct
 
+/// Returns `local_file_path`, downloading it from `remote_download_url` first if it does not exist yet.
+/// See existinate_from_mirrors(), which this calls with one mirror and DownloadOptions::default().
 pub async fn existinate(
   local_file_path: impl Into<std::path::PathBuf>,
-  remote_download_url: &str
+  remote_download_url: &str,
+  expected: Option<&ExpectedFile>,
+) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+  existinate_from_mirrors(local_file_path, &[remote_download_url], expected, &DownloadOptions::default()).await
+}
+
+/// Returns `local_file_path`, fetching it from the first of `mirrors` which can provide it if it does not exist yet.
+/// A mirror is an http(s):// URL, a file:// URL or a local path; file:// URLs and paths naming a directory have the file name of
+/// `local_file_path` looked up in that directory, so air-gapped hosts can be seeded from a USB stick or NFS share.
+///
+/// With `expected` set, an existing file which does not match it (ie a truncated download from before a crash) is fetched again,
+/// and a download is only moved into place once it matches; one that does not is deleted and the next mirror tried, an IntegrityError
+/// being returned if none had a matching file.
+/// Downloads are written next to `local_file_path` as <file name>.download and renamed over it, so a crash never leaves a partial file
+/// behind under the real name; a later call resumes the .download file with an HTTP Range request instead of starting over.
+pub async fn existinate_from_mirrors(
+  local_file_path: impl Into<std::path::PathBuf>,
+  mirrors: &[impl AsRef<str>],
+  expected: Option<&ExpectedFile>,
+  options: &DownloadOptions,
 ) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
   let local_file_path = local_file_path.into();
+  let expected = expected.cloned().unwrap_or_default();
+  let mirrors: Vec<&str> = mirrors.iter().map(|mirror| mirror.as_ref()).filter(|mirror| mirror.len() > 0).collect();
+
+  if tokio::fs::try_exists(&local_file_path).await? {
+    match verify_file(&local_file_path, &expected).await {
+      Ok(()) => {
+        eprintln!("Found already-downloaded file {}", &local_file_path.to_string_lossy() );
+        return Ok(local_file_path);
+      }
+      Err(e) if e.is::<IntegrityError>() && mirrors.len() > 0 => {
+        eprintln!("{}, downloading it again", e);
+        tokio::fs::remove_file(&local_file_path).await.map_err(crate::err::eloc!())?;
+      }
+      Err(e) => return Err(e),
+    }
+  }
+
+  if mirrors.len() < 1 {
+    return Err(format!("The file {:?} does not exist and no URL was passed to download it!", &local_file_path).into());
+  }
 
-  if !tokio::fs::try_exists(&local_file_path).await? {
-    eprintln!("Downloading {} to {}", remote_download_url, &local_file_path.to_string_lossy() );
-    if remote_download_url.len() < 1 {
-      return Err(format!("The file {:?} does not exist and no URL was passed to download it!", &local_file_path).into());
+  let download_folder = local_file_path.parent().ok_or_else(|| return "No Parent Directory for passed file to be downloaded!" ).map_err(crate::err::eloc!())?;
+  tokio::fs::create_dir_all(download_folder).await.map_err(crate::err::eloc!())?;
+  let partial_file_path = get_partial_download_path(&local_file_path)?;
+  let client = reqwest::Client::builder()
+    .connect_timeout(options.connect_timeout)
+    .read_timeout(options.read_timeout)
+    .build().map_err(crate::err::eloc!())?;
+
+  let mut last_error: Option<Box<dyn std::error::Error>> = None;
+  for mirror in mirrors {
+    eprintln!("Downloading {} to {}", mirror, &local_file_path.to_string_lossy() );
+    if let Err(e) = download_from_mirror(&client, mirror, &local_file_path, &partial_file_path, options).await {
+      eprintln!("Could not download {} from {}: {}", &local_file_path.to_string_lossy(), mirror, e);
+      last_error = Some(e);
+      continue;
     }
+    if let Err(e) = verify_file(&partial_file_path, &expected).await {
+      eprintln!("Discarding download from {}: {}", mirror, e);
+      let _ = tokio::fs::remove_file(&partial_file_path).await;
+      last_error = Some(e);
+      continue;
+    }
+    tokio::fs::rename(&partial_file_path, &local_file_path).await.map_err(crate::err::eloc!())?;
+    return Ok(local_file_path);
+  }
+  Err(last_error.unwrap_or_else(|| format!("No mirror could provide {:?}", &local_file_path).into()))
+}
+
+/// Retry and timeout settings for existinate_from_mirrors()
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadOptions {
+  /// Tries per mirror for failures which may go away (connection errors, dropped connections, 5xx, 408 and 429) before moving on to the next mirror
+  pub max_attempts_per_mirror: u32,
+  /// Wait before the first retry, doubling with every retry after it up to max_backoff
+  pub initial_backoff: std::time::Duration,
+  pub max_backoff: std::time::Duration,
+  pub connect_timeout: std::time::Duration,
+  /// A connection which delivers nothing for this long counts as dropped
+  pub read_timeout: std::time::Duration,
+}
+
+impl Default for DownloadOptions {
+  fn default() -> Self {
+    Self {
+      max_attempts_per_mirror: 5,
+      initial_backoff: std::time::Duration::from_secs(1),
+      max_backoff: std::time::Duration::from_secs(30),
+      connect_timeout: std::time::Duration::from_secs(30),
+      read_timeout: std::time::Duration::from_secs(60),
+    }
+  }
+}
+
+// Whether retrying the same mirror could help
+enum FetchError {
+  Transient(Box<dyn std::error::Error>),
+  Permanent(Box<dyn std::error::Error>),
+}
 
-    let mut downloader = downloader::Downloader::builder()
-          .download_folder( local_file_path.parent().ok_or_else(|| return "No Parent Directory for passed file to be downloaded!" ).map_err(crate::err::eloc!())? )
-          .parallel_requests(2)
-          .build()?;
-    let dl_file_name_osstr = local_file_path.file_name().ok_or_else(|| return "No File Name for passed file to be downloaded!" ).map_err(crate::err::eloc!())?;
-    let dl_file_name_string = dl_file_name_osstr.to_string_lossy().into_owned();
+async fn download_from_mirror(
+  client: &reqwest::Client,
+  mirror: &str,
+  local_file_path: &std::path::Path,
+  partial_file_path: &std::path::Path,
+  options: &DownloadOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+  if let Some(source_path) = get_local_mirror_path(mirror, local_file_path)? {
+    tokio::fs::copy(&source_path, partial_file_path).await.map_err(crate::err::eloc!(format!("Copying {}", source_path.display())))?;
+    return Ok(());
+  }
 
-    let dl = downloader::Download::new(remote_download_url)
-                .file_name( &std::path::Path::new( &dl_file_name_string ) )
-                .progress(std::sync::Arc::new(
-                  DownloadProgressReporter::new()
-                ));
+  let mut backoff = options.initial_backoff;
+  let mut attempt = 1;
+  loop {
+    match fetch_url(client, mirror, partial_file_path).await {
+      Ok(()) => return Ok(()),
+      Err(FetchError::Permanent(e)) => return Err(e),
+      Err(FetchError::Transient(e)) if attempt >= options.max_attempts_per_mirror => return Err(e),
+      Err(FetchError::Transient(e)) => {
+        eprintln!("Attempt {} of {} to download {} failed ({}), retrying in {:?}", attempt, options.max_attempts_per_mirror, mirror, e, backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = std::cmp::min(backoff * 2, options.max_backoff);
+        attempt += 1;
+      }
+    }
+  }
+}
 
-    let _result = downloader.async_download(&[dl]).await?;
+/// The file a file:// URL or local path mirror points to, or None for mirrors which have to be downloaded
+pub fn get_local_mirror_path(mirror: &str, local_file_path: &std::path::Path) -> Result<Option<std::path::PathBuf>, Box<dyn std::error::Error>> {
+  let mirror_path = match mirror.strip_prefix("file://") {
+    // file:///C:/models on windows
+    Some(path) if cfg!(target_os="windows") && path.len() > 2 && path.as_bytes()[2] == b':' => std::path::PathBuf::from(&path[1..]),
+    Some(path) => std::path::PathBuf::from(path),
+    None if mirror.contains("://") => return Ok(None),
+    None => std::path::PathBuf::from(mirror),
+  };
+  if mirror_path.is_dir() {
+    let file_name = local_file_path.file_name().ok_or_else(|| return "No File Name for passed file to be downloaded!" ).map_err(crate::err::eloc!())?;
+    return Ok(Some(mirror_path.join(file_name)));
+  }
+  Ok(Some(mirror_path))
+}
 
+// One GET of `url` into `partial_file_path`, continuing from whatever is already in it if the server supports Range requests
+async fn fetch_url(client: &reqwest::Client, url: &str, partial_file_path: &std::path::Path) -> Result<(), FetchError> {
+  use tokio::io::AsyncWriteExt;
+  let resume_from = match tokio::fs::metadata(partial_file_path).await {
+    Ok(metadata) => metadata.len(),
+    Err(_) => 0,
+  };
+
+  let mut request = client.get(url);
+  if resume_from > 0 {
+    request = request.header(reqwest::header::RANGE, format!("bytes={}-", resume_from));
+  }
+  let mut response = request.send().await.map_err(|e| FetchError::Transient(e.into()))?;
+  let status = response.status();
+
+  let append = if status == reqwest::StatusCode::PARTIAL_CONTENT && resume_from > 0 {
+    let content_range = response.headers().get(reqwest::header::CONTENT_RANGE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    if get_content_range_start(content_range) != Some(resume_from) {
+      let _ = tokio::fs::remove_file(partial_file_path).await;
+      return Err(FetchError::Transient(format!("{} resumed at the wrong offset ({:?}), starting over", url, content_range).into()));
+    }
+    true
+  }
+  else if status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE && resume_from > 0 {
+    // The partial file is as long as the whole file (or longer, so not this file); verifying it tells which
+    let content_range = response.headers().get(reqwest::header::CONTENT_RANGE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    if content_range == format!("bytes */{}", resume_from) {
+      return Ok(());
+    }
+    let _ = tokio::fs::remove_file(partial_file_path).await;
+    return Err(FetchError::Transient(format!("{} has less than the {} bytes already downloaded, starting over", url, resume_from).into()));
+  }
+  else if status.is_success() {
+    false // The server ignored the Range header and sent everything
+  }
+  else if status.is_server_error() || status == reqwest::StatusCode::REQUEST_TIMEOUT || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+    return Err(FetchError::Transient(format!("{} answered {}", url, status).into()));
+  }
+  else {
+    return Err(FetchError::Permanent(format!("{} answered {}", url, status).into()));
+  };
+
+  let mut file = if append {
+    tokio::fs::OpenOptions::new().append(true).open(partial_file_path).await
   }
   else {
-    eprintln!("Found already-downloaded file {}", &local_file_path.to_string_lossy() );
+    tokio::fs::File::create(partial_file_path).await
+  }.map_err(|e| FetchError::Permanent(format!("Opening {}: {}", partial_file_path.display(), e).into()))?;
+
+  let already_written = if append { resume_from } else { 0 };
+  let progress = DownloadProgressReporter::new();
+  if let Some(content_length) = response.content_length() {
+    progress.setup(Some(already_written + content_length));
+  }
+  progress.progress(already_written);
+
+  let mut written = already_written;
+  loop {
+    match response.chunk().await {
+      Ok(Some(bytes)) => {
+        file.write_all(&bytes).await.map_err(|e| FetchError::Permanent(format!("Writing {}: {}", partial_file_path.display(), e).into()))?;
+        written += bytes.len() as u64;
+        progress.progress(written);
+      }
+      Ok(None) => break,
+      Err(e) => {
+        let _ = file.flush().await; // Keep what arrived for the next attempt to resume from
+        return Err(FetchError::Transient(format!("Connection to {} dropped after {} bytes: {}", url, written, e).into()));
+      }
+    }
+  }
+  // On disk before it is renamed into place, or a power cut could leave a file of zeros under the real name
+  file.sync_all().await.map_err(|e| FetchError::Permanent(format!("Writing {}: {}", partial_file_path.display(), e).into()))?;
+  progress.done();
+  Ok(())
+}
+
+// "bytes 100-199/200" -> Some(100)
+fn get_content_range_start(content_range: &str) -> Option<u64> {
+  let range = content_range.strip_prefix("bytes ")?;
+  let (start, _) = range.split_once('-')?;
+  start.trim().parse().ok()
+}
+
+/// Where existinate() writes `local_file_path` while it is being downloaded
+pub fn get_partial_download_path(local_file_path: &std::path::Path) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+  let file_name = local_file_path.file_name().ok_or_else(|| return "No File Name for passed file to be downloaded!" ).map_err(crate::err::eloc!())?;
+  let mut partial_file_name = file_name.to_os_string();
+  partial_file_name.push(".download");
+  Ok(local_file_path.with_file_name(partial_file_name))
+}
+
+/// What a file must look like to be trusted; checks left as None are skipped
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExpectedFile {
+  /// Hex digest, either case
+  pub sha256: Option<String>,
+  pub size_bytes: Option<u64>,
+}
+
+impl ExpectedFile {
+  pub fn sha256(sha256: &str) -> Self {
+    Self {
+      sha256: Some(sha256.to_string()),
+      size_bytes: None,
+    }
+  }
+
+  pub fn with_size_bytes(mut self, size_bytes: u64) -> Self {
+    self.size_bytes = Some(size_bytes);
+    self
+  }
+}
+
+/// A file on disk does not match its ExpectedFile. Returned un-wrapped so callers can `e.downcast_ref::<IntegrityError>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrityError {
+  WrongSize { path: std::path::PathBuf, expected: u64, actual: u64 },
+  WrongSha256 { path: std::path::PathBuf, expected: String, actual: String },
+}
+
+impl std::fmt::Display for IntegrityError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      IntegrityError::WrongSize { path, expected, actual } => write!(f, "{} is {} bytes, expected {}", path.display(), actual, expected),
+      IntegrityError::WrongSha256 { path, expected, actual } => write!(f, "{} has sha256 {}, expected {}", path.display(), actual, expected),
+    }
+  }
+}
+
+impl std::error::Error for IntegrityError { }
+
+/// Checks the file at `path` against `expected`, size first since that is free. Mismatches are returned as an IntegrityError.
+pub async fn verify_file(path: &std::path::Path, expected: &ExpectedFile) -> Result<(), Box<dyn std::error::Error>> {
+  if let Some(expected_size) = expected.size_bytes {
+    let actual_size = tokio::fs::metadata(path).await.map_err(crate::err::eloc!(format!("Reading {}", path.display())))?.len();
+    if actual_size != expected_size {
+      return Err(Box::new(IntegrityError::WrongSize { path: path.to_path_buf(), expected: expected_size, actual: actual_size }));
+    }
+  }
+  if let Some(expected_sha256) = &expected.sha256 {
+    let actual_sha256 = sha256_of_file(path).await?;
+    if !actual_sha256.eq_ignore_ascii_case(expected_sha256.trim()) {
+      return Err(Box::new(IntegrityError::WrongSha256 { path: path.to_path_buf(), expected: expected_sha256.trim().to_lowercase(), actual: actual_sha256 }));
+    }
   }
+  Ok(())
+}
 
-  Ok(local_file_path)
+/// Lowercase hex SHA-256 of the file at `path`, read on a blocking thread since model files run to several GB
+pub async fn sha256_of_file(path: &std::path::Path) -> Result<String, Box<dyn std::error::Error>> {
+  let path = path.to_path_buf();
+  let path_name = path.display().to_string();
+  let digest = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+    use sha2::Digest;
+    use std::io::Read;
+    let mut file = std::fs::File::open(&path)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+      let n = file.read(&mut buf)?;
+      if n < 1 {
+        break;
+      }
+      hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+  }).await.map_err(crate::err::eloc!())?.map_err(crate::err::eloc!(format!("Hashing {}", path_name)))?;
+  Ok(digest)
 }
 
 
@@ -52,32 +340,25 @@ impl DownloadProgressReporter {
             bar: indicatif::ProgressBar::no_length()
         }
     }
-}
-
-impl Drop for DownloadProgressReporter {
-    fn drop(&mut self) {
-        self.bar.finish();
-    }
-}
-
-
-impl downloader::progress::Reporter for DownloadProgressReporter {
-    fn setup(&self, max_progress: std::option::Option<u64>, message: &str) {
-        unsafe { *self.max_progress.get() = max_progress.into(); } // Assigns into a read-only reference; safe because I say the compiler won't optimize through an UnsafeCell
+    pub fn setup(&self, max_progress: std::option::Option<u64>) {
+        unsafe { *self.max_progress.get() = max_progress; } // Assigns into a read-only reference; safe because I say the compiler won't optimize through an UnsafeCell
         if let Some(max_progress_val) = max_progress {
             self.bar.set_length(max_progress_val);
         }
     }
-    fn progress(&self, current: u64) {
+    pub fn progress(&self, current: u64) {
         if current > self.bar.position() {
             let incr_amnt = current - self.bar.position();
             self.bar.inc(incr_amnt);
         }
     }
-    fn set_message(&self, message: &str) {
-
+    pub fn done(&self) {
+        self.bar.finish();
     }
-    fn done(&self) {
+}
+
+impl Drop for DownloadProgressReporter {
+    fn drop(&mut self) {
         self.bar.finish();
     }
 }
@@ -85,19 +366,306 @@ impl downloader::progress::Reporter for DownloadProgressReporter {
 
 
 
-pub async fn get_cache_dir() -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+
+// The cache root (where models, python packages and hf_home folders live) is, in order of precedence:
+//   $OLIANA_CACHE_DIR (oliana_server sets it for its workers from cache_dir in its config),
+//   the directory named in an oliana_portable.txt beside the executable (relative to it; empty means ./oliana_cache), so a USB build keeps everything next to itself,
+//   <dirs::cache_dir()>/oliana_lib
+
+pub const CACHE_DIR_ENV_VAR: &str = "OLIANA_CACHE_DIR";
+pub const PORTABLE_MARKER_FILE_NAME: &str = "oliana_portable.txt";
+pub const PORTABLE_CACHE_DIR_NAME: &str = "oliana_cache";
+/// Under dirs::cache_dir(); this used to be env!("CARGO_PKG_NAME"), kept so existing caches are still found
+pub const DEFAULT_CACHE_DIR_NAME: &str = "oliana_lib";
+
+/// Where get_cache_dir() points, without creating it
+pub fn resolve_cache_dir() -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+  let exe = std::env::current_exe().ok();
+  resolve_cache_dir_from(std::env::var_os(CACHE_DIR_ENV_VAR).as_deref(), exe.as_deref())
+}
+
+/// resolve_cache_dir() given the value of $OLIANA_CACHE_DIR and the path of the running executable
+pub fn resolve_cache_dir_from(cache_dir_env: Option<&std::ffi::OsStr>, exe: Option<&std::path::Path>) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+  if let Some(cache_dir_env) = cache_dir_env {
+    if cache_dir_env.len() > 0 {
+      // Relative to where we were started, made absolute so children started elsewhere agree
+      return Ok(std::env::current_dir().map_err(crate::err::eloc!())?.join(cache_dir_env));
+    }
+  }
+  if let Some(exe_dir) = exe.and_then(|exe| exe.parent()) {
+    let marker = exe_dir.join(PORTABLE_MARKER_FILE_NAME);
+    if marker.is_file() {
+      let marker_contents = std::fs::read_to_string(&marker).map_err(crate::err::eloc!(format!("Reading {}", marker.display())))?;
+      let marker_contents = marker_contents.trim();
+      return Ok(exe_dir.join(if marker_contents.len() > 0 { marker_contents } else { PORTABLE_CACHE_DIR_NAME }));
+    }
+  }
   let mut user_cache_path = dirs::cache_dir().ok_or_else(|| return "No Cache Directory on this operating system!" ).map_err(crate::err::eloc!())?;
-  user_cache_path.push(env!("CARGO_PKG_NAME"));
-  tokio::fs::create_dir_all(&user_cache_path).await?;
+  user_cache_path.push(DEFAULT_CACHE_DIR_NAME);
   Ok(user_cache_path)
 }
 
+pub async fn get_cache_dir() -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+  let cache_path = resolve_cache_dir()?;
+  tokio::fs::create_dir_all(&cache_path).await.map_err(crate::err::eloc!(format!("Creating {}", cache_path.display())))?;
+  Ok(cache_path)
+}
+
 pub async fn get_cache_file(file_name: &str) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
     let mut pb = get_cache_dir().await?;
     pb.push(file_name);
     Ok(pb)
 }
 
+// Cache entries: every file or directory directly under get_cache_dir() (ie Oliana-Text-hf_home), except that each directory under
+// one of CACHE_GROUP_DIRS (models/, see oliana_lib::models) is an entry of its own. Workers hold a CacheLease on the entries they use
+// for as long as they run; it records when the entry was last used and keeps prune_cache_entry() / enforce_cache_quota() off it.
+
+pub const CACHE_GROUP_DIRS: &[&str] = &["models"];
+const CACHE_LAST_USED_FILE_NAME: &str = ".oliana-last-used";
+const CACHE_LEASE_FILE_PREFIX: &str = ".oliana-lease-";
+const CACHE_PRUNING_PREFIX: &str = ".oliana-pruning-";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+  /// Relative to the cache root with / separators, ie "models/microsoft--Phi-3.5-mini-instruct"; what prune_cache_entry() takes
+  pub name: String,
+  pub path: std::path::PathBuf,
+  pub size_bytes: u64,
+  /// When a lease on it was last taken or released, else the newest modification of anything in it
+  pub last_used: std::time::SystemTime,
+  /// PIDs of running processes holding a lease on it
+  pub in_use_by: Vec<u32>,
+}
+
+/// Every entry under `cache_root`, most recently used first
+pub fn list_cache_entries(cache_root: &std::path::Path) -> Result<Vec<CacheEntry>, Box<dyn std::error::Error>> {
+  let mut entries = vec![];
+  if !cache_root.exists() {
+    return Ok(entries);
+  }
+  let mut sinfo = sysinfo::System::new();
+  sinfo.refresh_processes(sysinfo::ProcessesToUpdate::All, true);
+  for (name, path) in list_cache_entry_paths(cache_root)? {
+    let (size_bytes, newest_mtime) = measure_cache_entry(&path);
+    let last_used = std::fs::metadata(path.join(CACHE_LAST_USED_FILE_NAME)).and_then(|metadata| metadata.modified()).unwrap_or(newest_mtime);
+    entries.push(CacheEntry {
+      in_use_by: live_cache_lease_pids(&path, &sinfo),
+      name: name,
+      path: path,
+      size_bytes: size_bytes,
+      last_used: last_used,
+    });
+  }
+  entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_used));
+  Ok(entries)
+}
+
+/// Name of the cache entry holding `relative_path` (relative to the cache root), ie "models/etri-vilab--koala-lightning-1b" for "models/etri-vilab--koala-lightning-1b/unet"
+pub fn cache_entry_name(relative_path: &std::path::Path) -> Option<String> {
+  let mut components = relative_path.components().filter_map(|component| match component {
+    std::path::Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+    _ => None,
+  });
+  let first = components.next()?;
+  if CACHE_GROUP_DIRS.contains(&first.as_str()) {
+    if let Some(second) = components.next() {
+      return Some(format!("{}/{}", first, second));
+    }
+  }
+  Some(first)
+}
+
+fn list_cache_entry_paths(cache_root: &std::path::Path) -> Result<Vec<(String, std::path::PathBuf)>, Box<dyn std::error::Error>> {
+  let mut entry_paths = vec![];
+  for dir_entry in std::fs::read_dir(cache_root).map_err(crate::err::eloc!(format!("Reading {}", cache_root.display())))? {
+    let dir_entry = dir_entry.map_err(crate::err::eloc!())?;
+    let name = dir_entry.file_name().to_string_lossy().to_string();
+    if name.starts_with('.') {
+      continue; // Our own bookkeeping, ie a prune which was interrupted
+    }
+    if CACHE_GROUP_DIRS.contains(&name.as_str()) && dir_entry.path().is_dir() {
+      for group_entry in std::fs::read_dir(dir_entry.path()).map_err(crate::err::eloc!())? {
+        let group_entry = group_entry.map_err(crate::err::eloc!())?;
+        let group_entry_name = group_entry.file_name().to_string_lossy().to_string();
+        if !group_entry_name.starts_with('.') {
+          entry_paths.push((format!("{}/{}", name, group_entry_name), group_entry.path()));
+        }
+      }
+      continue;
+    }
+    entry_paths.push((name, dir_entry.path()));
+  }
+  Ok(entry_paths)
+}
+
+// Total size and newest mtime of the files under `path`, leaving out our own markers; symlinks (ie from a hf_home's snapshots/ into its blobs/)
+// are not followed, so nothing is counted twice
+fn measure_cache_entry(path: &std::path::Path) -> (u64, std::time::SystemTime) {
+  let mut size_bytes = 0;
+  let mut newest_mtime = None;
+  for entry in walkdir::WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+    if entry.file_name().to_string_lossy().starts_with(".oliana-") {
+      continue;
+    }
+    if let Ok(metadata) = entry.metadata() {
+      if !metadata.is_file() {
+        continue;
+      }
+      size_bytes += metadata.len();
+      if let Ok(mtime) = metadata.modified() {
+        newest_mtime = std::cmp::max(newest_mtime, Some(mtime));
+      }
+    }
+  }
+  // An empty directory was last used when it was created
+  let newest_mtime = newest_mtime.or_else(|| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()).unwrap_or(std::time::SystemTime::UNIX_EPOCH);
+  (size_bytes, newest_mtime)
+}
+
+// Leases left behind by a process which crashed (or whose PID now belongs to another program) do not count
+fn live_cache_lease_pids(entry_path: &std::path::Path, sinfo: &sysinfo::System) -> Vec<u32> {
+  let mut pids = vec![];
+  let dir_iterator = match std::fs::read_dir(entry_path) {
+    Ok(dir_iterator) => dir_iterator,
+    Err(_) => return pids, // A file, or gone
+  };
+  for dir_entry in dir_iterator.filter_map(|e| e.ok()) {
+    if !dir_entry.file_name().to_string_lossy().starts_with(CACHE_LEASE_FILE_PREFIX) {
+      continue;
+    }
+    if let Ok(Some(record)) = crate::launchers::read_pid_record(&dir_entry.path()) {
+      if let Some(process) = sinfo.process(sysinfo::Pid::from_u32(record.pid)) {
+        let exe = record.exe.clone().unwrap_or_default();
+        if record.matches(process, &exe) {
+          pids.push(record.pid);
+        }
+      }
+    }
+  }
+  pids
+}
+
+/// Held by a worker for as long as it uses a cache entry; dropping it releases the entry
+pub struct CacheLease {
+  pub entry_path: std::path::PathBuf,
+  lease_file: std::path::PathBuf,
+}
+
+/// Marks the cache entry at `entry_path` (created if missing) as used by this process until the returned lease is dropped
+pub fn lease_cache_entry(entry_path: impl Into<std::path::PathBuf>) -> Result<CacheLease, Box<dyn std::error::Error>> {
+  let entry_path = entry_path.into();
+  std::fs::create_dir_all(&entry_path).map_err(crate::err::eloc!(format!("Creating {}", entry_path.display())))?;
+  let pid = std::process::id();
+  let mut sinfo = sysinfo::System::new();
+  sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[sysinfo::Pid::from_u32(pid)]), true);
+  let process = sinfo.process(sysinfo::Pid::from_u32(pid));
+  let record = crate::launchers::PidRecord {
+    pid: pid,
+    start_time: process.map(|p| p.start_time()),
+    exe: process.and_then(|p| p.exe()).map(|exe| exe.to_path_buf()).or_else(|| std::env::current_exe().ok()),
+    output_to_log: false,
+  };
+  let lease_file = entry_path.join(format!("{}{}.json", CACHE_LEASE_FILE_PREFIX, pid));
+  crate::launchers::write_pid_record(&lease_file, &record)?;
+  touch_cache_entry(&entry_path)?;
+  Ok(CacheLease {
+    entry_path: entry_path,
+    lease_file: lease_file,
+  })
+}
+
+impl Drop for CacheLease {
+  fn drop(&mut self) {
+    if let Err(e) = touch_cache_entry(&self.entry_path) {
+      eprintln!("{}:{} {:?}", file!(), line!(), e);
+    }
+    if let Err(e) = std::fs::remove_file(&self.lease_file) {
+      eprintln!("Removing {}: {:?}", self.lease_file.display(), e);
+    }
+  }
+}
+
+fn touch_cache_entry(entry_path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+  let last_used = crate::misc::utc_timestamp_str(std::time::SystemTime::now());
+  std::fs::write(entry_path.join(CACHE_LAST_USED_FILE_NAME), last_used).map_err(crate::err::eloc!())?;
+  Ok(())
+}
+
+/// A cache entry could not be pruned because running processes hold a lease on it. Returned un-wrapped so callers can `e.downcast_ref::<CacheEntryInUse>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntryInUse {
+  pub name: String,
+  pub pids: Vec<u32>,
+}
+
+impl std::fmt::Display for CacheEntryInUse {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} is in use by PID(s) {:?}; stop them before pruning it", self.name, self.pids)
+  }
+}
+
+impl std::error::Error for CacheEntryInUse { }
+
+/// Deletes the entry `name` (as listed by list_cache_entries()) and returns how many bytes that freed; fails with CacheEntryInUse while anything leases it.
+/// The entry is renamed aside before it is deleted, so a worker starting at the same moment either leases it first (and the prune fails)
+/// or finds it gone and fetches it again; it never loads a half-deleted model.
+pub fn prune_cache_entry(cache_root: &std::path::Path, name: &str) -> Result<u64, Box<dyn std::error::Error>> {
+  let entry = list_cache_entries(cache_root)?.into_iter().find(|entry| entry.name == name).ok_or_else(|| format!("No cache entry named {:?} under {}", name, cache_root.display()))?;
+  if entry.in_use_by.len() > 0 {
+    return Err(Box::new(CacheEntryInUse { name: entry.name, pids: entry.in_use_by }));
+  }
+
+  let pruning_path = cache_root.join(format!("{}{}-{}", CACHE_PRUNING_PREFIX, name.replace('/', "-"), std::process::id()));
+  std::fs::rename(&entry.path, &pruning_path).map_err(crate::err::eloc!(format!("Moving {} aside", entry.path.display())))?;
+  // Leased between the check above and the rename
+  let mut sinfo = sysinfo::System::new();
+  sinfo.refresh_processes(sysinfo::ProcessesToUpdate::All, true);
+  let late_users = live_cache_lease_pids(&pruning_path, &sinfo);
+  if late_users.len() > 0 {
+    std::fs::rename(&pruning_path, &entry.path).map_err(crate::err::eloc!(format!("Moving {} back", entry.path.display())))?;
+    return Err(Box::new(CacheEntryInUse { name: entry.name, pids: late_users }));
+  }
+
+  if pruning_path.is_dir() {
+    std::fs::remove_dir_all(&pruning_path).map_err(crate::err::eloc!(format!("Deleting {}", pruning_path.display())))?;
+  }
+  else {
+    std::fs::remove_file(&pruning_path).map_err(crate::err::eloc!(format!("Deleting {}", pruning_path.display())))?;
+  }
+  Ok(entry.size_bytes)
+}
+
+/// Prunes the least recently used entries until everything under `cache_root` fits in `max_bytes`, skipping entries in use and those named in `keep`.
+/// Returns what was pruned; the cache can still be over quota afterwards if what is left is all in use or kept.
+pub fn enforce_cache_quota(cache_root: &std::path::Path, max_bytes: u64, keep: &[String]) -> Result<Vec<CacheEntry>, Box<dyn std::error::Error>> {
+  let mut entries = list_cache_entries(cache_root)?;
+  let mut total_bytes: u64 = entries.iter().map(|entry| entry.size_bytes).sum();
+  entries.reverse(); // Least recently used first
+  let mut pruned = vec![];
+  for entry in entries {
+    if total_bytes <= max_bytes {
+      break;
+    }
+    if entry.in_use_by.len() > 0 || keep.contains(&entry.name) {
+      continue;
+    }
+    match prune_cache_entry(cache_root, &entry.name) {
+      Ok(freed_bytes) => {
+        eprintln!("Pruned {} ({} bytes) from {} to stay under its quota of {} bytes", entry.name, freed_bytes, cache_root.display(), max_bytes);
+        total_bytes = total_bytes.saturating_sub(freed_bytes);
+        pruned.push(entry);
+      }
+      Err(e) if e.is::<CacheEntryInUse>() => continue,
+      Err(e) => return Err(e),
+    }
+  }
+  if total_bytes > max_bytes {
+    eprintln!("{} holds {} bytes, over its quota of {} bytes, but everything left is in use or kept", cache_root.display(), total_bytes, max_bytes);
+  }
+  Ok(pruned)
+}
+
 #[cfg(target_os="windows")]
 pub fn append_os_extention_to_bin(bin_name: &str) -> String {
     if bin_name.ends_with(".exe") || bin_name.ends_with(".EXE") {
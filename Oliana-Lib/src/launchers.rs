@@ -1,6 +1,224 @@
 
 use crate as oliana_lib;
 
+// Workers write their PID to <workdir>/WORKER_READY_FILE_NAME once their model is loaded and they are polling for jobs,
+// so anyone watching the workdir can tell "still loading" apart from "ready".
+pub const WORKER_READY_FILE_NAME: &str = "worker.ready";
+
+// Called by workers once their model is loaded, see WORKER_READY_FILE_NAME
+pub fn write_worker_ready_file(workdir: impl AsRef<std::path::Path>) -> Result<(), Box<dyn std::error::Error>> {
+  let ready_file = workdir.as_ref().join(WORKER_READY_FILE_NAME);
+  std::fs::write(&ready_file, format!("{}", std::process::id())).map_err(crate::err::eloc!())?;
+  Ok(())
+}
+
+// Workers re-write <workdir>/WORKER_HEARTBEAT_FILE_NAME from their own job loop: at least every WORKER_HEARTBEAT_INTERVAL while idle,
+// and on every token / diffusion step while busy. A worker which is alive but stuck (ie deadlocked in CUDA) stops beating,
+// which TrackedProcs notices after TrackedProcSpec::heartbeat_timeout; see OneTrackedProc::kill_if_hung().
+pub const WORKER_HEARTBEAT_FILE_NAME: &str = "worker.heartbeat";
+pub const WORKER_HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+// A job which failed (its worker reported an error, or was killed for missing heartbeats) gets <workdir>/<job>.FAILED_JOB_EXTENSION
+// holding an err::ErrorReport as JSON next to its .done; see write_job_failed()
+pub const FAILED_JOB_EXTENSION: &str = "failed";
+
+/// Fails `job` (a file stem in `workdir`) with `report`: writes <job>.failed, then <job>.done so anyone woken by .done finds the reason already there
+pub fn write_job_failed(workdir: &std::path::Path, job: &str, report: &crate::err::ErrorReport) -> Result<(), Box<dyn std::error::Error>> {
+  // Renamed into place so it is never read half-written
+  let failed_file = workdir.join(format!("{}.{}", job, FAILED_JOB_EXTENSION));
+  let tmp_failed_file = workdir.join(format!("{}.{}-tmp", job, FAILED_JOB_EXTENSION));
+  std::fs::write(&tmp_failed_file, serde_json::to_string(report).map_err(crate::err::eloc!())?).map_err(crate::err::eloc!())?;
+  std::fs::rename(&tmp_failed_file, &failed_file).map_err(crate::err::eloc!())?;
+  std::fs::write(workdir.join(format!("{}.done", job)), " ").map_err(crate::err::eloc!())?;
+  Ok(())
+}
+
+/// The contents of a <job>.failed file; plain text (as older servers wrote) becomes an ErrorKind::WorkerFailed report
+pub fn parse_job_failed(contents: &str) -> crate::err::ErrorReport {
+  match serde_json::from_str::<crate::err::ErrorReport>(contents) {
+    Ok(report) => report,
+    Err(_) => crate::err::ErrorReport::new(crate::err::ErrorKind::WorkerFailed, contents.trim()),
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerActivity {
+  Idle,
+  Busy,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Heartbeat {
+  pub pid: u32,
+  pub activity: WorkerActivity,
+  // Nonce of the job being worked on while Busy
+  pub job: Option<String>,
+  // Milliseconds since the epoch
+  pub beat_at_ms: u64,
+}
+
+impl Heartbeat {
+  pub fn age(&self) -> std::time::Duration {
+    let beat_at = std::time::UNIX_EPOCH + std::time::Duration::from_millis(self.beat_at_ms);
+    std::time::SystemTime::now().duration_since(beat_at).unwrap_or_default()
+  }
+}
+
+// Called by workers, see WORKER_HEARTBEAT_FILE_NAME
+pub fn write_worker_heartbeat(workdir: impl AsRef<std::path::Path>, activity: WorkerActivity, job: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+  let heartbeat = Heartbeat {
+    pid: std::process::id(),
+    activity: activity,
+    job: job.map(|job| job.to_string()),
+    beat_at_ms: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_err(crate::err::eloc!())?.as_millis() as u64,
+  };
+  let heartbeat_file = workdir.as_ref().join(WORKER_HEARTBEAT_FILE_NAME);
+  let tmp_heartbeat_file = heartbeat_file.with_extension("heartbeat-tmp");
+  std::fs::write(&tmp_heartbeat_file, serde_json::to_string(&heartbeat).map_err(crate::err::eloc!())?).map_err(crate::err::eloc!())?;
+  std::fs::rename(&tmp_heartbeat_file, &heartbeat_file).map_err(crate::err::eloc!())?;
+  Ok(())
+}
+
+pub fn read_worker_heartbeat(workdir: impl AsRef<std::path::Path>) -> Result<Option<Heartbeat>, Box<dyn std::error::Error>> {
+  let heartbeat_file = workdir.as_ref().join(WORKER_HEARTBEAT_FILE_NAME);
+  if !heartbeat_file.exists() {
+    return Ok(None);
+  }
+  let file_content = std::fs::read_to_string(&heartbeat_file).map_err(crate::err::eloc!())?;
+  let heartbeat: Heartbeat = serde_json::from_str(&file_content).map_err(crate::err::eloc!(format!("Parsing {}", heartbeat_file.display())))?;
+  Ok(Some(heartbeat))
+}
+
+// Written to <proc_track_dir>/<bin_name>-pid.txt for every spawned process. A bare PID is not enough to find our worker again:
+// once it exits the OS may hand the same PID to an unrelated process, so the start time and executable are recorded too and
+// the process behind the PID must match both before we believe it is ours.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PidRecord {
+  pub pid: u32,
+  // Seconds since the epoch, as reported by sysinfo::Process::start_time(); None for files written before PID records existed
+  pub start_time: Option<u64>,
+  pub exe: Option<std::path::PathBuf>,
+  // The process writes its stdout and stderr straight to its log file rather than to pipes read by the server which spawned it,
+  // so it keeps working once that server is gone; see OrphanPolicy::Adopt. False for files written before this was recorded.
+  #[serde(default)]
+  pub output_to_log: bool,
+}
+
+impl PidRecord {
+  // True if `process` is the one this record was written for. When the record carries no executable, `expected_exe` is checked instead.
+  pub fn matches(&self, process: &sysinfo::Process, expected_exe: &std::path::Path) -> bool {
+    if process.pid().as_u32() != self.pid {
+      return false;
+    }
+    if let Some(start_time) = self.start_time {
+      if process.start_time() != start_time {
+        return false;
+      }
+    }
+    let exe = self.exe.as_deref().unwrap_or(expected_exe);
+    match process.exe() {
+      Some(process_exe) => is_same_exe(process_exe, exe),
+      None => self.start_time.is_some(), // Unreadable (ie owned by another user, or a zombie); the start time has to do
+    }
+  }
+}
+
+// Linux appends " (deleted)" to the executable of a process whose binary was replaced since it started (ie by cargo build)
+fn is_same_exe(process_exe: &std::path::Path, exe: &std::path::Path) -> bool {
+  let process_exe_str = process_exe.to_string_lossy();
+  let process_exe = std::path::Path::new(process_exe_str.strip_suffix(" (deleted)").unwrap_or(&process_exe_str));
+  process_exe == exe || std::fs::canonicalize(exe).map(|exe| exe == process_exe).unwrap_or(false)
+}
+
+// Reads a PID file, accepting the bare-PID format older servers wrote. Unparseable files are an error.
+pub fn read_pid_record(pid_file: &std::path::Path) -> Result<Option<PidRecord>, Box<dyn std::error::Error>> {
+  if !pid_file.exists() {
+    return Ok(None);
+  }
+  let file_content = std::fs::read_to_string(pid_file).map_err(crate::err::eloc!())?;
+  if let Ok(pid) = file_content.trim().parse::<u32>() {
+    return Ok(Some(PidRecord { pid: pid, start_time: None, exe: None, output_to_log: false }));
+  }
+  let record: PidRecord = serde_json::from_str(&file_content).map_err(crate::err::eloc!(format!("Parsing {}", pid_file.display())))?;
+  Ok(Some(record))
+}
+
+// Written aside and renamed into place so nobody reads a half-written record
+pub fn write_pid_record(pid_file: &std::path::Path, record: &PidRecord) -> Result<(), Box<dyn std::error::Error>> {
+  let pid_file_content = serde_json::to_string(record).map_err(crate::err::eloc!())?;
+  let tmp_pid_file = pid_file.with_extension("tmp");
+  std::fs::write(&tmp_pid_file, pid_file_content).map_err(crate::err::eloc!())?;
+  std::fs::rename(&tmp_pid_file, pid_file).map_err(crate::err::eloc!())?;
+  Ok(())
+}
+
+// What TrackedProcs does with a worker a previous server run left behind, found through its PID file when the worker is first started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanPolicy {
+  // Kill it (and its process group) and spawn a fresh one
+  #[default]
+  Kill,
+  // Keep it running and track it as if we had spawned it, which saves re-loading a model. Its exit status is lost.
+  // Processes are then spawned with their output going straight to their log file, unprefixed and not rotated while they run,
+  // because output piped to a server which has since exited kills them on their next write (EPIPE). Orphans which were spawned
+  // with pipes (ie by a server using Kill) are killed even under Adopt.
+  Adopt,
+}
+
+// Everything needed to spawn (and re-spawn) one tracked process
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TrackedProcSpec {
+  // File name under expected_bin_directory, without any .exe extension
+  pub bin_name: String,
+  pub args: Vec<String>,
+  // Added on top of our own environment, replacing variables of the same name; this is how each worker gets its own PER_PROC_MEM_FRACT
+  pub env: Vec<(String, String)>,
+  // Working directory of the process; defaults to ours
+  pub cwd: Option<std::path::PathBuf>,
+  pub limits: crate::proc_limits::ResourceLimits,
+  // A running process whose heartbeat is older than this is killed and re-spawned; None turns heartbeat checks off
+  pub heartbeat_timeout: Option<std::time::Duration>,
+  // Where the process writes WORKER_READY_FILE_NAME and WORKER_HEARTBEAT_FILE_NAME; without one neither is checked
+  pub workdir: Option<std::path::PathBuf>,
+}
+
+impl TrackedProcSpec {
+  pub fn new(bin_name: &str, args: &[&str]) -> Self {
+    Self {
+      bin_name: bin_name.to_string(),
+      args: args.iter().map(|arg| arg.to_string()).collect(),
+      ..Default::default()
+    }
+  }
+
+  pub fn with_env(mut self, key: &str, val: &str) -> Self {
+    self.env.push((key.to_string(), val.to_string()));
+    self
+  }
+
+  pub fn with_cwd(mut self, cwd: impl Into<std::path::PathBuf>) -> Self {
+    self.cwd = Some(cwd.into());
+    self
+  }
+
+  pub fn with_limits(mut self, limits: crate::proc_limits::ResourceLimits) -> Self {
+    self.limits = limits;
+    self
+  }
+
+  pub fn with_heartbeat_timeout(mut self, heartbeat_timeout: std::time::Duration) -> Self {
+    self.heartbeat_timeout = Some(heartbeat_timeout);
+    self
+  }
+
+  pub fn with_workdir(mut self, workdir: impl Into<std::path::PathBuf>) -> Self {
+    self.workdir = Some(workdir.into());
+    self
+  }
+}
+
 // This structure is responsible for watching over the
 // Oliana-Text and Oliana-Text subprocesses and providing accessors to their
 // outputs over a directory structure.
@@ -10,9 +228,95 @@ pub struct TrackedProcs {
   pub proc_track_dir: std::path::PathBuf,
   pub expected_bin_directory: std::path::PathBuf,
   pub procs: Vec<OneTrackedProc>,
-  pub tracked_proc_args: Vec<(String, Vec<String>)>,
+  pub tracked_proc_specs: Vec<TrackedProcSpec>,
   pub sinfo: sysinfo::System,
   pub spawned_children: Vec<std::process::Child>,
+  // Killed by kill_if_hung() and not reaped yet; a process stuck in the kernel (ie in a wedged GPU driver) may take a while to go,
+  // so ensure_registered_procs_running() only try_wait()s these
+  pub killed_children: Vec<std::process::Child>,
+  // Used for every process without an entry in restart_policies
+  pub default_restart_policy: RestartPolicy,
+  pub restart_policies: std::collections::HashMap<String, RestartPolicy>,
+  // stdout and stderr of every process go to <proc_track_dir>/<bin_name>.log, see proc_logs.rs
+  pub log_rotation: crate::proc_logs::LogRotation,
+  pub orphan_policy: OrphanPolicy,
+  // Delegated cgroup v2 directory processes with cgroup limits get their own cgroup under, see proc_limits.rs
+  pub cgroup_parent: Option<std::path::PathBuf>,
+  // How long shutdown() waits after SIGTERM before it SIGKILLs what is left
+  pub shutdown_grace_period: std::time::Duration,
+  // Set by shutdown(); nothing is spawned afterwards
+  pub shutting_down: bool,
+}
+
+// How TrackedProcs re-spawns a process which exited. Each crash in a row waits twice as long as the one before (from initial_backoff
+// up to max_backoff), and a process which needed more than max_restarts re-spawns within restart_window is given up on and marked failed,
+// so a worker which cannot start (ie a failed pip install) does not thrash the machine forever.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestartPolicy {
+  pub initial_backoff: std::time::Duration,
+  pub max_backoff: std::time::Duration,
+  pub max_restarts: u32,
+  pub restart_window: std::time::Duration,
+  // A process which stayed up this long before exiting starts over from initial_backoff
+  pub healthy_after: std::time::Duration,
+}
+
+impl Default for RestartPolicy {
+  fn default() -> Self {
+    Self {
+      initial_backoff: std::time::Duration::from_secs(2),
+      max_backoff: std::time::Duration::from_secs(60),
+      max_restarts: 5,
+      restart_window: std::time::Duration::from_secs(300),
+      healthy_after: std::time::Duration::from_secs(60),
+    }
+  }
+}
+
+impl RestartPolicy {
+  // Time to wait before re-spawning after the n-th crash in a row (n starts at 1)
+  pub fn backoff(&self, consecutive_crashes: u32) -> std::time::Duration {
+    let doublings = consecutive_crashes.saturating_sub(1).min(31);
+    std::cmp::min(self.max_backoff, self.initial_backoff.saturating_mul(1 << doublings))
+  }
+}
+
+// Why a tracked process last exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+  Code(i32),
+  Signal(i32),
+  // The process was gone before we could collect its exit status (ie it was not our child)
+  Unknown,
+  // We killed the process because it stopped writing heartbeats
+  MissedHeartbeats,
+}
+
+impl ExitReason {
+  pub fn from_exit_status(status: &std::process::ExitStatus) -> Self {
+    if let Some(code) = status.code() {
+      return ExitReason::Code(code);
+    }
+    #[cfg(unix)]
+    {
+      use std::os::unix::process::ExitStatusExt;
+      if let Some(signal) = status.signal() {
+        return ExitReason::Signal(signal);
+      }
+    }
+    ExitReason::Unknown
+  }
+}
+
+impl std::fmt::Display for ExitReason {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ExitReason::Code(code) => write!(f, "exit code {}", code),
+      ExitReason::Signal(signal) => write!(f, "killed by signal {}", signal),
+      ExitReason::Unknown => write!(f, "unknown exit status"),
+      ExitReason::MissedHeartbeats => write!(f, "killed after missing heartbeats"),
+    }
+  }
 }
 
 impl TrackedProcs {
@@ -21,56 +325,361 @@ impl TrackedProcs {
       proc_track_dir: proc_track_dir.into(),
       expected_bin_directory: expected_bin_directory.into(),
       procs: Vec::with_capacity(8),
-      tracked_proc_args: Vec::with_capacity(8),
+      tracked_proc_specs: Vec::with_capacity(8),
       sinfo: sysinfo::System::new(),
       spawned_children: Vec::with_capacity(32),
+      killed_children: vec![],
+      default_restart_policy: RestartPolicy::default(),
+      restart_policies: std::collections::HashMap::new(),
+      log_rotation: crate::proc_logs::LogRotation::default(),
+      orphan_policy: OrphanPolicy::default(),
+      cgroup_parent: None,
+      shutdown_grace_period: std::time::Duration::from_secs(10),
+      shutting_down: false,
     }
   }
 
-  pub fn register_tracked_proc(&mut self, process_bin_name: &str, process_args: &[&str]) {
-    let mut owned_p_args = Vec::with_capacity(process_args.len());
-    for arg in process_args {
-      owned_p_args.push(arg.to_string());
+  pub fn is_registered(&self, process_bin_name: &str) -> bool {
+    self.tracked_proc_specs.iter().any(|spec| spec.bin_name == process_bin_name)
+  }
+
+  // The last num_lines lines process_bin_name wrote to stdout or stderr, across re-spawns
+  pub fn log_tail(&self, process_bin_name: &str, num_lines: usize) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if !self.is_registered(process_bin_name) {
+      return Err(format!("No tracked process named {:?}", process_bin_name).into());
     }
-    self.tracked_proc_args.push(
-      (process_bin_name.to_string(), owned_p_args)
-    );
+    crate::proc_logs::tail_log_file(&crate::proc_logs::log_file_path(&self.proc_track_dir, process_bin_name), num_lines)
+  }
+
+  // Applies to process_bin_name from its next exit on
+  pub fn set_restart_policy(&mut self, process_bin_name: &str, policy: RestartPolicy) {
+    for otp in self.procs.iter_mut() {
+      if otp.bin_name == process_bin_name {
+        otp.restart_policy = policy.clone();
+      }
+    }
+    self.restart_policies.insert(process_bin_name.to_string(), policy);
+  }
+
+  // Clears the failed state and restart counters of process_bin_name so ensure_registered_procs_running() spawns it again,
+  // ie once whatever kept it from starting has been fixed. Returns false if the process was not marked failed.
+  pub fn retry_failed_proc(&mut self, process_bin_name: &str) -> bool {
+    for otp in self.procs.iter_mut() {
+      if otp.bin_name == process_bin_name && otp.failed {
+        otp.failed = false;
+        otp.consecutive_crashes = 0;
+        otp.recent_restarts.clear();
+        otp.next_spawn_at = None;
+        return true;
+      }
+    }
+    false
+  }
+
+  pub fn register_tracked_proc(&mut self, spec: TrackedProcSpec) {
+    self.tracked_proc_specs.push(spec);
   }
 
   pub fn ensure_registered_procs_running(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-    for i in 0..self.tracked_proc_args.len() {
-      self.ensure_named_proc_running(self.tracked_proc_args[i].0.clone(), self.tracked_proc_args[i].1.clone())?; // TODO engineer those .clone()s out of here!
+    if self.shutting_down {
+      return Ok(());
+    }
+    self.killed_children.retain_mut(|c| match c.try_wait() {
+      Ok(Some(_)) => false,
+      Ok(None) => true,
+      Err(e) => {
+        eprintln!("Reaping {}: {:?}", c.id(), e);
+        false
+      }
+    });
+    for i in 0..self.tracked_proc_specs.len() {
+      let spec = self.tracked_proc_specs[i].clone();
+      self.ensure_named_proc_running(&spec)?;
     }
     Ok(())
   }
 
-  pub fn ensure_named_proc_running(&mut self, process_bin_name: String, process_args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+  pub fn ensure_named_proc_running(&mut self, spec: &TrackedProcSpec) -> Result<(), Box<dyn std::error::Error>> {
+    let process_bin_name = &spec.bin_name;
     let mut existing_proc_i: Option<usize> = None;
     for i in 0..self.procs.len() {
-      if self.procs[i].bin_name == process_bin_name {
+      if self.procs[i].bin_name == *process_bin_name {
         existing_proc_i = Some(i);
         // Found the process, is it running?
       }
     }
     if let Some(i) = existing_proc_i {
-      if !(self.procs[i].is_running(&mut self.sinfo, &mut self.spawned_children)?) {
-        self.procs[i].spawn_proc(&process_args, &mut self.spawned_children)?;
+      let mut running = self.procs[i].is_running(&mut self.sinfo, &mut self.spawned_children)?;
+      if running {
+        if let (Some(heartbeat_timeout), Some(workdir)) = (spec.heartbeat_timeout, &spec.workdir) {
+          running = !self.procs[i].kill_if_hung(workdir, heartbeat_timeout, &mut self.spawned_children, &mut self.killed_children)?;
+        }
+      }
+      if !running {
+        let otp = &mut self.procs[i];
+        if otp.failed {
+          return Ok(()); // Given up on; see retry_failed_proc()
+        }
+        let next_spawn_at = match otp.next_spawn_at {
+          Some(next_spawn_at) => next_spawn_at,
+          None => match otp.schedule_respawn() {
+            Some(next_spawn_at) => next_spawn_at,
+            None => return Ok(()),
+          }
+        };
+        if std::time::Instant::now() >= next_spawn_at {
+          otp.spawn_proc(spec, self.cgroup_parent.as_deref(), &mut self.spawned_children)?;
+          otp.recent_restarts.push_back(std::time::Instant::now());
+        }
       }
     }
     else {
       // Must create a new tracked process & spawn it
-      let otp = OneTrackedProc {
+      let mut otp = OneTrackedProc {
         proc_track_dir: self.proc_track_dir.clone(),
         bin_name: process_bin_name.to_string(),
-        filesystem_bin_path: crate::files::find_newest_mtime_bin_under_folder(&self.expected_bin_directory, &process_bin_name)?,
+        filesystem_bin_path: crate::files::find_newest_mtime_bin_under_folder(&self.expected_bin_directory, process_bin_name)?,
         filesystem_pid_filepath: self.proc_track_dir.join(format!("{}-pid.txt", process_bin_name)),
+        filesystem_log_filepath: crate::proc_logs::log_file_path(&self.proc_track_dir, process_bin_name),
+        log_rotation: self.log_rotation.clone(),
+        log: None,
+        output_to_log: self.orphan_policy == OrphanPolicy::Adopt,
+        spawn_count: 0,
+        last_spawned_at: None,
+        restart_policy: self.restart_policies.get(process_bin_name).unwrap_or(&self.default_restart_policy).clone(),
+        last_exit: None,
+        exited_at: None,
+        next_spawn_at: None,
+        consecutive_crashes: 0,
+        recent_restarts: std::collections::VecDeque::new(),
+        failed: false,
       };
-      otp.spawn_proc(&process_args, &mut self.spawned_children)?;
+      let adopted = otp.take_over_previous_run(&mut self.sinfo, self.orphan_policy)?;
+      if !adopted {
+        otp.spawn_proc(spec, self.cgroup_parent.as_deref(), &mut self.spawned_children)?;
+      }
       self.procs.push(otp);
     }
 
     Ok(())
   }
+
+  // Stops every tracked process along with whatever it started (ie pip and python under oliana_images): SIGTERM to each process group,
+  // then SIGKILL to the groups still around after shutdown_grace_period. PID files are removed and nothing is re-spawned afterwards.
+  // Also called on drop, so the workers (and the GPU memory they hold) do not outlive the server.
+  pub fn shutdown(&mut self) {
+    self.shutting_down = true;
+    self.spawned_children.append(&mut self.killed_children); // Already SIGKILLed, so reaped below along with everything else
+    // (pid, whether it leads its own process group)
+    let mut stopping: Vec<(u32, bool)> = vec![];
+    for i in 0..self.procs.len() {
+      let running = match self.procs[i].is_running(&mut self.sinfo, &mut self.spawned_children) {
+        Ok(running) => running,
+        Err(e) => {
+          eprintln!("{}:{} {:?}", file!(), line!(), e);
+          false
+        }
+      };
+      if let (true, Ok(Some(pid))) = (running, self.procs[i].get_expected_pid()) {
+        // Decided up front; once the leader is reaped its PID no longer tells us about the rest of its group
+        let whole_group = leads_process_group(pid);
+        eprintln!("Stopping {} (PID {}{})", self.procs[i].bin_name, pid, if whole_group { " and its process group" } else { "" });
+        self.procs[i].write_log_note(&format!("Stopping PID {} for shutdown", pid));
+        signal_proc_tree(pid, whole_group, false);
+        stopping.push((pid, whole_group));
+      }
+    }
+
+    let mut exit_reasons = std::collections::HashMap::<u32, ExitReason>::new();
+    let give_up_at = std::time::Instant::now() + self.shutdown_grace_period;
+    loop {
+      // Our own children stay zombies (and so count as alive) until reaped
+      self.spawned_children.retain_mut(|c| match c.try_wait() {
+        Ok(Some(status)) => {
+          exit_reasons.insert(c.id(), ExitReason::from_exit_status(&status));
+          false
+        }
+        _ => true,
+      });
+      stopping.retain(|(pid, whole_group)| proc_tree_alive(*pid, *whole_group));
+      if stopping.len() < 1 || std::time::Instant::now() >= give_up_at {
+        break;
+      }
+      std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    for (pid, whole_group) in stopping.iter() {
+      eprintln!("PID {} is still running {:?} after SIGTERM, killing it", pid, self.shutdown_grace_period);
+      signal_proc_tree(*pid, *whole_group, true);
+    }
+    self.spawned_children.retain_mut(|c| {
+      if let Err(e) = c.kill() {
+        if e.kind() != std::io::ErrorKind::InvalidInput { // Already exited
+          eprintln!("Killing {}: {:?}", c.id(), e);
+        }
+      }
+      match c.wait() {
+        Ok(status) => { exit_reasons.entry(c.id()).or_insert(ExitReason::from_exit_status(&status)); }
+        Err(e) => eprintln!("Reaping {}: {:?}", c.id(), e),
+      }
+      false
+    });
+
+    for otp in self.procs.iter_mut() {
+      if let Ok(Some(pid)) = otp.get_expected_pid() {
+        otp.record_exit(exit_reasons.get(&pid).copied().unwrap_or(ExitReason::Unknown));
+      }
+      otp.remove_pid_file();
+    }
+  }
+
+  // Reports on every registered process, including ones which could not be spawned yet (ie because their binary is missing)
+  pub fn status(&mut self) -> Vec<TrackedProcStatus> {
+    let mut statuses = Vec::with_capacity(self.tracked_proc_specs.len());
+    for spec in self.tracked_proc_specs.iter() {
+      let (bin_name, args) = (&spec.bin_name, &spec.args);
+      let mut status = TrackedProcStatus {
+        bin_name: bin_name.clone(),
+        args: args.clone(),
+        env: spec.env.clone(),
+        pid: None,
+        running: false,
+        model_loaded: false,
+        restarts: 0,
+        uptime: None,
+        failed: false,
+        last_exit: None,
+        next_restart_in: None,
+        heartbeat: None,
+      };
+      if let Some(otp) = self.procs.iter_mut().find(|p| p.bin_name == *bin_name) {
+        status.pid = otp.get_expected_pid().unwrap_or(None);
+        status.running = match otp.is_running(&mut self.sinfo, &mut self.spawned_children) {
+          Ok(running) => running,
+          Err(e) => {
+            eprintln!("{}:{} {:?}", file!(), line!(), e);
+            false
+          }
+        };
+        status.restarts = otp.spawn_count.saturating_sub(1);
+        if status.running {
+          status.uptime = otp.last_spawned_at.map(|t| t.elapsed());
+        }
+        status.failed = otp.failed;
+        status.last_exit = otp.last_exit;
+        if !status.running && !otp.failed {
+          status.next_restart_in = otp.next_spawn_at.map(|t| t.saturating_duration_since(std::time::Instant::now()));
+        }
+      }
+      if let (true, Some(pid), Some(workdir)) = (status.running, status.pid, &spec.workdir) {
+        if let Ok(ready_pid) = std::fs::read_to_string(workdir.join(WORKER_READY_FILE_NAME)) {
+          status.model_loaded = ready_pid.trim() == format!("{pid}");
+        }
+        // A heartbeat left by an earlier instance says nothing about this one
+        if let Ok(Some(heartbeat)) = read_worker_heartbeat(workdir) {
+          if heartbeat.pid == pid {
+            status.heartbeat = Some(heartbeat);
+          }
+        }
+      }
+      statuses.push(status);
+    }
+    statuses
+  }
+}
+
+impl Drop for TrackedProcs {
+  fn drop(&mut self) {
+    self.shutdown();
+  }
+}
+
+// Resolves once we are asked to stop: SIGINT (ie Ctrl-C) or SIGTERM (ie systemctl stop, docker stop) on unix, Ctrl-C elsewhere.
+// Returns the name of the signal. Workers live in process groups of their own, so a Ctrl-C in our terminal only reaches us and
+// the owner of the TrackedProcs is expected to call shutdown() when this returns.
+pub async fn wait_for_shutdown_signal() -> Result<&'static str, Box<dyn std::error::Error>> {
+  #[cfg(unix)]
+  {
+    let mut sigint = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt()).map_err(crate::err::eloc!())?;
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).map_err(crate::err::eloc!())?;
+    tokio::select! {
+      _ = sigint.recv() => Ok("SIGINT"),
+      _ = sigterm.recv() => Ok("SIGTERM"),
+    }
+  }
+  #[cfg(not(unix))]
+  {
+    tokio::signal::ctrl_c().await.map_err(crate::err::eloc!())?;
+    Ok("Ctrl-C")
+  }
+}
+
+// spawn_proc() makes every process the leader of a new process group, so signalling the group reaches the worker and everything it started.
+// Processes adopted from a server which predates that do not lead a group and are signalled on their own.
+#[cfg(unix)]
+fn leads_process_group(pid: u32) -> bool {
+  unsafe { libc::getpgid(pid as libc::pid_t) == pid as libc::pid_t }
+}
+#[cfg(not(unix))]
+fn leads_process_group(_pid: u32) -> bool {
+  false
+}
+
+// SIGTERM, or SIGKILL when `force`. Windows has no SIGTERM, so there the process is always killed outright.
+fn signal_proc_tree(pid: u32, whole_group: bool, force: bool) {
+  #[cfg(unix)]
+  {
+    let target = if whole_group { -(pid as libc::pid_t) } else { pid as libc::pid_t };
+    unsafe { libc::kill(target, if force { libc::SIGKILL } else { libc::SIGTERM }) };
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = (whole_group, force);
+    let mut sinfo = sysinfo::System::new();
+    sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[sysinfo::Pid::from_u32(pid)]), true);
+    if let Some(process) = sinfo.process(sysinfo::Pid::from_u32(pid)) {
+      process.kill();
+    }
+  }
+}
+
+// True while any process in the group (or `pid` alone) exists, zombies included
+fn proc_tree_alive(pid: u32, whole_group: bool) -> bool {
+  #[cfg(unix)]
+  {
+    let target = if whole_group { -(pid as libc::pid_t) } else { pid as libc::pid_t };
+    let signalled = unsafe { libc::kill(target, 0) } == 0;
+    signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = whole_group;
+    let mut sinfo = sysinfo::System::new();
+    sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[sysinfo::Pid::from_u32(pid)]), true);
+    sinfo.process(sysinfo::Pid::from_u32(pid)).is_some()
+  }
+}
+
+// Snapshot of one registered process, see TrackedProcs::status()
+#[derive(Debug, Clone)]
+pub struct TrackedProcStatus {
+  pub bin_name: String,
+  pub args: Vec<String>,
+  pub env: Vec<(String, String)>,
+  pub pid: Option<u32>,
+  pub running: bool,
+  // True once the process has written its PID to <workdir>/WORKER_READY_FILE_NAME
+  pub model_loaded: bool,
+  // How many times the process had to be re-spawned after the first launch
+  pub restarts: u32,
+  // Time since the current instance was spawned, if it is running
+  pub uptime: Option<std::time::Duration>,
+  // The process crashed too often for its RestartPolicy and is no longer re-spawned
+  pub failed: bool,
+  pub last_exit: Option<ExitReason>,
+  // Set while the process waits out its backoff before being re-spawned
+  pub next_restart_in: Option<std::time::Duration>,
+  // Latest heartbeat of the running instance, if it has written one yet
+  pub heartbeat: Option<Heartbeat>,
 }
 
 // This structure exists to store potentially-expensive-to-lookup items once (eg filesystem_bin_path looked up from bin_name)
@@ -79,20 +688,118 @@ pub struct OneTrackedProc {
   pub bin_name: String,
   pub filesystem_bin_path: std::path::PathBuf,
   pub filesystem_pid_filepath: std::path::PathBuf,
+  pub filesystem_log_filepath: std::path::PathBuf,
+  pub log_rotation: crate::proc_logs::LogRotation,
+  // Opened on first spawn and shared with the threads copying the child's stdout and stderr
+  pub log: Option<std::sync::Arc<std::sync::Mutex<crate::proc_logs::ProcLog>>>,
+  // Spawn with stdout and stderr opened on the log file itself instead of piped to us, so the process may be adopted; see OrphanPolicy::Adopt
+  pub output_to_log: bool,
+  pub spawn_count: u32,
+  pub last_spawned_at: Option<std::time::Instant>,
+  pub restart_policy: RestartPolicy,
+  // Recorded by is_running() when it notices the process is gone
+  pub last_exit: Option<ExitReason>,
+  pub exited_at: Option<std::time::Instant>,
+  // When the process may be re-spawned; None while it runs
+  pub next_spawn_at: Option<std::time::Instant>,
+  // Exits in a row which happened before the process was up for restart_policy.healthy_after
+  pub consecutive_crashes: u32,
+  // Re-spawns within the last restart_policy.restart_window
+  pub recent_restarts: std::collections::VecDeque<std::time::Instant>,
+  pub failed: bool,
 }
 
 impl OneTrackedProc {
+  // The PID recorded for this process; see is_running() for whether it still belongs to it
   pub fn get_expected_pid(&self) -> Result<Option<u32>, Box<dyn std::error::Error>> {
-    if self.filesystem_pid_filepath.exists() {
-      let file_content = std::fs::read_to_string(&self.filesystem_pid_filepath).map_err(crate::err::eloc!())?;
-      let pid_num = file_content.parse::<u32>().map_err(crate::err::eloc!())?;
-      return Ok(Some(pid_num));
+    Ok(read_pid_record(&self.filesystem_pid_filepath)?.map(|record| record.pid))
+  }
+
+  fn remove_pid_file(&self) {
+    if let Err(e) = std::fs::remove_file(&self.filesystem_pid_filepath) {
+      if e.kind() != std::io::ErrorKind::NotFound {
+        eprintln!("Removing {}: {:?}", self.filesystem_pid_filepath.display(), e);
+      }
     }
-    Ok(None)
   }
 
-  pub fn is_running(&self, sinfo: &mut sysinfo::System, spawned_child_holder: &mut Vec<std::process::Child>) -> Result<bool, Box<dyn std::error::Error>> {
-    if let Some(pid) = self.get_expected_pid().map_err(crate::err::eloc!())? {
+  // Deals with whatever a previous server run left in our PID file, before we spawn anything. Returns true if the process it
+  // recorded is still alive and was adopted (so must not be spawned again).
+  pub fn take_over_previous_run(&mut self, sinfo: &mut sysinfo::System, orphan_policy: OrphanPolicy) -> Result<bool, Box<dyn std::error::Error>> {
+    let record = match read_pid_record(&self.filesystem_pid_filepath) {
+      Ok(Some(record)) => record,
+      Ok(None) => return Ok(false),
+      Err(e) => {
+        eprintln!("Ignoring unreadable {}: {}", self.filesystem_pid_filepath.display(), e);
+        self.remove_pid_file();
+        return Ok(false);
+      }
+    };
+    let pid = sysinfo::Pid::from_u32(record.pid);
+    sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[pid]), true);
+    let is_orphan = match sinfo.process(pid) {
+      Some(process) if !matches!(process.status(), sysinfo::ProcessStatus::Zombie | sysinfo::ProcessStatus::Dead) => {
+        if record.matches(process, &self.filesystem_bin_path) {
+          true
+        }
+        else {
+          eprintln!("PID {} from {} now belongs to an unrelated process ({:?}), leaving it alone", record.pid, self.filesystem_pid_filepath.display(), process.name());
+          false
+        }
+      }
+      _ => false,
+    };
+    if !is_orphan {
+      self.remove_pid_file(); // Stale
+      return Ok(false);
+    }
+
+    let orphan_policy = if orphan_policy == OrphanPolicy::Adopt && !record.output_to_log {
+      eprintln!("{} (PID {}) writes to pipes of the server which spawned it, so it cannot be adopted", self.bin_name, record.pid);
+      OrphanPolicy::Kill
+    }
+    else {
+      orphan_policy
+    };
+    match orphan_policy {
+      OrphanPolicy::Adopt => {
+        eprintln!("Adopting {} (PID {}) left running by a previous server", self.bin_name, record.pid);
+        self.open_log()?;
+        self.write_log_note(&format!("Adopted PID {} from a previous server run", record.pid));
+        self.spawn_count += 1;
+        self.last_spawned_at = Some(std::time::Instant::now());
+        Ok(true)
+      }
+      OrphanPolicy::Kill => {
+        eprintln!("Killing {} (PID {}) left running by a previous server", self.bin_name, record.pid);
+        signal_proc_tree(record.pid, leads_process_group(record.pid), true);
+        let start = std::time::Instant::now();
+        while start.elapsed() < std::time::Duration::from_secs(5) {
+          sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[pid]), true);
+          match sinfo.process(pid) {
+            Some(process) if record.matches(process, &self.filesystem_bin_path) && !matches!(process.status(), sysinfo::ProcessStatus::Zombie | sysinfo::ProcessStatus::Dead) => {
+              std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            _ => break,
+          }
+        }
+        self.remove_pid_file();
+        Ok(false)
+      }
+    }
+  }
+
+  pub fn is_running(&mut self, sinfo: &mut sysinfo::System, spawned_child_holder: &mut Vec<std::process::Child>) -> Result<bool, Box<dyn std::error::Error>> {
+    let record = match read_pid_record(&self.filesystem_pid_filepath) {
+      Ok(record) => record,
+      Err(e) => {
+        eprintln!("Ignoring unreadable {}: {}", self.filesystem_pid_filepath.display(), e);
+        self.remove_pid_file();
+        None
+      }
+    };
+    if let Some(record) = record {
+      let pid = record.pid;
       //sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[sysinfo::Pid::from_u32(pid)]), true); // TODO potential future optimization where we don't scan _all_ processes?
       sinfo.refresh_processes(sysinfo::ProcessesToUpdate::All, true);
       // If <pid> is no longer in the process list, it exited!
@@ -104,31 +811,48 @@ impl OneTrackedProc {
             // Process _just_ exited, therefore it is _not_ running!
 
             // Retain all children which are NOT this process.
+            let mut exit_reason = ExitReason::Unknown;
             spawned_child_holder.retain_mut(|c| {
               if c.id() != pid {
                 true
               }
               else {
                 // Reap the child process
-                if let Err(e) = c.wait() {
-                  eprintln!("{:?}", e);
+                match c.wait() {
+                  Ok(status) => exit_reason = ExitReason::from_exit_status(&status),
+                  Err(e) => eprintln!("{:?}", e),
                 }
                 false
               }
             });
+            self.record_exit(exit_reason);
+            self.remove_pid_file();
 
             return Ok(false);
           }
           unused => {
-            return Ok(true); // <pid> is still running!
+            if record.matches(process, &self.filesystem_bin_path) {
+              return Ok(true); // <pid> is still running!
+            }
+            // Our process exited without us reaping it (ie an adopted one) and the OS re-used its PID
+            eprintln!("PID {} of {} now belongs to an unrelated process ({:?})", pid, self.bin_name, process.name());
+            self.record_exit(ExitReason::Unknown);
+            self.remove_pid_file();
+            return Ok(false);
           }
         }
       }
       else {
         // If we thought we had a PID and no longer have it, re-scan processes in spawned_child_holder and remove them
+        let mut exit_reason = ExitReason::Unknown;
         spawned_child_holder.retain_mut(|c| {
           match c.try_wait() {
-              Ok(Some(status)) => false, /* remove because exited w/ a code */
+              Ok(Some(status)) => { /* remove because exited w/ a code */
+                if c.id() == pid {
+                  exit_reason = ExitReason::from_exit_status(&status);
+                }
+                false
+              }
               Ok(None) => {
                   true /* has yet to exit, keep reference */
               }
@@ -139,19 +863,161 @@ impl OneTrackedProc {
               },
           }
         });
+        self.record_exit(exit_reason);
+        self.remove_pid_file();
       }
     }
     Ok(false)
   }
 
-  pub fn spawn_proc(&self, args: &Vec<String>, spawned_child_holder: &mut Vec<std::process::Child>) -> Result<(), Box<dyn std::error::Error>> {
+  // Kills the (running) process if its heartbeat in `workdir` is older than `heartbeat_timeout`, failing the job it was busy with
+  // so whoever waits on it gets an error instead of waiting forever. Returns true if the process was killed.
+  // A process which has not beaten yet (ie still loading its model) is left alone.
+  // Never waits for the process to go: its Child moves from spawned_child_holder to killed_children for the caller to reap later.
+  pub fn kill_if_hung(&mut self, workdir: &std::path::Path, heartbeat_timeout: std::time::Duration, spawned_child_holder: &mut Vec<std::process::Child>, killed_children: &mut Vec<std::process::Child>) -> Result<bool, Box<dyn std::error::Error>> {
+    let pid = match self.get_expected_pid()? {
+      Some(pid) => pid,
+      None => return Ok(false),
+    };
+    let heartbeat = match read_worker_heartbeat(workdir) {
+      Ok(Some(heartbeat)) if heartbeat.pid == pid => heartbeat,
+      Ok(_) => return Ok(false),
+      Err(e) => {
+        eprintln!("Ignoring unreadable heartbeat of {}: {}", self.bin_name, e);
+        return Ok(false);
+      }
+    };
+    let heartbeat_age = heartbeat.age();
+    if heartbeat_age <= heartbeat_timeout {
+      return Ok(false);
+    }
+
+    eprintln!("{} (PID {}) has not written a heartbeat in {:?}, killing it", self.bin_name, pid, heartbeat_age);
+    self.write_log_note(&format!("No heartbeat in {:?} (timeout is {:?}), killing PID {}", heartbeat_age, heartbeat_timeout, pid));
+    signal_proc_tree(pid, leads_process_group(pid), true);
+    if let Some(child_i) = spawned_child_holder.iter().position(|c| c.id() == pid) {
+      killed_children.push(spawned_child_holder.swap_remove(child_i));
+    }
+    self.record_exit(ExitReason::MissedHeartbeats);
+    self.remove_pid_file();
+
+    // Job names are file stems in workdir; anything else did not come from a well-behaved worker
+    if let (WorkerActivity::Busy, Some(job)) = (heartbeat.activity, heartbeat.job.as_ref().filter(|job| job.len() > 0 && !job.contains(['/', '\\', '.']))) {
+      let reason = format!("{} stopped responding while working on job {} (no heartbeat in {:?}) and was restarted", self.bin_name, job, heartbeat_age);
+      self.write_log_note(&format!("Failing job {}", job));
+      write_job_failed(workdir, job, &crate::err::ErrorReport::new(crate::err::ErrorKind::WorkerFailed, reason))?;
+    }
+    Ok(true)
+  }
+
+  // Only the first call after each spawn counts; is_running() keeps reporting the process as gone until it is re-spawned
+  fn record_exit(&mut self, exit_reason: ExitReason) {
+    if self.exited_at.is_some() || self.last_spawned_at.is_none() {
+      return;
+    }
+    eprintln!("{} exited ({})", self.bin_name, exit_reason);
+    self.write_log_note(&format!("Exited ({})", exit_reason));
+    self.last_exit = Some(exit_reason);
+    self.exited_at = Some(std::time::Instant::now());
+  }
+
+  // Called once the process is known to have exited; works out when it may be re-spawned, or marks it failed and returns None
+  pub fn schedule_respawn(&mut self) -> Option<std::time::Instant> {
+    let now = std::time::Instant::now();
+    let exited_at = self.exited_at.unwrap_or(now);
+    let uptime = self.last_spawned_at.map(|t| exited_at.saturating_duration_since(t)).unwrap_or_default();
+    if uptime >= self.restart_policy.healthy_after {
+      self.consecutive_crashes = 0;
+    }
+    self.consecutive_crashes += 1;
+
+    while let Some(restarted_at) = self.recent_restarts.front() {
+      if now.saturating_duration_since(*restarted_at) > self.restart_policy.restart_window {
+        self.recent_restarts.pop_front();
+      }
+      else {
+        break;
+      }
+    }
+    if self.recent_restarts.len() >= self.restart_policy.max_restarts as usize {
+      eprintln!("{} was re-spawned {} times within {:?} and keeps exiting (last: {}), giving up on it",
+        self.bin_name, self.recent_restarts.len(), self.restart_policy.restart_window, self.last_exit.unwrap_or(ExitReason::Unknown));
+      self.failed = true;
+      return None;
+    }
+
+    let backoff = self.restart_policy.backoff(self.consecutive_crashes);
+    eprintln!("Re-spawning {} in {:?}", self.bin_name, backoff);
+    let next_spawn_at = exited_at + backoff;
+    self.next_spawn_at = Some(next_spawn_at);
+    Some(next_spawn_at)
+  }
+
+  fn open_log(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    if self.log.is_none() {
+      let log = crate::proc_logs::ProcLog::open(&self.filesystem_log_filepath, self.log_rotation.clone())?;
+      self.log = Some(std::sync::Arc::new(std::sync::Mutex::new(log)));
+    }
+    Ok(())
+  }
+
+  // Lines about the process rather than from it go to its log as stream "launcher"
+  fn write_log_note(&self, note: &str) {
+    if let Some(log) = &self.log {
+      if let Ok(mut log) = log.lock() {
+        if let Err(e) = log.write_line("launcher", note) {
+          eprintln!("Writing to {}: {:?}", self.filesystem_log_filepath.display(), e);
+        }
+      }
+    }
+  }
 
-    let debug_process_line = format!("{} {}", self.filesystem_bin_path.display(), args.join(" "));
-    eprintln!("Spawning the process: {debug_process_line}");
+  // cgroup_parent is only needed when spec.limits has cgroup limits
+  pub fn spawn_proc(&mut self, spec: &TrackedProcSpec, cgroup_parent: Option<&std::path::Path>, spawned_child_holder: &mut Vec<std::process::Child>) -> Result<(), Box<dyn std::error::Error>> {
 
-    let child = std::process::Command::new(&self.filesystem_bin_path)
-                  .args(args)
-                  .spawn().map_err(crate::err::eloc!())?;
+    let debug_env_line: Vec<String> = spec.env.iter().map(|(key, val)| format!("{key}={val}")).collect();
+    let debug_process_line = format!("{} {} {}", debug_env_line.join(" "), self.filesystem_bin_path.display(), spec.args.join(" "));
+    eprintln!("Spawning the process: {}", debug_process_line.trim());
+
+    self.open_log()?;
+    eprintln!("Logging its output to {}", self.filesystem_log_filepath.display());
+
+    let mut command = std::process::Command::new(&self.filesystem_bin_path);
+    command.args(&spec.args)
+           .envs(spec.env.iter().map(|(key, val)| (key, val)))
+           .stdin(std::process::Stdio::null());
+    if self.output_to_log {
+      let log_file = std::fs::OpenOptions::new().create(true).append(true).open(&self.filesystem_log_filepath).map_err(crate::err::eloc!(format!("Opening {}", self.filesystem_log_filepath.display())))?;
+      command.stdout(log_file.try_clone().map_err(crate::err::eloc!())?)
+             .stderr(log_file);
+    }
+    else {
+      command.stdout(std::process::Stdio::piped())
+             .stderr(std::process::Stdio::piped());
+    }
+    if let Some(cwd) = &spec.cwd {
+      command.current_dir(cwd);
+    }
+    #[cfg(unix)]
+    {
+      // See leads_process_group(); this also keeps a Ctrl-C meant for the server from reaching its workers before it can stop them in order
+      use std::os::unix::process::CommandExt;
+      command.process_group(0);
+    }
+    let cgroup_procs_file = crate::proc_limits::prepare_cgroup(cgroup_parent, &self.bin_name, &spec.limits)?;
+    crate::proc_limits::apply_to_command(&mut command, &spec.limits, cgroup_procs_file.as_deref())?;
+    let mut child = command.spawn().map_err(crate::err::eloc!(format!("Spawning {}", self.filesystem_bin_path.display())))?;
+
+    self.write_log_note(&format!("Spawned PID {}: {}", child.id(), debug_process_line.trim()));
+    if let Some(log) = &self.log {
+      // The copier threads end by themselves once the child exits and its end of the pipes closes
+      if let Some(stdout) = child.stdout.take() {
+        crate::proc_logs::spawn_log_copier(stdout, "stdout", log.clone())?;
+      }
+      if let Some(stderr) = child.stderr.take() {
+        crate::proc_logs::spawn_log_copier(stderr, "stderr", log.clone())?;
+      }
+    }
 
     if let Some(dirname) = self.filesystem_pid_filepath.parent() {
       if !dirname.exists() {
@@ -161,13 +1027,24 @@ impl OneTrackedProc {
 
     let pid = child.id();
 
-    let pid_file_content = format!("{pid}");
-
-    eprintln!("Writing PID ({}) of new {} to {}", &pid_file_content[..], self.filesystem_bin_path.display(), self.filesystem_pid_filepath.display());
-
-    std::fs::write(&self.filesystem_pid_filepath, pid_file_content).map_err(crate::err::eloc!())?;
+    // Record the process as the OS sees it, so is_running() compares like with like
+    let mut sinfo = sysinfo::System::new();
+    sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[sysinfo::Pid::from_u32(pid)]), true);
+    let process = sinfo.process(sysinfo::Pid::from_u32(pid));
+    let record = PidRecord {
+      pid: pid,
+      start_time: process.map(|p| p.start_time()),
+      exe: Some(process.and_then(|p| p.exe()).map(|exe| exe.to_path_buf()).unwrap_or(self.filesystem_bin_path.clone())),
+      output_to_log: self.output_to_log,
+    };
+    eprintln!("Writing PID ({}) of new {} to {}", pid, self.filesystem_bin_path.display(), self.filesystem_pid_filepath.display());
+    write_pid_record(&self.filesystem_pid_filepath, &record)?;
 
     spawned_child_holder.push(child);
+    self.spawn_count += 1;
+    self.last_spawned_at = Some(std::time::Instant::now());
+    self.exited_at = None;
+    self.next_spawn_at = None;
     Ok(())
   }
 }
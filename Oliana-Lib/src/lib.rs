@@ -5,4 +5,9 @@ pub mod err;
 pub mod files;
 pub mod misc;
 pub mod launchers;
+pub mod watch;
+pub mod mock;
+pub mod proc_logs;
+pub mod proc_limits;
+pub mod models;
 
@@ -20,3 +20,21 @@ pub fn duration_to_display_str(d: &std::time::Duration) -> String {
 }
 
 
+
+// ie "2026-10-18T09:41:07.153Z"; written out by hand to avoid pulling in a date crate for log lines
+pub fn utc_timestamp_str(t: std::time::SystemTime) -> String {
+  let since_epoch = t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+  let secs = since_epoch.as_secs();
+  let (hour, minute, second) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+  // Days since 1970-01-01 to a proleptic gregorian date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+  let days = (secs / 86400) as i64 + 719468;
+  let era = days.div_euclid(146097);
+  let day_of_era = days.rem_euclid(146097);
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let mp = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+  format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, hour, minute, second, since_epoch.subsec_millis())
+}
@@ -0,0 +1,350 @@
+
+use crate as oliana_lib;
+
+// Stand-ins for the models behind oliana_text and oliana_images, so the server, CLI and GUI can be developed (and tested)
+// on machines without a GPU. run_mock_text_worker() and run_mock_image_worker() speak the same workdir protocol as the
+// real workers: `NAME.json` in, `NAME.txt` / `NAME.png` / previews / `NAME.progress` out, `NAME.cancel` honoured, `NAME.done` always written.
+// They also beat like the real workers (once per token / step while busy), so a long token_delay or step_delay looks like a hung worker.
+//
+// Output is a pure function of the seed and the prompts, so the same request always produces the same text and image.
+
+/// Default time between mock tokens; the first token takes MOCK_FIRST_TOKEN_DELAY_FACTOR times longer (prompt processing)
+pub const DEFAULT_MOCK_TOKEN_DELAY: std::time::Duration = std::time::Duration::from_millis(40);
+pub const MOCK_FIRST_TOKEN_DELAY_FACTOR: u32 = 6;
+/// Default time each mock diffusion step takes
+pub const DEFAULT_MOCK_STEP_DELAY: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Matches the resolution of oliana_images output (1/2 of SDXL's 1024px, to keep the uncompressed .png small)
+pub const MOCK_IMAGE_SIZE: u32 = 512;
+/// Previews are 1/8th of the final resolution, like the latent previews oliana_images writes
+pub const MOCK_PREVIEW_SIZE: u32 = MOCK_IMAGE_SIZE / 8;
+
+const MOCK_WORDS: &[&str] = &[
+  "the", "a", "model", "mock", "server", "token", "stream", "quickly", "slowly", "river", "mountain", "forest", "cow", "moon",
+  "fireworks", "kitchen", "pie", "recipe", "bake", "oven", "flour", "butter", "sugar", "apple", "warm", "golden", "crust",
+  "and", "but", "then", "while", "because", "every", "small", "large", "bright", "quiet", "over", "under", "through", "with",
+  "without", "careful", "simple", "steps", "first", "next", "finally", "enjoy", "ready", "minutes", "heat", "mix", "slice",
+];
+
+/// splitmix64; small, fast and identical on every platform, which is all the mock output needs
+#[derive(Debug, Clone)]
+pub struct MockRng {
+  state: u64,
+}
+
+impl MockRng {
+  pub fn new(seed: u64) -> Self {
+    Self {
+      state: seed
+    }
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = self.state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+  }
+
+  /// Uniform in [0, 1)
+  pub fn next_f32(&mut self) -> f32 {
+    (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+  }
+
+  /// Uniform in [low, high]
+  pub fn range(&mut self, low: u64, high: u64) -> u64 {
+    low + self.next_u64() % (high - low + 1)
+  }
+}
+
+/// Combines `seed` with every string in `parts` (FNV-1a), so each distinct prompt gets its own deterministic output
+pub fn mock_seed(seed: u64, parts: &[&str]) -> u64 {
+  let mut hash: u64 = 0xcbf29ce484222325 ^ seed;
+  for part in parts.iter() {
+    for byte in part.as_bytes().iter().chain(std::iter::once(&0u8)) {
+      hash ^= *byte as u64;
+      hash = hash.wrapping_mul(0x100000001b3);
+    }
+  }
+  hash
+}
+
+/// Pseudo-text for a prompt as (delay before the token, token) pairs; tokens carry their leading space like LLM tokens do
+pub fn mock_text_tokens(seed: u64, system_prompt: &str, user_prompt: &str, token_delay: std::time::Duration) -> Vec<(std::time::Duration, String)> {
+  let mut rng = MockRng::new(mock_seed(seed, &[system_prompt, user_prompt]));
+  let mut tokens: Vec<(std::time::Duration, String)> = vec![];
+  let num_sentences = rng.range(2, 5);
+  for _ in 0..num_sentences {
+    let num_words = rng.range(5, 14);
+    for word_i in 0..num_words {
+      let mut word = MOCK_WORDS[rng.range(0, MOCK_WORDS.len() as u64 - 1) as usize].to_string();
+      if word_i == 0 {
+        word = word[..1].to_uppercase() + &word[1..];
+      }
+      if word_i == num_words - 1 {
+        word.push('.');
+      }
+      else if rng.range(0, 9) == 0 {
+        word.push(',');
+      }
+      if tokens.len() > 0 {
+        word.insert(0, ' ');
+      }
+      // Jitter of 50% to 150% of token_delay, so clients see uneven arrival like a real model
+      let mut delay = token_delay.mul_f32(0.5 + rng.next_f32());
+      if tokens.len() < 1 {
+        delay *= MOCK_FIRST_TOKEN_DELAY_FACTOR;
+      }
+      tokens.push((delay, word));
+    }
+  }
+  tokens
+}
+
+/// A procedurally generated `size`x`size` .png for a prompt. `noise` from 0.0 to 1.0 mixes in static, which previews use to look like
+/// an image being denoised; the same prompt always produces the same picture underneath.
+pub fn mock_image_png(seed: u64, prompt: &str, negative_prompt: &str, size: u32, noise: f32) -> Vec<u8> {
+  let mut rng = MockRng::new(mock_seed(seed, &[prompt, negative_prompt]));
+  let mut waves: Vec<(f32, f32, f32)> = vec![];
+  for _ in 0..3 {
+    waves.push((rng.next_f32() * 4.0 + 0.5, rng.next_f32() * 4.0 + 0.5, rng.next_f32() * std::f32::consts::TAU));
+  }
+  let mut palette: Vec<[f32; 3]> = vec![];
+  for _ in 0..3 {
+    palette.push([rng.next_f32() * 255.0, rng.next_f32() * 255.0, rng.next_f32() * 255.0]);
+  }
+  let noise = noise.clamp(0.0, 1.0);
+  // Noise comes from its own stream so the picture underneath does not change with `noise`
+  let mut noise_rng = MockRng::new(rng.next_u64());
+
+  let mut rgb: Vec<u8> = Vec::with_capacity((size * size * 3) as usize);
+  for y in 0..size {
+    for x in 0..size {
+      let (fx, fy) = (x as f32 / size as f32, y as f32 / size as f32);
+      let mut v = 0.0;
+      for (x_freq, y_freq, phase) in waves.iter() {
+        v += (fx * x_freq * std::f32::consts::TAU + fy * y_freq * std::f32::consts::TAU + phase).sin();
+      }
+      let t = ((v / waves.len() as f32) + 1.0) / 2.0; // 0.0 to 1.0
+      let (from, to, t) = if t < 0.5 { (palette[0], palette[1], t * 2.0) } else { (palette[1], palette[2], (t - 0.5) * 2.0) };
+      for c in 0..3 {
+        let clean = from[c] + (to[c] - from[c]) * t;
+        let value = clean * (1.0 - noise) + noise_rng.next_f32() * 255.0 * noise;
+        rgb.push(value.clamp(0.0, 255.0) as u8);
+      }
+    }
+  }
+  encode_png_rgb(size, size, &rgb)
+}
+
+/// Encodes 8-bit RGB pixels as a .png using uncompressed deflate blocks; larger than a real encoder's output but needs no dependencies
+pub fn encode_png_rgb(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+  let row_len = (width * 3) as usize;
+  let mut raw: Vec<u8> = Vec::with_capacity((row_len + 1) * height as usize);
+  for row in rgb.chunks(row_len).take(height as usize) {
+    raw.push(0); // Filter type None
+    raw.extend_from_slice(row);
+  }
+
+  let mut zlib: Vec<u8> = vec![0x78, 0x01];
+  let mut blocks = raw.chunks(0xFFFF).peekable();
+  if blocks.peek().is_none() {
+    zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+  }
+  while let Some(block) = blocks.next() {
+    zlib.push(if blocks.peek().is_none() { 1 } else { 0 }); // BFINAL, BTYPE=00 (stored)
+    zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+    zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+    zlib.extend_from_slice(block);
+  }
+  zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+  let mut ihdr: Vec<u8> = vec![];
+  ihdr.extend_from_slice(&width.to_be_bytes());
+  ihdr.extend_from_slice(&height.to_be_bytes());
+  ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bits per channel, RGB, deflate, no filter method, no interlace
+
+  let mut png: Vec<u8> = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+  write_png_chunk(&mut png, b"IHDR", &ihdr);
+  write_png_chunk(&mut png, b"IDAT", &zlib);
+  write_png_chunk(&mut png, b"IEND", &[]);
+  png
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+  png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+  png.extend_from_slice(chunk_type);
+  png.extend_from_slice(data);
+  let mut crc: u32 = 0xFFFFFFFF;
+  for byte in chunk_type.iter().chain(data.iter()) {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+    }
+  }
+  png.extend_from_slice(&(!crc).to_be_bytes());
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  for chunk in data.chunks(5552) { // Largest run which cannot overflow before the modulo
+    for byte in chunk.iter() {
+      a += *byte as u32;
+      b += a;
+    }
+    a %= 65521;
+    b %= 65521;
+  }
+  (b << 16) | a
+}
+
+/// Serves `NAME.json` text requests from `workdir` with mock_text_tokens() until too many errors happen
+pub async fn run_mock_text_worker(workdir: &str, seed: u64, token_delay: std::time::Duration) -> Result<(), Box<dyn std::error::Error>> {
+  run_mock_worker(workdir, |json_file| async move {
+    mock_text_job(&json_file, seed, token_delay).await
+  }).await
+}
+
+/// Serves `NAME.json` image requests from `workdir` with mock_image_png() until too many errors happen
+pub async fn run_mock_image_worker(workdir: &str, seed: u64, step_delay: std::time::Duration) -> Result<(), Box<dyn std::error::Error>> {
+  run_mock_worker(workdir, |json_file| async move {
+    mock_image_job(&json_file, seed, step_delay).await
+  }).await
+}
+
+// The directory scan both mock workers share; same rules as oliana_text: only .json files newer than this process and newer than the last time we saw them
+async fn run_mock_worker<F, Fut>(workdir: &str, process_job: F) -> Result<(), Box<dyn std::error::Error>>
+  where F: Fn(std::path::PathBuf) -> Fut, Fut: std::future::Future<Output = Result<(), Box<dyn std::error::Error>>>
+{
+  tokio::fs::create_dir_all(workdir).await?;
+  let workdir_watcher = oliana_lib::watch::DirWatcher::new(workdir).map_err(oliana_lib::eloc!())?;
+  let mut workdir_events = workdir_watcher.subscribe();
+  oliana_lib::launchers::write_worker_ready_file(workdir).map_err(oliana_lib::eloc!())?; // There is no model to load
+
+  let our_start_time = std::time::SystemTime::now();
+  let mut last_seen_mtime = std::collections::HashMap::<std::path::PathBuf, std::time::SystemTime>::new();
+  let mut allowed_errors_remaining = 100;
+  while allowed_errors_remaining > 0 {
+    oliana_lib::launchers::write_worker_heartbeat(workdir, oliana_lib::launchers::WorkerActivity::Idle, None).map_err(oliana_lib::eloc!())?;
+    let mut dir_iterator = tokio::fs::read_dir(workdir).await?;
+    while let Some(entry) = dir_iterator.next_entry().await? {
+      let entry_path = entry.path();
+      if !entry_path.is_file() || entry_path.extension().and_then(std::ffi::OsStr::to_str) != Some("json") {
+        continue;
+      }
+      let file_mtime = match tokio::fs::metadata(&entry_path).await.and_then(|m| m.modified().map(|mtime| (m.len(), mtime))) {
+        Ok((0, _)) => continue, // Created but not written yet; the write wakes us up again
+        Ok((_, file_mtime)) => file_mtime,
+        Err(e) => {
+          allowed_errors_remaining -= 1;
+          eprintln!("{:?}", e);
+          continue;
+        }
+      };
+      if file_mtime <= our_start_time || file_mtime <= *last_seen_mtime.get(&entry_path).unwrap_or(&std::time::SystemTime::UNIX_EPOCH) {
+        continue;
+      }
+      println!("Processing {}", entry_path.display());
+      last_seen_mtime.insert(entry_path.clone(), std::time::SystemTime::now());
+
+      let done_file = entry_path.with_extension("done");
+      if done_file.exists() {
+        tokio::fs::remove_file(&done_file).await?;
+      }
+      if entry_path.with_extension("cancel").exists() {
+        println!("Skipping {} because it was cancelled before it began", entry_path.display());
+      }
+      else {
+        beat_busy(&entry_path)?;
+        if let Err(e) = process_job(entry_path.clone()).await {
+          allowed_errors_remaining -= 1;
+          eprintln!("{}", e);
+          let job_name = entry_path.file_stem().and_then(std::ffi::OsStr::to_str).unwrap_or("");
+          if let Err(e) = oliana_lib::launchers::write_job_failed(std::path::Path::new(workdir), job_name, &oliana_lib::err::ErrorReport::from_error(&*e)) {
+            eprintln!("{:?} when writing the error for {}", e, entry_path.display());
+          }
+        }
+      }
+      // Written whatever the outcome; the server frees the job's queue slot once it exists
+      tokio::fs::write(&done_file, " ").await?;
+    }
+    // Sleep until a .json is written or it is time for the next heartbeat
+    workdir_events.wait_for_extension("json", oliana_lib::launchers::WORKER_HEARTBEAT_INTERVAL).await;
+  }
+  Ok(())
+}
+
+// Heartbeat naming the job in `json_file` as the one being worked on
+fn beat_busy(json_file: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+  let workdir = json_file.parent().unwrap_or(std::path::Path::new("."));
+  let job_name = json_file.file_stem().and_then(std::ffi::OsStr::to_str);
+  oliana_lib::launchers::write_worker_heartbeat(workdir, oliana_lib::launchers::WorkerActivity::Busy, job_name).map_err(oliana_lib::eloc!())?;
+  Ok(())
+}
+
+fn read_json_string(input_data: &serde_json::Value, key: &str) -> String {
+  input_data.get(key).and_then(serde_json::Value::as_str).unwrap_or("").to_string()
+}
+
+async fn mock_text_job(json_file: &std::path::Path, seed: u64, token_delay: std::time::Duration) -> Result<(), Box<dyn std::error::Error>> {
+  let input_data: serde_json::Value = serde_json::from_str(&tokio::fs::read_to_string(json_file).await?).map_err(oliana_lib::eloc!())?;
+  let system_prompt = read_json_string(&input_data, "system_prompt");
+  let user_prompt = read_json_string(&input_data, "user_prompt");
+
+  let out_txt_file = json_file.with_extension("txt");
+  let in_cancel_file = json_file.with_extension("cancel");
+  // Zeroed, then appended to one token at a time like oliana_text does
+  tokio::fs::write(&out_txt_file, "").await?;
+  let mut out_txt_fd = tokio::fs::File::options().append(true).open(&out_txt_file).await?;
+  for (delay, token) in mock_text_tokens(seed, &system_prompt, &user_prompt, token_delay) {
+    tokio::time::sleep(delay).await;
+    beat_busy(json_file)?;
+    if in_cancel_file.exists() {
+      println!("Cancelled {}", json_file.display());
+      break;
+    }
+    tokio::io::AsyncWriteExt::write_all(&mut out_txt_fd, token.as_bytes()).await?;
+    tokio::io::AsyncWriteExt::flush(&mut out_txt_fd).await?;
+  }
+  Ok(())
+}
+
+async fn mock_image_job(json_file: &std::path::Path, seed: u64, step_delay: std::time::Duration) -> Result<(), Box<dyn std::error::Error>> {
+  let input_data: serde_json::Value = serde_json::from_str(&tokio::fs::read_to_string(json_file).await?).map_err(oliana_lib::eloc!())?;
+  let prompt = read_json_string(&input_data, "prompt");
+  let negative_prompt = read_json_string(&input_data, "negative_prompt");
+  let num_steps = input_data.get("num_inference_steps").and_then(serde_json::Value::as_u64).unwrap_or(10) as u32;
+
+  let job_name = json_file.file_stem().and_then(std::ffi::OsStr::to_str).unwrap_or("").to_string();
+  let in_cancel_file = json_file.with_extension("cancel");
+  for step in 1..=num_steps {
+    tokio::time::sleep(step_delay).await;
+    beat_busy(json_file)?;
+    if in_cancel_file.exists() {
+      println!("Cancelled {}", json_file.display());
+      return Ok(()); // No .png for cancelled runs
+    }
+    let noise = 1.0 - (step as f32 / num_steps as f32);
+    let preview_file = json_file.with_file_name(format!("{}.preview-{}.png", job_name, step));
+    write_then_rename(&preview_file, &mock_image_png(seed, &prompt, &negative_prompt, MOCK_PREVIEW_SIZE, noise)).await?;
+    write_then_rename(&json_file.with_extension("progress"), format!(r#"{{"step": {}, "num_steps": {}}}"#, step, num_steps).as_bytes()).await?;
+    // Only the newest frame is kept around
+    let previous_preview_file = json_file.with_file_name(format!("{}.preview-{}.png", job_name, step - 1));
+    if previous_preview_file.exists() {
+      tokio::fs::remove_file(&previous_preview_file).await?;
+    }
+  }
+  println!("Saving {}", json_file.with_extension("png").display());
+  write_then_rename(&json_file.with_extension("png"), &mock_image_png(seed, &prompt, &negative_prompt, MOCK_IMAGE_SIZE, 0.0)).await?;
+  Ok(())
+}
+
+// Readers never see a half-written file
+async fn write_then_rename(path: &std::path::Path, contents: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+  let mut tmp_path = path.as_os_str().to_owned();
+  tmp_path.push(".tmp");
+  tokio::fs::write(&tmp_path, contents).await?;
+  tokio::fs::rename(&tmp_path, path).await?;
+  Ok(())
+}
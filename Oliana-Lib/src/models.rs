@@ -0,0 +1,309 @@
+
+use crate as oliana_lib;
+
+// Which models the workers load, and where from, is data: a TOML manifest (models.toml next to this crate's Cargo.toml is built in,
+// OLIANA_MODELS_MANIFEST points workers at another one) lists each model's repository, revision, files, checksums and the directory
+// under the cache root its files are stored in. Workers call ensure_model() and load the model from the directory it returns;
+// prefetch_model() / prefetch_python_packages() fetch everything ahead of time (see `oliana_server --prefetch`).
+//
+// In offline mode (OLIANA_OFFLINE=1, set for workers by oliana_server's `offline = true`) nothing is downloaded or pip-installed;
+// a missing file is reported as a MissingArtifactsError naming it.
+
+pub const MODELS_MANIFEST_ENV_VAR: &str = "OLIANA_MODELS_MANIFEST";
+pub const OFFLINE_ENV_VAR: &str = "OLIANA_OFFLINE";
+
+pub const BUILTIN_MANIFEST_TOML: &str = include_str!("../models.toml");
+
+/// ModelEntry::name of what oliana_text loads
+pub const TEXT_MODEL_NAME: &str = "text";
+/// ModelEntry::name of what oliana_images loads
+pub const IMAGE_MODEL_NAME: &str = "images";
+/// HF_HOME of oliana_text, under the cache directory
+pub const TEXT_HF_HOME_DIR: &str = "Oliana-Text-hf_home";
+/// HF_HOME of oliana_images, under the cache directory
+pub const IMAGE_HF_HOME_DIR: &str = "Oliana-Images-hf_home";
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelManifest {
+  /// Files of every model are downloaded from <huggingface_url>/<repo>/resolve/<revision>/<path> after its mirrors
+  pub huggingface_url: String,
+  pub models: Vec<ModelEntry>,
+  /// What oliana_images needs installed into its python path
+  pub python: PythonPackages,
+}
+
+impl Default for ModelManifest {
+  fn default() -> Self {
+    Self {
+      huggingface_url: "https://huggingface.co".to_string(),
+      models: vec![],
+      python: PythonPackages::default(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelEntry {
+  /// What workers ask ensure_model() for, ie "text"
+  pub name: String,
+  /// Hugging Face repository, ie "microsoft/Phi-3.5-mini-instruct"
+  pub repo: String,
+  /// Branch, tag or commit hash; only a commit hash keeps the files (and their checksums) from changing under us
+  #[serde(default = "default_revision")]
+  pub revision: String,
+  /// Relative to the cache root; the model is loaded from this directory
+  pub cache_dir: std::path::PathBuf,
+  /// Base URLs or local directories holding the repository's files at the same relative paths, tried in order before Hugging Face
+  #[serde(default)]
+  pub mirrors: Vec<String>,
+  pub files: Vec<ModelFile>,
+}
+
+fn default_revision() -> String {
+  "main".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelFile {
+  /// Relative to both the repository and cache_dir, ie "unet/config.json"
+  pub path: String,
+  #[serde(default)]
+  pub sha256: Option<String>,
+  #[serde(default)]
+  pub size_bytes: Option<u64>,
+}
+
+impl ModelFile {
+  pub fn expected(&self) -> oliana_lib::files::ExpectedFile {
+    oliana_lib::files::ExpectedFile {
+      sha256: self.sha256.clone(),
+      size_bytes: self.size_bytes,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PythonPackages {
+  /// Where pip installs packages, relative to the cache root
+  pub site_packages_dir: std::path::PathBuf,
+  pub packages: Vec<PythonPackage>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PythonPackage {
+  /// Module which is importable once the requirements are installed, ie "torch"
+  pub import: String,
+  /// Passed to pip install, ie ["torch", "torchvision"]
+  pub requirements: Vec<String>,
+  #[serde(default)]
+  pub index_url: Option<String>,
+}
+
+impl ModelManifest {
+  /// The models.toml built into oliana_lib
+  pub fn builtin() -> Result<Self, Box<dyn std::error::Error>> {
+    Self::from_toml_str(BUILTIN_MANIFEST_TOML)
+  }
+
+  pub fn from_toml_str(manifest_text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    let manifest: Self = toml::from_str(manifest_text).map_err(oliana_lib::eloc!())?;
+    Ok(manifest)
+  }
+
+  pub fn load(path: &std::path::Path) -> Result<Self, Box<dyn std::error::Error>> {
+    let manifest_text = std::fs::read_to_string(path).map_err(oliana_lib::eloc!(format!("Reading {}", path.display())))?;
+    let manifest: Self = toml::from_str(&manifest_text).map_err(oliana_lib::eloc!(format!("Parsing {}", path.display())))?;
+    Ok(manifest)
+  }
+
+  /// The manifest $OLIANA_MODELS_MANIFEST names, else the built in one
+  pub fn load_default() -> Result<Self, Box<dyn std::error::Error>> {
+    match std::env::var(MODELS_MANIFEST_ENV_VAR) {
+      Ok(path) if path.len() > 0 => Self::load(std::path::Path::new(&path)),
+      _ => Self::builtin(),
+    }
+  }
+
+  pub fn model(&self, name: &str) -> Result<&ModelEntry, Box<dyn std::error::Error>> {
+    self.models.iter().find(|model| model.name == name).ok_or_else(|| format!("The models manifest has no model named {:?}", name).into())
+  }
+
+  /// Where each of `model`'s files may be fetched from, in the order they are tried
+  pub fn file_sources(&self, model: &ModelEntry, file: &ModelFile) -> Vec<String> {
+    let mut sources: Vec<String> = model.mirrors.iter().map(|mirror| format!("{}/{}", mirror.trim_end_matches('/'), file.path)).collect();
+    sources.push(format!("{}/{}/resolve/{}/{}", self.huggingface_url.trim_end_matches('/'), model.repo, model.revision, file.path));
+    sources
+  }
+}
+
+impl ModelEntry {
+  pub fn local_dir(&self, cache_root: &std::path::Path) -> std::path::PathBuf {
+    cache_root.join(&self.cache_dir)
+  }
+
+  pub fn local_file(&self, cache_root: &std::path::Path, file: &ModelFile) -> std::path::PathBuf {
+    self.local_dir(cache_root).join(&file.path)
+  }
+
+  /// Files which are not under local_dir(), or have the wrong size; cheap enough to run on every worker start, unlike hashing them
+  pub fn missing_files(&self, cache_root: &std::path::Path) -> Vec<&ModelFile> {
+    self.files.iter().filter(|file| {
+      match std::fs::metadata(self.local_file(cache_root, file)) {
+        Ok(metadata) => file.size_bytes.map(|size_bytes| size_bytes != metadata.len()).unwrap_or(false),
+        Err(_) => true,
+      }
+    }).collect()
+  }
+}
+
+/// True for a full commit hash, the only kind of revision which cannot move under a manifest's checksums
+pub fn is_commit_hash(revision: &str) -> bool {
+  revision.len() == 40 && revision.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The commit `model.revision` points to right now, asked from the Hugging Face API; a revision which already is one is returned as-is
+pub async fn resolve_revision(manifest: &ModelManifest, model: &ModelEntry) -> Result<String, Box<dyn std::error::Error>> {
+  if is_commit_hash(&model.revision) {
+    return Ok(model.revision.clone());
+  }
+  let url = format!("{}/api/models/{}/revision/{}", manifest.huggingface_url.trim_end_matches('/'), model.repo, model.revision);
+  let response = reqwest::get(&url).await.map_err(oliana_lib::eloc!(format!("Fetching {}", url)))?;
+  let response = response.error_for_status().map_err(oliana_lib::eloc!(format!("Fetching {}", url)))?;
+  let body = response.text().await.map_err(oliana_lib::eloc!(format!("Fetching {}", url)))?;
+  let revision_info: serde_json::Value = serde_json::from_str(&body).map_err(oliana_lib::eloc!(format!("Parsing {}", url)))?;
+  match revision_info.get("sha").and_then(serde_json::Value::as_str) {
+    Some(sha) if is_commit_hash(sha) => Ok(sha.to_string()),
+    _ => Err(format!("{} names no commit (\"sha\")", url).into()),
+  }
+}
+
+/// True when $OLIANA_OFFLINE is set to anything but "", "0" or "false"
+pub fn offline_mode() -> bool {
+  match std::env::var(OFFLINE_ENV_VAR) {
+    Ok(val) => !(val.len() < 1 || val == "0" || val.eq_ignore_ascii_case("false")),
+    Err(_) => false,
+  }
+}
+
+/// Offline mode and something a worker needs is not on disk. Returned un-wrapped so callers can `e.downcast_ref::<MissingArtifactsError>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingArtifactsError {
+  /// ie "model text (microsoft/Phi-3.5-mini-instruct)"
+  pub what: String,
+  pub missing: Vec<String>,
+}
+
+impl std::fmt::Display for MissingArtifactsError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Offline mode ({}) and {} is missing {}; run `oliana_server --prefetch` on a host with network access and copy its cache directory over",
+      OFFLINE_ENV_VAR, self.what, self.missing.join(", "))
+  }
+}
+
+impl std::error::Error for MissingArtifactsError { }
+
+/// Returns the directory the model called `name` can be loaded from, downloading any of its files which are missing unless `offline`
+pub async fn ensure_model(manifest: &ModelManifest, name: &str, cache_root: &std::path::Path, offline: bool) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+  let model = manifest.model(name)?;
+  let missing = model.missing_files(cache_root);
+  if missing.len() > 0 {
+    if offline {
+      return Err(Box::new(MissingArtifactsError {
+        what: format!("model {} ({})", model.name, model.repo),
+        missing: missing.iter().map(|file| model.local_file(cache_root, file).display().to_string()).collect(),
+      }));
+    }
+    for file in missing {
+      let local_file = model.local_file(cache_root, file); // existinate re-downloads one of the wrong size
+      oliana_lib::files::existinate_from_mirrors(&local_file, &manifest.file_sources(model, file), Some(&file.expected()), &oliana_lib::files::DownloadOptions::default()).await?;
+    }
+  }
+  Ok(model.local_dir(cache_root))
+}
+
+/// One file prefetch_model() made sure of
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefetchedFile {
+  pub path: std::path::PathBuf,
+  pub sha256: String,
+  pub size_bytes: u64,
+  /// Whether the manifest has a sha256 for it; the ones which do not can be pinned with `sha256` and `size_bytes`
+  pub pinned: bool,
+}
+
+/// Downloads every file of `model` which is missing or does not match its checksum (existing files are re-hashed, so this also verifies a cache)
+pub async fn prefetch_model(manifest: &ModelManifest, model: &ModelEntry, cache_root: &std::path::Path, options: &oliana_lib::files::DownloadOptions) -> Result<Vec<PrefetchedFile>, Box<dyn std::error::Error>> {
+  let mut prefetched = vec![];
+  for file in model.files.iter() {
+    let local_file = model.local_file(cache_root, file);
+    oliana_lib::files::existinate_from_mirrors(&local_file, &manifest.file_sources(model, file), Some(&file.expected()), options).await?;
+    let sha256 = match &file.sha256 {
+      Some(sha256) => sha256.to_lowercase(), // Just verified
+      None => oliana_lib::files::sha256_of_file(&local_file).await?,
+    };
+    prefetched.push(PrefetchedFile {
+      size_bytes: tokio::fs::metadata(&local_file).await.map_err(oliana_lib::eloc!())?.len(),
+      path: local_file,
+      sha256: sha256,
+      pinned: file.sha256.is_some(),
+    });
+  }
+  Ok(prefetched)
+}
+
+/// Where oliana_images installs python packages
+pub fn python_site_packages_dir(manifest: &ModelManifest, cache_root: &std::path::Path) -> std::path::PathBuf {
+  cache_root.join(&manifest.python.site_packages_dir)
+}
+
+/// The interpreter prefetch_python_packages() runs pip with
+pub fn python_interpreter() -> &'static str {
+  if cfg!(target_os="windows") { "python" } else { "python3" }
+}
+
+/// pip-installs every package of the manifest which `python` cannot import from the site packages dir yet, returning their import names.
+/// With `offline`, nothing is installed and missing packages are returned as a MissingArtifactsError instead.
+pub async fn prefetch_python_packages(manifest: &ModelManifest, cache_root: &std::path::Path, python: &str, offline: bool) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+  let site_packages = python_site_packages_dir(manifest, cache_root);
+  tokio::fs::create_dir_all(&site_packages).await.map_err(oliana_lib::eloc!())?;
+  let mut missing = vec![];
+  for package in manifest.python.packages.iter() {
+    let importable = tokio::process::Command::new(python)
+      .args(["-c", &format!("import {}", package.import)])
+      .env("PYTHONPATH", &site_packages)
+      .stdout(std::process::Stdio::null())
+      .stderr(std::process::Stdio::null())
+      .status().await.map_err(oliana_lib::eloc!(format!("Running {}", python)))?.success();
+    if !importable {
+      missing.push(package);
+    }
+  }
+  if offline && missing.len() > 0 {
+    return Err(Box::new(MissingArtifactsError {
+      what: format!("python packages under {}", site_packages.display()),
+      missing: missing.iter().map(|package| package.import.clone()).collect(),
+    }));
+  }
+
+  let mut installed = vec![];
+  for package in missing {
+    let mut args = vec!["-m".to_string(), "pip".to_string(), "install".to_string(), format!("--target={}", site_packages.display())];
+    args.extend(package.requirements.iter().cloned());
+    if let Some(index_url) = &package.index_url {
+      args.push("--index-url".to_string());
+      args.push(index_url.clone());
+    }
+    eprintln!("Installing {} into {}", package.requirements.join(" "), site_packages.display());
+    let status = tokio::process::Command::new(python).args(&args).status().await.map_err(oliana_lib::eloc!(format!("Running {}", python)))?;
+    if !status.success() {
+      return Err(format!("pip install {} failed with {}", package.requirements.join(" "), status).into());
+    }
+    installed.push(package.import.clone());
+  }
+  Ok(installed)
+}
@@ -0,0 +1,129 @@
+
+use crate as oliana_lib;
+
+// Limits TrackedProcs puts on a worker so a runaway one (ie a python worker leaking memory) cannot take the host down with it.
+// rlimits are set in the child between fork and exec and apply to the worker alone. cgroup limits need a cgroup v2 directory
+// delegated to us (TrackedProcs::cgroup_parent, ie one created by systemd with Delegate=yes); each worker gets a child cgroup
+// <cgroup_parent>/<bin_name> which it joins before exec, so the limits also cover anything it spawns.
+
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceLimits {
+  /// RLIMIT_AS. CUDA reserves far more address space than it ever touches, so GPU workers want cgroup_memory_max_bytes instead.
+  pub max_address_space_bytes: Option<u64>,
+  /// RLIMIT_CPU, in seconds of CPU time
+  pub max_cpu_secs: Option<u64>,
+  /// RLIMIT_NOFILE
+  pub max_open_files: Option<u64>,
+  /// cgroup v2 memory.max; the kernel OOM-kills the worker (and only the worker) past this
+  pub cgroup_memory_max_bytes: Option<u64>,
+  /// cgroup v2 cpu.max expressed in cores, ie 2.5 lets the worker use two and a half cores' worth of CPU time
+  pub cgroup_cpu_max_cores: Option<f64>,
+}
+
+// cpu.max quotas are given per period of this many microseconds
+const CGROUP_CPU_PERIOD_US: u64 = 100_000;
+
+impl ResourceLimits {
+  pub fn has_rlimits(&self) -> bool {
+    self.max_address_space_bytes.is_some() || self.max_cpu_secs.is_some() || self.max_open_files.is_some()
+  }
+
+  pub fn has_cgroup_limits(&self) -> bool {
+    self.cgroup_memory_max_bytes.is_some() || self.cgroup_cpu_max_cores.is_some()
+  }
+}
+
+/// Creates (or updates) <cgroup_parent>/<name> with the cgroup limits in `limits` and returns its cgroup.procs file,
+/// which apply_to_command() has the child join. Returns None when `limits` has no cgroup limits.
+pub fn prepare_cgroup(cgroup_parent: Option<&std::path::Path>, name: &str, limits: &ResourceLimits) -> Result<Option<std::path::PathBuf>, Box<dyn std::error::Error>> {
+  if !limits.has_cgroup_limits() {
+    return Ok(None);
+  }
+  if !cfg!(target_os = "linux") {
+    return Err(format!("cgroup limits for {} are only supported on linux", name).into());
+  }
+  let cgroup_parent = cgroup_parent.ok_or_else(|| format!("cgroup limits for {} need a cgroup_parent to create its cgroup under", name))?;
+  let cgroup = cgroup_parent.join(name);
+  if !cgroup.exists() {
+    std::fs::create_dir_all(&cgroup).map_err(oliana_lib::eloc!(format!("Creating cgroup {}", cgroup.display())))?;
+  }
+
+  // Controllers must be enabled for a parent's children before the children's memory.max / cpu.max exist
+  let mut controllers = vec![];
+  if limits.cgroup_memory_max_bytes.is_some() {
+    controllers.push("+memory");
+  }
+  if limits.cgroup_cpu_max_cores.is_some() {
+    controllers.push("+cpu");
+  }
+  write_cgroup_file(&cgroup_parent.join("cgroup.subtree_control"), &controllers.join(" "))?;
+
+  if let Some(memory_max_bytes) = limits.cgroup_memory_max_bytes {
+    write_cgroup_file(&cgroup.join("memory.max"), &format!("{}", memory_max_bytes))?;
+  }
+  if let Some(cpu_max_cores) = limits.cgroup_cpu_max_cores {
+    let quota_us = std::cmp::max(1000, (cpu_max_cores * CGROUP_CPU_PERIOD_US as f64) as u64);
+    write_cgroup_file(&cgroup.join("cpu.max"), &format!("{} {}", quota_us, CGROUP_CPU_PERIOD_US))?;
+  }
+  Ok(Some(cgroup.join("cgroup.procs")))
+}
+
+fn write_cgroup_file(path: &std::path::Path, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+  std::fs::write(path, value).map_err(oliana_lib::eloc!(format!("Writing {:?} to {}", value, path.display())))?;
+  Ok(())
+}
+
+/// Has the child set the rlimits in `limits` and join `cgroup_procs_file` (from prepare_cgroup()) before it execs
+pub fn apply_to_command(command: &mut std::process::Command, limits: &ResourceLimits, cgroup_procs_file: Option<&std::path::Path>) -> Result<(), Box<dyn std::error::Error>> {
+  if !limits.has_rlimits() && cgroup_procs_file.is_none() {
+    return Ok(());
+  }
+  #[cfg(unix)]
+  {
+    use std::os::unix::process::CommandExt;
+    let mut rlimits = vec![];
+    if let Some(max_address_space_bytes) = limits.max_address_space_bytes {
+      rlimits.push((libc::RLIMIT_AS, max_address_space_bytes as libc::rlim_t));
+    }
+    if let Some(max_cpu_secs) = limits.max_cpu_secs {
+      rlimits.push((libc::RLIMIT_CPU, max_cpu_secs as libc::rlim_t));
+    }
+    if let Some(max_open_files) = limits.max_open_files {
+      rlimits.push((libc::RLIMIT_NOFILE, max_open_files as libc::rlim_t));
+    }
+    let cgroup_procs_file = match cgroup_procs_file {
+      Some(path) => Some(std::ffi::CString::new(path.as_os_str().to_string_lossy().as_bytes()).map_err(oliana_lib::eloc!())?),
+      None => None,
+    };
+    // Runs in the forked child, where only async-signal-safe calls are allowed: no allocating, no locks
+    let set_limits = move || -> std::io::Result<()> {
+      for (resource, limit) in rlimits.iter() {
+        let rlimit = libc::rlimit { rlim_cur: *limit, rlim_max: *limit };
+        if unsafe { libc::setrlimit(*resource, &rlimit) } != 0 {
+          return Err(std::io::Error::last_os_error());
+        }
+      }
+      if let Some(cgroup_procs_file) = &cgroup_procs_file {
+        // Writing "0" to cgroup.procs moves the writing process
+        let fd = unsafe { libc::open(cgroup_procs_file.as_ptr(), libc::O_WRONLY) };
+        if fd < 0 {
+          return Err(std::io::Error::last_os_error());
+        }
+        let written = unsafe { libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) };
+        let write_error = std::io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        if written != 1 {
+          return Err(write_error);
+        }
+      }
+      Ok(())
+    };
+    unsafe { command.pre_exec(set_limits) };
+    Ok(())
+  }
+  #[cfg(not(unix))]
+  {
+    Err("Resource limits for tracked processes are only supported on unix".into())
+  }
+}
@@ -0,0 +1,166 @@
+
+use crate as oliana_lib;
+
+// Tracked processes (see launchers.rs) get their stdout and stderr piped into <proc_track_dir>/<bin_name>.log instead of sharing
+// the server's terminal. Every line is prefixed with a UTC timestamp and the stream it came from, the file is appended to across
+// re-spawns, and once it grows past LogRotation::max_bytes it is renamed to <bin_name>.log.1 (shifting older files up to max_files).
+//
+//   2026-10-18T09:41:07.153Z [stderr] Traceback (most recent call last):
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRotation {
+  pub max_bytes: u64,
+  /// Rotated files kept next to the live one (<bin_name>.log.1 is the newest); 0 truncates the log instead of rotating it
+  pub max_files: u32,
+}
+
+impl Default for LogRotation {
+  fn default() -> Self {
+    Self {
+      max_bytes: 8 * 1024 * 1024,
+      max_files: 3,
+    }
+  }
+}
+
+pub fn log_file_path(proc_track_dir: &std::path::Path, bin_name: &str) -> std::path::PathBuf {
+  proc_track_dir.join(format!("{}.log", bin_name))
+}
+
+fn rotated_log_file_path(path: &std::path::Path, n: u32) -> std::path::PathBuf {
+  let mut rotated = path.as_os_str().to_os_string();
+  rotated.push(format!(".{}", n));
+  rotated.into()
+}
+
+// One log file, shared by the threads copying a child's stdout and stderr (and by TrackedProcs for its own spawn/exit notes)
+pub struct ProcLog {
+  pub path: std::path::PathBuf,
+  pub rotation: LogRotation,
+  file: std::fs::File,
+  written: u64,
+}
+
+impl ProcLog {
+  pub fn open(path: impl Into<std::path::PathBuf>, rotation: LogRotation) -> Result<Self, Box<dyn std::error::Error>> {
+    let path = path.into();
+    if let Some(dirname) = path.parent() {
+      if !dirname.exists() {
+        std::fs::create_dir_all(dirname).map_err(oliana_lib::eloc!())?;
+      }
+    }
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(&path).map_err(oliana_lib::eloc!(format!("Opening {}", path.display())))?;
+    let written = file.metadata().map_err(oliana_lib::eloc!())?.len();
+    Ok(Self {
+      path: path,
+      rotation: rotation,
+      file: file,
+      written: written,
+    })
+  }
+
+  /// Appends one timestamped line; `stream` is usually "stdout" or "stderr"
+  pub fn write_line(&mut self, stream: &str, line: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    let entry = format!("{} [{}] {}\n", crate::misc::utc_timestamp_str(std::time::SystemTime::now()), stream, line);
+    if self.written > 0 && self.written + entry.len() as u64 > self.rotation.max_bytes {
+      self.rotate()?;
+    }
+    self.file.write_all(entry.as_bytes()).map_err(oliana_lib::eloc!())?;
+    self.written += entry.len() as u64;
+    Ok(())
+  }
+
+  fn rotate(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    if self.rotation.max_files > 0 {
+      for n in (1..self.rotation.max_files).rev() {
+        let older = rotated_log_file_path(&self.path, n);
+        if older.exists() {
+          std::fs::rename(&older, rotated_log_file_path(&self.path, n + 1)).map_err(oliana_lib::eloc!())?;
+        }
+      }
+      std::fs::rename(&self.path, rotated_log_file_path(&self.path, 1)).map_err(oliana_lib::eloc!())?;
+    }
+    self.file = std::fs::OpenOptions::new().create(true).write(true).truncate(true).open(&self.path).map_err(oliana_lib::eloc!())?;
+    self.written = 0;
+    Ok(())
+  }
+}
+
+/// Copies `reader` into `log` line by line on a new thread until the child closes it.
+/// Carriage returns also end a line so progress bars (ie tqdm in oliana_images) log each update instead of one enormous line.
+pub fn spawn_log_copier(reader: impl std::io::Read + Send + 'static, stream: &'static str, log: std::sync::Arc<std::sync::Mutex<ProcLog>>) -> Result<std::thread::JoinHandle<()>, Box<dyn std::error::Error>> {
+  let thread_name = format!("log-{}", stream);
+  let handle = std::thread::Builder::new().name(thread_name).spawn(move || {
+    use std::io::BufRead;
+    let mut reader = std::io::BufReader::new(reader);
+    let mut buf: Vec<u8> = vec![];
+    loop {
+      buf.clear();
+      match reader.read_until(b'\n', &mut buf) {
+        Ok(0) => break,
+        Ok(_) => { }
+        Err(e) => {
+          eprintln!("Reading {} of a tracked process: {:?}", stream, e);
+          break;
+        }
+      }
+      let text = String::from_utf8_lossy(&buf);
+      if let Ok(mut log) = log.lock() {
+        for line in text.trim_end_matches(['\n', '\r']).split('\r') {
+          if line.len() > 0 {
+            if let Err(e) = log.write_line(stream, line) {
+              eprintln!("Writing to {}: {:?}", log.path.display(), e);
+            }
+          }
+        }
+      }
+    }
+  }).map_err(oliana_lib::eloc!())?;
+  Ok(handle)
+}
+
+/// The last `num_lines` lines of the log at `path`, oldest first, reaching into <path>.1, <path>.2, ... if the live file is shorter than that.
+/// A log which does not exist yet has no lines.
+pub fn tail_log_file(path: &std::path::Path, num_lines: usize) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+  let mut lines = tail_one_file(path, num_lines)?;
+  let mut n = 1;
+  while lines.len() < num_lines {
+    let older_path = rotated_log_file_path(path, n);
+    if !older_path.exists() {
+      break;
+    }
+    let mut older_lines = tail_one_file(&older_path, num_lines - lines.len())?;
+    older_lines.append(&mut lines);
+    lines = older_lines;
+    n += 1;
+  }
+  Ok(lines)
+}
+
+// Reads backwards from the end in chunks so tailing a large log does not read all of it
+fn tail_one_file(path: &std::path::Path, num_lines: usize) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+  use std::io::{Read, Seek};
+  const CHUNK_SIZE: u64 = 64 * 1024;
+  if num_lines < 1 || !path.exists() {
+    return Ok(vec![]);
+  }
+  let mut file = std::fs::File::open(path).map_err(oliana_lib::eloc!(format!("Opening {}", path.display())))?;
+  let file_len = file.metadata().map_err(oliana_lib::eloc!())?.len();
+  let mut start = file_len;
+  let mut tail: Vec<u8> = vec![];
+  // One newline more than lines wanted means the first line in `tail` is complete
+  while start > 0 && tail.iter().filter(|b| **b == b'\n').count() <= num_lines {
+    let chunk_start = start.saturating_sub(CHUNK_SIZE);
+    let mut chunk = vec![0u8; (start - chunk_start) as usize];
+    file.seek(std::io::SeekFrom::Start(chunk_start)).map_err(oliana_lib::eloc!())?;
+    file.read_exact(&mut chunk).map_err(oliana_lib::eloc!())?;
+    chunk.append(&mut tail);
+    tail = chunk;
+    start = chunk_start;
+  }
+  let text = String::from_utf8_lossy(&tail);
+  let all_lines: Vec<&str> = text.lines().collect();
+  let skip = all_lines.len().saturating_sub(num_lines);
+  Ok(all_lines[skip..].iter().map(|line| line.to_string()).collect())
+}
@@ -0,0 +1,184 @@
+
+use crate as oliana_lib;
+
+// Workers and the server talk through work directories (see oliana_images / oliana_text), and both sides need to know
+// when the other has written something. DirWatcher replaces 100ms polling loops with OS file events (inotify on linux),
+// falling back to a PollWatcher when native events are unavailable (ie inotify watch limits exhausted, network filesystems).
+//
+// Typical use is to subscribe() BEFORE checking the filesystem, then wait on the subscription; that ordering guarantees
+// a file created between the check and the wait still wakes the waiter.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchEventKind {
+  /// A file appeared, either by being created or by being renamed into the directory
+  Created,
+  /// A file's contents changed or a writer closed it
+  Modified,
+  /// Events were dropped (the subscriber fell behind, or the OS queue overflowed); callers should re-scan the whole directory
+  Rescan,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+  pub kind: WatchEventKind,
+  /// For WatchEventKind::Rescan this is the watched directory itself
+  pub path: std::path::PathBuf,
+}
+
+// How many events a slow subscriber may fall behind by before it is handed a WatchEventKind::Rescan
+const WATCH_EVENT_BUFFER: usize = 1024;
+
+pub struct DirWatcher {
+  pub dir: std::path::PathBuf,
+  /// True when native events could not be used and we are polling the directory instead
+  pub is_polling: bool,
+  sender: tokio::sync::broadcast::Sender<WatchEvent>,
+  _watcher: std::sync::Mutex<Box<dyn notify::Watcher + Send>>, // Never read, but must be kept alive for events to keep flowing
+}
+
+impl DirWatcher {
+  /// Watches `dir` (non-recursively) using native OS events, falling back to polling every 100ms if those are unavailable.
+  pub fn new(dir: impl Into<std::path::PathBuf>) -> Result<Self, Box<dyn std::error::Error>> {
+    let dir = dir.into();
+    let (sender, _) = tokio::sync::broadcast::channel(WATCH_EVENT_BUFFER);
+
+    let native_sender = sender.clone();
+    let native_dir = dir.clone();
+    match notify::recommended_watcher(move |res| forward_notify_event(&native_sender, &native_dir, res)) {
+      Ok(mut watcher) => {
+        match notify::Watcher::watch(&mut watcher, &dir, notify::RecursiveMode::NonRecursive) {
+          Ok(()) => {
+            return Ok(Self {
+              dir: dir,
+              is_polling: false,
+              sender: sender,
+              _watcher: std::sync::Mutex::new(Box::new(watcher)),
+            });
+          }
+          Err(e) => {
+            eprintln!("Cannot natively watch {}, falling back to polling ({:?})", dir.display(), e);
+          }
+        }
+      }
+      Err(e) => {
+        eprintln!("Cannot natively watch {}, falling back to polling ({:?})", dir.display(), e);
+      }
+    }
+
+    Self::new_polling(dir, std::time::Duration::from_millis(100))
+  }
+
+  /// Watches `dir` (non-recursively) by scanning it every `poll_interval`.
+  pub fn new_polling(dir: impl Into<std::path::PathBuf>, poll_interval: std::time::Duration) -> Result<Self, Box<dyn std::error::Error>> {
+    let dir = dir.into();
+    let (sender, _) = tokio::sync::broadcast::channel(WATCH_EVENT_BUFFER);
+
+    let poll_sender = sender.clone();
+    let poll_dir = dir.clone();
+    let mut watcher = notify::PollWatcher::new(
+      move |res| forward_notify_event(&poll_sender, &poll_dir, res),
+      notify::Config::default().with_poll_interval(poll_interval)
+    ).map_err(crate::err::eloc!())?;
+    notify::Watcher::watch(&mut watcher, &dir, notify::RecursiveMode::NonRecursive).map_err(crate::err::eloc!())?;
+
+    Ok(Self {
+      dir: dir,
+      is_polling: true,
+      sender: sender,
+      _watcher: std::sync::Mutex::new(Box::new(watcher)),
+    })
+  }
+
+  /// Returns a receiver for every event that happens after this call.
+  pub fn subscribe(&self) -> DirSubscription {
+    DirSubscription {
+      dir: self.dir.clone(),
+      receiver: self.sender.subscribe(),
+    }
+  }
+}
+
+fn forward_notify_event(sender: &tokio::sync::broadcast::Sender<WatchEvent>, dir: &std::path::Path, res: notify::Result<notify::Event>) {
+  let event = match res {
+    Ok(event) => event,
+    Err(e) => {
+      eprintln!("{}:{} {:?}", file!(), line!(), e);
+      let _ = sender.send(WatchEvent { kind: WatchEventKind::Rescan, path: dir.to_path_buf() });
+      return;
+    }
+  };
+  if event.need_rescan() {
+    let _ = sender.send(WatchEvent { kind: WatchEventKind::Rescan, path: dir.to_path_buf() });
+    return;
+  }
+  let kind = match event.kind {
+    notify::EventKind::Create(_) => WatchEventKind::Created,
+    notify::EventKind::Modify(notify::event::ModifyKind::Name(notify::event::RenameMode::From)) => return, // The old name of a rename is gone, nothing to read
+    notify::EventKind::Modify(notify::event::ModifyKind::Name(_)) => WatchEventKind::Created,
+    notify::EventKind::Modify(_) => WatchEventKind::Modified,
+    notify::EventKind::Access(notify::event::AccessKind::Close(notify::event::AccessMode::Write)) => WatchEventKind::Modified,
+    _ => return,
+  };
+  for path in event.paths {
+    // send() only fails when nobody is subscribed, which is not an error for us
+    let _ = sender.send(WatchEvent { kind: kind, path: path });
+  }
+}
+
+pub struct DirSubscription {
+  pub dir: std::path::PathBuf,
+  receiver: tokio::sync::broadcast::Receiver<WatchEvent>,
+}
+
+impl DirSubscription {
+  /// Waits up to `timeout` for the next event in the directory, returning None if nothing happened.
+  pub async fn next_event(&mut self, timeout: std::time::Duration) -> Option<WatchEvent> {
+    match tokio::time::timeout(timeout, self.receiver.recv()).await {
+      Ok(Ok(event)) => Some(event),
+      Ok(Err(tokio::sync::broadcast::error::RecvError::Lagged(_))) => Some(WatchEvent { kind: WatchEventKind::Rescan, path: self.dir.clone() }),
+      Ok(Err(tokio::sync::broadcast::error::RecvError::Closed)) => {
+        // The DirWatcher was dropped; degrade to sleeping so callers re-check the filesystem at `timeout` intervals
+        tokio::time::sleep(timeout).await;
+        None
+      }
+      Err(_elapsed) => None,
+    }
+  }
+
+  /// Waits up to `timeout` for an event where `predicate(event)` is true, returning false if `timeout` elapsed first.
+  /// A WatchEventKind::Rescan also returns true because the event we were waiting for may have been dropped.
+  pub async fn wait_for<F: FnMut(&WatchEvent) -> bool>(&mut self, timeout: std::time::Duration, mut predicate: F) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+      let now = tokio::time::Instant::now();
+      if now >= deadline {
+        return false;
+      }
+      match self.next_event(deadline - now).await {
+        Some(event) => {
+          if event.kind == WatchEventKind::Rescan || predicate(&event) {
+            return true;
+          }
+        }
+        None => {
+          return false;
+        }
+      }
+    }
+  }
+
+  /// Waits up to `timeout` for an event touching any of `paths`, returning false if `timeout` elapsed first.
+  /// Paths are compared by file name because the directory is watched non-recursively.
+  pub async fn wait_for_any(&mut self, paths: &[&std::path::Path], timeout: std::time::Duration) -> bool {
+    self.wait_for(timeout, |event| {
+      paths.iter().any(|path| path.file_name().is_some() && path.file_name() == event.path.file_name())
+    }).await
+  }
+
+  /// Waits up to `timeout` for an event on a file ending in `.<extension>` (compared case-insensitively), returning false if `timeout` elapsed first.
+  pub async fn wait_for_extension(&mut self, extension: &str, timeout: std::time::Duration) -> bool {
+    self.wait_for(timeout, |event| {
+      event.path.extension().and_then(std::ffi::OsStr::to_str).map(|e| e.eq_ignore_ascii_case(extension)).unwrap_or(false)
+    }).await
+  }
+}
@@ -0,0 +1,149 @@
+
+// OlianaServer answers RPCs and keeps track of which jobs belong to which connection; a backend is what actually runs the jobs.
+// One TextBackend and one ImageBackend are shared by every connection, chosen per worker type by the server config:
+//
+//   workdir     The original protocol: `<id>.json` is written for a worker process (oliana_text, oliana_images) which writes
+//               its results back as files. See workdir_backend.rs
+//   in_process  Jobs run on threads inside the server against a model kept resident in memory. See in_process_backend.rs
+//   remote      Jobs are forwarded to another oliana_server, ie one with a bigger GPU. See remote_backend.rs
+//
+// Job ids are allocated by the backends and are unique for the life of the server process (see crate::next_job_nonce).
+
+use crate::{JobPriority, JobStatus, ImagePreview, OlianaError, QueueStatus};
+
+/// Boxed so backends can be used as `Arc<dyn TextBackend>`
+pub type BackendFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = Result<T, OlianaError>> + Send + 'a>>;
+
+/// Input to TextBackend::begin(); serializes to the `<id>.json` read by oliana_text
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TextRequest {
+    pub system_prompt: String,
+    pub user_prompt: String,
+    #[serde(skip)]
+    pub priority: JobPriority,
+    /// See OlianaServer::client_key
+    #[serde(skip)]
+    pub client_key: String,
+}
+
+/// Input to ImageBackend::begin(); serializes to the `<id>.json` read by oliana_images
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImageRequest {
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub guidance_scale: f32,
+    pub num_inference_steps: u32,
+    #[serde(skip)]
+    pub priority: JobPriority,
+    #[serde(skip)]
+    pub client_key: String,
+}
+
+/// Returned by TextBackend::read()
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextRead {
+    /// Output past the requested byte offset; pass `next_byte_i` to the next read()
+    Text { text: String, next_byte_i: usize },
+    /// Every byte of output has been read
+    Finished,
+}
+
+pub trait TextBackend: Send + Sync {
+    /// Used in errors and logs, ie "oliana_text"
+    fn name(&self) -> String;
+
+    /// Queues `request` and returns its job id without waiting for it to start
+    fn begin(&self, request: TextRequest) -> BackendFuture<'_, u64>;
+
+    /// Waits up to `wait` for output beyond `from_byte`. Err(OlianaError::TimedOut) means nothing new arrived yet.
+    fn read(&self, job: u64, from_byte: usize, wait: std::time::Duration) -> BackendFuture<'_, TextRead>;
+
+    fn status(&self, job: u64) -> BackendFuture<'_, JobStatus>;
+
+    /// Stops `job` (or drops it from the queue). Returns false if it had already finished, in which case nothing changes.
+    fn cancel(&self, job: u64) -> BackendFuture<'_, bool>;
+
+    fn queue_status(&self) -> BackendFuture<'_, QueueStatus>;
+}
+
+pub trait ImageBackend: Send + Sync {
+    /// Used in errors and logs, ie "oliana_images"
+    fn name(&self) -> String;
+
+    /// Queues `request` and returns its job id without waiting for it to start
+    fn begin(&self, request: ImageRequest) -> BackendFuture<'_, u64>;
+
+    /// Waits up to `wait` for a preview of a step after `after_step`. Returns None once the final image (or an error) is available.
+    fn next_preview(&self, job: u64, after_step: u32, wait: std::time::Duration) -> BackendFuture<'_, Option<ImagePreview>>;
+
+    /// Waits up to `wait` for the finished .png. Err(OlianaError::TimedOut) means it is not ready yet.
+    fn get_result(&self, job: u64, wait: std::time::Duration) -> BackendFuture<'_, Vec<u8>>;
+
+    fn status(&self, job: u64) -> BackendFuture<'_, JobStatus>;
+
+    /// Stops `job` (or drops it from the queue). Returns false if it had already finished, in which case nothing changes.
+    fn cancel(&self, job: u64) -> BackendFuture<'_, bool>;
+
+    fn queue_status(&self) -> BackendFuture<'_, QueueStatus>;
+}
+
+/// Models the server binary can run in_process, keyed by the name used in the `generator` config field
+#[derive(Default, Clone)]
+pub struct InProcessGenerators {
+    pub text: std::collections::BTreeMap<String, std::sync::Arc<dyn crate::in_process_backend::TextGenerator>>,
+    pub image: std::collections::BTreeMap<String, std::sync::Arc<dyn crate::in_process_backend::ImageGenerator>>,
+}
+
+/// Everything a workdir backend needs from the running server
+#[derive(Clone)]
+pub struct WorkdirContext {
+    pub workdir: std::path::PathBuf,
+    pub watcher: Option<std::sync::Arc<oliana_lib::watch::DirWatcher>>,
+    pub procs: Option<std::sync::Arc<std::sync::RwLock<oliana_lib::launchers::TrackedProcs>>>,
+}
+
+/// Creates the text backend selected by `backend_config`, spawning its queue dispatcher if it has one
+pub fn build_text_backend(backend_config: &crate::config::BackendConfig, queue_config: &crate::config::QueueConfig, workdir: WorkdirContext, generators: &InProcessGenerators) -> Result<std::sync::Arc<dyn TextBackend>, Box<dyn std::error::Error>> {
+    use crate::config::BackendConfig;
+    match backend_config {
+        BackendConfig::Workdir => {
+            let queue = std::sync::Arc::new(crate::queue::JobQueue::new(crate::TEXT_WORKER_BIN_NAME, &workdir.workdir, queue_config.max_concurrent_jobs, queue_config.max_job_runtime()));
+            queue.clone().spawn_dispatcher(workdir.watcher.clone());
+            Ok(std::sync::Arc::new(crate::workdir_backend::WorkdirTextBackend::new(crate::TEXT_WORKER_BIN_NAME, workdir, Some(queue))))
+        }
+        BackendConfig::InProcess { generator } => {
+            let text_generator = generators.text.get(generator).ok_or_else(|| unknown_generator_error("text", generator, generators.text.keys()))?;
+            Ok(crate::in_process_backend::InProcessTextBackend::new(generator, text_generator.clone(), queue_config))
+        }
+        BackendConfig::Remote(remote) => {
+            Ok(std::sync::Arc::new(crate::remote_backend::RemoteBackend::new(remote)))
+        }
+    }
+}
+
+/// Creates the image backend selected by `backend_config`, spawning its queue dispatcher if it has one
+pub fn build_image_backend(backend_config: &crate::config::BackendConfig, queue_config: &crate::config::QueueConfig, workdir: WorkdirContext, generators: &InProcessGenerators) -> Result<std::sync::Arc<dyn ImageBackend>, Box<dyn std::error::Error>> {
+    use crate::config::BackendConfig;
+    match backend_config {
+        BackendConfig::Workdir => {
+            let queue = std::sync::Arc::new(crate::queue::JobQueue::new(crate::IMAGE_WORKER_BIN_NAME, &workdir.workdir, queue_config.max_concurrent_jobs, queue_config.max_job_runtime()));
+            queue.clone().spawn_dispatcher(workdir.watcher.clone());
+            Ok(std::sync::Arc::new(crate::workdir_backend::WorkdirImageBackend::new(crate::IMAGE_WORKER_BIN_NAME, workdir, Some(queue))))
+        }
+        BackendConfig::InProcess { generator } => {
+            let image_generator = generators.image.get(generator).ok_or_else(|| unknown_generator_error("image", generator, generators.image.keys()))?;
+            Ok(crate::in_process_backend::InProcessImageBackend::new(generator, image_generator.clone(), queue_config))
+        }
+        BackendConfig::Remote(remote) => {
+            Ok(std::sync::Arc::new(crate::remote_backend::RemoteBackend::new(remote)))
+        }
+    }
+}
+
+fn unknown_generator_error<'a>(kind: &str, generator: &str, available: impl Iterator<Item = &'a String>) -> Box<dyn std::error::Error> {
+    let available: Vec<&str> = available.map(|name| name.as_str()).collect();
+    if available.len() < 1 {
+        return format!("No in_process {} generators are built into this server, so generator = {:?} cannot be used", kind, generator).into();
+    }
+    format!("Unknown in_process {} generator {:?}, expected one of: {}", kind, generator, available.join(", ")).into()
+}
@@ -0,0 +1,550 @@
+
+// oliana_server reads its deployment settings from a TOML file (see ServerConfig::load) so GPU hosts can move
+// ports, folders and workers around without patching code. Every field has a default matching the historical
+// hardcoded behavior, so an empty file (or no file at all) runs the server exactly as before.
+//
+// Example oliana_server.toml:
+//
+//   bind_addresses = ["0.0.0.0"]
+//   port = 9050
+//   bin_directory = "/opt/oliana/bin"
+//   images_workdir = "/var/lib/oliana/image-procesing"
+//   models_manifest = "/etc/oliana/models.toml"
+//   offline = true
+//   cache_quota_bytes = 107374182400
+//   cache_dir = "/mnt/nvme/oliana-cache"
+//
+//   [[workers]]
+//   bin_name = "oliana_text"
+//   args = ["--workdir", "{text_workdir}"]
+//   env = { PER_PROC_MEM_FRACT = "0.55" }
+//   restart = { max_restarts = 10, max_backoff_secs = 120 }
+//   limits = { cgroup_memory_max_bytes = 34359738368, cgroup_cpu_max_cores = 4.0 }
+//
+//   [[workers]]
+//   bin_name = "oliana_images"
+//   args = ["--workdir", "{images_workdir}"]
+//   env = { PER_PROC_MEM_FRACT = "0.35" }
+//   cwd = "/var/lib/oliana"
+//   heartbeat_timeout_secs = 300
+//
+//   [auth_tokens]
+//   alice = "long-random-string"
+//
+//   [tls]
+//   generate_self_signed = true
+//
+//   [http_gateway]
+//   port = 9051
+//
+//   [image_backend]
+//   kind = "remote"
+//   server_url = "big-gpu-box:9050"
+
+pub const DEFAULT_CONFIG_FILE_NAME: &str = "oliana_server.toml";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Every address a listener is started on. If the host OS dual-stacks "::" then "0.0.0.0" fails to bind, which is fine so long as one listener starts.
+    pub bind_addresses: Vec<std::net::IpAddr>,
+    pub port: u16,
+    /// Where eg oliana_images[.exe] can be found. Defaults to ./target if it exists, else the current directory.
+    pub bin_directory: Option<std::path::PathBuf>,
+    /// Where eg oliana_images[.exe]-pid.txt are written. Defaults to bin_directory.
+    pub track_proc_dir: Option<std::path::PathBuf>,
+    /// Where images are generated into and read by the server. Relative paths are relative to track_proc_dir.
+    pub images_workdir: std::path::PathBuf,
+    /// Where text is generated into and read by the server. Relative paths are relative to track_proc_dir.
+    pub text_workdir: std::path::PathBuf,
+    /// Processes the server keeps alive; see WorkerConfig
+    pub workers: Vec<WorkerConfig>,
+    /// Connections are wrapped in TLS when this is set; see TlsConfig
+    pub tls: Option<TlsConfig>,
+    /// user name -> pre-shared token clients must present (see transport.rs). When empty every client is accepted.
+    pub auth_tokens: std::collections::BTreeMap<String, String>,
+    /// Connections from one IP beyond this are dropped. Fairness between clients is handled by the job queues, so this only guards against runaway clients.
+    pub max_channels_per_ip: usize,
+    /// Connections across all IPs beyond this are dropped
+    pub max_channels: usize,
+    /// Limits for jobs handed to the text worker; see queue.rs
+    pub text_queue: QueueConfig,
+    /// Limits for jobs handed to the image worker; see queue.rs
+    pub image_queue: QueueConfig,
+    /// When set, an OpenAI-compatible HTTP API is also served; see http_gateway.rs
+    pub http_gateway: Option<HttpGatewayConfig>,
+    /// Rotation of the <bin_name>.log files worker output is written to under track_proc_dir
+    pub worker_logs: WorkerLogConfig,
+    /// Delegated cgroup v2 directory (ie /sys/fs/cgroup/oliana.slice) workers with cgroup limits get their own cgroup under; see oliana_lib::proc_limits
+    pub cgroup_parent: Option<std::path::PathBuf>,
+    /// "kill" or "adopt" workers a previous server run left running (found through their <bin_name>-pid.txt); adopting saves re-loading models.
+    /// Only a server which crashed or was SIGKILLed leaves workers behind; a clean shutdown stops them.
+    /// With "adopt" workers write straight to their log file, so they can outlive us; see oliana_lib::launchers::OrphanPolicy.
+    pub orphaned_workers: oliana_lib::launchers::OrphanPolicy,
+    /// On SIGINT / SIGTERM workers get this long to exit after SIGTERM before they (and anything they started) are SIGKILLed
+    pub worker_shutdown_grace_secs: u64,
+    /// Models (and python packages) workers load; see oliana_lib::models. Relative paths are relative to track_proc_dir. Defaults to the manifest built into oliana_lib.
+    pub models_manifest: Option<std::path::PathBuf>,
+    /// Workers never download models or pip-install packages, and exit naming whatever is missing; fill the cache beforehand with `oliana_server --prefetch`
+    pub offline: bool,
+    /// Before workers start (and after `--prefetch`) the least recently used cache entries are pruned until the cache directory fits in this many bytes.
+    /// Entries the configured workers load, and entries any running process has leased, are never pruned; see oliana_lib::files::enforce_cache_quota
+    pub cache_quota_bytes: Option<u64>,
+    /// Where models, python packages and hf_home folders are kept (ie on a separate NVMe mount); relative paths are relative to track_proc_dir.
+    /// Workers are pointed at it through $OLIANA_CACHE_DIR. Defaults to wherever oliana_lib::files::resolve_cache_dir() points.
+    pub cache_dir: Option<std::path::PathBuf>,
+    /// What runs text jobs; see backend.rs
+    pub text_backend: BackendConfig,
+    /// What runs image jobs; see backend.rs
+    pub image_backend: BackendConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BackendConfig {
+    /// Hand jobs to the worker process through the workdir (text_workdir or images_workdir)
+    #[default]
+    Workdir,
+    /// Run jobs inside the server with a model built into it; `generator` names one of backend::InProcessGenerators ("mock" is always built in)
+    InProcess { generator: String },
+    /// Forward jobs to another oliana_server
+    Remote(RemoteBackendConfig),
+}
+
+impl BackendConfig {
+    pub fn is_workdir(&self) -> bool {
+        *self == BackendConfig::Workdir
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RemoteBackendConfig {
+    /// Hostname and port of the upstream oliana_server
+    pub server_url: String,
+    /// Connect over TLS (implied by tls_ca_cert)
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM certificate to trust when the upstream uses a self-signed certificate
+    #[serde(default)]
+    pub tls_ca_cert: Option<std::path::PathBuf>,
+    /// Name to verify the upstream's certificate against, if different from the host in server_url
+    #[serde(default)]
+    pub tls_server_name: Option<String>,
+    /// Pre-shared token the upstream was configured with
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpGatewayConfig {
+    /// Defaults to the same addresses as the tarpc listeners
+    pub bind_addresses: Vec<std::net::IpAddr>,
+    pub port: u16,
+}
+
+impl Default for HttpGatewayConfig {
+    fn default() -> Self {
+        Self {
+            bind_addresses: ServerConfig::default().bind_addresses,
+            port: 9051,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// How many jobs the worker is handed at once; more than 1 only helps workers which run jobs in parallel
+    pub max_concurrent_jobs: usize,
+    /// A job which has not finished after this many seconds stops occupying a slot (ie because its worker crashed mid-job)
+    pub max_job_runtime_secs: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_jobs: 1,
+            max_job_runtime_secs: 600,
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn max_job_runtime(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.max_job_runtime_secs)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain. Relative paths are relative to track_proc_dir.
+    pub cert_file: std::path::PathBuf,
+    /// PEM private key. Relative paths are relative to track_proc_dir.
+    pub key_file: std::path::PathBuf,
+    /// Write a self-signed cert_file + key_file if they do not exist yet; clients pass the cert_file as --tls-ca-cert
+    pub generate_self_signed: bool,
+    /// DNS names or IPs clients will connect with, baked into a generated certificate
+    pub self_signed_names: Vec<String>,
+}
+
+/// TlsConfig with its paths made absolute
+#[derive(Debug, Clone)]
+pub struct ResolvedTlsConfig {
+    pub cert_file: std::path::PathBuf,
+    pub key_file: std::path::PathBuf,
+    pub generate_self_signed: bool,
+    pub self_signed_names: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_file: "oliana_server.cert.pem".into(),
+            key_file: "oliana_server.key.pem".into(),
+            generate_self_signed: false,
+            self_signed_names: vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// File name under bin_directory, without any .exe extension
+    pub bin_name: String,
+    /// "{images_workdir}", "{text_workdir}", "{bin_directory}" and "{track_proc_dir}" are replaced with resolved paths
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Added on top of the server's own environment; values get the same replacements as args
+    #[serde(default)]
+    pub env: std::collections::BTreeMap<String, String>,
+    /// Working directory of the worker, with the same replacements as args; defaults to the server's
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// Where the worker writes its ready file and heartbeat, with the same replacements as args. Defaults to text_workdir / images_workdir
+    /// for oliana_text / oliana_images; other workers without one are never checked for heartbeats.
+    #[serde(default)]
+    pub workdir: Option<String>,
+    /// rlimits and cgroup caps; see oliana_lib::proc_limits::ResourceLimits
+    #[serde(default)]
+    pub limits: oliana_lib::proc_limits::ResourceLimits,
+    /// How the worker is re-spawned when it exits; see RestartConfig
+    #[serde(default)]
+    pub restart: RestartConfig,
+    /// A worker which has not written a heartbeat for this long is killed, its in-flight job failed, and re-spawned; 0 never kills it.
+    /// Workers beat on every token / step, so this only has to cover the slowest single step.
+    #[serde(default = "default_heartbeat_timeout_secs")]
+    pub heartbeat_timeout_secs: u64,
+}
+
+pub const DEFAULT_HEARTBEAT_TIMEOUT_SECS: u64 = 120;
+
+fn default_heartbeat_timeout_secs() -> u64 {
+    DEFAULT_HEARTBEAT_TIMEOUT_SECS
+}
+
+/// See oliana_lib::proc_logs::LogRotation
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerLogConfig {
+    /// A log is rotated to <bin_name>.log.1 once it grows past this
+    pub max_bytes: u64,
+    /// Rotated logs kept per worker
+    pub max_files: u32,
+}
+
+impl Default for WorkerLogConfig {
+    fn default() -> Self {
+        let rotation = oliana_lib::proc_logs::LogRotation::default();
+        Self {
+            max_bytes: rotation.max_bytes,
+            max_files: rotation.max_files,
+        }
+    }
+}
+
+impl WorkerLogConfig {
+    pub fn to_rotation(&self) -> oliana_lib::proc_logs::LogRotation {
+        oliana_lib::proc_logs::LogRotation {
+            max_bytes: self.max_bytes,
+            max_files: self.max_files,
+        }
+    }
+}
+
+/// See oliana_lib::launchers::RestartPolicy
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RestartConfig {
+    /// Wait before re-spawning after a crash; doubles with every crash in a row
+    pub initial_backoff_secs: u64,
+    /// Longest wait between re-spawns
+    pub max_backoff_secs: u64,
+    /// Re-spawns allowed within restart_window_secs before the worker is marked failed and left alone
+    pub max_restarts: u32,
+    pub restart_window_secs: u64,
+    /// A worker which stayed up this long before exiting goes back to initial_backoff_secs
+    pub healthy_after_secs: u64,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        let policy = oliana_lib::launchers::RestartPolicy::default();
+        Self {
+            initial_backoff_secs: policy.initial_backoff.as_secs(),
+            max_backoff_secs: policy.max_backoff.as_secs(),
+            max_restarts: policy.max_restarts,
+            restart_window_secs: policy.restart_window.as_secs(),
+            healthy_after_secs: policy.healthy_after.as_secs(),
+        }
+    }
+}
+
+impl RestartConfig {
+    pub fn to_policy(&self) -> oliana_lib::launchers::RestartPolicy {
+        oliana_lib::launchers::RestartPolicy {
+            initial_backoff: std::time::Duration::from_secs(self.initial_backoff_secs),
+            max_backoff: std::time::Duration::from_secs(self.max_backoff_secs),
+            max_restarts: self.max_restarts,
+            restart_window: std::time::Duration::from_secs(self.restart_window_secs),
+            healthy_after: std::time::Duration::from_secs(self.healthy_after_secs),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        // Backends read PER_PROC_MEM_FRACT to avoid over-allocating eachother's slice of the GPU pie; respect a value set by whoever launched us.
+        let per_proc_mem_fract = match std::env::var("PER_PROC_MEM_FRACT") {
+            Ok(val) if val.len() > 0 => val,
+            _ => "0.40".to_string(),
+        };
+        let mut worker_env = std::collections::BTreeMap::new();
+        worker_env.insert("PER_PROC_MEM_FRACT".to_string(), per_proc_mem_fract);
+
+        Self {
+            bind_addresses: vec![
+                std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+                std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+            ],
+            port: 9050,
+            bin_directory: None,
+            track_proc_dir: None,
+            images_workdir: "image-procesing".into(),
+            text_workdir: "text-procesing".into(),
+            workers: vec![
+                WorkerConfig {
+                    bin_name: crate::IMAGE_WORKER_BIN_NAME.to_string(),
+                    args: vec!["--workdir".to_string(), "{images_workdir}".to_string()],
+                    env: worker_env.clone(),
+                    cwd: None,
+                    workdir: None,
+                    limits: oliana_lib::proc_limits::ResourceLimits::default(),
+                    restart: RestartConfig::default(),
+                    heartbeat_timeout_secs: default_heartbeat_timeout_secs(),
+                },
+                WorkerConfig {
+                    bin_name: crate::TEXT_WORKER_BIN_NAME.to_string(),
+                    args: vec!["--workdir".to_string(), "{text_workdir}".to_string()],
+                    env: worker_env,
+                    cwd: None,
+                    workdir: None,
+                    limits: oliana_lib::proc_limits::ResourceLimits::default(),
+                    restart: RestartConfig::default(),
+                    heartbeat_timeout_secs: default_heartbeat_timeout_secs(),
+                },
+            ],
+            tls: None,
+            auth_tokens: std::collections::BTreeMap::new(),
+            max_channels_per_ip: 4,
+            max_channels: 10,
+            text_queue: QueueConfig::default(),
+            image_queue: QueueConfig::default(),
+            http_gateway: None,
+            worker_logs: WorkerLogConfig::default(),
+            orphaned_workers: oliana_lib::launchers::OrphanPolicy::default(),
+            worker_shutdown_grace_secs: 10,
+            cgroup_parent: None,
+            models_manifest: None,
+            offline: false,
+            cache_quota_bytes: None,
+            cache_dir: None,
+            text_backend: BackendConfig::Workdir,
+            image_backend: BackendConfig::Workdir,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads `path` as TOML; missing fields take their defaults.
+    pub fn load(path: &std::path::Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let config_text = std::fs::read_to_string(path).map_err(oliana_lib::eloc!(format!("Reading {}", path.display())))?;
+        let config: Self = toml::from_str(&config_text).map_err(oliana_lib::eloc!(format!("Parsing {}", path.display())))?;
+        Ok(config)
+    }
+
+    /// Loads `path` if given, else ./oliana_server.toml if it exists, else the defaults.
+    pub fn load_or_default(path: Option<&std::path::Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(path) = path {
+            return Self::load(path);
+        }
+        let default_path = std::path::Path::new(DEFAULT_CONFIG_FILE_NAME);
+        if default_path.exists() {
+            return Self::load(default_path);
+        }
+        Ok(Self::default())
+    }
+
+    pub fn to_toml_string(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(toml::to_string_pretty(self).map_err(oliana_lib::eloc!())?)
+    }
+
+    pub fn resolve_bin_directory(&self) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        if let Some(bin_directory) = &self.bin_directory {
+            return Ok(bin_directory.clone());
+        }
+        let mut expected_bin_directory = std::env::current_dir().map_err(oliana_lib::eloc!())?;
+        if expected_bin_directory.join("target").exists() {
+            expected_bin_directory = expected_bin_directory.join("target");
+        }
+        Ok(expected_bin_directory)
+    }
+
+    pub fn resolve_track_proc_dir(&self) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        match &self.track_proc_dir {
+            Some(track_proc_dir) => Ok(track_proc_dir.clone()),
+            None => self.resolve_bin_directory(),
+        }
+    }
+
+    pub fn resolve_images_workdir(&self) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        Ok(self.resolve_track_proc_dir()?.join(&self.images_workdir)) // join() keeps absolute paths as-is
+    }
+
+    pub fn resolve_text_workdir(&self) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        Ok(self.resolve_track_proc_dir()?.join(&self.text_workdir))
+    }
+
+    pub fn resolve_models_manifest(&self) -> Result<Option<std::path::PathBuf>, Box<dyn std::error::Error>> {
+        match &self.models_manifest {
+            Some(models_manifest) => Ok(Some(self.resolve_track_proc_dir()?.join(models_manifest))),
+            None => Ok(None),
+        }
+    }
+
+    /// The manifest workers are pointed at
+    pub fn load_models_manifest(&self) -> Result<oliana_lib::models::ModelManifest, Box<dyn std::error::Error>> {
+        match self.resolve_models_manifest()? {
+            Some(path) => oliana_lib::models::ModelManifest::load(&path),
+            None => oliana_lib::models::ModelManifest::builtin(),
+        }
+    }
+
+    /// The cache root this server and its workers use
+    pub fn resolve_cache_dir(&self) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        match &self.cache_dir {
+            Some(cache_dir) => Ok(self.resolve_track_proc_dir()?.join(cache_dir)),
+            None => oliana_lib::files::resolve_cache_dir(),
+        }
+    }
+
+    /// Cache entries (see oliana_lib::files::list_cache_entries) the configured workers load, which enforce_cache_quota() leaves alone
+    pub fn worker_cache_entries(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let manifest = self.load_models_manifest()?;
+        let mut paths: Vec<std::path::PathBuf> = vec![];
+        if self.text_backend.is_workdir() {
+            paths.push(manifest.model(oliana_lib::models::TEXT_MODEL_NAME)?.cache_dir.clone());
+            paths.push(oliana_lib::models::TEXT_HF_HOME_DIR.into());
+        }
+        if self.image_backend.is_workdir() {
+            paths.push(manifest.model(oliana_lib::models::IMAGE_MODEL_NAME)?.cache_dir.clone());
+            paths.push(oliana_lib::models::IMAGE_HF_HOME_DIR.into());
+            paths.push(manifest.python.site_packages_dir.clone());
+        }
+        let mut entries: Vec<String> = paths.iter().filter_map(|path| oliana_lib::files::cache_entry_name(path)).collect();
+        entries.sort();
+        entries.dedup();
+        Ok(entries)
+    }
+
+    /// Prunes `cache_root` down to cache_quota_bytes, if set; returns the names of pruned entries
+    pub fn enforce_cache_quota(&self, cache_root: &std::path::Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let max_bytes = match self.cache_quota_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return Ok(vec![]),
+        };
+        let pruned = oliana_lib::files::enforce_cache_quota(cache_root, max_bytes, &self.worker_cache_entries()?)?;
+        Ok(pruned.into_iter().map(|entry| entry.name).collect())
+    }
+
+    pub fn resolve_tls(&self) -> Result<Option<ResolvedTlsConfig>, Box<dyn std::error::Error>> {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return Ok(None),
+        };
+        let track_proc_dir = self.resolve_track_proc_dir()?;
+        Ok(Some(ResolvedTlsConfig {
+            cert_file: track_proc_dir.join(&tls.cert_file),
+            key_file: track_proc_dir.join(&tls.key_file),
+            generate_self_signed: tls.generate_self_signed,
+            self_signed_names: tls.self_signed_names.clone(),
+        }))
+    }
+
+    /// Replaces the {placeholders} documented on WorkerConfig::args
+    pub fn expand_placeholders(&self, value: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(value
+            .replace("{images_workdir}", &self.resolve_images_workdir()?.to_string_lossy())
+            .replace("{text_workdir}", &self.resolve_text_workdir()?.to_string_lossy())
+            .replace("{bin_directory}", &self.resolve_bin_directory()?.to_string_lossy())
+            .replace("{track_proc_dir}", &self.resolve_track_proc_dir()?.to_string_lossy()))
+    }
+
+    /// Registers every configured worker with `procs`, expanding placeholders in their args, env and cwd.
+    /// oliana_text and oliana_images are skipped when their jobs go to another backend, so they do not hold GPU memory for nothing.
+    /// models_manifest, offline and the cache root reach workers as $OLIANA_MODELS_MANIFEST, $OLIANA_OFFLINE and $OLIANA_CACHE_DIR, which a worker's own env can override.
+    pub fn register_workers(&self, procs: &mut oliana_lib::launchers::TrackedProcs) -> Result<(), Box<dyn std::error::Error>> {
+        let mut models_env: Vec<(String, String)> = vec![
+            (oliana_lib::files::CACHE_DIR_ENV_VAR.to_string(), self.resolve_cache_dir()?.to_string_lossy().to_string()),
+        ];
+        if let Some(models_manifest) = self.resolve_models_manifest()? {
+            models_env.push((oliana_lib::models::MODELS_MANIFEST_ENV_VAR.to_string(), models_manifest.to_string_lossy().to_string()));
+        }
+        if self.offline {
+            models_env.push((oliana_lib::models::OFFLINE_ENV_VAR.to_string(), "1".to_string()));
+        }
+        for worker in self.workers.iter() {
+            if (worker.bin_name == crate::TEXT_WORKER_BIN_NAME && !self.text_backend.is_workdir()) || (worker.bin_name == crate::IMAGE_WORKER_BIN_NAME && !self.image_backend.is_workdir()) {
+                continue;
+            }
+            let mut spec = oliana_lib::launchers::TrackedProcSpec::new(&worker.bin_name, &[]).with_limits(worker.limits.clone());
+            for arg in worker.args.iter() {
+                spec.args.push(self.expand_placeholders(arg)?);
+            }
+            spec.env.extend(models_env.iter().cloned());
+            for (key, val) in worker.env.iter() {
+                spec.env.push((key.clone(), self.expand_placeholders(val)?));
+            }
+            if let Some(cwd) = &worker.cwd {
+                spec = spec.with_cwd(self.expand_placeholders(cwd)?);
+            }
+            let workdir = match &worker.workdir {
+                Some(workdir) => Some(std::path::PathBuf::from(self.expand_placeholders(workdir)?)),
+                None if worker.bin_name == crate::TEXT_WORKER_BIN_NAME => Some(self.resolve_text_workdir()?),
+                None if worker.bin_name == crate::IMAGE_WORKER_BIN_NAME => Some(self.resolve_images_workdir()?),
+                None => None,
+            };
+            if let Some(workdir) = workdir {
+                spec = spec.with_workdir(workdir);
+            }
+            if worker.heartbeat_timeout_secs > 0 {
+                spec = spec.with_heartbeat_timeout(std::time::Duration::from_secs(worker.heartbeat_timeout_secs));
+            }
+            procs.register_tracked_proc(spec);
+            procs.set_restart_policy(&worker.bin_name, worker.restart.to_policy());
+        }
+        Ok(())
+    }
+}
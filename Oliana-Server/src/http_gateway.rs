@@ -0,0 +1,453 @@
+
+// An OpenAI-compatible HTTP front door for tools which cannot link oliana_server_lib. Every request is served by calling
+// the same Oliana RPC methods a tarpc client would, so HTTP jobs share the workers + job queues with everyone else.
+//
+//   GET  /v1/models
+//   POST /v1/chat/completions   (set "stream": true for server-sent events)
+//   POST /v1/images/generations (only "response_format": "b64_json" is supported)
+//
+// Clients authenticate with "Authorization: Bearer <token>" using the same auth_tokens as the tarpc transport.
+// Non-standard request fields "priority", "negative_prompt", "guidance_scale" and "num_inference_steps" are honored when present.
+
+use crate::Oliana;
+use futures::StreamExt;
+
+pub const TEXT_MODEL_ID: &str = "oliana-text";
+pub const IMAGE_MODEL_ID: &str = "oliana-images";
+
+// Same default budget as oliana_client's --retries; each retry is one wait on the worker of up to 10 seconds (tarpc::context::current()'s deadline)
+const GATEWAY_RETRIES: usize = 30;
+
+/// Builds the OlianaServer which runs requests for a peer + authenticated user name
+pub type MakeServerFn = std::sync::Arc<dyn Fn(std::net::SocketAddr, &str) -> crate::OlianaServer + Send + Sync>;
+
+#[derive(Clone)]
+pub struct GatewayState {
+    pub make_server: MakeServerFn,
+    /// user name -> token; empty means every client is accepted
+    pub auth_tokens: std::sync::Arc<std::collections::BTreeMap<String, String>>,
+}
+
+pub fn router(state: GatewayState) -> axum::Router {
+    axum::Router::new()
+        .route("/v1/models", axum::routing::get(list_models))
+        .route("/v1/chat/completions", axum::routing::post(chat_completions))
+        .route("/v1/images/generations", axum::routing::post(images_generations))
+        .with_state(state)
+}
+
+/// Serves the gateway on `listener` forever, wrapping connections in TLS when `tls_acceptor` is set.
+pub async fn serve_http_gateway(listener: tokio::net::TcpListener, tls_acceptor: Option<tokio_rustls::TlsAcceptor>, state: GatewayState) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let app = router(state).into_make_service_with_connect_info::<PeerAddr>();
+    match tls_acceptor {
+        Some(tls_acceptor) => axum::serve(TlsListener::new(listener, tls_acceptor)?, app).await?,
+        None => axum::serve(listener, app).await?,
+    }
+    Ok(())
+}
+
+/// The client's address, for both plain and TLS listeners (axum only knows how to pull a SocketAddr out of its own listener types)
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub std::net::SocketAddr);
+
+impl axum::extract::connect_info::Connected<axum::serve::IncomingStream<'_, tokio::net::TcpListener>> for PeerAddr {
+    fn connect_info(stream: axum::serve::IncomingStream<'_, tokio::net::TcpListener>) -> Self {
+        PeerAddr(*stream.remote_addr())
+    }
+}
+
+impl axum::extract::connect_info::Connected<axum::serve::IncomingStream<'_, TlsListener>> for PeerAddr {
+    fn connect_info(stream: axum::serve::IncomingStream<'_, TlsListener>) -> Self {
+        PeerAddr(*stream.remote_addr())
+    }
+}
+
+/// Hands axum TLS streams; handshakes run on their own tasks so one slow client cannot stall everyone else's accept()
+pub struct TlsListener {
+    local_addr: std::net::SocketAddr,
+    handshaken: tokio::sync::mpsc::Receiver<(tokio_rustls::server::TlsStream<tokio::net::TcpStream>, std::net::SocketAddr)>,
+}
+
+impl TlsListener {
+    pub fn new(listener: tokio::net::TcpListener, tls_acceptor: tokio_rustls::TlsAcceptor) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (tcp_stream, peer_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("{}:{} {:?}", file!(), line!(), e);
+                        continue;
+                    }
+                };
+                let tls_acceptor = tls_acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(crate::transport::HANDSHAKE_TIMEOUT, tls_acceptor.accept(tcp_stream)).await {
+                        Ok(Ok(tls_stream)) => {
+                            let _ = sender.send((tls_stream, peer_addr)).await;
+                        }
+                        Ok(Err(e)) => eprintln!("Refusing {}: {}", peer_addr, e),
+                        Err(_elapsed) => eprintln!("Refusing {}: no TLS handshake within {:?}", peer_addr, crate::transport::HANDSHAKE_TIMEOUT),
+                    }
+                });
+            }
+        });
+        Ok(Self { local_addr: local_addr, handshaken: receiver })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = tokio_rustls::server::TlsStream<tokio::net::TcpStream>;
+    type Addr = std::net::SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.handshaken.recv().await {
+            Some(accepted) => accepted,
+            None => std::future::pending().await, // The accept task only ends with the runtime
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// An error in OpenAI's {"error": {...}} shape
+pub struct GatewayError {
+    pub status: axum::http::StatusCode,
+    pub message: String,
+    pub error_type: &'static str,
+}
+
+impl GatewayError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self { status: axum::http::StatusCode::BAD_REQUEST, message: message.into(), error_type: "invalid_request_error" }
+    }
+}
+
+impl From<crate::OlianaError> for GatewayError {
+    fn from(e: crate::OlianaError) -> Self {
+        use axum::http::StatusCode;
+        let (status, error_type) = match e {
+            crate::OlianaError::BadInput { .. } => (StatusCode::BAD_REQUEST, "invalid_request_error"),
+            crate::OlianaError::UnknownJob { .. } => (StatusCode::NOT_FOUND, "invalid_request_error"),
+            crate::OlianaError::Cancelled { .. } => (StatusCode::CONFLICT, "cancelled"),
+            crate::OlianaError::WorkerCrashed { .. } => (StatusCode::SERVICE_UNAVAILABLE, "server_error"),
+            crate::OlianaError::TimedOut { .. } => (StatusCode::GATEWAY_TIMEOUT, "server_error"),
+            crate::OlianaError::BackendTraceback { .. } | crate::OlianaError::Internal { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "server_error"),
+        };
+        Self { status: status, message: format!("{}", e), error_type: error_type }
+    }
+}
+
+impl axum::response::IntoResponse for GatewayError {
+    fn into_response(self) -> axum::response::Response {
+        let body = serde_json::json!({
+            "error": { "message": self.message, "type": self.error_type, "code": self.status.as_u16() }
+        });
+        (self.status, axum::Json(body)).into_response()
+    }
+}
+
+// Returns the OlianaServer for this request after checking its bearer token
+fn authenticate(state: &GatewayState, peer_addr: std::net::SocketAddr, headers: &axum::http::HeaderMap) -> Result<crate::OlianaServer, GatewayError> {
+    let token = headers.get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+    match crate::transport::authenticate_token(&state.auth_tokens, token.trim().as_bytes()) {
+        Some(user) => Ok((state.make_server)(peer_addr, &user)),
+        None => Err(GatewayError { status: axum::http::StatusCode::UNAUTHORIZED, message: "Unknown bearer token".to_string(), error_type: "invalid_request_error" }),
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+async fn list_models(
+    axum::extract::State(state): axum::extract::State<GatewayState>,
+    axum::extract::ConnectInfo(PeerAddr(peer_addr)): axum::extract::ConnectInfo<PeerAddr>,
+    headers: axum::http::HeaderMap,
+) -> Result<axum::Json<serde_json::Value>, GatewayError> {
+    authenticate(&state, peer_addr, &headers)?;
+    Ok(axum::Json(serde_json::json!({
+        "object": "list",
+        "data": [
+            { "id": TEXT_MODEL_ID, "object": "model", "created": 0, "owned_by": "oliana" },
+            { "id": IMAGE_MODEL_ID, "object": "model", "created": 0, "owned_by": "oliana" },
+        ]
+    })))
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    /// Either a plain string or a list of {"type": "text", "text": ...} parts; non-text parts are ignored
+    #[serde(default)]
+    pub content: serde_json::Value,
+}
+
+impl ChatMessage {
+    pub fn content_text(&self) -> String {
+        match &self.content {
+            serde_json::Value::String(s) => s.clone(),
+            serde_json::Value::Array(parts) => parts.iter()
+                .filter_map(|part| part.get("text").and_then(|t| t.as_str()))
+                .collect::<Vec<&str>>()
+                .join(""),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ChatCompletionRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub priority: Option<crate::JobPriority>,
+}
+
+impl ChatCompletionRequest {
+    /// oliana_text takes one system prompt and one user prompt; earlier turns of a conversation are folded into the user prompt as a transcript.
+    pub fn to_prompts(&self) -> (String, String) {
+        let system_prompt = self.messages.iter()
+            .filter(|m| m.role == "system" || m.role == "developer")
+            .map(|m| m.content_text())
+            .collect::<Vec<String>>()
+            .join("\n");
+        let turns: Vec<&ChatMessage> = self.messages.iter().filter(|m| m.role != "system" && m.role != "developer").collect();
+        let user_prompt = if turns.len() == 1 {
+            turns[0].content_text()
+        }
+        else {
+            turns.iter().map(|m| format!("{}: {}", m.role, m.content_text())).collect::<Vec<String>>().join("\n")
+        };
+        (system_prompt, user_prompt)
+    }
+}
+
+// Asks the worker to stop if the HTTP client hangs up before the reply is complete
+struct CancelTextOnDrop {
+    server: Option<crate::OlianaServer>,
+    job: crate::TextJobId,
+}
+
+impl CancelTextOnDrop {
+    fn disarm(&mut self) {
+        self.server = None;
+    }
+}
+
+impl Drop for CancelTextOnDrop {
+    fn drop(&mut self) {
+        if let Some(server) = self.server.take() {
+            let job = self.job;
+            tokio::spawn(async move {
+                if let Err(e) = server.cancel_text(tarpc::context::current(), job).await {
+                    eprintln!("{}:{} {}", file!(), line!(), e);
+                }
+            });
+        }
+    }
+}
+
+// Same for the images of a /v1/images/generations request; `jobs` holds those begun and not yet fetched
+struct CancelImagesOnDrop {
+    server: crate::OlianaServer,
+    jobs: Vec<crate::ImageJobId>,
+}
+
+impl Drop for CancelImagesOnDrop {
+    fn drop(&mut self) {
+        for job in self.jobs.drain(..) {
+            let server = self.server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.cancel_image(tarpc::context::current(), job).await {
+                    eprintln!("{}:{} {}", file!(), line!(), e);
+                }
+            });
+        }
+    }
+}
+
+// generate_text_next_token, re-asking after retryable errors (ie while the job waits in the queue)
+async fn next_token_with_retries(server: &crate::OlianaServer, job: crate::TextJobId) -> Result<Option<String>, crate::OlianaError> {
+    let mut remaining_retries = GATEWAY_RETRIES;
+    loop {
+        match server.clone().generate_text_next_token(tarpc::context::current(), job).await {
+            Err(e) if e.is_retryable() && remaining_retries > 0 => {
+                if let Ok(crate::JobStatus::Queued { .. }) = server.clone().generate_text_status(tarpc::context::current(), job).await {
+                    continue; // Waiting our turn does not use up a retry
+                }
+                remaining_retries -= 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn chat_completions(
+    axum::extract::State(state): axum::extract::State<GatewayState>,
+    axum::extract::ConnectInfo(PeerAddr(peer_addr)): axum::extract::ConnectInfo<PeerAddr>,
+    headers: axum::http::HeaderMap,
+    axum::Json(request): axum::Json<ChatCompletionRequest>,
+) -> Result<axum::response::Response, GatewayError> {
+    use axum::response::IntoResponse;
+    let server = authenticate(&state, peer_addr, &headers)?;
+    let (system_prompt, user_prompt) = request.to_prompts();
+    let model = request.model.clone().unwrap_or_else(|| TEXT_MODEL_ID.to_string());
+    let priority = request.priority.unwrap_or(crate::JobPriority::Interactive);
+
+    let job = server.clone().generate_text_begin(tarpc::context::current(), system_prompt, user_prompt, priority).await?;
+    let completion_id = format!("chatcmpl-{}", job.0);
+    let created = unix_now();
+    let mut cancel_guard = CancelTextOnDrop { server: Some(server.clone()), job: job };
+
+    if !request.stream {
+        let mut content = String::new();
+        while let Some(token) = next_token_with_retries(&server, job).await? {
+            content.push_str(&token);
+        }
+        cancel_guard.disarm();
+        return Ok(axum::Json(serde_json::json!({
+            "id": completion_id,
+            "object": "chat.completion",
+            "created": created,
+            "model": model,
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": "stop",
+            }],
+        })).into_response());
+    }
+
+    let chunk = move |delta: serde_json::Value, finish_reason: Option<&str>| -> axum::response::sse::Event {
+        axum::response::sse::Event::default().data(serde_json::json!({
+            "id": completion_id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        }).to_string())
+    };
+
+    // The first event announces the assistant role, then one event per token, a final event with finish_reason, then [DONE]
+    let first_event = chunk(serde_json::json!({ "role": "assistant" }), None);
+    let events = futures::stream::unfold(Some((server, cancel_guard, chunk)), move |stream_state| async move {
+        let (server, mut cancel_guard, chunk) = stream_state?;
+        match next_token_with_retries(&server, job).await {
+            Ok(Some(token)) => {
+                let event = chunk(serde_json::json!({ "content": token }), None);
+                Some((vec![event], Some((server, cancel_guard, chunk))))
+            }
+            Ok(None) => {
+                cancel_guard.disarm();
+                let events = vec![
+                    chunk(serde_json::json!({}), Some("stop")),
+                    axum::response::sse::Event::default().data("[DONE]"),
+                ];
+                Some((events, None))
+            }
+            Err(e) => {
+                let error: GatewayError = e.into();
+                let event = axum::response::sse::Event::default().data(serde_json::json!({
+                    "error": { "message": error.message, "type": error.error_type, "code": error.status.as_u16() }
+                }).to_string());
+                Some((vec![event], None))
+            }
+        }
+    });
+    let events = futures::stream::once(async move { vec![first_event] })
+        .chain(events)
+        .flat_map(|events| futures::stream::iter(events.into_iter().map(Ok::<_, std::convert::Infallible>)));
+
+    Ok(axum::response::sse::Sse::new(events).keep_alive(axum::response::sse::KeepAlive::default()).into_response())
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ImageGenerationRequest {
+    pub prompt: String,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub n: Option<u32>,
+    #[serde(default)]
+    pub response_format: Option<String>,
+    #[serde(default)]
+    pub negative_prompt: Option<String>,
+    #[serde(default)]
+    pub guidance_scale: Option<f32>,
+    #[serde(default)]
+    pub num_inference_steps: Option<u32>,
+    #[serde(default)]
+    pub priority: Option<crate::JobPriority>,
+}
+
+// Largest "n" accepted; every image is a full diffusion run on the GPU
+const MAX_IMAGES_PER_REQUEST: u32 = 4;
+
+async fn images_generations(
+    axum::extract::State(state): axum::extract::State<GatewayState>,
+    axum::extract::ConnectInfo(PeerAddr(peer_addr)): axum::extract::ConnectInfo<PeerAddr>,
+    headers: axum::http::HeaderMap,
+    axum::Json(request): axum::Json<ImageGenerationRequest>,
+) -> Result<axum::Json<serde_json::Value>, GatewayError> {
+    use base64::Engine;
+    let server = authenticate(&state, peer_addr, &headers)?;
+    if let Some(response_format) = &request.response_format {
+        if response_format != "b64_json" {
+            return Err(GatewayError::bad_request(format!("response_format {:?} is not supported, use \"b64_json\"", response_format)));
+        }
+    }
+    let n = request.n.unwrap_or(1);
+    if n < 1 || n > MAX_IMAGES_PER_REQUEST {
+        return Err(GatewayError::bad_request(format!("n must be between 1 and {}", MAX_IMAGES_PER_REQUEST)));
+    }
+    let priority = request.priority.unwrap_or(crate::JobPriority::Normal);
+
+    // All n jobs are queued up-front so they can share worker slots as they free up.
+    // Every job begun is cancelled if we return early or the client goes away.
+    let mut cancel_guard = CancelImagesOnDrop { server: server.clone(), jobs: vec![] };
+    for _ in 0..n {
+        cancel_guard.jobs.push(server.clone().generate_image_begin(
+            tarpc::context::current(),
+            request.prompt.clone(),
+            request.negative_prompt.clone().unwrap_or_default(),
+            request.guidance_scale.unwrap_or(3.5),
+            request.num_inference_steps.unwrap_or(12),
+            priority,
+        ).await?);
+    }
+
+    let mut data = vec![];
+    while let Some(&job) = cancel_guard.jobs.first() {
+        let mut remaining_retries = GATEWAY_RETRIES;
+        let png_bytes = loop {
+            match server.clone().generate_image_get_result(tarpc::context::current(), job).await {
+                Ok(png_bytes) => break png_bytes,
+                Err(e) if e.is_retryable() && remaining_retries > 0 => {
+                    if let Ok(crate::JobStatus::Queued { .. }) = server.clone().generate_image_status(tarpc::context::current(), job).await {
+                        continue;
+                    }
+                    remaining_retries -= 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        cancel_guard.jobs.remove(0);
+        data.push(serde_json::json!({ "b64_json": base64::engine::general_purpose::STANDARD.encode(&png_bytes) }));
+    }
+
+    Ok(axum::Json(serde_json::json!({
+        "created": unix_now(),
+        "data": data,
+    })))
+}
@@ -0,0 +1,403 @@
+
+// Runs jobs on blocking threads inside the server against a model which stays loaded between jobs, so nothing pays for
+// a worker process start-up or a trip through the filesystem. Jobs still go through a JobQueue, so priorities and
+// per-client fairness behave exactly like the workdir backend.
+//
+// Models plug in by implementing TextGenerator / ImageGenerator and being registered in backend::InProcessGenerators
+// under the name the config's `generator` field refers to.
+
+use crate::backend::{BackendFuture, ImageBackend, ImageRequest, TextBackend, TextRead, TextRequest};
+use crate::{ImagePreview, ImageProgress, JobStatus, OlianaError, QueueStatus};
+
+/// Finished jobs are forgotten this long after they end, whether or not anyone collected their output
+pub const KEEP_FINISHED_JOBS_FOR: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+pub trait TextGenerator: Send + Sync {
+    /// Runs on a blocking thread. Calls `on_token` with each piece of text as it is generated and stops early once it returns false.
+    fn generate(&self, request: &TextRequest, on_token: &mut dyn FnMut(&str) -> bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+pub trait ImageGenerator: Send + Sync {
+    /// Runs on a blocking thread and returns the final .png. Calls `on_step` after each diffusion step (with a preview .png if one is cheap to make)
+    /// and stops early once it returns false.
+    fn generate(&self, request: &ImageRequest, on_step: &mut dyn FnMut(ImageProgress, Option<Vec<u8>>) -> bool) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[derive(Default)]
+struct InProcessJob<O> {
+    cancelled: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    started: bool,
+    finished_at: Option<std::time::Instant>,
+    error: Option<oliana_lib::err::ErrorReport>,
+    output: O,
+}
+
+#[derive(Default)]
+struct ImageOutput {
+    progress: Option<ImageProgress>,
+    preview: Option<ImagePreview>,
+    png_bytes: Option<Vec<u8>>,
+}
+
+// Job bookkeeping shared by the text and image backends
+struct JobTable<O> {
+    worker: String,
+    queue: std::sync::Arc<crate::queue::JobQueue>,
+    jobs: std::sync::Mutex<std::collections::HashMap<u64, InProcessJob<O>>>,
+    // Bumped after every change to `jobs`; waiters subscribe before checking so they cannot miss an update
+    updates: tokio::sync::watch::Sender<u64>,
+}
+
+impl<O: Default> JobTable<O> {
+    fn new(worker: &str, queue_config: &crate::config::QueueConfig) -> Self {
+        Self {
+            worker: worker.to_string(),
+            queue: std::sync::Arc::new(crate::queue::JobQueue::new_in_process(worker, queue_config.max_concurrent_jobs, queue_config.max_job_runtime())),
+            jobs: std::sync::Mutex::new(std::collections::HashMap::new()),
+            updates: tokio::sync::watch::channel(0).0,
+        }
+    }
+
+    // A generator which panicked while we held the lock must not wedge every later job, so poisoning is ignored
+    fn lock(&self) -> std::sync::MutexGuard<'_, std::collections::HashMap<u64, InProcessJob<O>>> {
+        self.jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn begin(&self, nonce: u64, priority: crate::JobPriority, client_key: &str, input_json: String) {
+        {
+            let mut jobs = self.lock();
+            jobs.retain(|_, job| job.finished_at.map(|t| t.elapsed() < KEEP_FINISHED_JOBS_FOR).unwrap_or(true));
+            jobs.insert(nonce, InProcessJob::default());
+        }
+        self.queue.enqueue(crate::queue::QueuedJob {
+            nonce: nonce,
+            client_key: client_key.to_string(),
+            priority: priority,
+            input_json: input_json,
+        });
+    }
+
+    fn update(&self, nonce: u64, f: impl FnOnce(&mut InProcessJob<O>)) {
+        if let Some(job) = self.lock().get_mut(&nonce) {
+            f(job);
+        }
+        self.updates.send_modify(|n| *n = n.wrapping_add(1));
+    }
+
+    /// Marks `nonce` as started and returns its cancel flag, or None if the job was forgotten or cancelled before it started
+    fn start(&self, nonce: u64) -> Option<std::sync::Arc<std::sync::atomic::AtomicBool>> {
+        let mut jobs = self.lock();
+        let job = jobs.get_mut(&nonce)?;
+        if job.cancelled.load(std::sync::atomic::Ordering::SeqCst) {
+            return None;
+        }
+        job.started = true;
+        Some(job.cancelled.clone())
+    }
+
+    fn complete(&self, nonce: u64, error: Option<oliana_lib::err::ErrorReport>) {
+        self.update(nonce, |job| {
+            job.finished_at = Some(std::time::Instant::now());
+            job.error = error;
+        });
+        self.queue.finish(nonce);
+    }
+
+    /// Calls `check` whenever the job changes until it returns Some, or `wait` elapses
+    async fn wait_for<R>(&self, nonce: u64, wait: std::time::Duration, mut check: impl FnMut(&InProcessJob<O>) -> Option<Result<R, OlianaError>>) -> Result<R, OlianaError> {
+        let poll_start = std::time::Instant::now();
+        let give_up_at = poll_start + wait;
+        let mut updates = self.updates.subscribe();
+        loop {
+            {
+                let jobs = self.lock();
+                let job = jobs.get(&nonce).ok_or(OlianaError::UnknownJob { job: nonce })?;
+                if job.cancelled.load(std::sync::atomic::Ordering::SeqCst) {
+                    return Err(OlianaError::Cancelled { job: nonce });
+                }
+                if let Some(result) = check(job) {
+                    return result;
+                }
+            }
+            let now = std::time::Instant::now();
+            if now >= give_up_at {
+                return Err(OlianaError::TimedOut { worker: self.worker.clone(), waited_ms: poll_start.elapsed().as_millis() as u64 });
+            }
+            let _ = tokio::time::timeout(give_up_at - now, updates.changed()).await;
+        }
+    }
+
+    fn status(&self, nonce: u64, is_running: impl FnOnce(&O) -> bool) -> Result<JobStatus, OlianaError> {
+        if let Some(position) = self.queue.position(nonce) {
+            return Ok(JobStatus::Queued { position: position });
+        }
+        let jobs = self.lock();
+        let job = jobs.get(&nonce).ok_or(OlianaError::UnknownJob { job: nonce })?;
+        if job.cancelled.load(std::sync::atomic::Ordering::SeqCst) {
+            return Ok(JobStatus::Cancelled);
+        }
+        if job.finished_at.is_some() {
+            return Ok(if job.error.is_some() { JobStatus::Failed } else { JobStatus::Done });
+        }
+        if job.started && is_running(&job.output) {
+            return Ok(JobStatus::Running);
+        }
+        Ok(JobStatus::Pending)
+    }
+
+    fn cancel(&self, nonce: u64) -> Result<bool, OlianaError> {
+        {
+            let jobs = self.lock();
+            let job = jobs.get(&nonce).ok_or(OlianaError::UnknownJob { job: nonce })?;
+            if job.finished_at.is_some() {
+                return Ok(false); // Nothing left to stop
+            }
+            job.cancelled.store(true, std::sync::atomic::Ordering::SeqCst); // The generator sees this on its next callback
+        }
+        self.queue.remove_waiting(nonce);
+        self.updates.send_modify(|n| *n = n.wrapping_add(1));
+        Ok(true)
+    }
+
+    fn queue_status(&self) -> QueueStatus {
+        QueueStatus::of(&Some(self.queue.clone()))
+    }
+}
+
+fn job_failed(worker: &str, report: &oliana_lib::err::ErrorReport) -> OlianaError {
+    OlianaError::BackendTraceback { worker: worker.to_string(), traceback: report.to_string(), report: Some(Box::new(report.clone())) }
+}
+
+// Runs `generate` on a blocking thread, turning panics into job errors so the slot is always freed
+fn spawn_generation<O, F>(jobs: std::sync::Arc<JobTable<O>>, nonce: u64, generate: F)
+    where O: Default + Send + 'static, F: FnOnce() -> Result<(), Box<dyn std::error::Error + Send + Sync>> + Send + 'static
+{
+    tokio::task::spawn_blocking(move || {
+        let error = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(generate)) {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(oliana_lib::err::ErrorReport::from_error(&*e)),
+            Err(_) => Some(oliana_lib::err::ErrorReport::new(oliana_lib::err::ErrorKind::WorkerFailed, format!("The {} generator panicked", jobs.worker))),
+        };
+        jobs.complete(nonce, error);
+    });
+}
+
+pub struct InProcessTextBackend {
+    jobs: std::sync::Arc<JobTable<String>>,
+}
+
+impl InProcessTextBackend {
+    /// Spawns the queue dispatcher, so this must be called from inside a tokio runtime
+    pub fn new(name: &str, generator: std::sync::Arc<dyn TextGenerator>, queue_config: &crate::config::QueueConfig) -> std::sync::Arc<Self> {
+        let jobs = std::sync::Arc::new(JobTable::<String>::new(name, queue_config));
+        let dispatch_jobs = jobs.clone();
+        jobs.queue.clone().spawn_callback_dispatcher(move |queued| {
+            let jobs = dispatch_jobs.clone();
+            let generator = generator.clone();
+            let nonce = queued.nonce;
+            let cancelled = match jobs.start(nonce) {
+                Some(cancelled) => cancelled,
+                None => {
+                    jobs.queue.finish(nonce);
+                    return;
+                }
+            };
+            spawn_generation(jobs.clone(), nonce, move || {
+                let request: TextRequest = serde_json::from_str(&queued.input_json)?;
+                generator.generate(&request, &mut |token| {
+                    jobs.update(nonce, |job| job.output.push_str(token));
+                    !cancelled.load(std::sync::atomic::Ordering::SeqCst)
+                })
+            });
+        });
+        std::sync::Arc::new(Self { jobs: jobs })
+    }
+}
+
+impl TextBackend for InProcessTextBackend {
+    fn name(&self) -> String {
+        self.jobs.worker.clone()
+    }
+
+    fn begin(&self, request: TextRequest) -> BackendFuture<'_, u64> {
+        Box::pin(async move {
+            let nonce = crate::next_job_nonce();
+            let input_json = serde_json::to_string(&request).map_err(oliana_lib::eloc!())?;
+            self.jobs.begin(nonce, request.priority, &request.client_key, input_json);
+            Ok(nonce)
+        })
+    }
+
+    fn read(&self, job: u64, from_byte: usize, wait: std::time::Duration) -> BackendFuture<'_, TextRead> {
+        Box::pin(async move {
+            let worker = self.jobs.worker.clone();
+            self.jobs.wait_for(job, wait, |in_process_job| {
+                let output = &in_process_job.output;
+                if output.len() < from_byte {
+                    return Some(Ok(TextRead::Finished));
+                }
+                if output.len() > from_byte && output.is_char_boundary(from_byte) {
+                    return Some(Ok(TextRead::Text { text: output[from_byte..].to_string(), next_byte_i: output.len() }));
+                }
+                if in_process_job.finished_at.is_some() {
+                    return Some(match &in_process_job.error {
+                        Some(error) => Err(job_failed(&worker, error)),
+                        None => Ok(TextRead::Finished),
+                    });
+                }
+                None
+            }).await
+        })
+    }
+
+    fn status(&self, job: u64) -> BackendFuture<'_, JobStatus> {
+        Box::pin(async move {
+            self.jobs.status(job, |output| output.len() > 0)
+        })
+    }
+
+    fn cancel(&self, job: u64) -> BackendFuture<'_, bool> {
+        Box::pin(async move {
+            self.jobs.cancel(job)
+        })
+    }
+
+    fn queue_status(&self) -> BackendFuture<'_, QueueStatus> {
+        Box::pin(async move {
+            Ok(self.jobs.queue_status())
+        })
+    }
+}
+
+pub struct InProcessImageBackend {
+    jobs: std::sync::Arc<JobTable<ImageOutput>>,
+}
+
+impl InProcessImageBackend {
+    /// Spawns the queue dispatcher, so this must be called from inside a tokio runtime
+    pub fn new(name: &str, generator: std::sync::Arc<dyn ImageGenerator>, queue_config: &crate::config::QueueConfig) -> std::sync::Arc<Self> {
+        let jobs = std::sync::Arc::new(JobTable::<ImageOutput>::new(name, queue_config));
+        let dispatch_jobs = jobs.clone();
+        jobs.queue.clone().spawn_callback_dispatcher(move |queued| {
+            let jobs = dispatch_jobs.clone();
+            let generator = generator.clone();
+            let nonce = queued.nonce;
+            let cancelled = match jobs.start(nonce) {
+                Some(cancelled) => cancelled,
+                None => {
+                    jobs.queue.finish(nonce);
+                    return;
+                }
+            };
+            spawn_generation(jobs.clone(), nonce, move || {
+                let request: ImageRequest = serde_json::from_str(&queued.input_json)?;
+                let png_bytes = generator.generate(&request, &mut |progress, preview_png_bytes| {
+                    jobs.update(nonce, |job| {
+                        job.output.progress = Some(progress);
+                        if let Some(preview_png_bytes) = preview_png_bytes {
+                            job.output.preview = Some(ImagePreview { step: progress.step, num_steps: progress.num_steps, png_bytes: preview_png_bytes });
+                        }
+                    });
+                    !cancelled.load(std::sync::atomic::Ordering::SeqCst)
+                })?;
+                jobs.update(nonce, |job| job.output.png_bytes = Some(png_bytes));
+                Ok(())
+            });
+        });
+        std::sync::Arc::new(Self { jobs: jobs })
+    }
+}
+
+impl ImageBackend for InProcessImageBackend {
+    fn name(&self) -> String {
+        self.jobs.worker.clone()
+    }
+
+    fn begin(&self, request: ImageRequest) -> BackendFuture<'_, u64> {
+        Box::pin(async move {
+            let nonce = crate::next_job_nonce();
+            let input_json = serde_json::to_string(&request).map_err(oliana_lib::eloc!())?;
+            self.jobs.begin(nonce, request.priority, &request.client_key, input_json);
+            Ok(nonce)
+        })
+    }
+
+    fn next_preview(&self, job: u64, after_step: u32, wait: std::time::Duration) -> BackendFuture<'_, Option<ImagePreview>> {
+        Box::pin(async move {
+            self.jobs.wait_for(job, wait, |in_process_job| {
+                if in_process_job.finished_at.is_some() || in_process_job.output.png_bytes.is_some() {
+                    return Some(Ok(None));
+                }
+                match &in_process_job.output.preview {
+                    Some(preview) if preview.step > after_step => Some(Ok(Some(preview.clone()))),
+                    _ => None,
+                }
+            }).await
+        })
+    }
+
+    fn get_result(&self, job: u64, wait: std::time::Duration) -> BackendFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            let worker = self.jobs.worker.clone();
+            self.jobs.wait_for(job, wait, |in_process_job| {
+                if let Some(error) = &in_process_job.error {
+                    return Some(Err(job_failed(&worker, error)));
+                }
+                if in_process_job.finished_at.is_some() {
+                    return Some(in_process_job.output.png_bytes.clone().ok_or(OlianaError::BackendTraceback { worker: worker.clone(), traceback: "The generator finished without an image".to_string(), report: None }));
+                }
+                None
+            }).await
+        })
+    }
+
+    fn status(&self, job: u64) -> BackendFuture<'_, JobStatus> {
+        Box::pin(async move {
+            self.jobs.status(job, |output| output.progress.is_some())
+        })
+    }
+
+    fn cancel(&self, job: u64) -> BackendFuture<'_, bool> {
+        Box::pin(async move {
+            self.jobs.cancel(job)
+        })
+    }
+
+    fn queue_status(&self) -> BackendFuture<'_, QueueStatus> {
+        Box::pin(async move {
+            Ok(self.jobs.queue_status())
+        })
+    }
+}
+
+/// GPU-free stand-in built on oliana_lib::mock, registered as generator = "mock"; output matches `oliana_text --backend mock`
+pub struct MockTextGenerator;
+
+impl TextGenerator for MockTextGenerator {
+    fn generate(&self, request: &TextRequest, on_token: &mut dyn FnMut(&str) -> bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for (delay, token) in oliana_lib::mock::mock_text_tokens(0, &request.system_prompt, &request.user_prompt, oliana_lib::mock::DEFAULT_MOCK_TOKEN_DELAY) {
+            std::thread::sleep(delay);
+            if !on_token(&token) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// GPU-free stand-in built on oliana_lib::mock, registered as generator = "mock"; output matches `oliana_images --backend mock`
+pub struct MockImageGenerator;
+
+impl ImageGenerator for MockImageGenerator {
+    fn generate(&self, request: &ImageRequest, on_step: &mut dyn FnMut(ImageProgress, Option<Vec<u8>>) -> bool) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        for step in 1..=request.num_inference_steps {
+            std::thread::sleep(oliana_lib::mock::DEFAULT_MOCK_STEP_DELAY);
+            let noise = 1.0 - (step as f32 / request.num_inference_steps as f32);
+            let preview_png_bytes = oliana_lib::mock::mock_image_png(0, &request.prompt, &request.negative_prompt, oliana_lib::mock::MOCK_PREVIEW_SIZE, noise);
+            if !on_step(ImageProgress { step: step, num_steps: request.num_inference_steps }, Some(preview_png_bytes)) {
+                break;
+            }
+        }
+        Ok(oliana_lib::mock::mock_image_png(0, &request.prompt, &request.negative_prompt, oliana_lib::mock::MOCK_IMAGE_SIZE, 0.0))
+    }
+}
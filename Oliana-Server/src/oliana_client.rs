@@ -32,26 +32,89 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
 
   println!("Connecting to {:?}", args.server_url);
 
-  let mut transport = tarpc::serde_transport::tcp::connect(args.server_url, tarpc::tokio_serde::formats::Bincode::default);
-  transport.config_mut().max_frame_length(usize::MAX);
+  let transport_config = oliana_server_lib::transport::ClientTransportConfig {
+    tls: args.tls || args.tls_ca_cert.is_some(),
+    tls_ca_cert: args.tls_ca_cert.clone(),
+    tls_server_name: args.tls_server_name.clone(),
+    token: args.token.clone(),
+  };
+  let client = oliana_server_lib::transport::connect(&args.server_url, &transport_config).await?;
 
-  // OlianaClient is generated by the service attribute. It has a constructor `new` that takes a
-  // config and any Transport as input.
-  let client = oliana_server_lib::OlianaClient::new(tarpc::client::Config::default(), transport.await?).spawn();
-
-  if args.command == Command::Text {
-    let text_begin_diagnostic = client.generate_text_begin(
+  if args.command == Command::Status {
+    let status = client.server_status(tarpc::context::current()).await??;
+    println!("oliana_server {}", status.version);
+    for worker in status.workers.iter() {
+      let state = if worker.failed { "FAILED, no longer re-spawned".to_string() }
+        else if let Some(secs) = worker.next_restart_in_secs { format!("NOT RUNNING, re-spawning in {secs}s") }
+        else if !worker.running { "NOT RUNNING".to_string() }
+        else if let Some(job) = &worker.busy_with_job { format!("busy with job {job}") }
+        else if worker.model_loaded { "ready".to_string() }
+        else { "loading model".to_string() };
+      let uptime = worker.uptime_secs.map(|secs| oliana_lib::misc::duration_to_display_str(&std::time::Duration::from_secs(secs))).unwrap_or("-".to_string());
+      println!("  {}: {} (pid {}, up {}, {} restarts, PER_PROC_MEM_FRACT={})",
+        worker.bin_name, state,
+        worker.pid.map(|pid| format!("{pid}")).unwrap_or("-".to_string()),
+        uptime, worker.restarts,
+        worker.per_proc_mem_fract.as_deref().unwrap_or("-"));
+      if let Some(heartbeat_age_secs) = worker.heartbeat_age_secs {
+        println!("    last heartbeat: {}s ago", heartbeat_age_secs);
+      }
+      if let Some(last_exit) = &worker.last_exit {
+        println!("    last exit: {}", last_exit);
+      }
+    }
+    println!("  text queue: {} waiting, {} of {} slots running", status.text_queue.waiting, status.text_queue.running, status.text_queue.max_concurrent_jobs);
+    println!("  image queue: {} waiting, {} of {} slots running", status.image_queue.waiting, status.image_queue.running, status.image_queue.max_concurrent_jobs);
+  }
+  else if args.command == Command::Logs {
+    for line in client.worker_log_tail(tarpc::context::current(), args.worker.clone(), args.lines).await?? {
+      println!("{}", line);
+    }
+  }
+  else if args.command == Command::Text {
+    let text_job = client.generate_text_begin(
       tarpc::context::current(),
       args.system_prompt.clone(),
-      args.prompt.clone()
-    ).await?;
-    eprintln!("From Server: {:?}", &text_begin_diagnostic);
+      args.prompt.clone(),
+      args.priority
+    ).await??;
+    eprintln!("Server began text job {:?}", &text_job);
+
+    // Ctrl+C asks the server to stop generating; the loop below then ends with OlianaError::Cancelled
+    let cancel_client = client.clone();
+    tokio::spawn(async move {
+      if tokio::signal::ctrl_c().await.is_ok() {
+        eprintln!("Cancelling {:?}", &text_job);
+        match cancel_client.cancel_text(tarpc::context::current(), text_job).await {
+          Ok(Ok(())) => { }
+          Ok(Err(e)) => eprintln!("[ cancel_text ] {}", e),
+          Err(e) => eprintln!("[ cancel_text ] {}", e),
+        }
+      }
+    });
+
     let mut generated_text = String::with_capacity(4096);
-    while let Some(next_token) = client.generate_text_next_token(tarpc::context::current()).await? {
-      eprint!("{}", &next_token);
-      //eprint!(" ");
-      generated_text.push_str(&next_token);
-      //generated_text.push_str(" ");
+    let mut remaining_retries = args.retries;
+    loop {
+      match client.generate_text_next_token(tarpc::context::current(), text_job).await? {
+        Ok(Some(next_token)) => {
+          eprint!("{}", &next_token);
+          //eprint!(" ");
+          generated_text.push_str(&next_token);
+          //generated_text.push_str(" ");
+        }
+        Ok(None) => break,
+        Err(e) if e.is_retryable() && remaining_retries > 0 => {
+          // Waiting in the server's queue is expected and does not use up a retry
+          if let Ok(Ok(oliana_server_lib::JobStatus::Queued { position })) = client.generate_text_status(tarpc::context::current(), text_job).await {
+            eprintln!("[ generate_text_next_token ] Queued at position {}", position);
+            continue;
+          }
+          remaining_retries -= 1;
+          eprintln!("[ generate_text_next_token ] {} (retrying, {} retries remaining)", e, remaining_retries);
+        }
+        Err(e) => return Err(e.into()),
+      }
     }
     eprintln!();
     if args.output.len() > 0 {
@@ -60,16 +123,67 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     }
   }
   else if args.command == Command::Image {
-    let text_begin_diagnostic = client.generate_image_begin(
+    let image_job = client.generate_image_begin(
       tarpc::context::current(),
       args.prompt.clone(),
       args.negative_prompt.clone(),
       args.guidance_scale,
-      args.num_inference_steps
-    ).await?;
-    eprintln!("From Server: {:?}", &text_begin_diagnostic);
+      args.num_inference_steps,
+      args.priority
+    ).await??;
+    eprintln!("Server began image job {:?}", &image_job);
+
+    // Ctrl+C asks the server to stop diffusing; the loops below then end with OlianaError::Cancelled
+    let cancel_client = client.clone();
+    tokio::spawn(async move {
+      if tokio::signal::ctrl_c().await.is_ok() {
+        eprintln!("Cancelling {:?}", &image_job);
+        match cancel_client.cancel_image(tarpc::context::current(), image_job).await {
+          Ok(Ok(())) => { }
+          Ok(Err(e)) => eprintln!("[ cancel_image ] {}", e),
+          Err(e) => eprintln!("[ cancel_image ] {}", e),
+        }
+      }
+    });
+
+    if args.save_previews {
+      let mut remaining_retries = args.retries;
+      loop {
+        match client.generate_image_next_preview(tarpc::context::current(), image_job).await? {
+          Ok(Some(preview)) => {
+            let preview_path = std::path::Path::new(&args.output).with_extension(format!("preview-{}.png", preview.step));
+            eprintln!("Writing step {} of {} ({} bytes) to {}", preview.step, preview.num_steps, preview.png_bytes.len(), preview_path.display());
+            tokio::fs::write(&preview_path, &preview.png_bytes).await?;
+          }
+          Ok(None) => break,
+          Err(e) if e.is_retryable() && remaining_retries > 0 => {
+            if let Ok(Ok(oliana_server_lib::JobStatus::Queued { position })) = client.generate_image_status(tarpc::context::current(), image_job).await {
+              eprintln!("[ generate_image_next_preview ] Queued at position {}", position);
+              continue;
+            }
+            remaining_retries -= 1;
+            eprintln!("[ generate_image_next_preview ] {} (retrying, {} retries remaining)", e, remaining_retries);
+          }
+          Err(e) => return Err(e.into()),
+        }
+      }
+    }
 
-    let png_bytes = client.generate_image_get_result(tarpc::context::current()).await?;
+    let mut remaining_retries = args.retries;
+    let png_bytes = loop {
+      match client.generate_image_get_result(tarpc::context::current(), image_job).await? {
+        Ok(png_bytes) => break png_bytes,
+        Err(e) if e.is_retryable() && remaining_retries > 0 => {
+          if let Ok(Ok(oliana_server_lib::JobStatus::Queued { position })) = client.generate_image_status(tarpc::context::current(), image_job).await {
+            eprintln!("[ generate_image_get_result ] Queued at position {}", position);
+            continue;
+          }
+          remaining_retries -= 1;
+          eprintln!("[ generate_image_get_result ] {} (retrying, {} retries remaining)", e, remaining_retries);
+        }
+        Err(e) => return Err(e.into()),
+      }
+    };
 
     if args.output.len() > 0 {
       eprintln!("Writing {} bytes to {}", png_bytes.len(), &args.output);
@@ -84,6 +198,10 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
 #[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
 pub enum Command {
   Text, Image,
+  /// Print the state of the server's workers and job queues
+  Status,
+  /// Print the end of a worker's log (see --worker and --lines)
+  Logs,
   Help
 }
 
@@ -119,6 +237,10 @@ pub struct Args {
     #[arg(short, long, default_value="12")]
     pub num_inference_steps: u32,
 
+    /// With command 'image' only - also write each in-progress diffusion frame next to --output (ie out.preview-3.png)
+    #[arg(long)]
+    pub save_previews: bool,
+
     /// File path to write Image or Text AI response back to (defaults to out.png when using Image command, writes to stdout if unspecified in Text command)
     #[arg(short, long, default_value="")]
     pub output: String,
@@ -127,6 +249,38 @@ pub struct Args {
     #[arg(short, long, default_value="localhost:9050")]
     pub server_url: String,
 
+    /// Connect over TLS (implied by --tls-ca-cert)
+    #[arg(long)]
+    pub tls: bool,
+
+    /// PEM certificate to trust when the server uses a self-signed certificate (ie the server's oliana_server.cert.pem)
+    #[arg(long)]
+    pub tls_ca_cert: Option<std::path::PathBuf>,
+
+    /// Name to verify the server's certificate against, if different from the host in --server-url
+    #[arg(long)]
+    pub tls_server_name: Option<String>,
+
+    /// Pre-shared token the server was configured with
+    #[arg(long, env = "OLIANA_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Where the job goes in the server's queue relative to other jobs; interactive jobs run before normal ones, which run before background ones
+    #[arg(long, value_enum, default_value_t=oliana_server_lib::JobPriority::Normal)]
+    pub priority: oliana_server_lib::JobPriority,
+
+    /// How many times to re-ask the server after a retryable error (ie a timeout while the worker loads its model) before giving up
+    #[arg(long, default_value="30")]
+    pub retries: usize,
+
+    /// With command 'logs' only - the worker to print the log of
+    #[arg(long, default_value="oliana_text")]
+    pub worker: String,
+
+    /// With command 'logs' only - how many lines from the end of the log to print
+    #[arg(long, default_value="100")]
+    pub lines: u32,
+
     /// Amount of verbosity in printed status messages; can be specified multiple times (ie "-v", "-vv", "-vvv" for greater verbosity)
     #[arg(short = 'v', long, action = clap::ArgAction::Count)]
     pub verbose: u8,
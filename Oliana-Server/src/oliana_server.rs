@@ -21,180 +21,214 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
   Ok(())
 }
 
-async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
-    tokio::spawn(fut);
-}
-
 async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
-    use tarpc::server::Channel;
-    use oliana_server_lib::Oliana;
-    use futures::StreamExt;
-    use tarpc::server::incoming::Incoming;
-
-    // TODO allow passing in configs for each of these folders
-    let mut expected_bin_directory = std::env::current_dir()?;
-    if expected_bin_directory.join("target").exists() {
-        expected_bin_directory = expected_bin_directory.join("target");
-    }
-    let track_proc_dir = expected_bin_directory.clone();
-
-    let mut procs = oliana_lib::launchers::TrackedProcs::new(expected_bin_directory.clone(), track_proc_dir.clone());
+    use clap::Parser;
 
-    // This is where we do some general config of how & where the child processes will live.
-    // Once registered, the server will regularly poll .ensure_registered_procs_running() to re-spawn anything that dies.
-    let ai_workdir_images = track_proc_dir.join("image-procesing");
-    let ai_workdir_text = track_proc_dir.join("text-procesing");
+    let args = ServerArgs::parse();
+    let config = args.load_config()?;
 
-    if !ai_workdir_images.exists() {
-        std::fs::create_dir_all(ai_workdir_images.clone()).map_err(oliana_lib::eloc!())?;
-    }
-    if !ai_workdir_text.exists() {
-        std::fs::create_dir_all(ai_workdir_text.clone()).map_err(oliana_lib::eloc!())?;
+    if args.print_config {
+        print!("{}", config.to_toml_string()?);
+        return Ok(());
     }
 
-    // Delete all files in ai_workdir_images and ai_workdir_text; this prevents concurrency build-up over time
-    for working_dir in [&ai_workdir_images, &ai_workdir_text] {
-        let mut dir_iterator = tokio::fs::read_dir(&working_dir).await?;
-        while let Some(entry) = dir_iterator.next_entry().await? {
-            let entry_path = entry.path();
-            if entry_path.is_file() {
-                tokio::fs::remove_file(&entry_path).await?;
-            }
-        }
+    if args.cache_list || args.cache_prune.len() > 0 {
+        return manage_cache(&args, &config).await;
     }
 
-    // We set & pass down this value which backends may read to avoid over-allocating eachother's slice of the GPU pie.
-    let mut per_proc_mem_already_defined = false;
-    if let Ok(per_proc_mem_val) = std::env::var("PER_PROC_MEM_FRACT") {
-        if per_proc_mem_val.len() > 0 {
-            per_proc_mem_already_defined = true;
-            eprintln!("Not changing already-existing PER_PROC_MEM_FRACT value of {}", &per_proc_mem_val);
+    if args.prefetch {
+        prefetch(&config).await?;
+        let pruned = config.enforce_cache_quota(&config.resolve_cache_dir()?)?;
+        if pruned.len() > 0 {
+            println!("Pruned to stay under cache_quota_bytes: {}", pruned.join(", "));
         }
-    }
-    if !per_proc_mem_already_defined {
-        eprintln!("Setting PER_PROC_MEM_FRACT=0.40 for child processes (otherwise they will over-allocate and eat >100% of GPU memory and one will lose the race and go home cryting for more VRAM)");
-        std::env::set_var(
-          "PER_PROC_MEM_FRACT", "0.40"
-        );
+        return Ok(());
     }
 
-    procs.register_tracked_proc("oliana_images", &[
-        "--workdir", &ai_workdir_images.to_string_lossy()
-    ]);
-
-    procs.register_tracked_proc("oliana_text", &[
-        "--workdir", &ai_workdir_text.to_string_lossy()
-    ]);
-
-    procs.ensure_registered_procs_running()?;
-
-    let shareable_procs = std::sync::Arc::new(std::sync::RwLock::new(procs));
-    let shareable_ipv6_ai_workdir_images = ai_workdir_images.to_string_lossy().to_string();
-    let shareable_ipv6_ai_workdir_text = ai_workdir_text.to_string_lossy().to_string();
-    let shareable_ipv4_ai_workdir_images = ai_workdir_images.to_string_lossy().to_string();
-    let shareable_ipv4_ai_workdir_text = ai_workdir_text.to_string_lossy().to_string();
-
-    // Start an infinite tokio task to call ensure_registered_procs_running()? every 2 seconds or so.
-    let ensure_registered_procs_running_t_shareable_procs = shareable_procs.clone();
-    tokio::task::spawn(async move {
-        loop {
-            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-            if let Ok(mut write_lock_guard) = ensure_registered_procs_running_t_shareable_procs.try_write() {
-                if let Err(e) = write_lock_guard.ensure_registered_procs_running() {
-                    eprintln!("Error polling ensure_registered_procs_running: {:?}", e);
-                }
-            }
-        }
-    });
+    oliana_server_lib::serve::serve(config).await
+}
+
+// See docs for clap's derive implementations at
+//   https://docs.rs/clap/latest/clap/_derive/index.html#overview
+// Flags override whatever the config file says.
+#[derive(Debug, Clone, clap::Parser, Default)]
+pub struct ServerArgs {
+
+    /// TOML config file to read (see Oliana-Server/src/config.rs for every key); defaults to ./oliana_server.toml if that exists
+    #[arg(short, long)]
+    pub config: Option<std::path::PathBuf>,
 
-    let port: u16 = 9050;
+    /// Port every bind address listens on
+    #[arg(short, long)]
+    pub port: Option<u16>,
 
-    let ipv4_server_addr = (std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), port);
-    let ipv6_server_addr = (std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED), port);
+    /// Address to listen on; can be specified multiple times (ie "--bind 127.0.0.1 --bind ::1")
+    #[arg(short, long)]
+    pub bind: Vec<std::net::IpAddr>,
 
-    println!("ipv4_server_addr = {ipv4_server_addr:?}");
-    println!("ipv6_server_addr = {ipv6_server_addr:?}");
-    println!("port = {port:?} (used by both ipv4 and v6 servers)");
+    /// Folder where eg oliana_images[.exe] can be found
+    #[arg(long)]
+    pub bin_directory: Option<std::path::PathBuf>,
 
-    println!("expected_bin_directory = {expected_bin_directory:?} (Where eg oliana_images[.exe] can be found)");
-    println!("track_proc_dir = {track_proc_dir:?} (Where eg oliana_images[.exe]-pid.txt may be found)");
-    println!("ai_workdir_images = {ai_workdir_images:?} (Where images are generated into and read by the server)");
-    println!("ai_workdir_text = {ai_workdir_text:?} (Where text is generated into and read by the server)");
+    /// Folder where eg oliana_images[.exe]-pid.txt are written and relative workdirs live
+    #[arg(long)]
+    pub track_proc_dir: Option<std::path::PathBuf>,
 
+    /// Wrap connections in TLS using this PEM certificate chain (with --tls-key)
+    #[arg(long)]
+    pub tls_cert: Option<std::path::PathBuf>,
 
-    // JSON transport is provided by the json_transport tarpc module. It makes it easy
-    // to start up a serde-powered json serialization strategy over TCP.
-    let mut ipv6_listener = tarpc::serde_transport::tcp::listen(&ipv6_server_addr, tarpc::tokio_serde::formats::Bincode::default).await?;
-    println!("Server Listening on {:?}", &ipv6_server_addr);
+    /// PEM private key for --tls-cert
+    #[arg(long)]
+    pub tls_key: Option<std::path::PathBuf>,
 
-    // Infrastructure detail: If the Host OS has dual-stacking turned on, the above ipv6_listener will bind to both ipv6 and v4 addresses.
-    //                        If the Host OS has dual-stacking turned off, we still want to explicitly launch a v4 connector to support v4 clients.
-    let mut maybe_ipv4_listener = None;
-    if let Ok(ipv4_listener) = tarpc::serde_transport::tcp::listen(&ipv4_server_addr, tarpc::tokio_serde::formats::Bincode::default).await {
-        maybe_ipv4_listener = Some(ipv4_listener);
-        println!("Server Listening on {:?}", &ipv4_server_addr);
+    /// Wrap connections in TLS, generating a self-signed certificate under track_proc_dir if none exists yet
+    #[arg(long)]
+    pub tls_self_signed: bool,
+
+    /// Pre-shared token every client must present; added to the config file's auth_tokens as user "default"
+    #[arg(long, env = "OLIANA_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Also serve the OpenAI-compatible HTTP gateway on this port (see Oliana-Server/src/http_gateway.rs)
+    #[arg(long)]
+    pub http_port: Option<u16>,
+
+    /// Print the effective configuration as TOML and exit; useful as a starting point for a config file
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Download every model and python package the configured workers load into the cache directory, then exit.
+    /// With --offline nothing is downloaded; the cache is only checked for everything an offline run needs.
+    #[arg(long)]
+    pub prefetch: bool,
+
+    /// Workers never touch the network and exit naming any model file or python package missing from the cache
+    #[arg(long)]
+    pub offline: bool,
+
+    /// Keep models, python packages and hf_home folders here instead of the cache_dir of the config file (relative to the current directory)
+    #[arg(long, env = "OLIANA_CACHE_DIR")]
+    pub cache_dir: Option<std::path::PathBuf>,
+
+    /// List every cache entry (models, python packages, hf_home folders) with its size, when it was last used and which PIDs use it, then exit
+    #[arg(long)]
+    pub cache_list: bool,
+
+    /// Delete this cache entry (a name --cache-list prints) unless a running worker uses it, then exit; can be specified multiple times
+    #[arg(long)]
+    pub cache_prune: Vec<String>,
+
+}
+
+impl ServerArgs {
+    pub fn load_config(&self) -> Result<oliana_server_lib::config::ServerConfig, Box<dyn std::error::Error>> {
+        let mut config = oliana_server_lib::config::ServerConfig::load_or_default(self.config.as_deref())?;
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if self.bind.len() > 0 {
+            config.bind_addresses = self.bind.clone();
+        }
+        if let Some(bin_directory) = &self.bin_directory {
+            config.bin_directory = Some(bin_directory.clone());
+        }
+        if let Some(track_proc_dir) = &self.track_proc_dir {
+            config.track_proc_dir = Some(track_proc_dir.clone());
+        }
+        if self.tls_cert.is_some() || self.tls_key.is_some() || self.tls_self_signed {
+            let mut tls = config.tls.clone().unwrap_or_default();
+            if let Some(tls_cert) = &self.tls_cert {
+                tls.cert_file = tls_cert.clone();
+            }
+            if let Some(tls_key) = &self.tls_key {
+                tls.key_file = tls_key.clone();
+            }
+            if self.tls_self_signed {
+                tls.generate_self_signed = true;
+            }
+            config.tls = Some(tls);
+        }
+        if let Some(http_port) = self.http_port {
+            let mut http_gateway = config.http_gateway.clone().unwrap_or_default();
+            http_gateway.port = http_port;
+            config.http_gateway = Some(http_gateway);
+        }
+        if self.offline {
+            config.offline = true;
+        }
+        if let Some(cache_dir) = self.cache_dir.as_ref().filter(|cache_dir| cache_dir.as_os_str().len() > 0) {
+            config.cache_dir = Some(std::env::current_dir()?.join(cache_dir));
+        }
+        if let Some(token) = &self.token {
+            if token.len() > 0 {
+                config.auth_tokens.insert("default".to_string(), token.clone());
+            }
+        }
+        Ok(config)
     }
+}
 
-    if let Some(ref mut ipv4_listener) = maybe_ipv4_listener {
-        ipv4_listener.config_mut().max_frame_length(usize::MAX);
+// --prefetch: makes sure the cache holds everything the workers of `config` load, printing how to pin files the manifest has no checksum for
+async fn prefetch(config: &oliana_server_lib::config::ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = config.load_models_manifest()?;
+    let cache_root = config.resolve_cache_dir()?;
+    tokio::fs::create_dir_all(&cache_root).await.map_err(oliana_lib::eloc!(format!("Creating {}", cache_root.display())))?;
+    let uses_images_worker = config.image_backend.is_workdir();
+    let mut missing = vec![];
+    for model in manifest.models.iter() {
+        if (model.name == oliana_lib::models::TEXT_MODEL_NAME && !config.text_backend.is_workdir()) || (model.name == oliana_lib::models::IMAGE_MODEL_NAME && !uses_images_worker) {
+            continue; // Its jobs go to another backend
+        }
+        if config.offline {
+            missing.extend(model.missing_files(&cache_root).iter().map(|file| model.local_file(&cache_root, file).display().to_string()));
+            continue;
+        }
+        // Files are fetched from the commit a branch or tag points to now, so the checksums printed below belong to that commit
+        let mut model = model.clone();
+        let unpinned_revision = if oliana_lib::models::is_commit_hash(&model.revision) { None } else { Some(model.revision.clone()) };
+        model.revision = oliana_lib::models::resolve_revision(&manifest, &model).await?;
+        let prefetched = oliana_lib::models::prefetch_model(&manifest, &model, &cache_root, &oliana_lib::files::DownloadOptions::default()).await?;
+        println!("{} ({} at {}): {} files in {}", model.name, model.repo, model.revision, prefetched.len(), model.local_dir(&cache_root).display());
+        if let Some(unpinned_revision) = unpinned_revision {
+            println!("  revision {:?} is not a commit hash; to pin it, use:", unpinned_revision);
+            println!("  revision = {:?}", model.revision);
+        }
+        if prefetched.iter().any(|fetched| !fetched.pinned) {
+            println!("  Some files have no sha256 in the manifest; to pin them, use these as the model's files:");
+            for (file, fetched) in model.files.iter().zip(prefetched.iter()) {
+                println!("  {{ path = {:?}, sha256 = {:?}, size_bytes = {} }},", file.path, fetched.sha256, fetched.size_bytes);
+            }
+        }
     }
-    ipv6_listener.config_mut().max_frame_length(usize::MAX);
-
-    let mut all_futures = vec![];
-    let ipv6_movable_shareable_procs = shareable_procs.clone();
-    let ipv6_futures = tokio::spawn(ipv6_listener
-            // Ignore accept errors.
-            .filter_map(|r| future::ready(r.ok()))
-            .map(tarpc::server::BaseChannel::with_defaults)
-            // Limit channels to 1 per IP.
-            .max_channels_per_key(1, |t| t.transport().peer_addr().unwrap().ip())
-            // serve is generated by the service attribute. It takes as input any type implementing
-            // the generated World trait.
-            .map(move |channel| {
-                let server = oliana_server_lib::OlianaServer::new(
-                    channel.transport().peer_addr().expect("IPv6 Client had no peer_addr!"),
-                    ipv6_movable_shareable_procs.clone(),
-                    &shareable_ipv6_ai_workdir_images[..],
-                    &shareable_ipv6_ai_workdir_text[..]
-                );
-                channel.execute(server.serve()).for_each(spawn)
-            })
-            // Max 10 channels.
-            .buffer_unordered(10)
-            .for_each(|_| async {}));
-
-    all_futures.push(ipv6_futures);
-
-    if let Some(ipv4_listener) = maybe_ipv4_listener {
-            all_futures.push(
-                tokio::spawn(ipv4_listener
-                    // Ignore accept errors.
-                    .filter_map(|r| future::ready(r.ok()))
-                    .map(tarpc::server::BaseChannel::with_defaults)
-                    // Limit channels to 1 per IP.
-                    .max_channels_per_key(1, |t| t.transport().peer_addr().unwrap().ip())
-                    // serve is generated by the service attribute. It takes as input any type implementing
-                    // the generated World trait.
-                    .map(move |channel| {
-                        let server = oliana_server_lib::OlianaServer::new(
-                            channel.transport().peer_addr().expect("IPv4 Client had no peer_addr!"),
-                            shareable_procs.clone(),
-                            &shareable_ipv4_ai_workdir_images[..],
-                            &shareable_ipv4_ai_workdir_text[..]
-                        );
-                        channel.execute(server.serve()).for_each(spawn)
-                    })
-                    // Max 10 channels.
-                    .buffer_unordered(10)
-                    .for_each(|_| async {}))
-            );
+    if missing.len() > 0 {
+        return Err(Box::new(oliana_lib::models::MissingArtifactsError { what: cache_root.display().to_string(), missing: missing }));
     }
 
-    // all_futures may have 1 or two listeners
-    for fut in all_futures {
-        fut.await?;
+    if uses_images_worker && manifest.python.packages.len() > 0 {
+        let installed = oliana_lib::models::prefetch_python_packages(&manifest, &cache_root, oliana_lib::models::python_interpreter(), config.offline).await?;
+        println!("python packages in {}: {} installed now, {} already there", oliana_lib::models::python_site_packages_dir(&manifest, &cache_root).display(), installed.len(), manifest.python.packages.len() - installed.len());
+    }
+    if config.offline {
+        println!("Everything an offline run needs is in {}", cache_root.display());
     }
-
     Ok(())
 }
 
+// --cache-list and --cache-prune
+async fn manage_cache(args: &ServerArgs, config: &oliana_server_lib::config::ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let cache_root = config.resolve_cache_dir()?;
+    for name in args.cache_prune.iter() {
+        let freed_bytes = oliana_lib::files::prune_cache_entry(&cache_root, name)?;
+        println!("Pruned {} ({} bytes)", name, freed_bytes);
+    }
+    if args.cache_list {
+        let entries = oliana_lib::files::list_cache_entries(&cache_root)?;
+        println!("{} ({} bytes)", cache_root.display(), entries.iter().map(|entry| entry.size_bytes).sum::<u64>());
+        for entry in entries.iter() {
+            let in_use = if entry.in_use_by.len() > 0 { format!(", in use by {:?}", entry.in_use_by) } else { String::new() };
+            println!("  {}: {} bytes, last used {}{}", entry.name, entry.size_bytes, oliana_lib::misc::utc_timestamp_str(entry.last_used), in_use);
+        }
+    }
+    Ok(())
+}
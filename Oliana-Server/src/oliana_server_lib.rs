@@ -1,5 +1,15 @@
 #![allow(unused_imports, unused_variables, unused_mut)]
 
+pub mod backend;
+pub mod config;
+pub mod http_gateway;
+pub mod in_process_backend;
+pub mod queue;
+pub mod remote_backend;
+pub mod serve;
+pub mod transport;
+pub mod workdir_backend;
+
 use tokio::io::AsyncReadExt;
 use futures::prelude::*;
 use tarpc::{
@@ -8,311 +18,625 @@ use tarpc::{
 };
 
 // This is the service definition. It looks a lot like a trait definition.
-// It defines one RPC, hello, which takes one arg, name, and returns a String.
+// Every *_begin RPC hands back a job id, and every follow-up RPC for that job takes the id back; this lets
+// one connection keep several text and image jobs in flight at once.
 #[tarpc::service]
 pub trait Oliana {
-    /// Runs an LLM and returns immediately; callers should concatinate results of generate_text_next_token() until it returns None for the reply. Return is some diagnostic text from server.
-    async fn generate_text_begin(system_prompt: String, user_prompt: String) -> String;
-    /// Returns None when token generation is complete
-    async fn generate_text_next_token() -> Option<String>;
+    /// Queues an LLM run and returns immediately; callers should concatinate results of generate_text_next_token(job) until it returns None for the reply.
+    /// Higher `priority` jobs are handed to oliana_text first; see queue.rs for how jobs of equal priority are ordered.
+    async fn generate_text_begin(system_prompt: String, user_prompt: String, priority: JobPriority) -> Result<TextJobId, OlianaError>;
+    /// Returns None when token generation for `job` is complete. Err(OlianaError::TimedOut) means no new tokens arrived yet (possibly because the job is still queued,
+    /// see generate_text_status); the job is still alive and callers may ask again.
+    async fn generate_text_next_token(job: TextJobId) -> Result<Option<String>, OlianaError>;
+    /// Reports how far along `job` is without consuming any tokens
+    async fn generate_text_status(job: TextJobId) -> Result<JobStatus, OlianaError>;
+    /// Asks oliana_text to stop generating `job` (or to skip it if it has not started yet). Follow-up RPCs for `job` return OlianaError::Cancelled.
+    async fn cancel_text(job: TextJobId) -> Result<(), OlianaError>;
+
+    /// Queues an AI model run and returns immediately; callers should wait on generate_image_get_result(job) to read a .png vector of bytes back.
+    async fn generate_image_begin(prompt: String, negative_prompt: String, guidance_scale: f32, num_inference_steps: u32, priority: JobPriority) -> Result<ImageJobId, OlianaError>;
+    /// Waits until the diffusion run for `job` has produced a step newer than the last one returned and returns a low-resolution preview of it.
+    /// Returns None once the final image is available (or the run failed); callers should then use generate_image_get_result(job).
+    async fn generate_image_next_preview(job: ImageJobId) -> Result<Option<ImagePreview>, OlianaError>;
+    /// Waits until image `job` has completed and returns result. Err(OlianaError::TimedOut) means the image is not ready yet and callers may ask again.
+    async fn generate_image_get_result(job: ImageJobId) -> Result<Vec<u8>, OlianaError>;
+    /// Reports how far along `job` is without waiting on it
+    async fn generate_image_status(job: ImageJobId) -> Result<JobStatus, OlianaError>;
+    /// Asks oliana_images to stop diffusing `job` after the current step (or to skip it if it has not started yet). Follow-up RPCs for `job` return OlianaError::Cancelled.
+    async fn cancel_image(job: ImageJobId) -> Result<(), OlianaError>;
+
+    /// Reports on the worker processes and job queues so clients can tell a server still loading models apart from a broken one
+    async fn server_status() -> Result<ServerStatus, OlianaError>;
+    /// The last `num_lines` lines (at most MAX_LOG_TAIL_LINES) a worker wrote to stdout or stderr, oldest first and prefixed with timestamps.
+    /// `bin_name` is one of the WorkerStatus::bin_name values from server_status().
+    async fn worker_log_tail(bin_name: String, num_lines: u32) -> Result<Vec<String>, OlianaError>;
+
+}
+
+/// Handle for one text generation, returned by generate_text_begin().
+/// The inner number is also the file stem used in the text work directory (ie `<id>.json`, `<id>.txt`, `<id>.done`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct TextJobId(pub u64);
+
+/// Handle for one image generation, returned by generate_image_begin().
+/// The inner number is also the file stem used in the image work directory (ie `<id>.json`, `<id>.png`, `<id>.txt`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct ImageJobId(pub u64);
+
+pub use queue::JobPriority;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum JobStatus {
+    /// The job id was not handed out on this connection
+    Unknown,
+    /// The job is waiting in the server's queue for a free worker slot; `position` 1 is next in line
+    Queued { position: u32 },
+    /// Input was handed to the worker, which has not begun writing output yet
+    Pending,
+    /// The worker is writing output (for images, at least one diffusion step has finished)
+    Running,
+    Done,
+    Failed,
+    /// cancel_text() or cancel_image() was called before the job finished
+    Cancelled,
+}
+
+/// Every Oliana RPC reports failure through this type so clients can tell players something meaningful and decide
+/// whether to retry (see OlianaError::is_retryable()).
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum OlianaError {
+    /// The job id was not handed out on this connection
+    UnknownJob { job: u64 },
+    /// The job was cancelled by cancel_text() or cancel_image()
+    Cancelled { job: u64 },
+    /// The request could not be run as given (empty prompt, zero inference steps, ...)
+    BadInput { msg: String },
+    /// The worker process responsible for the job is not running; it will be re-spawned by the server shortly
+    WorkerCrashed { worker: String },
+    /// The worker has not produced output within `waited_ms`; the job may still complete
+    TimedOut { worker: String, waited_ms: u64 },
+    /// The worker ran the job and reported a failure; `traceback` holds its diagnostic output (ie a python stack-trace from oliana_images).
+    /// `report` is the error as the worker recorded it, when it wrote one (see oliana_lib::launchers::write_job_failed)
+    BackendTraceback { worker: String, traceback: String, report: Option<Box<oliana_lib::err::ErrorReport>> },
+    /// Something went wrong on the server itself; `location` is the file:line:column the error was raised from, `report` the whole chain when there is one
+    Internal { msg: String, location: String, report: Option<Box<oliana_lib::err::ErrorReport>> },
+}
+
+impl OlianaError {
+    /// Category of the error; the worker's or server's own ErrorKind where a report carries one
+    pub fn kind(&self) -> oliana_lib::err::ErrorKind {
+        match self {
+            OlianaError::BackendTraceback { report: Some(report), .. } | OlianaError::Internal { report: Some(report), .. } => report.kind,
+            OlianaError::UnknownJob { .. } => oliana_lib::err::ErrorKind::NotFound,
+            OlianaError::Cancelled { .. } => oliana_lib::err::ErrorKind::Cancelled,
+            OlianaError::BadInput { .. } => oliana_lib::err::ErrorKind::InvalidInput,
+            OlianaError::WorkerCrashed { .. } | OlianaError::BackendTraceback { .. } => oliana_lib::err::ErrorKind::WorkerFailed,
+            OlianaError::TimedOut { .. } => oliana_lib::err::ErrorKind::Timeout,
+            OlianaError::Internal { .. } => oliana_lib::err::ErrorKind::Other,
+        }
+    }
+
+    /// True for errors where asking again (the same RPC for the same job, or a fresh job) can reasonably succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            OlianaError::WorkerCrashed { .. } | OlianaError::TimedOut { .. } | OlianaError::Internal { .. } => true,
+            OlianaError::UnknownJob { .. } | OlianaError::Cancelled { .. } | OlianaError::BadInput { .. } | OlianaError::BackendTraceback { .. } => false,
+        }
+    }
+}
+
+impl std::error::Error for OlianaError { }
+
+impl std::fmt::Display for OlianaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OlianaError::UnknownJob { job } => write!(f, "No job {} exists on this connection", job),
+            OlianaError::Cancelled { job } => write!(f, "Job {} was cancelled", job),
+            OlianaError::BadInput { msg } => write!(f, "Bad input: {}", msg),
+            OlianaError::WorkerCrashed { worker } => write!(f, "The {} worker is not running", worker),
+            OlianaError::TimedOut { worker, waited_ms } => write!(f, "Timed out after {} waiting on {}", oliana_lib::misc::duration_to_display_str(&std::time::Duration::from_millis(*waited_ms)), worker),
+            OlianaError::BackendTraceback { worker, traceback, .. } => write!(f, "{} reported an error:\n{}", worker, traceback),
+            OlianaError::Internal { msg, location, .. } => write!(f, "{} from {}", msg, location),
+        }
+    }
+}
+
+impl From<oliana_lib::err::LocatedError> for OlianaError {
+    fn from(e: oliana_lib::err::LocatedError) -> Self {
+        let msg = if e.addtl_msg.len() > 0 { format!("{} ({})", e.inner, e.addtl_msg) } else { format!("{}", e.inner) };
+        OlianaError::Internal {
+            msg: msg,
+            location: format!("{}:{}:{}", e.file, e.line, e.column),
+            report: Some(Box::new(e.report())),
+        }
+    }
+}
+
+/// Upper bound on worker_log_tail()'s num_lines, so one reply stays a reasonable size
+pub const MAX_LOG_TAIL_LINES: u32 = 10_000;
+
+pub const TEXT_WORKER_BIN_NAME: &str = "oliana_text";
+pub const IMAGE_WORKER_BIN_NAME: &str = "oliana_images";
 
-    /// Runs an AI model and returns immediately; callers should wait on generate_image_get_result() to read a .png vector of bytes back
-    async fn generate_image_begin(prompt: String, negative_prompt: String, guidance_scale: f32, num_inference_steps: u32) -> String;
-    /// Waits until image has completed and returns result.
-    async fn generate_image_get_result() -> Vec<u8>;
+// Replies need this long to travel back before the client's deadline passes
+const DEADLINE_MARGIN: std::time::Duration = std::time::Duration::from_millis(500);
 
+// How long an RPC may wait on a backend: up to `max_wait`, but cut short so the reply still beats the request's deadline (10 seconds
+// for tarpc::context::current()). Waiting any longer would turn a retryable OlianaError::TimedOut into an RpcError::DeadlineExceeded.
+fn wait_within_deadline(ctx: &context::Context, max_wait: std::time::Duration) -> std::time::Duration {
+    let until_deadline = ctx.deadline.saturating_duration_since(std::time::Instant::now()).saturating_sub(DEADLINE_MARGIN);
+    std::cmp::min(max_wait, until_deadline)
+}
+
+// Job ids are unique for the life of the server process (not just per-connection) because every connection shares the same backends.
+static NEXT_JOB_NONCE: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+pub fn next_job_nonce() -> u64 {
+    NEXT_JOB_NONCE.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+}
+
+/// Returns a nonce which has no `<nonce>.json` under `workdir` yet
+pub async fn allocate_job_nonce(workdir: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    loop {
+        let nonce = next_job_nonce();
+        if !tokio::fs::try_exists( std::path::Path::new(workdir).join(format!("{nonce}.json")) ).await? {
+            return Ok(nonce);
+        }
+    }
+}
+
+/// Waits until one of `paths` is touched or `timeout` elapses, returning false on timeout.
+/// Without a subscription (ie a workdir backend built without a watcher) this degrades to a 100ms sleep so callers keep re-checking the filesystem.
+pub async fn wait_for_workdir_change(workdir_events: &mut Option<oliana_lib::watch::DirSubscription>, paths: &[&std::path::Path], timeout: std::time::Duration) -> bool {
+    match workdir_events {
+        Some(ref mut workdir_events) => workdir_events.wait_for_any(paths, timeout).await,
+        None => {
+            tokio::time::sleep( std::cmp::min(timeout, std::time::Duration::from_millis(100)) ).await;
+            false
+        }
+    }
+}
+
+/// Per-connection bookkeeping for one text job
+#[derive(Debug, Clone, Default)]
+pub struct TextJobState {
+    pub next_byte_i: usize, // Keeps track of how far into the job's output we have read for streaming purposes
+    pub cancelled: bool,
+    /// When the job was cancelled or its last token / error was returned; the state is dropped KEEP_FINISHED_JOBS_FOR after this
+    pub finished_at: Option<std::time::Instant>,
+}
+
+/// One in-progress frame of a diffusion run, returned by generate_image_next_preview()
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImagePreview {
+    /// 1-based index of the diffusion step this frame was taken after
+    pub step: u32,
+    pub num_steps: u32,
+    /// .png bytes of the preview; these are 1/8th the resolution of the final image
+    pub png_bytes: Vec<u8>,
+}
+
+/// The contents of `<id>.progress`, written by oliana_images after each diffusion step
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImageProgress {
+    pub step: u32,
+    pub num_steps: u32,
+}
+
+/// Per-connection bookkeeping for one image job
+#[derive(Debug, Clone, Default)]
+pub struct ImageJobState {
+    pub last_preview_step: u32, // Keeps track of the newest preview returned from generate_image_next_preview() so we only ever return newer frames
+    pub cancelled: bool,
+    /// When the job was cancelled or its image / error was returned; the state is dropped KEEP_FINISHED_JOBS_FOR after this
+    pub finished_at: Option<std::time::Instant>,
+}
+
+/// Returned by server_status()
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ServerStatus {
+    /// oliana_server's crate version
+    pub version: String,
+    pub workers: Vec<WorkerStatus>,
+    pub text_queue: QueueStatus,
+    pub image_queue: QueueStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WorkerStatus {
+    pub bin_name: String,
+    pub pid: Option<u32>,
+    pub running: bool,
+    /// The worker has finished loading its model and is picking up jobs (see oliana_lib::launchers::WORKER_READY_FILE_NAME)
+    pub model_loaded: bool,
+    /// Times the server had to re-spawn the worker after its first launch
+    pub restarts: u32,
+    /// Seconds since the running instance was spawned
+    pub uptime_secs: Option<u64>,
+    /// The worker kept crashing and the server stopped re-spawning it (see config::RestartConfig)
+    pub failed: bool,
+    /// Why the worker last exited, ie "exit code 1" or "killed by signal 9"
+    pub last_exit: Option<String>,
+    /// Seconds until a crashed worker is re-spawned
+    pub next_restart_in_secs: Option<u64>,
+    /// Seconds since the running worker last wrote a heartbeat (see oliana_lib::launchers::WORKER_HEARTBEAT_FILE_NAME)
+    pub heartbeat_age_secs: Option<u64>,
+    /// The job the worker said it was working on in its latest heartbeat; None while idle
+    pub busy_with_job: Option<String>,
+    /// The worker's PER_PROC_MEM_FRACT, if the server sets one
+    pub per_proc_mem_fract: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub struct QueueStatus {
+    /// Jobs waiting in the server for a worker slot
+    pub waiting: u32,
+    /// Jobs handed to the worker which have not finished
+    pub running: u32,
+    pub max_concurrent_jobs: u32,
+}
+
+impl QueueStatus {
+    pub fn of(queue: &Option<std::sync::Arc<queue::JobQueue>>) -> Self {
+        match queue {
+            Some(queue) => Self {
+                waiting: queue.waiting_len() as u32,
+                running: queue.running_len() as u32,
+                max_concurrent_jobs: queue.max_concurrent_jobs as u32,
+            },
+            None => Self { waiting: 0, running: 0, max_concurrent_jobs: 0 },
+        }
+    }
 }
 
 // This is the type that implements the generated World trait. It is the business logic
 // and is used to start the server.
 // There will be one OlianaServer client for each TCP connection; a dis-connect and re-connect will allocate a new OlianaServer.
 // Also for each message OlianaServer::clone() is called -_- necessitaging syncronization primitives
+// The jobs themselves are run by the shared text_backend / image_backend (see backend.rs); OlianaServer only tracks which jobs belong to this connection.
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct OlianaServer {
     pub client_socket: std::net::SocketAddr,
+    /// "<user>@<ip>"; jobs from different client keys take turns in the queues
+    pub client_key: String,
 
     #[serde(skip)]
     pub shareable_procs: Option<std::sync::Arc<std::sync::RwLock<oliana_lib::launchers::TrackedProcs>>>,
 
     #[serde(skip)]
-    pub ai_workdir_images: String,
+    pub text_backend: Option<std::sync::Arc<dyn backend::TextBackend>>,
     #[serde(skip)]
-    pub ai_workdir_text: String,
-
-    pub text_input_nonce: std::sync::Arc<std::sync::RwLock<usize>>,
-    pub generate_text_next_byte_i: std::sync::Arc<std::sync::RwLock<usize>>, // Keeps track of how far into the output .txt file we have read for streaming purposes
+    pub image_backend: Option<std::sync::Arc<dyn backend::ImageBackend>>,
 
-    pub image_input_nonce: std::sync::Arc<std::sync::RwLock<usize>>,
+    #[serde(skip)]
+    pub text_jobs: std::sync::Arc<std::sync::RwLock<std::collections::HashMap<TextJobId, TextJobState>>>,
+    #[serde(skip)]
+    pub image_jobs: std::sync::Arc<std::sync::RwLock<std::collections::HashMap<ImageJobId, ImageJobState>>>,
 }
 
 impl OlianaServer {
     pub fn new(client_socket: std::net::SocketAddr,
+               client_user: &str,
                shareable_procs: std::sync::Arc<std::sync::RwLock<oliana_lib::launchers::TrackedProcs>>,
-               ai_workdir_images: &str,
-               ai_workdir_text: &str
+               text_backend: std::sync::Arc<dyn backend::TextBackend>,
+               image_backend: std::sync::Arc<dyn backend::ImageBackend>,
         ) -> Self {
         Self {
             client_socket: client_socket,
+            client_key: format!("{}@{}", client_user, client_socket.ip()),
 
             shareable_procs: Some(shareable_procs),
-            ai_workdir_images: ai_workdir_images.to_string(),
-            ai_workdir_text: ai_workdir_text.to_string(),
 
-            text_input_nonce: std::sync::Arc::new(std::sync::RwLock::new( 0 )),
-            generate_text_next_byte_i: std::sync::Arc::new(std::sync::RwLock::new( 0 )),
+            text_backend: Some(text_backend),
+            image_backend: Some(image_backend),
 
-            image_input_nonce: std::sync::Arc::new(std::sync::RwLock::new( 0 )),
+            text_jobs: std::sync::Arc::new(std::sync::RwLock::new( std::collections::HashMap::new() )),
+            image_jobs: std::sync::Arc::new(std::sync::RwLock::new( std::collections::HashMap::new() )),
+        }
+    }
+
+    pub fn text_backend(&self) -> Result<&std::sync::Arc<dyn backend::TextBackend>, OlianaError> {
+        self.text_backend.as_ref().ok_or_else(|| OlianaError::Internal { msg: "No text backend is configured".to_string(), location: format!("{}:{}", file!(), line!()), report: None })
+    }
 
+    pub fn image_backend(&self) -> Result<&std::sync::Arc<dyn backend::ImageBackend>, OlianaError> {
+        self.image_backend.as_ref().ok_or_else(|| OlianaError::Internal { msg: "No image backend is configured".to_string(), location: format!("{}:{}", file!(), line!()), report: None })
+    }
+
+    /// Returns Err(OlianaError::Cancelled) if cancel_text() has been called for `job`
+    pub fn ensure_text_job_not_cancelled(&self, job: TextJobId) -> Result<(), OlianaError> {
+        if self.read_text_job_state(job).map(|s| s.cancelled).unwrap_or(false) {
+            return Err(OlianaError::Cancelled { job: job.0 });
         }
+        Ok(())
     }
 
-    pub fn read_text_input_nonce(&self) -> usize {
-        let mut ret_val: usize = 0;
-        match self.text_input_nonce.read() {
-            Ok(text_input_nonce_rg) => {
-                ret_val = *text_input_nonce_rg;
+    /// Returns None if `job` was never begun on this connection
+    pub fn read_text_job_state(&self, job: TextJobId) -> Option<TextJobState> {
+        match self.text_jobs.read() {
+            Ok(text_jobs_rg) => {
+                return text_jobs_rg.get(&job).cloned();
             }
             Err(e) => {
                 eprintln!("{}:{} {:?}", file!(), line!(), e);
             }
         }
-        ret_val
+        None
     }
 
-    pub async fn increment_to_next_free_text_input_nonce(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
-        while tokio::fs::try_exists( self.get_current_text_input_json_path() ).await? {
-            if let Ok(ref mut text_input_nonce_wg) = self.text_input_nonce.write() {
-                **text_input_nonce_wg += 1;
+    /// Also forgets jobs which finished more than KEEP_FINISHED_JOBS_FOR ago, so a long-lived connection does not accumulate them
+    pub fn write_text_job_state(&self, job: TextJobId, state: TextJobState) {
+        match self.text_jobs.write() {
+            Ok(mut text_jobs_wg) => {
+                text_jobs_wg.retain(|_, job_state| job_state.finished_at.map(|t| t.elapsed() < in_process_backend::KEEP_FINISHED_JOBS_FOR).unwrap_or(true));
+                text_jobs_wg.insert(job, state);
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
             }
         }
-        Ok(self.read_text_input_nonce())
-    }
-    pub fn get_current_text_input_json_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_text).join(format!("{}.json", self.read_text_input_nonce()))
-    }
-    pub fn get_current_text_output_txt_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_text).join(format!("{}.txt", self.read_text_input_nonce()))
-    }
-    pub fn get_current_text_output_done_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_text).join(format!("{}.done", self.read_text_input_nonce()))
     }
 
-    pub fn read_generate_text_next_byte_i(&self) -> usize {
-        let mut ret_val: usize = 0;
-        match self.generate_text_next_byte_i.read() {
-            Ok(generate_text_next_byte_i_rg) => {
-                ret_val = *generate_text_next_byte_i_rg;
+    /// Starts the KEEP_FINISHED_JOBS_FOR countdown of `job`, unless it already has
+    pub fn mark_text_job_finished(&self, job: TextJobId) {
+        match self.text_jobs.write() {
+            Ok(mut text_jobs_wg) => {
+                if let Some(job_state) = text_jobs_wg.get_mut(&job) {
+                    job_state.finished_at.get_or_insert_with(std::time::Instant::now);
+                }
             }
             Err(e) => {
                 eprintln!("{}:{} {:?}", file!(), line!(), e);
             }
         }
-        ret_val
     }
 
+    /// Returns Err(OlianaError::Cancelled) if cancel_image() has been called for `job`
+    pub fn ensure_image_job_not_cancelled(&self, job: ImageJobId) -> Result<(), OlianaError> {
+        if self.read_image_job_state(job).map(|s| s.cancelled).unwrap_or(false) {
+            return Err(OlianaError::Cancelled { job: job.0 });
+        }
+        Ok(())
+    }
 
-    pub fn read_image_input_nonce(&self) -> usize {
-        let mut ret_val: usize = 0;
-        match self.image_input_nonce.read() {
-            Ok(image_input_nonce_rg) => {
-                ret_val = *image_input_nonce_rg;
+    /// Returns None if `job` was never begun on this connection
+    pub fn read_image_job_state(&self, job: ImageJobId) -> Option<ImageJobState> {
+        match self.image_jobs.read() {
+            Ok(image_jobs_rg) => {
+                return image_jobs_rg.get(&job).cloned();
             }
             Err(e) => {
                 eprintln!("{}:{} {:?}", file!(), line!(), e);
             }
         }
-        ret_val
+        None
     }
 
-    pub async fn increment_to_next_free_image_input_nonce(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
-        while tokio::fs::try_exists( self.get_current_image_input_json_path() ).await? {
-            if let Ok(ref mut image_input_nonce_wg) = self.image_input_nonce.write() {
-                **image_input_nonce_wg += 1;
+    /// Also forgets jobs which finished more than KEEP_FINISHED_JOBS_FOR ago, so a long-lived connection does not accumulate them
+    pub fn write_image_job_state(&self, job: ImageJobId, state: ImageJobState) {
+        match self.image_jobs.write() {
+            Ok(mut image_jobs_wg) => {
+                image_jobs_wg.retain(|_, job_state| job_state.finished_at.map(|t| t.elapsed() < in_process_backend::KEEP_FINISHED_JOBS_FOR).unwrap_or(true));
+                image_jobs_wg.insert(job, state);
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
             }
         }
-        Ok(self.read_image_input_nonce())
-    }
-    pub fn get_current_image_input_json_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_images).join(format!("{}.json", self.read_image_input_nonce()))
-    }
-    pub fn get_current_image_output_png_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_images).join(format!("{}.png", self.read_image_input_nonce()))
-    }
-    pub fn get_current_image_output_txt_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_images).join(format!("{}.txt", self.read_image_input_nonce()))
     }
 
+    /// Starts the KEEP_FINISHED_JOBS_FOR countdown of `job`, unless it already has
+    pub fn mark_image_job_finished(&self, job: ImageJobId) {
+        match self.image_jobs.write() {
+            Ok(mut image_jobs_wg) => {
+                if let Some(job_state) = image_jobs_wg.get_mut(&job) {
+                    job_state.finished_at.get_or_insert_with(std::time::Instant::now);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+            }
+        }
+    }
 
 }
 
 // These methods are run in the context of the client connection, on the server.
 impl Oliana for OlianaServer {
-    async fn generate_text_begin(mut self, _: context::Context, system_prompt: String, user_prompt: String) -> String {
-
-        if let Ok(ref mut generate_text_next_byte_i_wg) = self.generate_text_next_byte_i.write() {
-            **generate_text_next_byte_i_wg = 0;
-        }
-
-        if let Err(e) = self.increment_to_next_free_text_input_nonce().await {
-            eprintln!("[ increment_to_next_free_text_input_nonce ] {:?}", e);
-            return format!("[ increment_to_next_free_text_input_nonce ] {:?}", e);
+    async fn generate_text_begin(mut self, _: context::Context, system_prompt: String, user_prompt: String, priority: JobPriority) -> Result<TextJobId, OlianaError> {
+        if user_prompt.trim().len() < 1 {
+            return Err(OlianaError::BadInput { msg: "user_prompt must not be empty".to_string() });
         }
+        let job = TextJobId( self.text_backend()?.begin(backend::TextRequest {
+            system_prompt: system_prompt,
+            user_prompt: user_prompt,
+            priority: priority,
+            client_key: self.client_key.clone(),
+        }).await? );
 
-        let input_data = serde_json::json!({
-            "system_prompt": system_prompt,
-            "user_prompt": user_prompt
-        });
-        let input_data_s = input_data.to_string();
+        self.write_text_job_state(job, TextJobState::default());
 
-        let current_text_input_json = self.get_current_text_input_json_path();
+        Ok(job)
+    }
 
-        let response_txt_file = self.get_current_text_output_txt_path();
-        if response_txt_file.exists() {
-            if let Err(e) = tokio::fs::remove_file(response_txt_file).await {
-                eprintln!("[ tokio::fs::remove_file ] {:?}", e);
-                return format!("[ tokio::fs::remove_file ] {:?}", e);
+    async fn generate_text_next_token(mut self, ctx: context::Context, job: TextJobId) -> Result<Option<String>, OlianaError> {
+        let mut job_state = self.read_text_job_state(job).ok_or(OlianaError::UnknownJob { job: job.0 })?;
+        self.ensure_text_job_not_cancelled(job)?;
+
+        let read = self.text_backend()?.read(job.0, job_state.next_byte_i, wait_within_deadline(&ctx, std::time::Duration::from_secs(12))).await;
+        self.ensure_text_job_not_cancelled(job)?; // A concurrent cancel_text() wins over whatever the backend returned
+        match read {
+            Ok(backend::TextRead::Text { text, next_byte_i }) => {
+                job_state.next_byte_i = next_byte_i;
+                self.write_text_job_state(job, job_state);
+                Ok(Some(text))
+            }
+            Ok(backend::TextRead::Finished) => {
+                self.mark_text_job_finished(job);
+                Ok(None)
+            }
+            Err(e) => {
+                if !e.is_retryable() {
+                    self.mark_text_job_finished(job);
+                }
+                Err(e)
             }
         }
+    }
 
-        if let Err(e) = tokio::fs::write(current_text_input_json, input_data_s.as_bytes()).await {
-            eprintln!("[ tokio::fs::write ] {:?}", e);
-            return format!("[ tokio::fs::write ] {:?}", e);
+    async fn generate_text_status(self, _: context::Context, job: TextJobId) -> Result<JobStatus, OlianaError> {
+        match self.read_text_job_state(job) {
+            None => return Ok(JobStatus::Unknown),
+            Some(job_state) if job_state.cancelled => return Ok(JobStatus::Cancelled),
+            Some(_) => { }
         }
-
-        String::new()
+        self.text_backend()?.status(job.0).await
     }
 
-    async fn generate_text_next_token(mut self, _: context::Context) -> Option<String> {
-        // Right now we just wait for get_current_text_output_txt_path() to be created + return one giant chunk, but eventually Oliana-Text should iteratively update the file
-        // so we can poll & return a streamed response.
-        let response_txt_file = self.get_current_text_output_txt_path();
-        while ! response_txt_file.exists() {
-            tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
+    async fn cancel_text(self, _: context::Context, job: TextJobId) -> Result<(), OlianaError> {
+        let mut job_state = self.read_text_job_state(job).ok_or(OlianaError::UnknownJob { job: job.0 })?;
+        if job_state.cancelled {
+            return Ok(()); // Nothing left to stop
         }
-
-        let response_done_file = self.get_current_text_output_done_path();
-
-        // Wait until the file's size is > self.read_generate_text_next_byte_i()
-        let mut remaining_polls_before_give_up: usize = 12 * 10; // 12 seconds worth at 10 polls/sec
-        loop {
-            let next_byte_i = self.read_generate_text_next_byte_i();
-            if let Ok(file_bytes) = tokio::fs::read(&response_txt_file).await {
-                if file_bytes.len() < next_byte_i {
-                    return None; // Somehow the file was truncated! .len() should always grow; it is allowed to be == next_byte_i.
-                }
-                if let Ok(the_string) = std::str::from_utf8(&file_bytes[next_byte_i..]) {
-
-                    // Update the index we know we have read to to file_bytes.len()
-                    match self.generate_text_next_byte_i.write() {
-                        Ok(mut generate_text_next_byte_i_wg) => {
-                            *generate_text_next_byte_i_wg = file_bytes.len();
-                        }
-                        Err(e) => {
-                            eprintln!("{}:{} {:?}", file!(), line!(), e);
-                        }
-                    }
-
-                    // It's possible to read 0 new bytes, in which case we do NOT want to return empty string; instead we fall down to the `response_done_file.exists() || remaining_polls_before_give_up < 1` check below.
-                    if the_string.len() > 0 {
-                        return Some(the_string.to_string());
-                    }
-                }
-            }
-            if response_done_file.exists() || remaining_polls_before_give_up < 1 { // What we just read must be the remaining bytes, because .done is created AFTER a write to .txt
-                break;
-            }
-            tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
-            remaining_polls_before_give_up -= 1;
+        if self.text_backend()?.cancel(job.0).await? {
+            job_state.cancelled = true;
+            job_state.finished_at = Some(std::time::Instant::now());
+            self.write_text_job_state(job, job_state);
         }
-        return None;
+        Ok(())
     }
 
-    async fn generate_image_begin(mut self, _: tarpc::context::Context, prompt: String, negative_prompt: String, guidance_scale: f32, num_inference_steps: u32) -> std::string::String {
-        if let Err(e) = self.increment_to_next_free_image_input_nonce().await {
-            eprintln!("[ increment_to_next_free_image_input_nonce ] {:?}", e);
-            return format!("[ increment_to_next_free_image_input_nonce ] {:?}", e);
+    async fn generate_image_begin(mut self, _: tarpc::context::Context, prompt: String, negative_prompt: String, guidance_scale: f32, num_inference_steps: u32, priority: JobPriority) -> Result<ImageJobId, OlianaError> {
+        if prompt.trim().len() < 1 {
+            return Err(OlianaError::BadInput { msg: "prompt must not be empty".to_string() });
         }
-
-        let input_data = serde_json::json!({
-            "prompt": prompt,
-            "negative_prompt": negative_prompt,
-            "guidance_scale": guidance_scale,
-            "num_inference_steps": num_inference_steps,
-        });
-        let input_data_s = input_data.to_string();
-
-        let current_text_input_json = self.get_current_image_input_json_path();
-
-        let response_txt_file = self.get_current_image_output_txt_path();
-        if response_txt_file.exists() {
-            if let Err(e) = tokio::fs::remove_file(response_txt_file).await {
-                eprintln!("[ tokio::fs::remove_file ] {:?}", e);
-                return format!("[ tokio::fs::remove_file ] {:?}", e);
-            }
+        if num_inference_steps < 1 {
+            return Err(OlianaError::BadInput { msg: "num_inference_steps must be at least 1".to_string() });
         }
-
-        let response_png_file = self.get_current_image_output_png_path();
-        if response_png_file.exists() {
-            if let Err(e) = tokio::fs::remove_file(response_png_file).await {
-                eprintln!("[ tokio::fs::remove_file ] {:?}", e);
-                return format!("[ tokio::fs::remove_file ] {:?}", e);
-            }
+        if !guidance_scale.is_finite() {
+            return Err(OlianaError::BadInput { msg: format!("guidance_scale must be a finite number, got {}", guidance_scale) });
         }
+        let job = ImageJobId( self.image_backend()?.begin(backend::ImageRequest {
+            prompt: prompt,
+            negative_prompt: negative_prompt,
+            guidance_scale: guidance_scale,
+            num_inference_steps: num_inference_steps,
+            priority: priority,
+            client_key: self.client_key.clone(),
+        }).await? );
+
+        self.write_image_job_state(job, ImageJobState::default());
+
+        Ok(job)
+    }
 
-        if let Err(e) = tokio::fs::write(current_text_input_json, input_data_s.as_bytes()).await {
-            eprintln!("[ tokio::fs::write ] {:?}", e);
-            return format!("[ tokio::fs::write ] {:?}", e);
-        }
+    async fn generate_image_next_preview(self, ctx: tarpc::context::Context, job: ImageJobId) -> Result<Option<ImagePreview>, OlianaError> {
+        let mut job_state = self.read_image_job_state(job).ok_or(OlianaError::UnknownJob { job: job.0 })?;
+        self.ensure_image_job_not_cancelled(job)?;
 
-        String::new()
+        let preview = self.image_backend()?.next_preview(job.0, job_state.last_preview_step, wait_within_deadline(&ctx, std::time::Duration::from_secs(24))).await;
+        self.ensure_image_job_not_cancelled(job)?;
+        let preview = preview?;
+        if let Some(ref preview) = preview {
+            job_state.last_preview_step = preview.step;
+            self.write_image_job_state(job, job_state);
+        }
+        Ok(preview)
     }
 
-    async fn generate_image_get_result(self, _: tarpc::context::Context) -> Vec<u8> {
-        let mut result_bytes: Vec<u8> = Vec::with_capacity(1024 * 1024);
-
-        let response_txt_file = self.get_current_image_output_txt_path();
-        let response_png_file = self.get_current_image_output_png_path();
+    async fn generate_image_get_result(self, ctx: tarpc::context::Context, job: ImageJobId) -> Result<Vec<u8>, OlianaError> {
+        if self.read_image_job_state(job).is_none() {
+            return Err(OlianaError::UnknownJob { job: job.0 });
+        }
+        self.ensure_image_job_not_cancelled(job)?;
 
-        let mut remaining_polls_before_give_up: usize = 24 * 10; // 24 seconds worth at 10 polls/sec
-        while !response_txt_file.exists() && !response_png_file.exists() && remaining_polls_before_give_up > 1 {
-            tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
-            remaining_polls_before_give_up -= 1;
+        let result = self.image_backend()?.get_result(job.0, wait_within_deadline(&ctx, std::time::Duration::from_secs(24))).await;
+        self.ensure_image_job_not_cancelled(job)?;
+        match &result {
+            Err(e) if e.is_retryable() => { } // The client will ask again
+            _ => self.mark_image_job_finished(job),
         }
+        result
+    }
 
-        if response_png_file.exists() {
-            // Just because it _exists_ doesn't mean we're done writing to it. Give the OS a tick to flush writes and continue when 100ms elapses w/ identical length values for the file
-            remaining_polls_before_give_up = 4 * 10; // 4 seconds at 10 polls/sec
-            let mut last_file_len: u64 = 0;
-            while remaining_polls_before_give_up > 1 {
-                tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
-                let mut this_file_len: u64 = 1;
-                if let Ok(mut metadata) = tokio::fs::metadata(&response_png_file).await {
-                    this_file_len = metadata.len();
-                }
-                if this_file_len == last_file_len {
-                    break; // Success!
-                }
-                last_file_len = this_file_len;
-                remaining_polls_before_give_up -= 1;
-            }
-            tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
+    async fn generate_image_status(self, _: tarpc::context::Context, job: ImageJobId) -> Result<JobStatus, OlianaError> {
+        match self.read_image_job_state(job) {
+            None => return Ok(JobStatus::Unknown),
+            Some(job_state) if job_state.cancelled => return Ok(JobStatus::Cancelled),
+            Some(_) => { }
+        }
+        self.image_backend()?.status(job.0).await
+    }
 
-            if let Ok(mut fd) = tokio::fs::File::open(&response_png_file).await {
-                if let Err(e) = fd.read_to_end(&mut result_bytes).await {
-                    eprintln!("{}:{} {:?}", file!(), line!(), e);
-                }
-            }
+    async fn cancel_image(self, _: tarpc::context::Context, job: ImageJobId) -> Result<(), OlianaError> {
+        let mut job_state = self.read_image_job_state(job).ok_or(OlianaError::UnknownJob { job: job.0 })?;
+        if job_state.cancelled {
+            return Ok(()); // Nothing left to stop
         }
+        if self.image_backend()?.cancel(job.0).await? {
+            job_state.cancelled = true;
+            job_state.finished_at = Some(std::time::Instant::now());
+            self.write_image_job_state(job, job_state);
+        }
+        Ok(())
+    }
 
-        if response_txt_file.exists() {
-            let response_err_msg = std::fs::read_to_string(&response_txt_file).unwrap_or_else(|_| String::new());
-            eprintln!("Got error from Oliana-Images: {:?}", response_err_msg);
+    async fn server_status(self, _: tarpc::context::Context) -> Result<ServerStatus, OlianaError> {
+        let mut workers = vec![];
+        if let Some(ref shareable_procs) = self.shareable_procs {
+            // status() scans the host's processes once per worker under the write lock, so it runs on a blocking thread.
+            // A poisoned lock just means we report no workers.
+            let shareable_procs = shareable_procs.clone();
+            let proc_statuses = tokio::task::spawn_blocking(move || {
+                shareable_procs.write().map(|mut procs_wg| procs_wg.status()).unwrap_or_default()
+            }).await.unwrap_or_default();
+            for proc_status in proc_statuses {
+                workers.push(WorkerStatus {
+                    per_proc_mem_fract: proc_status.env.iter().find(|(key, _)| key == "PER_PROC_MEM_FRACT").map(|(_, val)| val.clone()),
+                    bin_name: proc_status.bin_name,
+                    pid: proc_status.pid,
+                    running: proc_status.running,
+                    model_loaded: proc_status.model_loaded,
+                    restarts: proc_status.restarts,
+                    uptime_secs: proc_status.uptime.map(|uptime| uptime.as_secs()),
+                    failed: proc_status.failed,
+                    last_exit: proc_status.last_exit.map(|last_exit| last_exit.to_string()),
+                    next_restart_in_secs: proc_status.next_restart_in.map(|next_restart_in| next_restart_in.as_secs()),
+                    heartbeat_age_secs: proc_status.heartbeat.as_ref().map(|heartbeat| heartbeat.age().as_secs()),
+                    busy_with_job: proc_status.heartbeat.and_then(|heartbeat| match heartbeat.activity {
+                        oliana_lib::launchers::WorkerActivity::Busy => heartbeat.job,
+                        oliana_lib::launchers::WorkerActivity::Idle => None,
+                    }),
+                });
+            }
         }
+        // An unreachable remote backend should not hide the state of everything else
+        let text_queue = match self.text_backend() {
+            Ok(text_backend) => text_backend.queue_status().await.unwrap_or_default(),
+            Err(_) => QueueStatus::default(),
+        };
+        let image_queue = match self.image_backend() {
+            Ok(image_backend) => image_backend.queue_status().await.unwrap_or_default(),
+            Err(_) => QueueStatus::default(),
+        };
+        Ok(ServerStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            workers: workers,
+            text_queue: text_queue,
+            image_queue: image_queue,
+        })
+    }
 
-        return result_bytes;
+    async fn worker_log_tail(self, _: tarpc::context::Context, bin_name: String, num_lines: u32) -> Result<Vec<String>, OlianaError> {
+        let shareable_procs = self.shareable_procs.clone().ok_or_else(|| OlianaError::BadInput { msg: "This server does not run any workers".to_string() })?;
+        // The lock waits behind the respawn poll and shutdown, and the log is read synchronously, so both happen on a blocking thread
+        tokio::task::spawn_blocking(move || {
+            let procs = shareable_procs.read().map_err(|e| OlianaError::Internal { msg: format!("{}", e), location: format!("{}:{}", file!(), line!()), report: None })?;
+            if !procs.is_registered(&bin_name) {
+                return Err(OlianaError::BadInput { msg: format!("No worker named {:?}", bin_name) });
+            }
+            let lines = procs.log_tail(&bin_name, std::cmp::min(num_lines, MAX_LOG_TAIL_LINES) as usize).map_err(oliana_lib::eloc!())?;
+            Ok(lines)
+        }).await.map_err(oliana_lib::eloc!())?
     }
 }
-
-
-
-
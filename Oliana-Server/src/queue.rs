@@ -0,0 +1,293 @@
+
+// Workers process whatever `<id>.json` files exist in their work directory, so the order (and number) of files we write
+// there is the order jobs run in. JobQueue holds jobs in the server until a worker slot frees up, then writes their .json.
+//
+// Ordering rules, applied every time a slot frees:
+//   1. Higher JobPriority lanes always go first (Interactive, then Normal, then Background)
+//   2. Inside a lane, clients take turns round-robin, so one client queueing 50 jobs cannot starve another who queued 1
+//   3. Each client's own jobs run in the order they were begun
+//
+// A job stops occupying a slot once the worker writes `<id>.done` (both oliana_text and oliana_images write one whatever
+// the outcome), or after max_job_runtime as a safety net for workers which died mid-job.
+// Queues without a workdir (see JobQueue::new_in_process) hand jobs to a callback instead and are told about finished jobs through JobQueue::finish.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, serde::Serialize, serde::Deserialize, clap::ValueEnum)]
+pub enum JobPriority {
+    /// Someone is waiting on the result right now (ie narration for the current scene)
+    Interactive,
+    #[default]
+    Normal,
+    /// Nice-to-have work such as prefetching images for scenes the player may visit
+    Background,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuedJob {
+    pub nonce: u64,
+    /// Jobs sharing a client key take turns with other keys; see OlianaServer::client_key
+    pub client_key: String,
+    pub priority: JobPriority,
+    /// Written to `<workdir>/<nonce>.json` when the job is dispatched
+    pub input_json: String,
+}
+
+// Jobs of one priority, grouped per client
+#[derive(Debug, Clone, Default)]
+struct QueueLane {
+    client_turns: std::collections::VecDeque<String>,
+    client_jobs: std::collections::HashMap<String, std::collections::VecDeque<QueuedJob>>,
+}
+
+impl QueueLane {
+    fn push(&mut self, job: QueuedJob) {
+        let jobs = self.client_jobs.entry(job.client_key.clone()).or_default();
+        if jobs.len() < 1 {
+            self.client_turns.push_back(job.client_key.clone());
+        }
+        jobs.push_back(job);
+    }
+
+    fn pop(&mut self) -> Option<QueuedJob> {
+        while let Some(client_key) = self.client_turns.pop_front() {
+            if let Some(jobs) = self.client_jobs.get_mut(&client_key) {
+                if let Some(job) = jobs.pop_front() {
+                    if jobs.len() > 0 {
+                        self.client_turns.push_back(client_key); // Back of the line until every other client has had a turn
+                    }
+                    else {
+                        self.client_jobs.remove(&client_key);
+                    }
+                    return Some(job);
+                }
+                self.client_jobs.remove(&client_key);
+            }
+        }
+        None
+    }
+
+    fn remove(&mut self, nonce: u64) -> bool {
+        for (client_key, jobs) in self.client_jobs.iter_mut() {
+            if let Some(job_i) = jobs.iter().position(|j| j.nonce == nonce) {
+                jobs.remove(job_i);
+                if jobs.len() < 1 {
+                    let client_key = client_key.clone();
+                    self.client_jobs.remove(&client_key);
+                    self.client_turns.retain(|k| *k != client_key);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    fn len(&self) -> usize {
+        self.client_jobs.values().map(|jobs| jobs.len()).sum()
+    }
+}
+
+#[derive(Debug, Default)]
+struct JobQueueState {
+    lanes: std::collections::BTreeMap<JobPriority, QueueLane>,
+    running: std::collections::HashMap<u64, std::time::Instant>, // nonce -> when its .json was written
+}
+
+impl JobQueueState {
+    fn pop(&mut self) -> Option<QueuedJob> {
+        for lane in self.lanes.values_mut() {
+            if let Some(job) = lane.pop() {
+                return Some(job);
+            }
+        }
+        None
+    }
+}
+
+pub struct JobQueue {
+    /// The worker jobs are dispatched to (ie oliana_text); used in log lines
+    pub worker: String,
+    /// Where `<nonce>.json` is written for the worker; None when jobs go to a spawn_callback_dispatcher callback instead
+    pub workdir: Option<std::path::PathBuf>,
+    pub max_concurrent_jobs: usize,
+    pub max_job_runtime: std::time::Duration,
+    state: std::sync::Mutex<JobQueueState>,
+    changed: tokio::sync::Notify,
+}
+
+impl JobQueue {
+    pub fn new(worker: &str, workdir: impl Into<std::path::PathBuf>, max_concurrent_jobs: usize, max_job_runtime: std::time::Duration) -> Self {
+        Self {
+            worker: worker.to_string(),
+            workdir: Some(workdir.into()),
+            max_concurrent_jobs: std::cmp::max(1, max_concurrent_jobs),
+            max_job_runtime: max_job_runtime,
+            state: std::sync::Mutex::new(JobQueueState::default()),
+            changed: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// A queue for jobs run inside the server process; use spawn_callback_dispatcher() and call finish() as jobs complete.
+    pub fn new_in_process(worker: &str, max_concurrent_jobs: usize, max_job_runtime: std::time::Duration) -> Self {
+        Self {
+            workdir: None,
+            ..Self::new(worker, std::path::PathBuf::new(), max_concurrent_jobs, max_job_runtime)
+        }
+    }
+
+    pub fn enqueue(&self, job: QueuedJob) {
+        match self.state.lock() {
+            Ok(mut state) => {
+                state.lanes.entry(job.priority).or_default().push(job);
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+            }
+        }
+        self.changed.notify_one();
+    }
+
+    /// Drops `nonce` if it has not been dispatched yet, returning true if it was removed.
+    pub fn remove_waiting(&self, nonce: u64) -> bool {
+        match self.state.lock() {
+            Ok(mut state) => {
+                for lane in state.lanes.values_mut() {
+                    if lane.remove(nonce) {
+                        return true;
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+            }
+        }
+        false
+    }
+
+    /// Returns Some(1) if `nonce` is the next job to be dispatched, Some(2) if it is after that, and so on.
+    /// None means the job is not waiting (it was dispatched, cancelled, or never queued).
+    pub fn position(&self, nonce: u64) -> Option<u32> {
+        let mut lanes = match self.state.lock() {
+            Ok(state) => state.lanes.clone(),
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                return None;
+            }
+        };
+        // Replay dispatch order on a copy; queues are short enough that this beats keeping a second index in sync
+        let mut position = 1;
+        for lane in lanes.values_mut() {
+            while let Some(job) = lane.pop() {
+                if job.nonce == nonce {
+                    return Some(position);
+                }
+                position += 1;
+            }
+        }
+        None
+    }
+
+    /// Number of jobs waiting for a slot
+    pub fn waiting_len(&self) -> usize {
+        self.state.lock().map(|state| state.lanes.values().map(|lane| lane.len()).sum()).unwrap_or(0)
+    }
+
+    /// Number of jobs handed to the worker which have not finished yet
+    pub fn running_len(&self) -> usize {
+        self.state.lock().map(|state| state.running.len()).unwrap_or(0)
+    }
+
+    /// Frees the slot of a dispatched job; workdir queues notice `<nonce>.done` on their own and do not need this.
+    pub fn finish(&self, nonce: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.running.remove(&nonce);
+        }
+        self.changed.notify_one();
+    }
+
+    // Frees the slots of jobs the worker has finished (or which ran past max_job_runtime)
+    fn reap_finished(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            let workdir = &self.workdir;
+            let max_job_runtime = self.max_job_runtime;
+            let worker = &self.worker;
+            state.running.retain(|nonce, dispatched_at| {
+                if let Some(workdir) = workdir {
+                    if workdir.join(format!("{}.done", nonce)).exists() {
+                        return false;
+                    }
+                }
+                if dispatched_at.elapsed() > max_job_runtime {
+                    eprintln!("{} has run job {} for over {}, freeing its slot", worker, nonce, oliana_lib::misc::duration_to_display_str(&max_job_runtime));
+                    return false;
+                }
+                true
+            });
+        }
+    }
+
+    // Moves the next job from waiting to running if a slot is free
+    fn take_next_if_slot_free(&self) -> Option<QueuedJob> {
+        let mut state = self.state.lock().ok()?;
+        if state.running.len() >= self.max_concurrent_jobs {
+            return None;
+        }
+        let job = state.pop()?;
+        state.running.insert(job.nonce, std::time::Instant::now());
+        Some(job)
+    }
+
+    /// Runs forever, handing jobs to the worker as slots free up. `workdir_watcher` should watch self.workdir so finished jobs are noticed immediately.
+    pub fn spawn_dispatcher(self: std::sync::Arc<Self>, workdir_watcher: Option<std::sync::Arc<oliana_lib::watch::DirWatcher>>) -> tokio::task::JoinHandle<()> {
+        let queue = self;
+        tokio::task::spawn(async move {
+            let workdir = match queue.workdir.clone() {
+                Some(workdir) => workdir,
+                None => {
+                    eprintln!("{}:{} The {} queue has no workdir, use spawn_callback_dispatcher() instead", file!(), line!(), queue.worker);
+                    return;
+                }
+            };
+            let mut workdir_events = workdir_watcher.map(|w| w.subscribe());
+            loop {
+                queue.reap_finished();
+                while let Some(job) = queue.take_next_if_slot_free() {
+                    let input_json_path = workdir.join(format!("{}.json", job.nonce));
+                    if let Err(e) = tokio::fs::write(&input_json_path, job.input_json.as_bytes()).await {
+                        // Nothing will ever write .done for this job, so give the slot straight back; the client's RPCs will time out
+                        eprintln!("{}:{} Cannot dispatch {}: {:?}", file!(), line!(), input_json_path.display(), e);
+                        if let Ok(mut state) = queue.state.lock() {
+                            state.running.remove(&job.nonce);
+                        }
+                    }
+                }
+                // Wake on new jobs, on a worker finishing, or every 5 seconds in case an event was lost
+                let wait_for_done = async {
+                    match workdir_events {
+                        Some(ref mut workdir_events) => { workdir_events.wait_for_extension("done", std::time::Duration::from_secs(5)).await; }
+                        None => { tokio::time::sleep(std::time::Duration::from_millis(100)).await; }
+                    }
+                };
+                tokio::select! {
+                    _ = queue.changed.notified() => { }
+                    _ = wait_for_done => { }
+                }
+            }
+        })
+    }
+
+    /// Runs forever, calling `dispatch` with each job as a slot frees up. `dispatch` must not block; whoever runs the job calls finish() once it is over.
+    pub fn spawn_callback_dispatcher<F>(self: std::sync::Arc<Self>, dispatch: F) -> tokio::task::JoinHandle<()>
+        where F: Fn(QueuedJob) + Send + 'static
+    {
+        let queue = self;
+        tokio::task::spawn(async move {
+            loop {
+                queue.reap_finished();
+                while let Some(job) = queue.take_next_if_slot_free() {
+                    dispatch(job);
+                }
+                // finish() and enqueue() both notify; the timeout only matters for reaping jobs past max_job_runtime
+                let _ = tokio::time::timeout(std::time::Duration::from_secs(5), queue.changed.notified()).await;
+            }
+        })
+    }
+}
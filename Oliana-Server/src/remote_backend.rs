@@ -0,0 +1,294 @@
+
+// Forwards jobs to another oliana_server (ie a machine with a bigger GPU) over the same RPCs oliana_client uses.
+// The upstream server runs its own queues, so every client of this server shares one place in the upstream's
+// per-client rotation; priorities are passed through unchanged.
+//
+// The connection is opened on first use and re-opened after any transport error. Jobs begun on a connection which
+// was lost are unknown to the upstream after the re-connect, so their RPCs fail with OlianaError::UnknownJob.
+//
+// RPCs which wait on a job are sent with a deadline `wait` from now, which the upstream shortens its own wait to fit.
+// Jobs nobody has asked about for FORGET_JOBS_AFTER are dropped along with the text kept for them.
+
+use crate::backend::{BackendFuture, ImageBackend, ImageRequest, TextBackend, TextRead, TextRequest};
+use crate::{ImageJobId, ImagePreview, JobStatus, OlianaError, QueueStatus, TextJobId};
+
+/// By then a job has finished and been collected, or its client has gone away
+pub const FORGET_JOBS_AFTER: std::time::Duration = crate::in_process_backend::KEEP_FINISHED_JOBS_FOR;
+
+pub struct RemoteBackend {
+    pub server_url: String,
+    transport_config: crate::transport::ClientTransportConfig,
+    client: std::sync::Mutex<Option<crate::OlianaClient>>,
+    text_jobs: std::sync::Mutex<std::collections::HashMap<u64, RemoteTextJob>>,
+    image_jobs: std::sync::Mutex<std::collections::HashMap<u64, RemoteImageJob>>,
+}
+
+#[derive(Clone)]
+struct RemoteTextJob {
+    remote_job: TextJobId,
+    // The upstream hands out each token once, so they are kept here to answer read()s from any byte offset
+    output: std::sync::Arc<tokio::sync::Mutex<RemoteTextOutput>>,
+    last_used: std::time::Instant,
+}
+
+struct RemoteImageJob {
+    remote_job: ImageJobId,
+    last_used: std::time::Instant,
+}
+
+struct RemoteTextOutput {
+    text: String,
+    finished: bool,
+}
+
+impl RemoteBackend {
+    pub fn new(config: &crate::config::RemoteBackendConfig) -> Self {
+        Self {
+            server_url: config.server_url.clone(),
+            transport_config: crate::transport::ClientTransportConfig {
+                tls: config.tls || config.tls_ca_cert.is_some(),
+                tls_ca_cert: config.tls_ca_cert.clone(),
+                tls_server_name: config.tls_server_name.clone(),
+                token: config.token.clone(),
+            },
+            client: std::sync::Mutex::new(None),
+            text_jobs: std::sync::Mutex::new(std::collections::HashMap::new()),
+            image_jobs: std::sync::Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+
+    pub fn upstream_name(&self) -> String {
+        format!("oliana_server at {}", self.server_url)
+    }
+
+    async fn client(&self) -> Result<crate::OlianaClient, OlianaError> {
+        if let Some(client) = self.client.lock().ok().and_then(|client| client.clone()) {
+            return Ok(client);
+        }
+        // Two callers may race to connect here; the loser's connection is simply dropped
+        let client = match crate::transport::connect(&self.server_url, &self.transport_config).await {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("{}:{} Cannot connect to {}: {}", file!(), line!(), self.server_url, e);
+                return Err(OlianaError::WorkerCrashed { worker: self.upstream_name() });
+            }
+        };
+        if let Ok(mut client_slot) = self.client.lock() {
+            *client_slot = Some(client.clone());
+        }
+        Ok(client)
+    }
+
+    /// Runs one RPC against the upstream, turning transport failures into OlianaErrors and dropping the connection after them
+    async fn call<T, F, Fut>(&self, rpc: F) -> Result<T, OlianaError>
+        where F: FnOnce(crate::OlianaClient) -> Fut, Fut: std::future::Future<Output = Result<Result<T, OlianaError>, tarpc::client::RpcError>>
+    {
+        let client = self.client().await?;
+        let call_start = std::time::Instant::now();
+        match rpc(client).await {
+            Ok(result) => result,
+            Err(tarpc::client::RpcError::DeadlineExceeded) => {
+                Err(OlianaError::TimedOut { worker: self.upstream_name(), waited_ms: call_start.elapsed().as_millis() as u64 })
+            }
+            Err(e) => {
+                eprintln!("{}:{} {} from {}", file!(), line!(), e, self.server_url);
+                if let Ok(mut client_slot) = self.client.lock() {
+                    *client_slot = None;
+                }
+                Err(OlianaError::WorkerCrashed { worker: self.upstream_name() })
+            }
+        }
+    }
+
+    fn text_job(&self, job: u64) -> Result<RemoteTextJob, OlianaError> {
+        let mut text_jobs = self.text_jobs.lock().map_err(|_| OlianaError::UnknownJob { job: job })?;
+        let remote_text_job = text_jobs.get_mut(&job).ok_or(OlianaError::UnknownJob { job: job })?;
+        remote_text_job.last_used = std::time::Instant::now();
+        Ok(remote_text_job.clone())
+    }
+
+    fn image_job(&self, job: u64) -> Result<ImageJobId, OlianaError> {
+        let mut image_jobs = self.image_jobs.lock().map_err(|_| OlianaError::UnknownJob { job: job })?;
+        let remote_image_job = image_jobs.get_mut(&job).ok_or(OlianaError::UnknownJob { job: job })?;
+        remote_image_job.last_used = std::time::Instant::now();
+        Ok(remote_image_job.remote_job)
+    }
+}
+
+// A context whose deadline gives the upstream `wait` to answer in; it keeps to that (see wait_within_deadline), so a job with
+// nothing new reads as a retryable OlianaError::TimedOut after `wait` instead of whenever tarpc::context::current() expires.
+fn context_waiting(wait: std::time::Duration) -> tarpc::context::Context {
+    let mut ctx = tarpc::context::current();
+    ctx.deadline = std::time::Instant::now() + wait;
+    ctx
+}
+
+// Errors naming the upstream's job id are re-labelled with ours
+fn with_local_job(e: OlianaError, job: u64) -> OlianaError {
+    match e {
+        OlianaError::UnknownJob { .. } => OlianaError::UnknownJob { job: job },
+        OlianaError::Cancelled { .. } => OlianaError::Cancelled { job: job },
+        other => other,
+    }
+}
+
+impl TextBackend for RemoteBackend {
+    fn name(&self) -> String {
+        self.upstream_name()
+    }
+
+    fn begin(&self, request: TextRequest) -> BackendFuture<'_, u64> {
+        Box::pin(async move {
+            let remote_job = self.call(|client| async move {
+                client.generate_text_begin(tarpc::context::current(), request.system_prompt, request.user_prompt, request.priority).await
+            }).await?;
+            let job = crate::next_job_nonce();
+            if let Ok(mut text_jobs) = self.text_jobs.lock() {
+                text_jobs.retain(|_, text_job| text_job.last_used.elapsed() < FORGET_JOBS_AFTER);
+                text_jobs.insert(job, RemoteTextJob {
+                    remote_job: remote_job,
+                    output: std::sync::Arc::new(tokio::sync::Mutex::new(RemoteTextOutput { text: String::new(), finished: false })),
+                    last_used: std::time::Instant::now(),
+                });
+            }
+            Ok(job)
+        })
+    }
+
+    fn read(&self, job: u64, from_byte: usize, wait: std::time::Duration) -> BackendFuture<'_, TextRead> {
+        Box::pin(async move {
+            let remote_text_job = self.text_job(job)?;
+            let remote_job = remote_text_job.remote_job;
+            let mut output = remote_text_job.output.lock().await;
+            if output.text.len() < from_byte {
+                return Ok(TextRead::Finished);
+            }
+            if output.text.len() > from_byte {
+                return Ok(TextRead::Text { text: output.text[from_byte..].to_string(), next_byte_i: output.text.len() });
+            }
+            if output.finished {
+                return Ok(TextRead::Finished);
+            }
+            let next_token = self.call(|client| async move {
+                client.generate_text_next_token(context_waiting(wait), remote_job).await
+            }).await.map_err(|e| with_local_job(e, job))?;
+            match next_token {
+                Some(token) => {
+                    output.text.push_str(&token);
+                    Ok(TextRead::Text { text: token, next_byte_i: output.text.len() })
+                }
+                None => {
+                    output.finished = true;
+                    Ok(TextRead::Finished)
+                }
+            }
+        })
+    }
+
+    fn status(&self, job: u64) -> BackendFuture<'_, JobStatus> {
+        Box::pin(async move {
+            let remote_job = self.text_job(job)?.remote_job;
+            self.call(|client| async move {
+                client.generate_text_status(tarpc::context::current(), remote_job).await
+            }).await.map_err(|e| with_local_job(e, job))
+        })
+    }
+
+    fn cancel(&self, job: u64) -> BackendFuture<'_, bool> {
+        Box::pin(async move {
+            let remote_job = self.text_job(job)?.remote_job;
+            let status = self.call(|client| async move {
+                client.generate_text_status(tarpc::context::current(), remote_job).await
+            }).await.map_err(|e| with_local_job(e, job))?;
+            if status == JobStatus::Done || status == JobStatus::Failed {
+                return Ok(false);
+            }
+            self.call(|client| async move {
+                client.cancel_text(tarpc::context::current(), remote_job).await
+            }).await.map_err(|e| with_local_job(e, job))?;
+            Ok(true)
+        })
+    }
+
+    fn queue_status(&self) -> BackendFuture<'_, QueueStatus> {
+        Box::pin(async move {
+            let status = self.call(|client| async move {
+                client.server_status(tarpc::context::current()).await
+            }).await?;
+            Ok(status.text_queue)
+        })
+    }
+}
+
+impl ImageBackend for RemoteBackend {
+    fn name(&self) -> String {
+        self.upstream_name()
+    }
+
+    fn begin(&self, request: ImageRequest) -> BackendFuture<'_, u64> {
+        Box::pin(async move {
+            let remote_job = self.call(|client| async move {
+                client.generate_image_begin(tarpc::context::current(), request.prompt, request.negative_prompt, request.guidance_scale, request.num_inference_steps, request.priority).await
+            }).await?;
+            let job = crate::next_job_nonce();
+            if let Ok(mut image_jobs) = self.image_jobs.lock() {
+                image_jobs.retain(|_, image_job| image_job.last_used.elapsed() < FORGET_JOBS_AFTER);
+                image_jobs.insert(job, RemoteImageJob { remote_job: remote_job, last_used: std::time::Instant::now() });
+            }
+            Ok(job)
+        })
+    }
+
+    // The upstream tracks which previews it has already returned on our connection, so `after_step` is not forwarded
+    fn next_preview(&self, job: u64, after_step: u32, wait: std::time::Duration) -> BackendFuture<'_, Option<ImagePreview>> {
+        Box::pin(async move {
+            let remote_job = self.image_job(job)?;
+            self.call(|client| async move {
+                client.generate_image_next_preview(context_waiting(wait), remote_job).await
+            }).await.map_err(|e| with_local_job(e, job))
+        })
+    }
+
+    fn get_result(&self, job: u64, wait: std::time::Duration) -> BackendFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            let remote_job = self.image_job(job)?;
+            self.call(|client| async move {
+                client.generate_image_get_result(context_waiting(wait), remote_job).await
+            }).await.map_err(|e| with_local_job(e, job))
+        })
+    }
+
+    fn status(&self, job: u64) -> BackendFuture<'_, JobStatus> {
+        Box::pin(async move {
+            let remote_job = self.image_job(job)?;
+            self.call(|client| async move {
+                client.generate_image_status(tarpc::context::current(), remote_job).await
+            }).await.map_err(|e| with_local_job(e, job))
+        })
+    }
+
+    fn cancel(&self, job: u64) -> BackendFuture<'_, bool> {
+        Box::pin(async move {
+            let remote_job = self.image_job(job)?;
+            let status = self.call(|client| async move {
+                client.generate_image_status(tarpc::context::current(), remote_job).await
+            }).await.map_err(|e| with_local_job(e, job))?;
+            if status == JobStatus::Done || status == JobStatus::Failed {
+                return Ok(false);
+            }
+            self.call(|client| async move {
+                client.cancel_image(tarpc::context::current(), remote_job).await
+            }).await.map_err(|e| with_local_job(e, job))?;
+            Ok(true)
+        })
+    }
+
+    fn queue_status(&self) -> BackendFuture<'_, QueueStatus> {
+        Box::pin(async move {
+            let status = self.call(|client| async move {
+                client.server_status(tarpc::context::current()).await
+            }).await?;
+            Ok(status.image_queue)
+        })
+    }
+}
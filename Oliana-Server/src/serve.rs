@@ -0,0 +1,230 @@
+
+// Everything oliana_server does once its configuration is known. It lives in the library so a whole server, workers included,
+// can be started in-process by the integration tests in Oliana-Tests.
+
+/// Spawns the configured workers and serves RPCs (and the HTTP gateway, if configured) until a listener fails or SIGINT / SIGTERM arrives.
+/// The workers are stopped before this returns.
+pub async fn serve(config: crate::config::ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let expected_bin_directory = config.resolve_bin_directory()?;
+    let track_proc_dir = config.resolve_track_proc_dir()?;
+
+    let mut procs = oliana_lib::launchers::TrackedProcs::new(track_proc_dir.clone(), expected_bin_directory.clone());
+
+    // This is where we do some general config of how & where the child processes will live.
+    // Once registered, the server will regularly poll .ensure_registered_procs_running() to re-spawn anything that dies.
+    let ai_workdir_images = config.resolve_images_workdir()?;
+    let ai_workdir_text = config.resolve_text_workdir()?;
+
+    if !ai_workdir_images.exists() {
+        std::fs::create_dir_all(ai_workdir_images.clone()).map_err(oliana_lib::eloc!())?;
+    }
+    if !ai_workdir_text.exists() {
+        std::fs::create_dir_all(ai_workdir_text.clone()).map_err(oliana_lib::eloc!())?;
+    }
+
+    // Delete all files in ai_workdir_images and ai_workdir_text; this prevents concurrency build-up over time
+    for working_dir in [&ai_workdir_images, &ai_workdir_text] {
+        let mut dir_iterator = tokio::fs::read_dir(&working_dir).await?;
+        while let Some(entry) = dir_iterator.next_entry().await? {
+            let entry_path = entry.path();
+            if entry_path.is_file() {
+                tokio::fs::remove_file(&entry_path).await?;
+            }
+        }
+    }
+
+    // Evicting before workers start means none of them is mid-load of something we delete; they lease what they use once running
+    if config.cache_quota_bytes.is_some() {
+        config.enforce_cache_quota(&config.resolve_cache_dir()?)?;
+    }
+
+    // Worker args + env (including the PER_PROC_MEM_FRACT each backend reads to avoid over-allocating eachother's slice of the GPU pie) come from config.workers
+    config.register_workers(&mut procs)?;
+    procs.log_rotation = config.worker_logs.to_rotation();
+    procs.orphan_policy = config.orphaned_workers;
+    procs.cgroup_parent = config.cgroup_parent.clone();
+    procs.shutdown_grace_period = std::time::Duration::from_secs(config.worker_shutdown_grace_secs);
+
+    let shareable_procs = std::sync::Arc::new(std::sync::RwLock::new(procs));
+    let shutdown_shareable_procs = shareable_procs.clone();
+
+    // Everything from here on returns through the shutdown below, so however serving ends the workers are stopped on a blocking thread
+    // rather than by TrackedProcs::drop() waiting out their grace period on the runtime
+    let served = async {
+        // The first spawn deals with workers a previous server left running, which can mean waiting for them to be killed
+        let first_spawn_shareable_procs = shareable_procs.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut procs_wg = first_spawn_shareable_procs.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            procs_wg.ensure_registered_procs_running().map_err(|e| oliana_lib::err::ErrorReport::from_error(&*e))
+        }).await??;
+
+        // One watcher per work directory is shared by every connection; each waiting RPC takes its own subscription.
+        let ai_workdir_images_watcher = std::sync::Arc::new(oliana_lib::watch::DirWatcher::new(&ai_workdir_images).map_err(oliana_lib::eloc!())?);
+        let ai_workdir_text_watcher = std::sync::Arc::new(oliana_lib::watch::DirWatcher::new(&ai_workdir_text).map_err(oliana_lib::eloc!())?);
+
+        // Every connection shares one backend per worker type; workdir backends queue jobs until their worker has a free slot (see queue.rs)
+        let mut in_process_generators = crate::backend::InProcessGenerators::default();
+        in_process_generators.text.insert("mock".to_string(), std::sync::Arc::new(crate::in_process_backend::MockTextGenerator));
+        in_process_generators.image.insert("mock".to_string(), std::sync::Arc::new(crate::in_process_backend::MockImageGenerator));
+        let text_backend = crate::backend::build_text_backend(&config.text_backend, &config.text_queue, crate::backend::WorkdirContext {
+            workdir: ai_workdir_text.clone(),
+            watcher: Some(ai_workdir_text_watcher.clone()),
+            procs: Some(shareable_procs.clone()),
+        }, &in_process_generators)?;
+        let image_backend = crate::backend::build_image_backend(&config.image_backend, &config.image_queue, crate::backend::WorkdirContext {
+            workdir: ai_workdir_images.clone(),
+            watcher: Some(ai_workdir_images_watcher.clone()),
+            procs: Some(shareable_procs.clone()),
+        }, &in_process_generators)?;
+
+        // Start an infinite tokio task to call ensure_registered_procs_running()? every 2 seconds or so.
+        // Each poll scans processes and may kill a hung worker, so it runs where blocking is allowed.
+        let ensure_registered_procs_running_t_shareable_procs = shareable_procs.clone();
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+                let shareable_procs = ensure_registered_procs_running_t_shareable_procs.clone();
+                let polled = tokio::task::spawn_blocking(move || {
+                    if let Ok(mut write_lock_guard) = shareable_procs.try_write() {
+                        if let Err(e) = write_lock_guard.ensure_registered_procs_running() {
+                            eprintln!("Error polling ensure_registered_procs_running: {:?}", e);
+                        }
+                    }
+                }).await;
+                if let Err(e) = polled {
+                    eprintln!("Error polling ensure_registered_procs_running: {:?}", e);
+                }
+            }
+        });
+
+        let port = config.port;
+
+        println!("bind_addresses = {:?}", &config.bind_addresses);
+        println!("port = {port:?} (used by every bind address)");
+
+        println!("expected_bin_directory = {expected_bin_directory:?} (Where eg oliana_images[.exe] can be found)");
+        println!("track_proc_dir = {track_proc_dir:?} (Where eg oliana_images[.exe]-pid.txt may be found)");
+        println!("ai_workdir_images = {ai_workdir_images:?} (Where images are generated into and read by the server)");
+        println!("ai_workdir_text = {ai_workdir_text:?} (Where text is generated into and read by the server)");
+        println!("text_backend = {}", text_backend.name());
+        println!("image_backend = {}", image_backend.name());
+
+        let tls_acceptor = match config.resolve_tls()? {
+            Some(tls) => {
+                println!("tls cert_file = {:?} key_file = {:?}", &tls.cert_file, &tls.key_file);
+                Some(crate::transport::load_tls_acceptor(&tls)?)
+            }
+            None => None,
+        };
+        if config.auth_tokens.len() < 1 {
+            eprintln!("WARNING: No auth_tokens configured, any client that can reach the server may use it. Set --token or [auth_tokens] before exposing this host to the internet.");
+        }
+        else if tls_acceptor.is_none() {
+            eprintln!("WARNING: auth_tokens are configured without TLS, so tokens cross the network in cleartext. Set --tls-self-signed or a [tls] section.");
+        }
+        let auth_tokens = std::sync::Arc::new(config.auth_tokens.clone());
+
+        // Every tarpc connection and every HTTP gateway request gets its own OlianaServer from this, all sharing the same backends
+        let make_server: crate::http_gateway::MakeServerFn = std::sync::Arc::new(move |peer_addr, user| {
+            crate::OlianaServer::new(
+                peer_addr,
+                user,
+                shareable_procs.clone(),
+                text_backend.clone(),
+                image_backend.clone(),
+            )
+        });
+
+        // Infrastructure detail: If the Host OS has dual-stacking turned on, a "::" listener will bind to both ipv6 and v4 addresses and a later "0.0.0.0" bind fails.
+        //                        If the Host OS has dual-stacking turned off, we still want to explicitly launch a v4 connector to support v4 clients.
+        //                        Either way we only give up if no address could be bound.
+        let mut all_futures = vec![];
+        for bind_address in config.bind_addresses.iter() {
+            let server_addr = (*bind_address, port);
+            let listener = match tokio::net::TcpListener::bind(&server_addr).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Cannot listen on {:?} ({}), skipping it", &server_addr, e);
+                    continue;
+                }
+            };
+            println!("Server Listening on {:?}", &server_addr);
+
+            let listener_config = crate::transport::ListenerConfig {
+                tls_acceptor: tls_acceptor.clone(),
+                auth_tokens: auth_tokens.clone(),
+                max_channels_per_ip: config.max_channels_per_ip,
+                max_channels: config.max_channels,
+            };
+            let listener_make_server = make_server.clone();
+            all_futures.push(tokio::spawn(crate::transport::serve_listener(listener, listener_config, move |peer_addr, user| {
+                listener_make_server(peer_addr, user)
+            })));
+        }
+
+        if let Some(http_gateway) = &config.http_gateway {
+            if auth_tokens.len() > 0 && tls_acceptor.is_none() {
+                eprintln!("WARNING: The HTTP gateway will receive bearer tokens in cleartext. Set --tls-self-signed or a [tls] section.");
+            }
+            for bind_address in http_gateway.bind_addresses.iter() {
+                let gateway_addr = (*bind_address, http_gateway.port);
+                let listener = match tokio::net::TcpListener::bind(&gateway_addr).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        eprintln!("Cannot listen on {:?} ({}), skipping it", &gateway_addr, e);
+                        continue;
+                    }
+                };
+                println!("HTTP gateway Listening on {:?} ({})", &gateway_addr, if tls_acceptor.is_some() { "https" } else { "http" });
+                let gateway_state = crate::http_gateway::GatewayState {
+                    make_server: make_server.clone(),
+                    auth_tokens: auth_tokens.clone(),
+                };
+                let gateway_tls_acceptor = tls_acceptor.clone();
+                all_futures.push(tokio::spawn(async move {
+                    if let Err(e) = crate::http_gateway::serve_http_gateway(listener, gateway_tls_acceptor, gateway_state).await {
+                        eprintln!("[ http_gateway ] {}", e);
+                    }
+                }));
+            }
+        }
+
+        if all_futures.len() < 1 {
+            return Err(format!("Could not listen on any of {:?} at port {}", &config.bind_addresses, port).into());
+        }
+
+        let listeners = async move {
+            for fut in all_futures {
+                fut.await?;
+            }
+            Ok::<(), tokio::task::JoinError>(())
+        };
+        let shutdown_signal = async {
+            match oliana_lib::launchers::wait_for_shutdown_signal().await.map_err(|e| e.to_string()) {
+                Ok(signal) => signal,
+                Err(e) => {
+                    eprintln!("Cannot listen for shutdown signals ({e}); workers will only be stopped when the server exits normally");
+                    std::future::pending().await
+                }
+            }
+        };
+        let listeners_result = tokio::select! {
+            listeners_result = listeners => listeners_result,
+            signal = shutdown_signal => {
+                println!("Received {signal}, stopping workers");
+                Ok(())
+            }
+        };
+        listeners_result?;
+        Ok::<(), Box<dyn std::error::Error>>(())
+    }.await.map_err(|e| oliana_lib::err::ErrorReport::from_error(&*e)); // Held across the await below, so it has to be Send
+
+    // Waits out the workers' grace period, so it runs where blocking is allowed
+    tokio::task::spawn_blocking(move || {
+        if let Ok(mut procs_wg) = shutdown_shareable_procs.write() {
+            procs_wg.shutdown();
+        }
+    }).await?;
+
+    Ok(served?)
+}
@@ -0,0 +1,316 @@
+
+// Every connection to oliana_server goes through the same steps before tarpc sees it:
+//
+//   1. TCP accept
+//   2. Optional TLS (rustls); the server may generate its own self-signed certificate, which clients then pass as their CA
+//   3. An auth handshake; the client sends "OLIANA-AUTH-1\n", a big-endian u16 length, then that many token bytes.
+//      The server answers with one byte (AUTH_ACCEPTED or AUTH_REJECTED). When the server has no tokens configured
+//      every token (including an empty one) is accepted, which keeps the historical open behavior for LAN setups.
+//   4. bincode tarpc frames, exactly as before
+//
+// Tokens map back to a user name (see ServerConfig::auth_tokens) so the server can log who connected.
+
+use futures::StreamExt;
+use tarpc::server::Channel;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+pub const AUTH_HELLO: &[u8] = b"OLIANA-AUTH-1\n";
+pub const AUTH_ACCEPTED: u8 = 0;
+pub const AUTH_REJECTED: u8 = 1;
+
+// Slow or silent peers are dropped after this long so they cannot hold a connection slot open
+pub const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Anything tarpc frames can be written over; lets plain TCP and TLS streams share one code path.
+pub trait AsyncStream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin {}
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+fn crypto_provider() -> std::sync::Arc<rustls::crypto::CryptoProvider> {
+    std::sync::Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Wraps an already-authenticated stream in the same length-delimited bincode framing tarpc::serde_transport::tcp uses.
+pub fn bincode_transport<Item, SinkItem>(stream: BoxedStream) -> tarpc::serde_transport::Transport<BoxedStream, Item, SinkItem, tarpc::tokio_serde::formats::Bincode<Item, SinkItem>>
+    where Item: for<'de> serde::Deserialize<'de>, SinkItem: serde::Serialize
+{
+    let codec = tokio_util::codec::LengthDelimitedCodec::builder().max_frame_length(usize::MAX).new_codec();
+    tarpc::serde_transport::new(tokio_util::codec::Framed::new(stream, codec), tarpc::tokio_serde::formats::Bincode::default())
+}
+
+/// Writes a fresh self-signed certificate + private key for `subject_alt_names` to the given PEM files.
+pub fn generate_self_signed_cert(subject_alt_names: &[String], cert_file: &std::path::Path, key_file: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    let certified_key = rcgen::generate_simple_self_signed(subject_alt_names.to_vec()).map_err(oliana_lib::eloc!())?;
+    for pem_file in [cert_file, key_file] {
+        if let Some(parent) = pem_file.parent() {
+            std::fs::create_dir_all(parent).map_err(oliana_lib::eloc!())?;
+        }
+    }
+    std::fs::write(cert_file, certified_key.cert.pem()).map_err(oliana_lib::eloc!(format!("Writing {}", cert_file.display())))?;
+    std::fs::write(key_file, certified_key.key_pair.serialize_pem()).map_err(oliana_lib::eloc!(format!("Writing {}", key_file.display())))?;
+    Ok(())
+}
+
+fn read_pem_certs(cert_file: &std::path::Path) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, Box<dyn std::error::Error>> {
+    use rustls::pki_types::pem::PemObject;
+    let mut certs = vec![];
+    for cert in rustls::pki_types::CertificateDer::pem_file_iter(cert_file).map_err(oliana_lib::eloc_str!(format!("Reading {}", cert_file.display())))? {
+        certs.push(cert.map_err(oliana_lib::eloc_str!(format!("Parsing {}", cert_file.display())))?);
+    }
+    if certs.len() < 1 {
+        return Err(format!("No certificates found in {}", cert_file.display()).into());
+    }
+    Ok(certs)
+}
+
+/// Builds the server half of TLS from PEM files, generating a self-signed pair first if asked to and the files do not exist yet.
+pub fn load_tls_acceptor(tls: &crate::config::ResolvedTlsConfig) -> Result<tokio_rustls::TlsAcceptor, Box<dyn std::error::Error>> {
+    use rustls::pki_types::pem::PemObject;
+    if tls.generate_self_signed && !(tls.cert_file.exists() && tls.key_file.exists()) {
+        eprintln!("Generating a self-signed certificate for {:?} into {:?} (clients should pass it as --tls-ca-cert)", &tls.self_signed_names, &tls.cert_file);
+        generate_self_signed_cert(&tls.self_signed_names, &tls.cert_file, &tls.key_file)?;
+    }
+    let certs = read_pem_certs(&tls.cert_file)?;
+    let key = rustls::pki_types::PrivateKeyDer::from_pem_file(&tls.key_file).map_err(oliana_lib::eloc_str!(format!("Reading {}", tls.key_file.display())))?;
+    let server_config = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions().map_err(oliana_lib::eloc!())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key).map_err(oliana_lib::eloc!())?;
+    Ok(tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(server_config)))
+}
+
+/// Builds the client half of TLS; `ca_cert_file` should be the server's certificate when it is self-signed, otherwise the public webpki roots are trusted.
+pub fn load_tls_connector(ca_cert_file: Option<&std::path::Path>) -> Result<tokio_rustls::TlsConnector, Box<dyn std::error::Error>> {
+    let mut roots = rustls::RootCertStore::empty();
+    match ca_cert_file {
+        Some(ca_cert_file) => {
+            for cert in read_pem_certs(ca_cert_file)? {
+                roots.add(cert).map_err(oliana_lib::eloc!())?;
+            }
+        }
+        None => {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+    }
+    let client_config = rustls::ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions().map_err(oliana_lib::eloc!())?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(tokio_rustls::TlsConnector::from(std::sync::Arc::new(client_config)))
+}
+
+// Compares every byte regardless of where the first mismatch is, so response timing does not leak how much of a token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Returns the user name `token` belongs to. With no tokens configured everyone is "anonymous".
+pub fn authenticate_token(auth_tokens: &std::collections::BTreeMap<String, String>, token: &[u8]) -> Option<String> {
+    if auth_tokens.len() < 1 {
+        return Some("anonymous".to_string());
+    }
+    let mut user = None;
+    for (user_name, user_token) in auth_tokens.iter() {
+        if constant_time_eq(user_token.as_bytes(), token) {
+            user = Some(user_name.clone());
+        }
+    }
+    user
+}
+
+/// Server side of the auth handshake; returns the authenticated user name.
+pub async fn server_handshake(stream: &mut BoxedStream, auth_tokens: &std::collections::BTreeMap<String, String>) -> Result<String, Box<dyn std::error::Error>> {
+    let mut hello = [0u8; AUTH_HELLO.len()];
+    stream.read_exact(&mut hello).await.map_err(oliana_lib::eloc!())?;
+    if &hello[..] != AUTH_HELLO {
+        return Err("Client did not send an Oliana auth hello; is it an older oliana_client, or TLS talking to a plaintext server?".into());
+    }
+    let token_len = stream.read_u16().await.map_err(oliana_lib::eloc!())? as usize;
+    let mut token = vec![0u8; token_len];
+    stream.read_exact(&mut token).await.map_err(oliana_lib::eloc!())?;
+
+    match authenticate_token(auth_tokens, &token) {
+        Some(user) => {
+            stream.write_u8(AUTH_ACCEPTED).await.map_err(oliana_lib::eloc!())?;
+            stream.flush().await.map_err(oliana_lib::eloc!())?;
+            Ok(user)
+        }
+        None => {
+            stream.write_u8(AUTH_REJECTED).await.map_err(oliana_lib::eloc!())?;
+            stream.flush().await.map_err(oliana_lib::eloc!())?;
+            Err("Client sent an unknown token".into())
+        }
+    }
+}
+
+/// Client side of the auth handshake; an empty token is sent when `token` is None.
+pub async fn client_handshake(stream: &mut BoxedStream, token: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let token = token.unwrap_or("").as_bytes();
+    if token.len() > u16::MAX as usize {
+        return Err(format!("Token is {} bytes long, the limit is {}", token.len(), u16::MAX).into());
+    }
+    stream.write_all(AUTH_HELLO).await.map_err(oliana_lib::eloc!())?;
+    stream.write_u16(token.len() as u16).await.map_err(oliana_lib::eloc!())?;
+    stream.write_all(token).await.map_err(oliana_lib::eloc!())?;
+    stream.flush().await.map_err(oliana_lib::eloc!())?;
+    match stream.read_u8().await.map_err(oliana_lib::eloc!())? {
+        AUTH_ACCEPTED => Ok(()),
+        AUTH_REJECTED => Err("Server rejected our token (pass --token or set OLIANA_TOKEN)".into()),
+        other => Err(format!("Unexpected auth reply byte {}", other).into()),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientTransportConfig {
+    /// Wrap the connection in TLS
+    pub tls: bool,
+    /// PEM file holding the server's (self-signed) certificate; when None the public webpki roots are trusted
+    pub tls_ca_cert: Option<std::path::PathBuf>,
+    /// Name to verify the server certificate against; defaults to the host part of the server url
+    pub tls_server_name: Option<String>,
+    /// Pre-shared token the server maps to a user
+    pub token: Option<String>,
+}
+
+// "localhost:9050" -> "localhost", "[::1]:9050" -> "::1"
+fn host_of_server_url(server_url: &str) -> &str {
+    let host = match server_url.rfind(':') {
+        Some(colon_i) if !server_url[colon_i..].contains(']') => &server_url[..colon_i],
+        _ => server_url,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Connects to an oliana_server at `server_url` (ie "localhost:9050"), running TLS + the auth handshake as configured.
+pub async fn connect(server_url: &str, transport_config: &ClientTransportConfig) -> Result<crate::OlianaClient, Box<dyn std::error::Error>> {
+    let tcp_stream = tokio::net::TcpStream::connect(server_url).await.map_err(oliana_lib::eloc!(format!("Connecting to {}", server_url)))?;
+    let mut stream: BoxedStream = if transport_config.tls {
+        let connector = load_tls_connector(transport_config.tls_ca_cert.as_deref())?;
+        let server_name = transport_config.tls_server_name.clone().unwrap_or_else(|| host_of_server_url(server_url).to_string());
+        let server_name = rustls::pki_types::ServerName::try_from(server_name).map_err(oliana_lib::eloc!())?;
+        Box::new(connector.connect(server_name, tcp_stream).await.map_err(oliana_lib::eloc!())?)
+    }
+    else {
+        Box::new(tcp_stream)
+    };
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, client_handshake(&mut stream, transport_config.token.as_deref())).await.map_err(oliana_lib::eloc!())??;
+
+    // OlianaClient is generated by the service attribute. It has a constructor `new` that takes a
+    // config and any Transport as input.
+    Ok(crate::OlianaClient::new(tarpc::client::Config::default(), bincode_transport(stream)).spawn())
+}
+
+// Replaces tarpc's max_channels_per_key + buffer_unordered limits, which need the raw TcpStream's peer_addr()
+struct ConnectionLimits {
+    max_per_ip: usize,
+    per_ip: std::sync::Mutex<std::collections::HashMap<std::net::IpAddr, usize>>,
+    total: std::sync::Arc<tokio::sync::Semaphore>,
+}
+
+struct ConnectionSlot {
+    limits: std::sync::Arc<ConnectionLimits>,
+    ip: std::net::IpAddr,
+    _total_permit: tokio::sync::OwnedSemaphorePermit,
+}
+
+impl ConnectionLimits {
+    fn try_acquire(limits: &std::sync::Arc<Self>, ip: std::net::IpAddr) -> Option<ConnectionSlot> {
+        let total_permit = limits.total.clone().try_acquire_owned().ok()?;
+        let mut per_ip = limits.per_ip.lock().ok()?;
+        let ip_count = per_ip.entry(ip).or_insert(0);
+        if *ip_count >= limits.max_per_ip {
+            return None;
+        }
+        *ip_count += 1;
+        Some(ConnectionSlot { limits: limits.clone(), ip: ip, _total_permit: total_permit })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Ok(mut per_ip) = self.limits.per_ip.lock() {
+            if let Some(ip_count) = per_ip.get_mut(&self.ip) {
+                *ip_count -= 1;
+                if *ip_count < 1 {
+                    per_ip.remove(&self.ip);
+                }
+            }
+        }
+    }
+}
+
+pub struct ListenerConfig {
+    pub tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    /// user name -> token; empty means every client is accepted
+    pub auth_tokens: std::sync::Arc<std::collections::BTreeMap<String, String>>,
+    pub max_channels_per_ip: usize,
+    pub max_channels: usize,
+}
+
+/// Accepts connections forever, authenticating each one before serving it with the OlianaServer `make_server` builds for that peer + user name.
+pub async fn serve_listener<F>(listener: tokio::net::TcpListener, listener_config: ListenerConfig, make_server: F)
+    where F: Fn(std::net::SocketAddr, &str) -> crate::OlianaServer + Send + Sync + 'static
+{
+    use crate::Oliana;
+    let limits = std::sync::Arc::new(ConnectionLimits {
+        max_per_ip: listener_config.max_channels_per_ip,
+        per_ip: std::sync::Mutex::new(std::collections::HashMap::new()),
+        total: std::sync::Arc::new(tokio::sync::Semaphore::new(listener_config.max_channels)),
+    });
+    let make_server = std::sync::Arc::new(make_server);
+    loop {
+        let (tcp_stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Ignore accept errors.
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                continue;
+            }
+        };
+        let slot = match ConnectionLimits::try_acquire(&limits, peer_addr.ip()) {
+            Some(slot) => slot,
+            None => {
+                eprintln!("Dropping connection from {} (connection limit reached)", peer_addr);
+                continue;
+            }
+        };
+        let tls_acceptor = listener_config.tls_acceptor.clone();
+        let auth_tokens = listener_config.auth_tokens.clone();
+        let make_server = make_server.clone();
+        tokio::spawn(async move {
+            let _slot = slot; // Held until the client disconnects
+            let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, async move {
+                let mut stream: BoxedStream = match tls_acceptor {
+                    Some(tls_acceptor) => Box::new(tls_acceptor.accept(tcp_stream).await.map_err(oliana_lib::eloc!())?),
+                    None => Box::new(tcp_stream),
+                };
+                let user = server_handshake(&mut stream, &auth_tokens).await?;
+                Ok::<_, Box<dyn std::error::Error>>((stream, user))
+            });
+            // Box<dyn Error> is not Send, so it must not be bound to a variable that lives across the awaits below
+            let accepted: Result<(BoxedStream, String), String> = match handshake.await {
+                Ok(Ok(accepted)) => Ok(accepted),
+                Ok(Err(e)) => Err(format!("{}", e)),
+                Err(_elapsed) => Err(format!("no handshake within {:?}", HANDSHAKE_TIMEOUT)),
+            };
+            let (stream, user) = match accepted {
+                Ok(accepted) => accepted,
+                Err(msg) => {
+                    eprintln!("Refusing {}: {}", peer_addr, msg);
+                    return;
+                }
+            };
+            eprintln!("Accepted {} as {}", peer_addr, user);
+            let server = make_server(peer_addr, &user);
+            tarpc::server::BaseChannel::with_defaults(bincode_transport(stream))
+                .execute(server.serve())
+                .for_each(|response| async move {
+                    tokio::spawn(response);
+                }).await;
+        });
+    }
+}
@@ -0,0 +1,436 @@
+
+// The original Oliana protocol: jobs are `<id>.json` files handed to a worker process through a JobQueue, and the worker
+// writes `<id>.txt` / `<id>.png` / `<id>.progress` / `<id>.done` back into the same folder. Writing `<id>.cancel` asks it to stop.
+// A worker which fails a job writes `<id>.failed` holding an ErrorReport (see oliana_lib::launchers::write_job_failed); when TrackedProcs
+// kills a worker for missing heartbeats mid-job it writes `<id>.failed` (and `<id>.done`) on the worker's behalf.
+// See Oliana-Text/src/main.rs and Oliana-Images/src/main.rs for the worker side.
+
+use crate::backend::{BackendFuture, ImageBackend, ImageRequest, TextBackend, TextRead, TextRequest, WorkdirContext};
+use crate::{ImagePreview, ImageProgress, JobStatus, OlianaError, QueueStatus};
+
+pub struct WorkdirTextBackend {
+    pub bin_name: String,
+    pub workdir: WorkdirContext,
+    /// When None, `<id>.json` is written as soon as a job begins
+    pub queue: Option<std::sync::Arc<crate::queue::JobQueue>>,
+}
+
+pub struct WorkdirImageBackend {
+    pub bin_name: String,
+    pub workdir: WorkdirContext,
+    /// When None, `<id>.json` is written as soon as a job begins
+    pub queue: Option<std::sync::Arc<crate::queue::JobQueue>>,
+}
+
+/// Hands `input_json` to `queue`, or straight to the worker by writing `<workdir>/<nonce>.json` when there is no queue.
+async fn enqueue_job(queue: &Option<std::sync::Arc<crate::queue::JobQueue>>, workdir: &WorkdirContext, nonce: u64, priority: crate::JobPriority, client_key: &str, input_json: String) -> Result<(), OlianaError> {
+    match queue {
+        Some(queue) => {
+            queue.enqueue(crate::queue::QueuedJob {
+                nonce: nonce,
+                client_key: client_key.to_string(),
+                priority: priority,
+                input_json: input_json,
+            });
+        }
+        None => {
+            tokio::fs::write(workdir.workdir.join(format!("{}.json", nonce)), input_json.as_bytes()).await.map_err(oliana_lib::eloc!())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns Err(OlianaError::WorkerCrashed) if the tracked process `bin_name` is known to be dead.
+/// If we have no process tracking (or the lock is poisoned) we assume the worker is fine and let timeouts catch problems.
+/// Checking scans the host's processes under the TrackedProcs write lock, so it runs on a blocking thread.
+async fn ensure_worker_running(workdir: &WorkdirContext, bin_name: &str) -> Result<(), OlianaError> {
+    let shareable_procs = match workdir.procs {
+        Some(ref shareable_procs) => shareable_procs.clone(),
+        None => return Ok(()),
+    };
+    let bin_name = bin_name.to_string();
+    tokio::task::spawn_blocking(move || -> Result<(), OlianaError> {
+        if let Ok(mut procs_wg) = shareable_procs.write() {
+            let procs = &mut *procs_wg;
+            for i in 0..procs.procs.len() {
+                if procs.procs[i].bin_name == bin_name {
+                    let is_running = procs.procs[i].is_running(&mut procs.sinfo, &mut procs.spawned_children).map_err(oliana_lib::eloc!())?;
+                    if !is_running {
+                        return Err(OlianaError::WorkerCrashed { worker: bin_name });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }).await.map_err(oliana_lib::eloc!())?
+}
+
+/// Err(OlianaError::BackendTraceback) with the report the worker (or TrackedProcs, if it gave up on the worker) left in `failed_file`
+async fn check_job_failed(bin_name: &str, failed_file: &std::path::Path) -> Result<(), OlianaError> {
+    if let Ok(contents) = tokio::fs::read_to_string(failed_file).await {
+        let report = oliana_lib::launchers::parse_job_failed(&contents);
+        return Err(OlianaError::BackendTraceback { worker: bin_name.to_string(), traceback: report.to_string(), report: Some(Box::new(report)) });
+    }
+    Ok(())
+}
+
+async fn remove_if_exists(path: &std::path::Path) -> Result<(), OlianaError> {
+    if path.exists() {
+        tokio::fs::remove_file(path).await.map_err(oliana_lib::eloc!())?;
+    }
+    Ok(())
+}
+
+impl WorkdirTextBackend {
+    pub fn new(bin_name: &str, workdir: WorkdirContext, queue: Option<std::sync::Arc<crate::queue::JobQueue>>) -> Self {
+        Self {
+            bin_name: bin_name.to_string(),
+            workdir: workdir,
+            queue: queue,
+        }
+    }
+
+    pub fn get_job_path(&self, job: u64, extension: &str) -> std::path::PathBuf {
+        self.workdir.workdir.join(format!("{}.{}", job, extension))
+    }
+    pub fn get_output_txt_path(&self, job: u64) -> std::path::PathBuf {
+        self.get_job_path(job, "txt")
+    }
+    pub fn get_output_done_path(&self, job: u64) -> std::path::PathBuf {
+        self.get_job_path(job, "done")
+    }
+    /// See oliana_lib::launchers::FAILED_JOB_EXTENSION
+    pub fn get_output_failed_path(&self, job: u64) -> std::path::PathBuf {
+        self.get_job_path(job, oliana_lib::launchers::FAILED_JOB_EXTENSION)
+    }
+    /// oliana_text checks for this marker before starting a job and between tokens
+    pub fn get_input_cancel_path(&self, job: u64) -> std::path::PathBuf {
+        self.get_job_path(job, "cancel")
+    }
+
+    // Callers must subscribe BEFORE checking the filesystem, otherwise a file written in-between is missed until the wait times out.
+    fn subscribe(&self) -> Option<oliana_lib::watch::DirSubscription> {
+        self.workdir.watcher.as_ref().map(|w| w.subscribe())
+    }
+}
+
+impl TextBackend for WorkdirTextBackend {
+    fn name(&self) -> String {
+        self.bin_name.clone()
+    }
+
+    fn begin(&self, request: TextRequest) -> BackendFuture<'_, u64> {
+        Box::pin(async move {
+            ensure_worker_running(&self.workdir, &self.bin_name).await?;
+
+            let workdir = self.workdir.workdir.to_string_lossy().to_string();
+            let job = crate::allocate_job_nonce(&workdir).await.map_err(oliana_lib::eloc!())?;
+
+            let input_data_s = serde_json::to_string(&request).map_err(oliana_lib::eloc!())?;
+
+            remove_if_exists(&self.get_output_txt_path(job)).await?;
+            remove_if_exists(&self.get_input_cancel_path(job)).await?;
+            remove_if_exists(&self.get_output_failed_path(job)).await?;
+
+            enqueue_job(&self.queue, &self.workdir, job, request.priority, &request.client_key, input_data_s).await?;
+
+            Ok(job)
+        })
+    }
+
+    fn read(&self, job: u64, from_byte: usize, wait: std::time::Duration) -> BackendFuture<'_, TextRead> {
+        Box::pin(async move {
+            let poll_start = std::time::Instant::now();
+
+            let response_txt_file = self.get_output_txt_path(job);
+            let response_done_file = self.get_output_done_path(job);
+            let response_failed_file = self.get_output_failed_path(job);
+            let response_cancel_file = self.get_input_cancel_path(job);
+
+            let mut workdir_events = self.subscribe();
+
+            // Wait until the file's size is > from_byte
+            let give_up_at = poll_start + wait;
+            loop {
+                if response_cancel_file.exists() {
+                    return Err(OlianaError::Cancelled { job: job });
+                }
+                if let Ok(file_bytes) = tokio::fs::read(&response_txt_file).await {
+                    if file_bytes.len() < from_byte {
+                        return Ok(TextRead::Finished); // Somehow the file was truncated! .len() should always grow; it is allowed to be == from_byte.
+                    }
+                    // A multi-byte character may be half-written; if so we wait for the rest of it
+                    if let Ok(the_string) = std::str::from_utf8(&file_bytes[from_byte..]) {
+                        // It's possible to read 0 new bytes, in which case we do NOT want to return empty string; instead we fall down to the `response_done_file.exists()` check below.
+                        if the_string.len() > 0 {
+                            return Ok(TextRead::Text { text: the_string.to_string(), next_byte_i: file_bytes.len() });
+                        }
+                    }
+                }
+                if response_done_file.exists() { // What we just read must be the remaining bytes, because .done is created AFTER a write to .txt
+                    // .failed is written before .done, so this cannot miss it; text written before the worker hung was still handed out above
+                    check_job_failed(&self.bin_name, &response_failed_file).await?;
+                    return Ok(TextRead::Finished);
+                }
+                let now = std::time::Instant::now();
+                if now >= give_up_at {
+                    ensure_worker_running(&self.workdir, &self.bin_name).await?;
+                    return Err(OlianaError::TimedOut { worker: self.bin_name.clone(), waited_ms: poll_start.elapsed().as_millis() as u64 });
+                }
+                crate::wait_for_workdir_change(&mut workdir_events, &[&response_txt_file, &response_done_file, &response_failed_file, &response_cancel_file], give_up_at - now).await;
+            }
+        })
+    }
+
+    fn status(&self, job: u64) -> BackendFuture<'_, JobStatus> {
+        Box::pin(async move {
+            if let Some(position) = self.queue.as_ref().and_then(|queue| queue.position(job)) {
+                return Ok(JobStatus::Queued { position: position });
+            }
+            if self.get_output_failed_path(job).exists() {
+                return Ok(JobStatus::Failed);
+            }
+            if self.get_output_done_path(job).exists() {
+                return Ok(JobStatus::Done);
+            }
+            ensure_worker_running(&self.workdir, &self.bin_name).await?;
+            if self.get_output_txt_path(job).exists() {
+                return Ok(JobStatus::Running);
+            }
+            Ok(JobStatus::Pending)
+        })
+    }
+
+    fn cancel(&self, job: u64) -> BackendFuture<'_, bool> {
+        Box::pin(async move {
+            if self.get_output_done_path(job).exists() {
+                return Ok(false); // Nothing left to stop
+            }
+            if let Some(ref queue) = self.queue {
+                queue.remove_waiting(job); // oliana_text never sees the job if this succeeds
+            }
+            // Written even for jobs dropped from the queue because it also wakes up anyone waiting on read()
+            tokio::fs::write(self.get_input_cancel_path(job), " ".as_bytes()).await.map_err(oliana_lib::eloc!())?;
+            Ok(true)
+        })
+    }
+
+    fn queue_status(&self) -> BackendFuture<'_, QueueStatus> {
+        Box::pin(async move {
+            Ok(QueueStatus::of(&self.queue))
+        })
+    }
+}
+
+impl WorkdirImageBackend {
+    pub fn new(bin_name: &str, workdir: WorkdirContext, queue: Option<std::sync::Arc<crate::queue::JobQueue>>) -> Self {
+        Self {
+            bin_name: bin_name.to_string(),
+            workdir: workdir,
+            queue: queue,
+        }
+    }
+
+    pub fn get_job_path(&self, job: u64, extension: &str) -> std::path::PathBuf {
+        self.workdir.workdir.join(format!("{}.{}", job, extension))
+    }
+    pub fn get_output_png_path(&self, job: u64) -> std::path::PathBuf {
+        self.get_job_path(job, "png")
+    }
+    pub fn get_output_txt_path(&self, job: u64) -> std::path::PathBuf {
+        self.get_job_path(job, "txt")
+    }
+    /// oliana_images writes this once it is finished with a job, whether it succeeded, failed or was cancelled
+    pub fn get_output_done_path(&self, job: u64) -> std::path::PathBuf {
+        self.get_job_path(job, "done")
+    }
+    /// See oliana_lib::launchers::FAILED_JOB_EXTENSION
+    pub fn get_output_failed_path(&self, job: u64) -> std::path::PathBuf {
+        self.get_job_path(job, oliana_lib::launchers::FAILED_JOB_EXTENSION)
+    }
+    /// oliana_images checks for this marker before starting a job and after every diffusion step
+    pub fn get_input_cancel_path(&self, job: u64) -> std::path::PathBuf {
+        self.get_job_path(job, "cancel")
+    }
+    pub fn get_output_progress_path(&self, job: u64) -> std::path::PathBuf {
+        self.get_job_path(job, "progress")
+    }
+    pub fn get_output_preview_png_path(&self, job: u64, step: u32) -> std::path::PathBuf {
+        self.get_job_path(job, &format!("preview-{}.png", step))
+    }
+
+    /// Returns None until oliana_images has finished the first diffusion step of `job`
+    pub async fn read_progress(&self, job: u64) -> Option<ImageProgress> {
+        let progress_json = tokio::fs::read_to_string(self.get_output_progress_path(job)).await.ok()?;
+        serde_json::from_str(&progress_json).ok()
+    }
+
+    fn subscribe(&self) -> Option<oliana_lib::watch::DirSubscription> {
+        self.workdir.watcher.as_ref().map(|w| w.subscribe())
+    }
+}
+
+impl ImageBackend for WorkdirImageBackend {
+    fn name(&self) -> String {
+        self.bin_name.clone()
+    }
+
+    fn begin(&self, request: ImageRequest) -> BackendFuture<'_, u64> {
+        Box::pin(async move {
+            ensure_worker_running(&self.workdir, &self.bin_name).await?;
+
+            let workdir = self.workdir.workdir.to_string_lossy().to_string();
+            let job = crate::allocate_job_nonce(&workdir).await.map_err(oliana_lib::eloc!())?;
+
+            let input_data_s = serde_json::to_string(&request).map_err(oliana_lib::eloc!())?;
+
+            remove_if_exists(&self.get_output_txt_path(job)).await?;
+            remove_if_exists(&self.get_output_png_path(job)).await?;
+            remove_if_exists(&self.get_output_progress_path(job)).await?;
+            remove_if_exists(&self.get_input_cancel_path(job)).await?;
+            remove_if_exists(&self.get_output_done_path(job)).await?;
+            remove_if_exists(&self.get_output_failed_path(job)).await?;
+
+            enqueue_job(&self.queue, &self.workdir, job, request.priority, &request.client_key, input_data_s).await?;
+
+            Ok(job)
+        })
+    }
+
+    fn next_preview(&self, job: u64, after_step: u32, wait: std::time::Duration) -> BackendFuture<'_, Option<ImagePreview>> {
+        Box::pin(async move {
+            let poll_start = std::time::Instant::now();
+
+            let response_txt_file = self.get_output_txt_path(job);
+            let response_png_file = self.get_output_png_path(job);
+            let response_progress_file = self.get_output_progress_path(job);
+            let response_failed_file = self.get_output_failed_path(job);
+            let response_cancel_file = self.get_input_cancel_path(job);
+
+            let mut workdir_events = self.subscribe();
+
+            let give_up_at = poll_start + wait;
+            loop {
+                if response_cancel_file.exists() {
+                    return Err(OlianaError::Cancelled { job: job });
+                }
+                if response_txt_file.exists() || response_png_file.exists() || response_failed_file.exists() {
+                    return Ok(None);
+                }
+                if let Some(progress) = self.read_progress(job).await {
+                    if progress.step > after_step {
+                        // oliana_images deletes the previous frame after writing a new one, so a failed read here just means we raced it and should re-read .progress on the next event
+                        if let Ok(png_bytes) = tokio::fs::read(self.get_output_preview_png_path(job, progress.step)).await {
+                            return Ok(Some(ImagePreview {
+                                step: progress.step,
+                                num_steps: progress.num_steps,
+                                png_bytes: png_bytes,
+                            }));
+                        }
+                    }
+                }
+                let now = std::time::Instant::now();
+                if now >= give_up_at {
+                    ensure_worker_running(&self.workdir, &self.bin_name).await?;
+                    return Err(OlianaError::TimedOut { worker: self.bin_name.clone(), waited_ms: poll_start.elapsed().as_millis() as u64 });
+                }
+                crate::wait_for_workdir_change(&mut workdir_events, &[&response_txt_file, &response_png_file, &response_progress_file, &response_failed_file, &response_cancel_file], give_up_at - now).await;
+            }
+        })
+    }
+
+    fn get_result(&self, job: u64, wait: std::time::Duration) -> BackendFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            use tokio::io::AsyncReadExt;
+            let mut result_bytes: Vec<u8> = Vec::with_capacity(1024 * 1024);
+
+            let poll_start = std::time::Instant::now();
+
+            let response_txt_file = self.get_output_txt_path(job);
+            let response_png_file = self.get_output_png_path(job);
+            let response_failed_file = self.get_output_failed_path(job);
+            let response_cancel_file = self.get_input_cancel_path(job);
+
+            let mut workdir_events = self.subscribe();
+
+            let give_up_at = poll_start + wait;
+            while !response_txt_file.exists() && !response_png_file.exists() && !response_failed_file.exists() {
+                if response_cancel_file.exists() {
+                    return Err(OlianaError::Cancelled { job: job });
+                }
+                let now = std::time::Instant::now();
+                if now >= give_up_at {
+                    break;
+                }
+                crate::wait_for_workdir_change(&mut workdir_events, &[&response_txt_file, &response_png_file, &response_failed_file, &response_cancel_file], give_up_at - now).await;
+            }
+            if response_cancel_file.exists() {
+                return Err(OlianaError::Cancelled { job: job });
+            }
+            check_job_failed(&self.bin_name, &response_failed_file).await?;
+
+            if response_txt_file.exists() { // Image workers older than <id>.failed wrote a bare python stack-trace here instead
+                let response_err_msg = tokio::fs::read_to_string(&response_txt_file).await.map_err(oliana_lib::eloc!())?;
+                let report = oliana_lib::launchers::parse_job_failed(&response_err_msg);
+                return Err(OlianaError::BackendTraceback { worker: self.bin_name.clone(), traceback: response_err_msg, report: Some(Box::new(report)) });
+            }
+
+            if !response_png_file.exists() {
+                ensure_worker_running(&self.workdir, &self.bin_name).await?;
+                return Err(OlianaError::TimedOut { worker: self.bin_name.clone(), waited_ms: poll_start.elapsed().as_millis() as u64 });
+            }
+
+            // Just because it _exists_ doesn't mean we're done writing to it. Continue once 100ms pass without the file being touched (or 4 seconds pass in total).
+            let settle_give_up_at = std::time::Instant::now() + std::time::Duration::from_secs(4);
+            while crate::wait_for_workdir_change(&mut workdir_events, &[&response_png_file], std::time::Duration::from_millis(100)).await {
+                if std::time::Instant::now() >= settle_give_up_at {
+                    break;
+                }
+            }
+
+            let mut fd = tokio::fs::File::open(&response_png_file).await.map_err(oliana_lib::eloc!())?;
+            fd.read_to_end(&mut result_bytes).await.map_err(oliana_lib::eloc!())?;
+
+            Ok(result_bytes)
+        })
+    }
+
+    fn status(&self, job: u64) -> BackendFuture<'_, JobStatus> {
+        Box::pin(async move {
+            if let Some(position) = self.queue.as_ref().and_then(|queue| queue.position(job)) {
+                return Ok(JobStatus::Queued { position: position });
+            }
+            if self.get_output_txt_path(job).exists() || self.get_output_failed_path(job).exists() {
+                return Ok(JobStatus::Failed);
+            }
+            if self.get_output_png_path(job).exists() {
+                return Ok(JobStatus::Done);
+            }
+            ensure_worker_running(&self.workdir, &self.bin_name).await?;
+            if self.get_output_progress_path(job).exists() {
+                return Ok(JobStatus::Running);
+            }
+            Ok(JobStatus::Pending)
+        })
+    }
+
+    fn cancel(&self, job: u64) -> BackendFuture<'_, bool> {
+        Box::pin(async move {
+            if self.get_output_png_path(job).exists() || self.get_output_txt_path(job).exists() || self.get_output_failed_path(job).exists() {
+                return Ok(false); // Nothing left to stop
+            }
+            if let Some(ref queue) = self.queue {
+                queue.remove_waiting(job); // oliana_images never sees the job if this succeeds
+            }
+            // Written even for jobs dropped from the queue because it also wakes up anyone waiting on the job
+            tokio::fs::write(self.get_input_cancel_path(job), " ".as_bytes()).await.map_err(oliana_lib::eloc!())?;
+            Ok(true)
+        })
+    }
+
+    fn queue_status(&self) -> BackendFuture<'_, QueueStatus> {
+        Box::pin(async move {
+            Ok(QueueStatus::of(&self.queue))
+        })
+    }
+}
@@ -0,0 +1,32 @@
+
+// Stand-in for oliana_text and oliana_images in the tests. TestServer copies this binary into its bin_directory under both names
+// and --kind picks which one it is; the workdir protocol is the same one `oliana_text --backend mock` and `oliana_images --backend mock`
+// speak (see oliana_lib::mock), but building it does not pull in either crate's GPU dependencies.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let arg_value = |name: &str| -> Option<String> {
+        args.iter().position(|n| n == name).and_then(|i| args.get(i+1)).cloned()
+    };
+    let kind = arg_value("--kind").unwrap_or("text".to_string());
+    let workdir = arg_value("--workdir").ok_or("--workdir is required")?;
+    let delay = match arg_value("--mock-delay-ms") {
+        Some(delay_ms) => std::time::Duration::from_millis(delay_ms.parse::<u64>().map_err(oliana_lib::eloc!())?),
+        None if kind == "image" => oliana_lib::mock::DEFAULT_MOCK_STEP_DELAY,
+        None => oliana_lib::mock::DEFAULT_MOCK_TOKEN_DELAY,
+    };
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .enable_io()
+        .build()?;
+
+    // Exits with an error once the workdir disappears, which is how workers left behind by a finished test stop
+    rt.block_on(async {
+        match kind.as_str() {
+            "text" => oliana_lib::mock::run_mock_text_worker(&workdir, 0, delay).await,
+            "image" => oliana_lib::mock::run_mock_image_worker(&workdir, 0, delay).await,
+            other => Err(format!("Unknown --kind {:?}, expected \"text\" or \"image\"", other).into()),
+        }
+    })
+}
@@ -0,0 +1,540 @@
+
+// Test harness for the whole stack: TestServer runs oliana_server_lib::serve::serve() on its own runtime, listening on a free
+// localhost port, with oliana_mock_worker standing in for oliana_text and oliana_images. Workers are real child processes
+// managed by TrackedProcs, so tests can crash them and watch the server re-spawn them.
+//
+// Everything a TestServer creates lives in one temporary directory which is removed, along with the workers, when it is dropped.
+
+pub use oliana_server_lib::{JobPriority, JobStatus, OlianaClient, OlianaError};
+
+/// Upper bound for anything a test waits on (workers loading, re-spawns, whole jobs); generous so slow CI machines do not flake
+pub const TEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct TestServerOptions {
+    /// The built oliana_mock_worker; integration tests pass env!("CARGO_BIN_EXE_oliana_mock_worker")
+    pub mock_worker_bin: std::path::PathBuf,
+    /// Time between tokens from the text worker
+    pub text_token_delay: std::time::Duration,
+    /// Time each diffusion step takes in the image worker
+    pub image_step_delay: std::time::Duration,
+    pub max_concurrent_jobs: usize,
+    /// Workers silent for this long are killed; with a token / step delay above it, a worker looks hung mid-job
+    pub heartbeat_timeout_secs: u64,
+    /// user name -> token, as ServerConfig::auth_tokens; client() presents the first token
+    pub auth_tokens: std::collections::BTreeMap<String, String>,
+    /// Like --tls-self-signed: the server writes its own certificate to TestServer::tls_cert_file(), which client() trusts
+    pub tls_self_signed: bool,
+    /// Also serve the OpenAI-compatible HTTP gateway, on TestServer::http_port
+    pub http_gateway: bool,
+}
+
+impl TestServerOptions {
+    pub fn new(mock_worker_bin: impl Into<std::path::PathBuf>) -> Self {
+        Self {
+            mock_worker_bin: mock_worker_bin.into(),
+            text_token_delay: std::time::Duration::from_millis(5),
+            image_step_delay: std::time::Duration::from_millis(20),
+            max_concurrent_jobs: 1,
+            heartbeat_timeout_secs: oliana_server_lib::config::DEFAULT_HEARTBEAT_TIMEOUT_SECS,
+            auth_tokens: std::collections::BTreeMap::new(),
+            tls_self_signed: false,
+            http_gateway: false,
+        }
+    }
+}
+
+pub struct TestServer {
+    pub port: u16,
+    /// Where the HTTP gateway listens when options.http_gateway is set
+    pub http_port: Option<u16>,
+    pub options: TestServerOptions,
+    /// bin_directory, track_proc_dir and both workdirs live under here
+    pub dir: tempfile::TempDir,
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+impl TestServer {
+    /// Starts the server and waits until it accepts connections and both workers report their (mock) model as loaded
+    pub async fn start(options: TestServerOptions) -> Result<Self, Box<dyn std::error::Error>> {
+        let dir = tempfile::Builder::new().prefix("oliana-tests-").tempdir()?;
+        let bin_directory = dir.path().join("bin");
+        std::fs::create_dir_all(&bin_directory)?;
+        for bin_name in [oliana_server_lib::TEXT_WORKER_BIN_NAME, oliana_server_lib::IMAGE_WORKER_BIN_NAME] {
+            let bin_path = bin_directory.join(oliana_lib::files::append_os_extention_to_bin(bin_name));
+            std::fs::copy(&options.mock_worker_bin, &bin_path).map_err(oliana_lib::eloc!(format!("Copying {:?}", &options.mock_worker_bin)))?;
+        }
+
+        let port = free_localhost_port()?;
+        let mut config = oliana_server_lib::config::ServerConfig::default();
+        config.bind_addresses = vec![std::net::Ipv4Addr::LOCALHOST.into()];
+        config.port = port;
+        config.bin_directory = Some(bin_directory.clone());
+        config.track_proc_dir = Some(dir.path().to_path_buf());
+        config.auth_tokens = options.auth_tokens.clone();
+        if options.tls_self_signed {
+            config.tls = Some(oliana_server_lib::config::TlsConfig {
+                generate_self_signed: true,
+                ..Default::default()
+            });
+        }
+        let http_port = if options.http_gateway { Some(free_localhost_port()?) } else { None };
+        if let Some(http_port) = http_port {
+            config.http_gateway = Some(oliana_server_lib::config::HttpGatewayConfig {
+                bind_addresses: vec![std::net::Ipv4Addr::LOCALHOST.into()],
+                port: http_port,
+            });
+        }
+        config.text_queue.max_concurrent_jobs = options.max_concurrent_jobs;
+        config.image_queue.max_concurrent_jobs = options.max_concurrent_jobs;
+        config.workers = vec![
+            mock_worker_config(oliana_server_lib::TEXT_WORKER_BIN_NAME, "text", "{text_workdir}", options.text_token_delay, options.heartbeat_timeout_secs),
+            mock_worker_config(oliana_server_lib::IMAGE_WORKER_BIN_NAME, "image", "{images_workdir}", options.image_step_delay, options.heartbeat_timeout_secs),
+        ];
+
+        // A runtime of its own so dropping the TestServer stops every task the server spawned, whatever runtime the test uses
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_time()
+            .enable_io()
+            .build()?;
+        runtime.spawn(async move {
+            if let Err(e) = oliana_server_lib::serve::serve(config).await {
+                eprintln!("[ TestServer ] {}", e);
+            }
+        });
+
+        let test_server = Self {
+            port: port,
+            http_port: http_port,
+            options: options,
+            dir: dir,
+            runtime: Some(runtime),
+        };
+        test_server.wait_for_workers_ready().await?;
+        Ok(test_server)
+    }
+
+    pub fn server_url(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    /// Where a tls_self_signed server writes its certificate; clients pass it as their tls_ca_cert
+    pub fn tls_cert_file(&self) -> std::path::PathBuf {
+        self.dir.path().join(oliana_server_lib::config::TlsConfig::default().cert_file)
+    }
+
+    /// What client() connects with: the first of options.auth_tokens, and TLS trusting tls_cert_file() if options.tls_self_signed
+    pub fn client_transport_config(&self) -> oliana_server_lib::transport::ClientTransportConfig {
+        oliana_server_lib::transport::ClientTransportConfig {
+            tls: self.options.tls_self_signed,
+            tls_ca_cert: if self.options.tls_self_signed { Some(self.tls_cert_file()) } else { None },
+            tls_server_name: None,
+            token: self.options.auth_tokens.values().next().cloned(),
+        }
+    }
+
+    /// A new connection; job ids are only valid on the connection which began them
+    pub async fn client(&self) -> Result<OlianaClient, Box<dyn std::error::Error>> {
+        let start = std::time::Instant::now();
+        loop {
+            match oliana_server_lib::transport::connect(&self.server_url(), &self.client_transport_config()).await {
+                Ok(client) => return Ok(client),
+                Err(e) if start.elapsed() > TEST_TIMEOUT => return Err(e),
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(50)).await, // Not listening yet
+            }
+        }
+    }
+
+    pub async fn worker_status(&self, bin_name: &str) -> Result<oliana_server_lib::WorkerStatus, Box<dyn std::error::Error>> {
+        let status = self.client().await?.server_status(tarpc::context::current()).await??;
+        status.workers.into_iter().find(|w| w.bin_name == bin_name).ok_or_else(|| format!("server_status() does not list {}", bin_name).into())
+    }
+
+    /// Polls server_status() until `ready` accepts the worker's status
+    pub async fn wait_for_worker<F: Fn(&oliana_server_lib::WorkerStatus) -> bool>(&self, bin_name: &str, ready: F) -> Result<oliana_server_lib::WorkerStatus, Box<dyn std::error::Error>> {
+        let start = std::time::Instant::now();
+        loop {
+            let status = self.worker_status(bin_name).await?;
+            if ready(&status) {
+                return Ok(status);
+            }
+            if start.elapsed() > TEST_TIMEOUT {
+                return Err(format!("{} did not become ready within {:?}, last status: {:?}", bin_name, TEST_TIMEOUT, status).into());
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+    }
+
+    pub async fn wait_for_workers_ready(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for bin_name in [oliana_server_lib::TEXT_WORKER_BIN_NAME, oliana_server_lib::IMAGE_WORKER_BIN_NAME] {
+            self.wait_for_worker(bin_name, |w| w.running && w.model_loaded).await?;
+        }
+        Ok(())
+    }
+
+    /// One request to the HTTP gateway; see http_request()
+    pub async fn http_request(&self, method: &str, path: &str, bearer_token: Option<&str>, json_body: Option<&serde_json::Value>) -> Result<HttpReply, Box<dyn std::error::Error>> {
+        let http_port = self.http_port.ok_or("The TestServer was started without options.http_gateway")?;
+        http_request(&format!("127.0.0.1:{}", http_port), method, path, bearer_token, json_body).await
+    }
+
+    /// Kills a worker the way a crash would, without telling the server
+    pub fn kill_worker(&self, pid: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sinfo = sysinfo::System::new();
+        let pid = sysinfo::Pid::from_u32(pid);
+        sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[pid]), true);
+        match sinfo.process(pid) {
+            Some(process) if process.kill() => Ok(()),
+            Some(_) => Err(format!("Could not kill {}", pid).into()),
+            None => Err(format!("No process {}", pid).into()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // Stop the server first so nothing re-spawns the workers, then stop the workers through the pid files TrackedProcs keeps
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+        let mut sinfo = sysinfo::System::new();
+        sinfo.refresh_processes(sysinfo::ProcessesToUpdate::All, true);
+        for bin_name in [oliana_server_lib::TEXT_WORKER_BIN_NAME, oliana_server_lib::IMAGE_WORKER_BIN_NAME] {
+            let pid_file = self.dir.path().join(format!("{}-pid.txt", bin_name));
+            let bin_path = self.dir.path().join("bin").join(oliana_lib::files::append_os_extention_to_bin(bin_name));
+            if let Ok(Some(record)) = oliana_lib::launchers::read_pid_record(&pid_file) {
+                if let Some(process) = sinfo.process(sysinfo::Pid::from_u32(record.pid)) {
+                    if record.matches(process, &bin_path) {
+                        process.kill();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs a whole text job and returns everything it generated
+pub async fn generate_text(client: &OlianaClient, system_prompt: &str, user_prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let job = client.generate_text_begin(tarpc::context::current(), system_prompt.to_string(), user_prompt.to_string(), JobPriority::default()).await??;
+    generate_text_of(client, job).await
+}
+
+/// Everything text `job` generates from here on, once it has finished
+pub async fn generate_text_of(client: &OlianaClient, job: oliana_server_lib::TextJobId) -> Result<String, Box<dyn std::error::Error>> {
+    let mut text = String::new();
+    while let Some(token) = client.generate_text_next_token(tarpc::context::current(), job).await?? {
+        text.push_str(&token);
+    }
+    Ok(text)
+}
+
+/// A TrackedProcs keeping its PID files and logs in `dir`, with `spec` registered but not started. `worker_bin` (ie
+/// env!("CARGO_BIN_EXE_oliana_mock_worker"), or /bin/sh) is copied into `dir` under spec.bin_name unless a previous call already did.
+pub fn tracked_worker(dir: &std::path::Path, worker_bin: impl AsRef<std::path::Path>, spec: oliana_lib::launchers::TrackedProcSpec) -> Result<oliana_lib::launchers::TrackedProcs, Box<dyn std::error::Error>> {
+    let bin_path = dir.join(oliana_lib::files::append_os_extention_to_bin(&spec.bin_name));
+    if !bin_path.exists() {
+        std::fs::copy(worker_bin.as_ref(), &bin_path)?;
+    }
+    let mut procs = oliana_lib::launchers::TrackedProcs::new(dir, dir);
+    procs.register_tracked_proc(spec);
+    Ok(procs)
+}
+
+/// False once `pid` has exited, even while it waits to be reaped as a zombie
+pub fn is_alive(pid: u32) -> bool {
+    let mut sinfo = sysinfo::System::new();
+    sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[sysinfo::Pid::from_u32(pid)]), true);
+    match sinfo.process(sysinfo::Pid::from_u32(pid)) {
+        Some(process) => !matches!(process.status(), sysinfo::ProcessStatus::Zombie | sysinfo::ProcessStatus::Dead),
+        None => false,
+    }
+}
+
+/// SIGKILLs `pid` if it is still there; for cleaning up after a test, unlike TestServer::kill_worker()
+pub fn kill(pid: u32) {
+    let mut sinfo = sysinfo::System::new();
+    sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[sysinfo::Pid::from_u32(pid)]), true);
+    if let Some(process) = sinfo.process(sysinfo::Pid::from_u32(pid)) {
+        process.kill();
+    }
+}
+
+fn mock_worker_config(bin_name: &str, kind: &str, workdir: &str, delay: std::time::Duration, heartbeat_timeout_secs: u64) -> oliana_server_lib::config::WorkerConfig {
+    oliana_server_lib::config::WorkerConfig {
+        bin_name: bin_name.to_string(),
+        args: vec![
+            "--kind".to_string(), kind.to_string(),
+            "--workdir".to_string(), workdir.to_string(),
+            "--mock-delay-ms".to_string(), delay.as_millis().to_string(),
+        ],
+        env: std::collections::BTreeMap::new(),
+        cwd: None,
+        workdir: Some(workdir.to_string()),
+        limits: oliana_lib::proc_limits::ResourceLimits::default(),
+        restart: oliana_server_lib::config::RestartConfig::default(),
+        heartbeat_timeout_secs: heartbeat_timeout_secs,
+    }
+}
+
+// The server only takes a port number, so ask the OS for a free one and hand it over. Another process could grab it in between,
+// which is unlikely enough on a test machine.
+fn free_localhost_port() -> Result<u16, Box<dyn std::error::Error>> {
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))?;
+    Ok(listener.local_addr()?.port())
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpReply {
+    pub status: u16,
+    /// Header names are lowercased
+    pub headers: Vec<(String, String)>,
+    /// With any chunked transfer-encoding already undone
+    pub body: Vec<u8>,
+}
+
+impl HttpReply {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header_name, _)| header_name == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        serde_json::from_slice(&self.body).map_err(|e| format!("{} parsing {:?}", e, String::from_utf8_lossy(&self.body)).into())
+    }
+}
+
+/// A bare-bones HTTP/1.1 client: sends one request with "Connection: close" to `addr` (ie "127.0.0.1:9051") and reads the reply until the server hangs up.
+/// Enough for the HTTP gateway, whose streamed replies end when the stream does.
+pub async fn http_request(addr: &str, method: &str, path: &str, bearer_token: Option<&str>, json_body: Option<&serde_json::Value>) -> Result<HttpReply, Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let body = match json_body {
+        Some(json_body) => serde_json::to_vec(json_body)?,
+        None => vec![],
+    };
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n", method, path, addr, body.len());
+    if json_body.is_some() {
+        request.push_str("Content-Type: application/json\r\n");
+    }
+    if let Some(bearer_token) = bearer_token {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", bearer_token));
+    }
+    request.push_str("\r\n");
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
+    let mut reply = vec![];
+    tokio::time::timeout(TEST_TIMEOUT, stream.read_to_end(&mut reply)).await??;
+
+    let head_len = reply.windows(4).position(|w| w == b"\r\n\r\n").ok_or("Reply has no end of headers")?;
+    let head = String::from_utf8_lossy(&reply[..head_len]).to_string();
+    let mut head_lines = head.split("\r\n");
+    let status_line = head_lines.next().unwrap_or("");
+    let status: u16 = status_line.split_whitespace().nth(1).and_then(|status| status.parse().ok()).ok_or_else(|| format!("Bad status line {:?}", status_line))?;
+    let headers: Vec<(String, String)> = head_lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    let mut body = reply[head_len + 4..].to_vec();
+    if headers.iter().any(|(name, value)| name == "transfer-encoding" && value.eq_ignore_ascii_case("chunked")) {
+        body = unchunk(&body)?;
+    }
+    Ok(HttpReply { status: status, headers: headers, body: body })
+}
+
+// Undoes "Transfer-Encoding: chunked": hex length, CRLF, that many bytes, CRLF, ... until a zero length chunk
+fn unchunk(mut chunked: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut body = vec![];
+    loop {
+        let line_len = chunked.windows(2).position(|w| w == b"\r\n").ok_or("Chunk without a length line")?;
+        let size_line = String::from_utf8_lossy(&chunked[..line_len]).to_string();
+        let chunk_len = usize::from_str_radix(size_line.split(';').next().unwrap_or("").trim(), 16).map_err(|e| format!("{} parsing chunk length {:?}", e, size_line))?;
+        if chunk_len < 1 {
+            return Ok(body);
+        }
+        let chunk_start = line_len + 2;
+        if chunked.len() < chunk_start + chunk_len + 2 {
+            return Err("Reply ended mid-chunk".into());
+        }
+        body.extend_from_slice(&chunked[chunk_start..chunk_start + chunk_len]);
+        chunked = &chunked[chunk_start + chunk_len + 2..];
+    }
+}
+
+/// A plain HTTP/1.1 server on a free localhost port serving fixed files, for testing downloads without the internet.
+/// Paths it was not given get a 404 with an HTML body, like a real server's error page. Stops when dropped.
+pub struct TestHttpServer {
+    pub url: String,
+    /// Every request so far, in order
+    pub requests: std::sync::Arc<std::sync::Mutex<Vec<TestHttpRequest>>>,
+    stop: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestHttpRequest {
+    pub path: String,
+    /// N of a "Range: bytes=N-" header
+    pub range_start: Option<u64>,
+}
+
+/// How a TestHttpServer serves one path. Failures are injected into the first requests for it, 503s before dropped connections.
+#[derive(Debug, Clone)]
+pub struct TestHttpRoute {
+    pub status: u16,
+    pub body: Vec<u8>,
+    /// Answer "Range: bytes=N-" requests with a 206 and the rest of the body, like a static file server
+    pub supports_range: bool,
+    /// The first this many requests get a 503
+    pub fail_first: usize,
+    /// The this many requests after those get cut_after_bytes of the body and then the connection closed
+    pub drop_first: usize,
+    pub cut_after_bytes: usize,
+}
+
+impl TestHttpRoute {
+    pub fn ok(body: &[u8]) -> Self {
+        Self {
+            status: 200,
+            body: body.to_vec(),
+            supports_range: true,
+            fail_first: 0,
+            drop_first: 0,
+            cut_after_bytes: 0,
+        }
+    }
+
+    pub fn with_failures(mut self, fail_first: usize) -> Self {
+        self.fail_first = fail_first;
+        self
+    }
+
+    pub fn with_dropped_connections(mut self, drop_first: usize, cut_after_bytes: usize) -> Self {
+        self.drop_first = drop_first;
+        self.cut_after_bytes = cut_after_bytes;
+        self
+    }
+
+    pub fn without_range_support(mut self) -> Self {
+        self.supports_range = false;
+        self
+    }
+}
+
+impl TestHttpServer {
+    /// `routes` maps a path (ie "/model.bin") to how it is served
+    pub fn start(routes: std::collections::HashMap<String, TestHttpRoute>) -> Result<Self, Box<dyn std::error::Error>> {
+        let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))?;
+        listener.set_nonblocking(true)?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let (thread_requests, thread_stop) = (requests.clone(), stop.clone());
+        std::thread::spawn(move || {
+            while !thread_stop.load(std::sync::atomic::Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = serve_one_request(stream, &routes, &thread_requests) {
+                            eprintln!("[ TestHttpServer ] {}", e);
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => std::thread::sleep(std::time::Duration::from_millis(10)),
+                    Err(e) => eprintln!("[ TestHttpServer ] {}", e),
+                }
+            }
+        });
+        Ok(Self {
+            url: url,
+            requests: requests,
+            stop: stop,
+        })
+    }
+
+    pub fn url_of(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    pub fn requests_for(&self, path: &str) -> Vec<TestHttpRequest> {
+        self.requests.lock().unwrap().iter().filter(|r| r.path == path).cloned().collect()
+    }
+}
+
+impl Drop for TestHttpServer {
+    fn drop(&mut self) {
+        self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+fn serve_one_request(mut stream: std::net::TcpStream, routes: &std::collections::HashMap<String, TestHttpRoute>, requests: &std::sync::Mutex<Vec<TestHttpRequest>>) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{BufRead, Write};
+    stream.set_nonblocking(false)?;
+    let mut reader = std::io::BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut range_start: Option<u64> = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? < 1 || header.trim().len() < 1 {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("range") {
+                range_start = value.trim().strip_prefix("bytes=").and_then(|range| range.trim_end_matches('-').parse().ok());
+            }
+        }
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/").to_string();
+    let previous_requests = {
+        let mut requests = requests.lock().map_err(|e| format!("{}", e))?;
+        let previous_requests = requests.iter().filter(|r| r.path == path).count();
+        requests.push(TestHttpRequest { path: path.clone(), range_start: range_start });
+        previous_requests
+    };
+
+    let route = match routes.get(&path) {
+        Some(route) => route,
+        None => return write_http_response(&mut stream, "404 Not Found", &[], b"<html><body>Not Found</body></html>"),
+    };
+    if previous_requests < route.fail_first {
+        return write_http_response(&mut stream, "503 Service Unavailable", &[], b"<html><body>Try again later</body></html>");
+    }
+    if route.status != 200 {
+        return write_http_response(&mut stream, &format!("{} X", route.status), &[], &route.body);
+    }
+
+    let (status, headers, body) = match range_start {
+        Some(start) if route.supports_range && start >= route.body.len() as u64 => {
+            return write_http_response(&mut stream, "416 Range Not Satisfiable", &[format!("Content-Range: bytes */{}", route.body.len())], &[]);
+        }
+        Some(start) if route.supports_range => {
+            let content_range = format!("Content-Range: bytes {}-{}/{}", start, route.body.len() - 1, route.body.len());
+            ("206 Partial Content", vec![content_range], &route.body[start as usize..])
+        }
+        _ => ("200 OK", vec![], &route.body[..]),
+    };
+    if previous_requests < route.fail_first + route.drop_first {
+        // Promise the whole body, deliver part of it, hang up
+        write_http_head(&mut stream, status, &headers, body.len())?;
+        stream.write_all(&body[..std::cmp::min(route.cut_after_bytes, body.len())])?;
+        stream.flush()?;
+        return Ok(());
+    }
+    write_http_response(&mut stream, status, &headers, body)
+}
+
+fn write_http_response(stream: &mut std::net::TcpStream, status: &str, headers: &[String], body: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    write_http_head(stream, status, headers, body.len())?;
+    stream.write_all(body)?;
+    Ok(())
+}
+
+fn write_http_head(stream: &mut std::net::TcpStream, status: &str, headers: &[String], content_length: usize) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\n", status, content_length)?;
+    for header in headers {
+        write!(stream, "{}\r\n", header)?;
+    }
+    write!(stream, "Connection: close\r\n\r\n")?;
+    Ok(())
+}
@@ -0,0 +1,185 @@
+// oliana_lib::files cache entries: listing them, leases keeping them from being pruned, and the LRU quota oliana_server enforces.
+
+fn write_entry_file(cache_root: &std::path::Path, relative_path: &str, num_bytes: usize, age: std::time::Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let path = cache_root.join(relative_path);
+    std::fs::create_dir_all(path.parent().ok_or("no parent")?)?;
+    std::fs::write(&path, vec![b'o'; num_bytes])?;
+    std::fs::File::options().write(true).open(&path)?.set_modified(std::time::SystemTime::now() - age)?;
+    Ok(())
+}
+
+fn hours(n: u64) -> std::time::Duration {
+    std::time::Duration::from_secs(n * 60 * 60)
+}
+
+fn entry_names(entries: &[oliana_lib::files::CacheEntry]) -> Vec<String> {
+    entries.iter().map(|entry| entry.name.clone()).collect()
+}
+
+// A lease file a process which is gone (or whose PID was re-used) left behind
+fn write_stale_lease(entry_path: &std::path::Path, record: &oliana_lib::launchers::PidRecord) -> Result<(), Box<dyn std::error::Error>> {
+    oliana_lib::launchers::write_pid_record(&entry_path.join(format!(".oliana-lease-{}.json", record.pid)), record)
+}
+
+#[test]
+fn entries_are_listed_with_sizes_most_recently_used_first() -> Result<(), Box<dyn std::error::Error>> {
+    let cache_root = tempfile::tempdir()?;
+    write_entry_file(cache_root.path(), "Oliana-Text-hf_home/hub/weights.bin", 300, hours(48))?;
+    write_entry_file(cache_root.path(), "Oliana-Text-hf_home/hub/config.json", 20, hours(30))?;
+    write_entry_file(cache_root.path(), "models/tiny/config.json", 10, hours(24))?;
+    write_entry_file(cache_root.path(), "models/tiny/unet/weights.bin", 100, hours(24))?;
+    write_entry_file(cache_root.path(), "models/small/weights.bin", 50, hours(72))?;
+    write_entry_file(cache_root.path(), "tos-accepted.txt", 5, hours(1))?;
+
+    let entries = oliana_lib::files::list_cache_entries(cache_root.path())?;
+    assert_eq!(entry_names(&entries), vec!["tos-accepted.txt", "models/tiny", "Oliana-Text-hf_home", "models/small"]);
+    let sizes: Vec<u64> = entries.iter().map(|entry| entry.size_bytes).collect();
+    assert_eq!(sizes, vec![5, 110, 320, 50]);
+    assert!(entries.iter().all(|entry| entry.in_use_by.len() < 1));
+
+    // Using an entry makes it the most recently used; its markers are not counted towards its size
+    drop(oliana_lib::files::lease_cache_entry(cache_root.path().join("models/small"))?);
+    let entries = oliana_lib::files::list_cache_entries(cache_root.path())?;
+    assert_eq!(entries[0].name, "models/small");
+    assert_eq!(entries[0].size_bytes, 50);
+
+    assert_eq!(oliana_lib::files::cache_entry_name(std::path::Path::new("models/tiny/unet")), Some("models/tiny".to_string()));
+    assert_eq!(oliana_lib::files::cache_entry_name(std::path::Path::new("Oliana-Images-site_packages")), Some("Oliana-Images-site_packages".to_string()));
+    Ok(())
+}
+
+#[test]
+fn leased_entries_are_not_pruned() -> Result<(), Box<dyn std::error::Error>> {
+    let cache_root = tempfile::tempdir()?;
+    write_entry_file(cache_root.path(), "models/tiny/weights.bin", 100, hours(1))?;
+
+    let lease = oliana_lib::files::lease_cache_entry(cache_root.path().join("models/tiny"))?;
+    let entries = oliana_lib::files::list_cache_entries(cache_root.path())?;
+    assert_eq!(entries[0].in_use_by, vec![std::process::id()]);
+
+    let e = oliana_lib::files::prune_cache_entry(cache_root.path(), "models/tiny").expect_err("pruned a leased entry");
+    let in_use = e.downcast_ref::<oliana_lib::files::CacheEntryInUse>().ok_or_else(|| format!("not a CacheEntryInUse: {}", e))?;
+    assert_eq!(in_use.pids, vec![std::process::id()]);
+    assert!(cache_root.path().join("models/tiny/weights.bin").exists());
+
+    drop(lease);
+    assert_eq!(oliana_lib::files::prune_cache_entry(cache_root.path(), "models/tiny")?, 100);
+    assert!(!cache_root.path().join("models/tiny").exists());
+    assert_eq!(oliana_lib::files::list_cache_entries(cache_root.path())?.len(), 0);
+
+    assert!(oliana_lib::files::prune_cache_entry(cache_root.path(), "models/tiny").is_err());
+    Ok(())
+}
+
+#[test]
+fn stale_leases_do_not_keep_entries() -> Result<(), Box<dyn std::error::Error>> {
+    let cache_root = tempfile::tempdir()?;
+    write_entry_file(cache_root.path(), "exited/weights.bin", 10, hours(1))?;
+    write_entry_file(cache_root.path(), "reused-pid/weights.bin", 10, hours(1))?;
+
+    let mut exited = std::process::Command::new("true").spawn()?;
+    let exited_pid = exited.id();
+    exited.wait()?;
+    write_stale_lease(&cache_root.path().join("exited"), &oliana_lib::launchers::PidRecord { pid: exited_pid, start_time: None, exe: None, output_to_log: false })?;
+    // Our own PID, but from a process which started at another time
+    write_stale_lease(&cache_root.path().join("reused-pid"), &oliana_lib::launchers::PidRecord { pid: std::process::id(), start_time: Some(1), exe: std::env::current_exe().ok(), output_to_log: false })?;
+
+    assert!(oliana_lib::files::list_cache_entries(cache_root.path())?.iter().all(|entry| entry.in_use_by.len() < 1));
+    assert_eq!(oliana_lib::files::prune_cache_entry(cache_root.path(), "exited")?, 10);
+    assert_eq!(oliana_lib::files::prune_cache_entry(cache_root.path(), "reused-pid")?, 10);
+    Ok(())
+}
+
+#[test]
+fn quota_evicts_least_recently_used_entries_first() -> Result<(), Box<dyn std::error::Error>> {
+    let cache_root = tempfile::tempdir()?;
+    write_entry_file(cache_root.path(), "models/oldest/weights.bin", 100, hours(40))?;
+    write_entry_file(cache_root.path(), "models/kept/weights.bin", 100, hours(30))?;
+    write_entry_file(cache_root.path(), "models/leased/weights.bin", 100, hours(20))?;
+    write_entry_file(cache_root.path(), "models/older/weights.bin", 100, hours(10))?;
+    write_entry_file(cache_root.path(), "models/newest/weights.bin", 100, hours(1))?;
+    let lease = oliana_lib::files::lease_cache_entry(cache_root.path().join("models/leased"))?;
+    // Taking the lease made it the most recently used; it is in use either way
+    let keep = vec!["models/kept".to_string()];
+
+    let pruned = oliana_lib::files::enforce_cache_quota(cache_root.path(), 500, &keep)?;
+    assert_eq!(pruned.len(), 0);
+
+    let pruned = oliana_lib::files::enforce_cache_quota(cache_root.path(), 300, &keep)?;
+    assert_eq!(entry_names(&pruned), vec!["models/oldest", "models/older"]);
+    assert_eq!(entry_names(&oliana_lib::files::list_cache_entries(cache_root.path())?), vec!["models/leased", "models/newest", "models/kept"]);
+
+    // Everything left is kept or in use, so this stays over quota
+    let pruned = oliana_lib::files::enforce_cache_quota(cache_root.path(), 0, &keep)?;
+    assert_eq!(entry_names(&pruned), vec!["models/newest"]);
+    assert_eq!(entry_names(&oliana_lib::files::list_cache_entries(cache_root.path())?), vec!["models/leased", "models/kept"]);
+
+    drop(lease);
+    let pruned = oliana_lib::files::enforce_cache_quota(cache_root.path(), 0, &keep)?;
+    assert_eq!(entry_names(&pruned), vec!["models/leased"]);
+    Ok(())
+}
+
+#[test]
+fn servers_keep_what_their_workers_load() -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = oliana_server_lib::config::ServerConfig::default();
+    assert_eq!(config.worker_cache_entries()?, vec![
+        "Oliana-Images-hf_home",
+        "Oliana-Images-site_packages",
+        "Oliana-Text-hf_home",
+        "models/etri-vilab--koala-lightning-1b",
+        "models/microsoft--Phi-3.5-mini-instruct",
+    ]);
+
+    config.image_backend = oliana_server_lib::config::BackendConfig::InProcess { generator: "mock".to_string() };
+    assert_eq!(config.worker_cache_entries()?, vec!["Oliana-Text-hf_home", "models/microsoft--Phi-3.5-mini-instruct"]);
+
+    let cache_root = tempfile::tempdir()?;
+    write_entry_file(cache_root.path(), "models/microsoft--Phi-3.5-mini-instruct/config.json", 100, hours(10))?;
+    write_entry_file(cache_root.path(), "models/etri-vilab--koala-lightning-1b/model_index.json", 100, hours(1))?;
+    assert_eq!(config.enforce_cache_quota(cache_root.path())?.len(), 0, "no cache_quota_bytes means no quota");
+    config.cache_quota_bytes = Some(150);
+    assert_eq!(config.enforce_cache_quota(cache_root.path())?, vec!["models/etri-vilab--koala-lightning-1b"]);
+    Ok(())
+}
+
+#[test]
+fn cache_root_comes_from_the_environment_then_a_portable_marker() -> Result<(), Box<dyn std::error::Error>> {
+    let bin_dir = tempfile::tempdir()?;
+    let exe = bin_dir.path().join("oliana_server");
+    let env_dir = tempfile::tempdir()?;
+
+    let default_dir = oliana_lib::files::resolve_cache_dir_from(None, Some(&exe))?;
+    assert!(default_dir.ends_with(oliana_lib::files::DEFAULT_CACHE_DIR_NAME), "{}", default_dir.display());
+
+    let marker = bin_dir.path().join(oliana_lib::files::PORTABLE_MARKER_FILE_NAME);
+    std::fs::write(&marker, "")?;
+    assert_eq!(oliana_lib::files::resolve_cache_dir_from(None, Some(&exe))?, bin_dir.path().join(oliana_lib::files::PORTABLE_CACHE_DIR_NAME));
+    std::fs::write(&marker, "data/cache\n")?;
+    assert_eq!(oliana_lib::files::resolve_cache_dir_from(None, Some(&exe))?, bin_dir.path().join("data/cache"));
+
+    // $OLIANA_CACHE_DIR beats the marker; an empty one is ignored
+    assert_eq!(oliana_lib::files::resolve_cache_dir_from(Some(env_dir.path().as_os_str()), Some(&exe))?, env_dir.path());
+    assert_eq!(oliana_lib::files::resolve_cache_dir_from(Some(std::ffi::OsStr::new("")), Some(&exe))?, bin_dir.path().join("data/cache"));
+    assert_eq!(oliana_lib::files::resolve_cache_dir_from(Some(std::ffi::OsStr::new("relative")), Some(&exe))?, std::env::current_dir()?.join("relative"));
+    Ok(())
+}
+
+#[test]
+fn servers_point_their_workers_at_their_cache_dir() -> Result<(), Box<dyn std::error::Error>> {
+    let track_proc_dir = tempfile::tempdir()?;
+    let mut config = oliana_server_lib::config::ServerConfig::default();
+    config.track_proc_dir = Some(track_proc_dir.path().to_path_buf());
+    config.bin_directory = Some(track_proc_dir.path().to_path_buf());
+    config.cache_dir = Some("nvme-cache".into());
+    assert_eq!(config.resolve_cache_dir()?, track_proc_dir.path().join("nvme-cache"));
+
+    let mut procs = oliana_lib::launchers::TrackedProcs::new(track_proc_dir.path(), track_proc_dir.path());
+    config.register_workers(&mut procs)?;
+    assert!(procs.tracked_proc_specs.len() > 0);
+    for spec in procs.tracked_proc_specs.iter() {
+        let cache_dir = spec.env.iter().rev().find(|(k, _)| k == oliana_lib::files::CACHE_DIR_ENV_VAR).map(|(_, v)| v.clone());
+        assert_eq!(cache_dir, Some(track_proc_dir.path().join("nvme-cache").to_string_lossy().to_string()), "{}", spec.bin_name);
+    }
+    Ok(())
+}
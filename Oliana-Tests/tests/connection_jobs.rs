@@ -0,0 +1,56 @@
+// The per-connection job bookkeeping of OlianaServer, which must not grow for as long as a client stays connected
+
+use oliana_server_lib::in_process_backend::KEEP_FINISHED_JOBS_FOR;
+use oliana_server_lib::{ImageJobId, ImageJobState, OlianaServer, TextJobId, TextJobState};
+
+fn connection() -> OlianaServer {
+    OlianaServer {
+        client_socket: (std::net::Ipv4Addr::LOCALHOST, 1234).into(),
+        client_key: "anonymous@127.0.0.1".to_string(),
+        shareable_procs: None,
+        text_backend: None,
+        image_backend: None,
+        text_jobs: Default::default(),
+        image_jobs: Default::default(),
+    }
+}
+
+fn long_ago() -> Option<std::time::Instant> {
+    std::time::Instant::now().checked_sub(KEEP_FINISHED_JOBS_FOR + std::time::Duration::from_secs(1))
+}
+
+#[test]
+fn finished_text_jobs_are_forgotten_after_keep_finished_jobs_for() {
+    let server = connection();
+    let Some(long_ago) = long_ago() else { return }; // The machine has not been up for that long
+    server.write_text_job_state(TextJobId(1), TextJobState { finished_at: Some(long_ago), ..Default::default() });
+    server.write_text_job_state(TextJobId(2), TextJobState { cancelled: true, finished_at: Some(long_ago), ..Default::default() });
+    server.write_text_job_state(TextJobId(3), TextJobState::default());
+    server.mark_text_job_finished(TextJobId(3));
+    server.write_text_job_state(TextJobId(4), TextJobState { next_byte_i: 12, ..Default::default() });
+
+    // Writing job 2 dropped job 1, writing job 4 dropped job 2; running and recently finished jobs stay
+    for (job, known) in [(1, false), (2, false), (3, true), (4, true)] {
+        assert_eq!(server.read_text_job_state(TextJobId(job)).is_some(), known, "job {}", job);
+    }
+    let finished_at = server.read_text_job_state(TextJobId(3)).and_then(|job_state| job_state.finished_at);
+    assert!(finished_at.is_some());
+    server.mark_text_job_finished(TextJobId(3));
+    assert_eq!(server.read_text_job_state(TextJobId(3)).and_then(|job_state| job_state.finished_at), finished_at, "the countdown only starts once");
+    assert_eq!(server.read_text_job_state(TextJobId(4)).and_then(|job_state| job_state.finished_at), None);
+}
+
+#[test]
+fn finished_image_jobs_are_forgotten_after_keep_finished_jobs_for() {
+    let server = connection();
+    let Some(long_ago) = long_ago() else { return };
+    server.write_image_job_state(ImageJobId(1), ImageJobState { cancelled: true, finished_at: Some(long_ago), ..Default::default() });
+    server.write_image_job_state(ImageJobId(2), ImageJobState { last_preview_step: 3, ..Default::default() });
+    server.mark_image_job_finished(ImageJobId(2));
+    server.write_image_job_state(ImageJobId(3), ImageJobState::default());
+
+    for (job, known) in [(1, false), (2, true), (3, true)] {
+        assert_eq!(server.read_image_job_state(ImageJobId(job)).is_some(), known, "job {}", job);
+    }
+    assert!(server.read_image_job_state(ImageJobId(2)).and_then(|job_state| job_state.finished_at).is_some());
+}
@@ -0,0 +1,214 @@
+// files::existinate() and existinate_from_mirrors() against local TestHttpServers standing in for model hosts: what ends up under the
+// real file name has to match the expected sha256 / size, however many retries, resumes and mirrors it took to get there.
+
+const MODEL_BYTES: &[u8] = b"Oliana model bytes\n";
+const MODEL_SHA256: &str = "07ae0f3e8c24277404e5c25b832b21d91ea7cc18852f019f441804fe0efb338f";
+
+fn model_server() -> Result<oliana_tests::TestHttpServer, Box<dyn std::error::Error>> {
+    serve_model(oliana_tests::TestHttpRoute::ok(MODEL_BYTES))
+}
+
+fn serve_model(route: oliana_tests::TestHttpRoute) -> Result<oliana_tests::TestHttpServer, Box<dyn std::error::Error>> {
+    let mut routes = std::collections::HashMap::new();
+    routes.insert("/model.bin".to_string(), route);
+    oliana_tests::TestHttpServer::start(routes)
+}
+
+fn fast_retries() -> oliana_lib::files::DownloadOptions {
+    oliana_lib::files::DownloadOptions {
+        max_attempts_per_mirror: 3,
+        initial_backoff: std::time::Duration::from_millis(50),
+        max_backoff: std::time::Duration::from_millis(100),
+        ..Default::default()
+    }
+}
+
+fn model_sha256() -> oliana_lib::files::ExpectedFile {
+    oliana_lib::files::ExpectedFile::sha256(MODEL_SHA256)
+}
+
+#[tokio::test]
+async fn downloads_are_verified_then_moved_into_place() -> Result<(), Box<dyn std::error::Error>> {
+    let server = model_server()?;
+    let dir = tempfile::tempdir()?;
+    let local_file = dir.path().join("model.bin");
+
+    let expected = oliana_lib::files::ExpectedFile::sha256(&MODEL_SHA256.to_uppercase()).with_size_bytes(MODEL_BYTES.len() as u64);
+    let path = oliana_lib::files::existinate(&local_file, &server.url_of("/model.bin"), Some(&expected)).await?;
+
+    assert_eq!(path, local_file);
+    assert_eq!(std::fs::read(&local_file)?, MODEL_BYTES);
+    assert!(!oliana_lib::files::get_partial_download_path(&local_file)?.exists());
+
+    // Already there and intact, so not downloaded again
+    oliana_lib::files::existinate(&local_file, &server.url_of("/model.bin"), Some(&expected)).await?;
+    assert_eq!(server.requests_for("/model.bin").len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn corrupt_files_are_downloaded_again() -> Result<(), Box<dyn std::error::Error>> {
+    let server = model_server()?;
+    let dir = tempfile::tempdir()?;
+    let local_file = dir.path().join("model.bin");
+    std::fs::write(&local_file, &MODEL_BYTES[..7])?; // Cut short by a crash
+
+    oliana_lib::files::existinate(&local_file, &server.url_of("/model.bin"), Some(&model_sha256())).await?;
+
+    assert_eq!(std::fs::read(&local_file)?, MODEL_BYTES);
+    assert_eq!(server.requests_for("/model.bin").len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn mismatched_downloads_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let server = model_server()?;
+    let dir = tempfile::tempdir()?;
+    let local_file = dir.path().join("model.bin");
+
+    let wrong_sha256 = "0".repeat(64);
+    let e = oliana_lib::files::existinate(&local_file, &server.url_of("/model.bin"), Some(&oliana_lib::files::ExpectedFile::sha256(&wrong_sha256))).await.unwrap_err();
+
+    match e.downcast_ref::<oliana_lib::files::IntegrityError>() {
+        Some(oliana_lib::files::IntegrityError::WrongSha256 { expected, actual, .. }) => {
+            assert_eq!(expected, &wrong_sha256);
+            assert_eq!(actual, MODEL_SHA256);
+        }
+        other => panic!("Expected WrongSha256, got {:?} ({})", other, e),
+    }
+    assert!(!local_file.exists());
+    assert!(!oliana_lib::files::get_partial_download_path(&local_file)?.exists());
+
+    let e = oliana_lib::files::existinate(&local_file, &server.url_of("/model.bin"), Some(&oliana_lib::files::ExpectedFile::default().with_size_bytes(1))).await.unwrap_err();
+    assert!(matches!(e.downcast_ref::<oliana_lib::files::IntegrityError>(), Some(oliana_lib::files::IntegrityError::WrongSize { expected: 1, .. })), "{}", e);
+    assert!(!local_file.exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn error_pages_are_not_saved() -> Result<(), Box<dyn std::error::Error>> {
+    let server = model_server()?;
+    let dir = tempfile::tempdir()?;
+    let local_file = dir.path().join("model.bin");
+
+    // No expected hash to catch it; the 404 alone has to
+    assert!(oliana_lib::files::existinate(&local_file, &server.url_of("/missing.bin"), None).await.is_err());
+
+    assert!(!local_file.exists());
+    assert!(!oliana_lib::files::get_partial_download_path(&local_file)?.exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_errors_are_retried_with_backoff() -> Result<(), Box<dyn std::error::Error>> {
+    let server = serve_model(oliana_tests::TestHttpRoute::ok(MODEL_BYTES).with_failures(2))?;
+    let dir = tempfile::tempdir()?;
+    let local_file = dir.path().join("model.bin");
+
+    let start = std::time::Instant::now();
+    oliana_lib::files::existinate_from_mirrors(&local_file, &[server.url_of("/model.bin")], Some(&model_sha256()), &fast_retries()).await?;
+
+    assert_eq!(std::fs::read(&local_file)?, MODEL_BYTES);
+    assert_eq!(server.requests_for("/model.bin").len(), 3);
+    assert!(start.elapsed() >= std::time::Duration::from_millis(50 + 100));
+    Ok(())
+}
+
+#[tokio::test]
+async fn dropped_connections_are_resumed() -> Result<(), Box<dyn std::error::Error>> {
+    let server = serve_model(oliana_tests::TestHttpRoute::ok(MODEL_BYTES).with_dropped_connections(2, 5))?;
+    let dir = tempfile::tempdir()?;
+    let local_file = dir.path().join("model.bin");
+
+    oliana_lib::files::existinate_from_mirrors(&local_file, &[server.url_of("/model.bin")], Some(&model_sha256()), &fast_retries()).await?;
+
+    assert_eq!(std::fs::read(&local_file)?, MODEL_BYTES);
+    let range_starts: Vec<Option<u64>> = server.requests_for("/model.bin").iter().map(|r| r.range_start).collect();
+    assert_eq!(range_starts, vec![None, Some(5), Some(10)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn partial_downloads_from_a_previous_run_are_resumed() -> Result<(), Box<dyn std::error::Error>> {
+    let server = model_server()?;
+    let dir = tempfile::tempdir()?;
+    let local_file = dir.path().join("model.bin");
+    std::fs::write(oliana_lib::files::get_partial_download_path(&local_file)?, &MODEL_BYTES[..7])?;
+
+    oliana_lib::files::existinate(&local_file, &server.url_of("/model.bin"), Some(&model_sha256())).await?;
+
+    assert_eq!(std::fs::read(&local_file)?, MODEL_BYTES);
+    assert_eq!(server.requests_for("/model.bin")[0].range_start, Some(7));
+
+    // A partial file which turns out to be complete already only needs verifying
+    std::fs::remove_file(&local_file)?;
+    std::fs::write(oliana_lib::files::get_partial_download_path(&local_file)?, MODEL_BYTES)?;
+    oliana_lib::files::existinate(&local_file, &server.url_of("/model.bin"), Some(&model_sha256())).await?;
+    assert_eq!(std::fs::read(&local_file)?, MODEL_BYTES);
+    Ok(())
+}
+
+#[tokio::test]
+async fn servers_without_range_support_start_over() -> Result<(), Box<dyn std::error::Error>> {
+    let server = serve_model(oliana_tests::TestHttpRoute::ok(MODEL_BYTES).without_range_support())?;
+    let dir = tempfile::tempdir()?;
+    let local_file = dir.path().join("model.bin");
+    std::fs::write(oliana_lib::files::get_partial_download_path(&local_file)?, &MODEL_BYTES[..7])?;
+
+    oliana_lib::files::existinate(&local_file, &server.url_of("/model.bin"), Some(&model_sha256())).await?;
+
+    assert_eq!(std::fs::read(&local_file)?, MODEL_BYTES);
+    Ok(())
+}
+
+#[tokio::test]
+async fn mirrors_are_tried_in_order() -> Result<(), Box<dyn std::error::Error>> {
+    let broken_server = serve_model(oliana_tests::TestHttpRoute::ok(MODEL_BYTES).with_failures(usize::MAX))?;
+    let corrupt_server = serve_model(oliana_tests::TestHttpRoute::ok(b"<html>Moved</html>"))?;
+    let good_server = model_server()?;
+    let unused_server = model_server()?;
+    let dir = tempfile::tempdir()?;
+    let local_file = dir.path().join("model.bin");
+
+    let mirrors = [
+        good_server.url_of("/missing.bin"),
+        broken_server.url_of("/model.bin"),
+        corrupt_server.url_of("/model.bin"),
+        good_server.url_of("/model.bin"),
+        unused_server.url_of("/model.bin"),
+    ];
+    oliana_lib::files::existinate_from_mirrors(&local_file, &mirrors, Some(&model_sha256()), &fast_retries()).await?;
+
+    assert_eq!(std::fs::read(&local_file)?, MODEL_BYTES);
+    assert_eq!(good_server.requests_for("/missing.bin").len(), 1); // A 404 is not retried
+    assert_eq!(broken_server.requests_for("/model.bin").len(), fast_retries().max_attempts_per_mirror as usize);
+    assert_eq!(corrupt_server.requests_for("/model.bin").len(), 1);
+    assert_eq!(good_server.requests_for("/model.bin").len(), 1);
+    assert_eq!(unused_server.requests_for("/model.bin").len(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_mirrors_seed_air_gapped_hosts() -> Result<(), Box<dyn std::error::Error>> {
+    let seed_dir = tempfile::tempdir()?; // ie a USB stick
+    std::fs::write(seed_dir.path().join("model.bin"), MODEL_BYTES)?;
+    let dir = tempfile::tempdir()?;
+
+    let nowhere = "http://127.0.0.1:9/model.bin"; // The discard port; nothing listens there
+    let from_dir = dir.path().join("from-dir").join("model.bin");
+    oliana_lib::files::existinate_from_mirrors(&from_dir, &[seed_dir.path().to_string_lossy().to_string()], Some(&model_sha256()), &fast_retries()).await?;
+    assert_eq!(std::fs::read(&from_dir)?, MODEL_BYTES);
+
+    let from_file_url = dir.path().join("from-file-url").join("model.bin");
+    let file_url = format!("file://{}", seed_dir.path().join("model.bin").display());
+    oliana_lib::files::existinate_from_mirrors(&from_file_url, &[file_url], Some(&model_sha256()), &fast_retries()).await?;
+    assert_eq!(std::fs::read(&from_file_url)?, MODEL_BYTES);
+
+    // A seed directory without the file is skipped like any other mirror that lacks it
+    let empty_seed_dir = tempfile::tempdir()?;
+    let from_fallback = dir.path().join("from-fallback").join("model.bin");
+    let mirrors = [empty_seed_dir.path().to_string_lossy().to_string(), seed_dir.path().to_string_lossy().to_string(), nowhere.to_string()];
+    oliana_lib::files::existinate_from_mirrors(&from_fallback, &mirrors, Some(&model_sha256()), &fast_retries()).await?;
+    assert_eq!(std::fs::read(&from_fallback)?, MODEL_BYTES);
+    Ok(())
+}
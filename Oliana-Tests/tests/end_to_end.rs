@@ -0,0 +1,326 @@
+
+// Every test starts its own TestServer, so they can run in parallel and a crashed worker in one never affects another.
+
+use oliana_server_lib::{ImageJobId, TextJobId};
+use oliana_tests::{JobPriority, JobStatus, OlianaClient, OlianaError, TestServer, TestServerOptions};
+
+fn options() -> TestServerOptions {
+    TestServerOptions::new(env!("CARGO_BIN_EXE_oliana_mock_worker"))
+}
+
+fn expected_text(server: &TestServer, system_prompt: &str, user_prompt: &str) -> String {
+    oliana_lib::mock::mock_text_tokens(0, system_prompt, user_prompt, server.options.text_token_delay).into_iter().map(|(_delay, token)| token).collect()
+}
+
+// Polls the job's status until `ready` accepts it
+async fn wait_for_text_status<F: Fn(&JobStatus) -> bool>(client: &OlianaClient, job: TextJobId, ready: F) -> Result<JobStatus, Box<dyn std::error::Error>> {
+    let start = std::time::Instant::now();
+    loop {
+        let status = client.generate_text_status(tarpc::context::current(), job).await??;
+        if ready(&status) || start.elapsed() > oliana_tests::TEST_TIMEOUT {
+            return Ok(status);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+}
+
+async fn wait_for_image_status<F: Fn(&JobStatus) -> bool>(client: &OlianaClient, job: ImageJobId, ready: F) -> Result<JobStatus, Box<dyn std::error::Error>> {
+    let start = std::time::Instant::now();
+    loop {
+        let status = client.generate_image_status(tarpc::context::current(), job).await??;
+        if ready(&status) || start.elapsed() > oliana_tests::TEST_TIMEOUT {
+            return Ok(status);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+}
+
+// True if the worker's log mentions the .json of job `nonce` on a line containing `what` (ie "Processing", "Cancelled")
+async fn worker_logged(client: &OlianaClient, bin_name: &str, what: &str, nonce: u64) -> Result<bool, Box<dyn std::error::Error>> {
+    let lines = client.worker_log_tail(tarpc::context::current(), bin_name.to_string(), oliana_server_lib::MAX_LOG_TAIL_LINES).await??;
+    Ok(lines.iter().any(|line| line.contains(what) && line.contains(&format!("{}{}.json", std::path::MAIN_SEPARATOR, nonce))))
+}
+
+async fn wait_for_worker_log(client: &OlianaClient, bin_name: &str, what: &str, nonce: u64) -> Result<bool, Box<dyn std::error::Error>> {
+    let start = std::time::Instant::now();
+    while !worker_logged(client, bin_name, what, nonce).await? {
+        if start.elapsed() > oliana_tests::TEST_TIMEOUT {
+            return Ok(false);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    Ok(true)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn text_is_streamed_token_by_token() -> Result<(), Box<dyn std::error::Error>> {
+    let server = TestServer::start(options()).await?;
+    let client = server.client().await?;
+
+    let job = client.generate_text_begin(tarpc::context::current(), "You are a test.".to_string(), "Say something".to_string(), JobPriority::default()).await??;
+    let mut tokens: Vec<String> = vec![];
+    while let Some(token) = client.generate_text_next_token(tarpc::context::current(), job).await?? {
+        tokens.push(token);
+    }
+
+    assert!(tokens.len() > 1, "expected several tokens, got {:?}", tokens);
+    assert_eq!(tokens.concat(), expected_text(&server, "You are a test.", "Say something"));
+    assert_eq!(client.generate_text_status(tarpc::context::current(), job).await??, JobStatus::Done);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn image_previews_then_png_are_returned() -> Result<(), Box<dyn std::error::Error>> {
+    let server = TestServer::start(options()).await?;
+    let client = server.client().await?;
+
+    let num_steps = 4;
+    let job = client.generate_image_begin(tarpc::context::current(), "A cow".to_string(), "".to_string(), 3.5, num_steps, JobPriority::default()).await??;
+    let mut preview_steps: Vec<u32> = vec![];
+    while let Some(preview) = client.generate_image_next_preview(tarpc::context::current(), job).await?? {
+        assert_eq!(preview.num_steps, num_steps);
+        assert!(preview.png_bytes.starts_with(b"\x89PNG"));
+        preview_steps.push(preview.step);
+    }
+    let png_bytes = client.generate_image_get_result(tarpc::context::current(), job).await??;
+
+    assert!(preview_steps.len() > 0, "expected at least one preview");
+    assert!(preview_steps.windows(2).all(|w| w[0] < w[1]), "previews out of order: {:?}", preview_steps);
+    assert_eq!(png_bytes, oliana_lib::mock::mock_image_png(0, "A cow", "", oliana_lib::mock::MOCK_IMAGE_SIZE, 0.0));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn crashed_worker_is_respawned() -> Result<(), Box<dyn std::error::Error>> {
+    let server = TestServer::start(options()).await?;
+    let before = server.worker_status(oliana_server_lib::TEXT_WORKER_BIN_NAME).await?;
+    let old_pid = before.pid.ok_or("oliana_text has no pid")?;
+
+    server.kill_worker(old_pid)?;
+    let after = server.wait_for_worker(oliana_server_lib::TEXT_WORKER_BIN_NAME, |w| w.running && w.model_loaded && w.pid.is_some() && w.pid != Some(old_pid)).await?;
+    assert_eq!(after.restarts, before.restarts + 1);
+    #[cfg(unix)]
+    assert_eq!(after.last_exit.as_deref(), Some("killed by signal 9"));
+
+    // The new worker picks up work as usual
+    let client = server.client().await?;
+    assert_eq!(oliana_tests::generate_text(&client, "", "After the crash").await?, expected_text(&server, "", "After the crash"));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_workers_time_out_without_losing_the_job() -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = options();
+    options.text_token_delay = std::time::Duration::from_secs(10); // The first token takes 3x to 9x this, far longer than any RPC waits
+    let server = TestServer::start(options).await?;
+    let client = server.client().await?;
+    let job = client.generate_text_begin(tarpc::context::current(), "".to_string(), "Take your time".to_string(), JobPriority::default()).await??;
+
+    // The server cuts its wait short to answer before the client's deadline, with an error saying to ask again...
+    let mut context = tarpc::context::current();
+    context.deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+    let start = std::time::Instant::now();
+    match client.generate_text_next_token(context, job).await? {
+        Err(e @ OlianaError::TimedOut { .. }) => assert!(e.is_retryable()),
+        other => panic!("expected OlianaError::TimedOut, got {:?}", other),
+    }
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+
+    // ...including tarpc's default deadline, which is shorter than the server's own longest wait
+    match client.generate_text_next_token(tarpc::context::current(), job).await? {
+        Err(OlianaError::TimedOut { .. }) => { }
+        other => panic!("expected OlianaError::TimedOut, got {:?}", other),
+    }
+
+    // Neither timeout affected the job itself
+    assert_eq!(client.generate_text_status(tarpc::context::current(), job).await??, JobStatus::Running);
+    client.cancel_text(tarpc::context::current(), job).await??;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_clients_get_their_own_results() -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = options();
+    options.max_concurrent_jobs = 2;
+    let server = TestServer::start(options).await?;
+
+    // Each client runs on its own connection and all of them wait on the server at once
+    let user_prompts: Vec<String> = (0..4).map(|client_i| format!("Client number {}", client_i)).collect();
+    let texts = futures::future::join_all(user_prompts.iter().map(|user_prompt| async {
+        let client = server.client().await?;
+        oliana_tests::generate_text(&client, "", user_prompt).await
+    })).await;
+    for (user_prompt, text) in user_prompts.iter().zip(texts) {
+        assert_eq!(text?, expected_text(&server, "", user_prompt));
+    }
+
+    // Job ids belong to the connection that began them
+    let owner = server.client().await?;
+    let stranger = server.client().await?;
+    let job = owner.generate_text_begin(tarpc::context::current(), "".to_string(), "Mine".to_string(), JobPriority::default()).await??;
+    assert_eq!(stranger.generate_text_next_token(tarpc::context::current(), job).await?, Err(OlianaError::UnknownJob { job: job.0 }));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn worker_output_is_logged_and_tailed() -> Result<(), Box<dyn std::error::Error>> {
+    let server = TestServer::start(options()).await?;
+    let client = server.client().await?;
+    oliana_tests::generate_text(&client, "", "Leave a trace").await?;
+
+    let lines = client.worker_log_tail(tarpc::context::current(), oliana_server_lib::TEXT_WORKER_BIN_NAME.to_string(), 100).await??;
+    assert!(lines.iter().any(|line| line.contains(" [launcher] Spawned PID ")), "{:?}", lines);
+    assert!(lines.iter().any(|line| line.contains(" [stdout] Processing ") && line.contains(".json")), "{:?}", lines);
+    assert!(lines.iter().all(|line| line.starts_with("20") && line.contains("Z [")), "{:?}", lines);
+
+    let last_two = client.worker_log_tail(tarpc::context::current(), oliana_server_lib::TEXT_WORKER_BIN_NAME.to_string(), 2).await??;
+    assert_eq!(last_two, lines[lines.len()-2..].to_vec());
+
+    match client.worker_log_tail(tarpc::context::current(), "no_such_worker".to_string(), 10).await? {
+        Err(OlianaError::BadInput { .. }) => { }
+        other => panic!("expected OlianaError::BadInput, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hung_workers_are_restarted_and_their_jobs_failed() -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = options();
+    // The mock workers beat once per token / step, so delays far past the timeout look like workers stuck mid-job
+    options.text_token_delay = std::time::Duration::from_secs(20);
+    options.image_step_delay = std::time::Duration::from_secs(20);
+    options.heartbeat_timeout_secs = 2;
+    let server = TestServer::start(options).await?;
+    let client = server.client().await?;
+    let text_before = server.worker_status(oliana_server_lib::TEXT_WORKER_BIN_NAME).await?;
+    let image_before = server.worker_status(oliana_server_lib::IMAGE_WORKER_BIN_NAME).await?;
+
+    let text_job = client.generate_text_begin(tarpc::context::current(), "".to_string(), "Never finished".to_string(), JobPriority::default()).await??;
+    let image_job = client.generate_image_begin(tarpc::context::current(), "Never finished".to_string(), "".to_string(), 3.5, 4, JobPriority::default()).await??;
+    server.wait_for_worker(oliana_server_lib::TEXT_WORKER_BIN_NAME, |w| w.busy_with_job == Some(format!("{}", text_job.0))).await?;
+
+    // Waiting callers are woken with the reason instead of timing out forever
+    let text_error = loop {
+        match client.generate_text_next_token(tarpc::context::current(), text_job).await? {
+            Err(OlianaError::TimedOut { .. }) => continue,
+            other => break other,
+        }
+    };
+    match text_error {
+        Err(OlianaError::BackendTraceback { worker, traceback, report }) => {
+            assert_eq!(worker, oliana_server_lib::TEXT_WORKER_BIN_NAME);
+            assert!(traceback.contains("heartbeat"), "{}", traceback);
+            assert_eq!(report.map(|report| report.kind), Some(oliana_lib::err::ErrorKind::WorkerFailed));
+        }
+        other => panic!("expected OlianaError::BackendTraceback, got {:?}", other),
+    }
+    assert_eq!(client.generate_text_status(tarpc::context::current(), text_job).await??, JobStatus::Failed);
+
+    loop {
+        match client.generate_image_next_preview(tarpc::context::current(), image_job).await? {
+            Err(OlianaError::TimedOut { .. }) => continue,
+            other => {
+                assert_eq!(other, Ok(None));
+                break;
+            }
+        }
+    }
+    match client.generate_image_get_result(tarpc::context::current(), image_job).await? {
+        Err(OlianaError::BackendTraceback { worker, .. }) => assert_eq!(worker, oliana_server_lib::IMAGE_WORKER_BIN_NAME),
+        other => panic!("expected OlianaError::BackendTraceback, got {:?}", other),
+    }
+    assert_eq!(client.generate_image_status(tarpc::context::current(), image_job).await??, JobStatus::Failed);
+
+    for (bin_name, before) in [(oliana_server_lib::TEXT_WORKER_BIN_NAME, text_before), (oliana_server_lib::IMAGE_WORKER_BIN_NAME, image_before)] {
+        let after = server.wait_for_worker(bin_name, |w| w.running && w.model_loaded && w.pid.is_some() && w.pid != before.pid).await?;
+        assert_eq!(after.restarts, before.restarts + 1);
+        assert_eq!(after.last_exit.as_deref(), Some("killed after missing heartbeats"));
+        assert_eq!(after.busy_with_job, None);
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancelled_queued_jobs_never_reach_the_worker() -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = options();
+    // Slow enough that the first job of each kind holds the only slot for the whole test
+    options.text_token_delay = std::time::Duration::from_millis(500);
+    options.image_step_delay = std::time::Duration::from_millis(500);
+    let server = TestServer::start(options).await?;
+    let client = server.client().await?;
+
+    let running_text = client.generate_text_begin(tarpc::context::current(), "".to_string(), "Keep the worker busy".to_string(), JobPriority::default()).await??;
+    wait_for_text_status(&client, running_text, |status| !matches!(status, JobStatus::Queued { .. })).await?;
+    let queued_text = client.generate_text_begin(tarpc::context::current(), "".to_string(), "Never run".to_string(), JobPriority::default()).await??;
+    assert_eq!(client.generate_text_status(tarpc::context::current(), queued_text).await??, JobStatus::Queued { position: 1 });
+    client.cancel_text(tarpc::context::current(), queued_text).await??;
+    assert_eq!(client.generate_text_status(tarpc::context::current(), queued_text).await??, JobStatus::Cancelled);
+    assert_eq!(client.generate_text_next_token(tarpc::context::current(), queued_text).await?, Err(OlianaError::Cancelled { job: queued_text.0 }));
+
+    let running_image = client.generate_image_begin(tarpc::context::current(), "Keep the worker busy".to_string(), "".to_string(), 3.5, 50, JobPriority::default()).await??;
+    wait_for_image_status(&client, running_image, |status| !matches!(status, JobStatus::Queued { .. })).await?;
+    let queued_image = client.generate_image_begin(tarpc::context::current(), "Never run".to_string(), "".to_string(), 3.5, 4, JobPriority::default()).await??;
+    assert_eq!(client.generate_image_status(tarpc::context::current(), queued_image).await??, JobStatus::Queued { position: 1 });
+    client.cancel_image(tarpc::context::current(), queued_image).await??;
+    assert_eq!(client.generate_image_status(tarpc::context::current(), queued_image).await??, JobStatus::Cancelled);
+    assert_eq!(client.generate_image_next_preview(tarpc::context::current(), queued_image).await?, Err(OlianaError::Cancelled { job: queued_image.0 }));
+    assert_eq!(client.generate_image_get_result(tarpc::context::current(), queued_image).await?, Err(OlianaError::Cancelled { job: queued_image.0 }));
+
+    // Stopping the running jobs frees the slots, and the cancelled jobs are not what takes them
+    client.cancel_text(tarpc::context::current(), running_text).await??;
+    client.cancel_image(tarpc::context::current(), running_image).await??;
+    let after_text = client.generate_text_begin(tarpc::context::current(), "".to_string(), "After the cancels".to_string(), JobPriority::default()).await??;
+    let after_image = client.generate_image_begin(tarpc::context::current(), "After the cancels".to_string(), "".to_string(), 3.5, 4, JobPriority::default()).await??;
+    assert!(wait_for_worker_log(&client, oliana_server_lib::TEXT_WORKER_BIN_NAME, "Processing", after_text.0).await?);
+    assert!(wait_for_worker_log(&client, oliana_server_lib::IMAGE_WORKER_BIN_NAME, "Processing", after_image.0).await?);
+    assert!(!worker_logged(&client, oliana_server_lib::TEXT_WORKER_BIN_NAME, "", queued_text.0).await?);
+    assert!(!worker_logged(&client, oliana_server_lib::IMAGE_WORKER_BIN_NAME, "", queued_image.0).await?);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancelled_running_jobs_stop_early_and_free_their_slot() -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = options();
+    options.text_token_delay = std::time::Duration::from_millis(100); // At least 10 tokens, so a second or more is left after the first
+    options.image_step_delay = std::time::Duration::from_millis(100);
+    let server = TestServer::start(options).await?;
+    let client = server.client().await?;
+
+    let cancelled_text = client.generate_text_begin(tarpc::context::current(), "".to_string(), "Stop me".to_string(), JobPriority::default()).await??;
+    let next_text = client.generate_text_begin(tarpc::context::current(), "".to_string(), "Run after".to_string(), JobPriority::default()).await??;
+    let first_token = client.generate_text_next_token(tarpc::context::current(), cancelled_text).await??.ok_or("no first token")?;
+    assert!(expected_text(&server, "", "Stop me").starts_with(&first_token));
+    assert_eq!(client.generate_text_status(tarpc::context::current(), cancelled_text).await??, JobStatus::Running);
+    assert_eq!(client.generate_text_status(tarpc::context::current(), next_text).await??, JobStatus::Queued { position: 1 });
+    client.cancel_text(tarpc::context::current(), cancelled_text).await??;
+    assert_eq!(client.generate_text_status(tarpc::context::current(), cancelled_text).await??, JobStatus::Cancelled);
+    assert_eq!(client.generate_text_next_token(tarpc::context::current(), cancelled_text).await?, Err(OlianaError::Cancelled { job: cancelled_text.0 }));
+
+    // The worker gives up on the cancelled job, which hands its slot to the next one
+    assert_eq!(oliana_tests::generate_text_of(&client, next_text).await?, expected_text(&server, "", "Run after"));
+    assert!(wait_for_worker_log(&client, oliana_server_lib::TEXT_WORKER_BIN_NAME, "Cancelled", cancelled_text.0).await?);
+    // Cancelling a finished job changes nothing
+    client.cancel_text(tarpc::context::current(), next_text).await??;
+    assert_eq!(client.generate_text_status(tarpc::context::current(), next_text).await??, JobStatus::Done);
+
+    let cancelled_image = client.generate_image_begin(tarpc::context::current(), "Stop me".to_string(), "".to_string(), 3.5, 50, JobPriority::default()).await??;
+    let next_image = client.generate_image_begin(tarpc::context::current(), "Run after".to_string(), "".to_string(), 3.5, 2, JobPriority::default()).await??;
+    client.generate_image_next_preview(tarpc::context::current(), cancelled_image).await??.ok_or("no first preview")?;
+    assert_eq!(client.generate_image_status(tarpc::context::current(), cancelled_image).await??, JobStatus::Running);
+    assert_eq!(client.generate_image_status(tarpc::context::current(), next_image).await??, JobStatus::Queued { position: 1 });
+    client.cancel_image(tarpc::context::current(), cancelled_image).await??;
+    assert_eq!(client.generate_image_status(tarpc::context::current(), cancelled_image).await??, JobStatus::Cancelled);
+    assert_eq!(client.generate_image_next_preview(tarpc::context::current(), cancelled_image).await?, Err(OlianaError::Cancelled { job: cancelled_image.0 }));
+    assert_eq!(client.generate_image_get_result(tarpc::context::current(), cancelled_image).await?, Err(OlianaError::Cancelled { job: cancelled_image.0 }));
+
+    // 50 steps would take 5 seconds; the next job is done well before that
+    let start = std::time::Instant::now();
+    let png_bytes = client.generate_image_get_result(tarpc::context::current(), next_image).await??;
+    assert_eq!(png_bytes, oliana_lib::mock::mock_image_png(0, "Run after", "", oliana_lib::mock::MOCK_IMAGE_SIZE, 0.0));
+    assert!(start.elapsed() < std::time::Duration::from_secs(4), "{:?}", start.elapsed());
+    assert!(wait_for_worker_log(&client, oliana_server_lib::IMAGE_WORKER_BIN_NAME, "Cancelled", cancelled_image.0).await?);
+    client.cancel_image(tarpc::context::current(), next_image).await??;
+    assert_eq!(client.generate_image_status(tarpc::context::current(), next_image).await??, JobStatus::Done);
+    assert_eq!(client.generate_image_get_result(tarpc::context::current(), next_image).await??, png_bytes);
+    Ok(())
+}
@@ -0,0 +1,95 @@
+// oliana_lib::err: LocatedError chains flattened into ErrorReports, and ErrorReports making it through a worker's <job>.failed file.
+
+fn read_missing_file(path: &std::path::Path) -> Result<String, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path).map_err(oliana_lib::eloc!(format!("Reading {}", path.display())))?;
+    Ok(contents)
+}
+
+fn load_settings(path: &std::path::Path) -> Result<String, Box<dyn std::error::Error>> {
+    let settings = read_missing_file(path).map_err(oliana_lib::eloc!())?;
+    Ok(settings)
+}
+
+#[test]
+fn reports_keep_every_location_an_error_passed() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let e = load_settings(&dir.path().join("settings.toml")).expect_err("read a file which does not exist");
+
+    let report = oliana_lib::err::ErrorReport::from_error(&*e);
+    assert_eq!(report.kind, oliana_lib::err::ErrorKind::NotFound);
+    assert_eq!(report.message, std::fs::read_to_string(dir.path().join("settings.toml")).expect_err("still missing").to_string());
+    assert_eq!(report.contexts.len(), 2);
+    assert!(report.contexts.iter().all(|context| context.file.ends_with("errors.rs")), "{:?}", report.contexts);
+    assert!(report.contexts[0].msg.starts_with("Reading "), "{:?}", report.contexts[0]);
+    assert!(report.contexts[0].line < report.contexts[1].line);
+    assert_eq!(report.location(), Some(format!("{}:{}:{}", report.contexts[0].file, report.contexts[0].line, report.contexts[0].column)));
+    // Prints just like the chain it came from
+    assert_eq!(report.to_string(), e.to_string());
+    Ok(())
+}
+
+#[test]
+fn kinds_can_be_set_where_errors_are_located() -> Result<(), Box<dyn std::error::Error>> {
+    let e: Result<(), _> = Err("no such model").map_err(oliana_lib::eloc!(kind = oliana_lib::err::ErrorKind::MissingArtifacts));
+    let e = e.expect_err("is an error");
+    assert_eq!(e.kind, oliana_lib::err::ErrorKind::MissingArtifacts);
+    assert_eq!(e.report().kind, oliana_lib::err::ErrorKind::MissingArtifacts);
+
+    // Wrapping again keeps the kind of what was wrapped
+    let wrapped = oliana_lib::err::LocatedError::new(Box::new(e), file!(), line!(), column!(), "Loading".to_string());
+    assert_eq!(wrapped.kind, oliana_lib::err::ErrorKind::MissingArtifacts);
+    assert_eq!(wrapped.report().contexts.len(), 2);
+
+    assert_eq!(oliana_lib::err::ErrorKind::of(&oliana_lib::files::CacheEntryInUse { name: "models/tiny".to_string(), pids: vec![1] }), oliana_lib::err::ErrorKind::InUse);
+    assert_eq!(oliana_lib::err::ErrorKind::of(&*Box::<dyn std::error::Error>::from("anything")), oliana_lib::err::ErrorKind::Other);
+    assert_eq!(oliana_lib::err::ErrorKind::WorkerFailed.code(), "worker_failed");
+    Ok(())
+}
+
+#[test]
+fn reports_survive_the_workdir_protocol() -> Result<(), Box<dyn std::error::Error>> {
+    let workdir = tempfile::tempdir()?;
+    let e = load_settings(&workdir.path().join("settings.toml")).expect_err("read a file which does not exist");
+    let mut report = oliana_lib::err::ErrorReport::from_error(&*e);
+    report.backtrace = Some("0: oliana_text::main".to_string());
+
+    oliana_lib::launchers::write_job_failed(workdir.path(), "42", &report)?;
+    assert!(workdir.path().join("42.done").exists());
+    let failed_file = workdir.path().join(format!("42.{}", oliana_lib::launchers::FAILED_JOB_EXTENSION));
+    assert_eq!(oliana_lib::launchers::parse_job_failed(&std::fs::read_to_string(&failed_file)?), report);
+
+    // A worker's report wrapped again on the server keeps the worker's contexts innermost
+    let relayed = oliana_lib::err::LocatedError::new(Box::new(report.clone()), file!(), line!(), column!(), String::new()).report();
+    assert_eq!(relayed.kind, report.kind);
+    assert_eq!(relayed.message, report.message);
+    assert_eq!(relayed.contexts[..2], report.contexts[..]);
+    assert_eq!(relayed.contexts.len(), 3);
+    assert_eq!(relayed.backtrace, report.backtrace);
+
+    // What older servers wrote, and kinds this build does not know
+    let legacy = oliana_lib::launchers::parse_job_failed("oliana_text stopped responding\n");
+    assert_eq!(legacy, oliana_lib::err::ErrorReport::new(oliana_lib::err::ErrorKind::WorkerFailed, "oliana_text stopped responding"));
+    let newer = oliana_lib::launchers::parse_job_failed(r#"{"kind": "gpu_lost", "message": "CUDA error"}"#);
+    assert_eq!(newer, oliana_lib::err::ErrorReport::new(oliana_lib::err::ErrorKind::Other, "CUDA error"));
+    Ok(())
+}
+
+#[test]
+fn rpc_errors_carry_the_report() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let e = read_missing_file(&dir.path().join("settings.toml")).expect_err("read a file which does not exist");
+    let located = e.downcast::<oliana_lib::err::LocatedError>().map_err(|e| format!("not a LocatedError: {}", e))?;
+    let report = located.report();
+
+    let rpc_error = oliana_server_lib::OlianaError::from(*located);
+    assert_eq!(rpc_error.kind(), oliana_lib::err::ErrorKind::NotFound);
+    match rpc_error {
+        oliana_server_lib::OlianaError::Internal { location, report: Some(rpc_report), .. } => {
+            assert_eq!(Some(location), report.location());
+            assert_eq!(*rpc_report, report);
+        }
+        other => panic!("expected OlianaError::Internal with a report, got {:?}", other),
+    }
+    assert_eq!(oliana_server_lib::OlianaError::TimedOut { worker: "oliana_text".to_string(), waited_ms: 1 }.kind(), oliana_lib::err::ErrorKind::Timeout);
+    Ok(())
+}
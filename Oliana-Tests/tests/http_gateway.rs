@@ -0,0 +1,137 @@
+// The OpenAI-compatible HTTP gateway of a TestServer, talked to with plain HTTP/1.1 requests
+
+use oliana_tests::{TestServer, TestServerOptions};
+
+const TOKEN: &str = "gateway-test-token";
+
+async fn start_server() -> Result<TestServer, Box<dyn std::error::Error>> {
+    let mut options = TestServerOptions::new(env!("CARGO_BIN_EXE_oliana_mock_worker"));
+    options.auth_tokens.insert("alice".to_string(), TOKEN.to_string());
+    options.http_gateway = true;
+    TestServer::start(options).await
+}
+
+fn expected_text(server: &TestServer, system_prompt: &str, user_prompt: &str) -> String {
+    oliana_lib::mock::mock_text_tokens(0, system_prompt, user_prompt, server.options.text_token_delay).into_iter().map(|(_delay, token)| token).collect()
+}
+
+fn chat_request(system_prompt: &str, user_prompt: &str, stream: bool) -> serde_json::Value {
+    serde_json::json!({
+        "model": oliana_server_lib::http_gateway::TEXT_MODEL_ID,
+        "messages": [
+            { "role": "system", "content": system_prompt },
+            { "role": "user", "content": user_prompt },
+        ],
+        "stream": stream,
+    })
+}
+
+// The data: of every server-sent event in `body`, in order; comments (ie keep-alives) are skipped
+fn sse_data(body: &[u8]) -> Vec<String> {
+    let body = String::from_utf8_lossy(body).replace("\r\n", "\n");
+    body.split("\n\n")
+        .filter_map(|event| {
+            let data: Vec<&str> = event.lines().filter_map(|line| line.strip_prefix("data:")).map(|data| data.strip_prefix(' ').unwrap_or(data)).collect();
+            if data.len() > 0 { Some(data.join("\n")) } else { None }
+        })
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_without_a_known_bearer_token_get_401() -> Result<(), Box<dyn std::error::Error>> {
+    let server = start_server().await?;
+    let request = chat_request("You are a test.", "Hello", false);
+
+    for bearer_token in [None, Some("not-the-token")] {
+        let reply = server.http_request("POST", "/v1/chat/completions", bearer_token, Some(&request)).await?;
+        assert_eq!(reply.status, 401, "token {:?}", bearer_token);
+        assert_eq!(reply.json()?["error"]["code"], 401);
+        let reply = server.http_request("GET", "/v1/models", bearer_token, None).await?;
+        assert_eq!(reply.status, 401, "token {:?}", bearer_token);
+    }
+
+    let reply = server.http_request("GET", "/v1/models", Some(TOKEN), None).await?;
+    assert_eq!(reply.status, 200);
+    let model_ids: Vec<serde_json::Value> = reply.json()?["data"].as_array().ok_or("no data")?.iter().map(|model| model["id"].clone()).collect();
+    assert_eq!(model_ids, vec![oliana_server_lib::http_gateway::TEXT_MODEL_ID, oliana_server_lib::http_gateway::IMAGE_MODEL_ID]);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chat_completions_return_the_whole_reply() -> Result<(), Box<dyn std::error::Error>> {
+    let server = start_server().await?;
+    let reply = server.http_request("POST", "/v1/chat/completions", Some(TOKEN), Some(&chat_request("You are a test.", "Hello there", false))).await?;
+    assert_eq!(reply.status, 200, "{}", String::from_utf8_lossy(&reply.body));
+
+    let completion = reply.json()?;
+    assert_eq!(completion["object"], "chat.completion");
+    assert_eq!(completion["choices"][0]["message"]["role"], "assistant");
+    assert_eq!(completion["choices"][0]["message"]["content"], expected_text(&server, "You are a test.", "Hello there"));
+    assert_eq!(completion["choices"][0]["finish_reason"], "stop");
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streamed_chat_completions_are_framed_like_openai() -> Result<(), Box<dyn std::error::Error>> {
+    let server = start_server().await?;
+    let reply = server.http_request("POST", "/v1/chat/completions", Some(TOKEN), Some(&chat_request("You are a test.", "Stream please", true))).await?;
+    assert_eq!(reply.status, 200, "{}", String::from_utf8_lossy(&reply.body));
+    assert!(reply.header("content-type").unwrap_or("").starts_with("text/event-stream"), "{:?}", reply.headers);
+
+    let events = sse_data(&reply.body);
+    assert!(events.len() >= 4, "{:?}", events);
+    assert_eq!(events.last().map(|data| data.as_str()), Some("[DONE]"));
+    let chunks: Vec<serde_json::Value> = events[..events.len() - 1].iter().map(|data| serde_json::from_str(data)).collect::<Result<_, _>>()?;
+    let id = chunks[0]["id"].clone();
+    for chunk in chunks.iter() {
+        assert_eq!(chunk["object"], "chat.completion.chunk");
+        assert_eq!(chunk["id"], id, "every chunk belongs to the same completion");
+    }
+
+    // First the role, then one chunk per token, then an empty delta which finishes it
+    let (first, rest) = chunks.split_first().ok_or("no chunks")?;
+    let (last, content_chunks) = rest.split_last().ok_or("no finishing chunk")?;
+    assert_eq!(first["choices"][0]["delta"], serde_json::json!({ "role": "assistant" }));
+    assert_eq!(first["choices"][0]["finish_reason"], serde_json::Value::Null);
+    assert_eq!(last["choices"][0]["delta"], serde_json::json!({}));
+    assert_eq!(last["choices"][0]["finish_reason"], "stop");
+
+    let mut content = String::new();
+    for chunk in content_chunks.iter() {
+        assert_eq!(chunk["choices"][0]["finish_reason"], serde_json::Value::Null);
+        content.push_str(chunk["choices"][0]["delta"]["content"].as_str().ok_or_else(|| format!("No content in {}", chunk))?);
+    }
+    assert_eq!(content, expected_text(&server, "You are a test.", "Stream please"));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn image_generations_check_n_and_response_format() -> Result<(), Box<dyn std::error::Error>> {
+    let server = start_server().await?;
+
+    for n in [0, 5] {
+        let request = serde_json::json!({ "prompt": "A lighthouse", "n": n });
+        let reply = server.http_request("POST", "/v1/images/generations", Some(TOKEN), Some(&request)).await?;
+        assert_eq!(reply.status, 400, "n = {}", n);
+        let error = reply.json()?;
+        assert_eq!(error["error"]["type"], "invalid_request_error");
+        assert!(error["error"]["message"].as_str().unwrap_or("").contains("n must be between 1 and 4"), "{}", error);
+    }
+
+    let request = serde_json::json!({ "prompt": "A lighthouse", "response_format": "url" });
+    let reply = server.http_request("POST", "/v1/images/generations", Some(TOKEN), Some(&request)).await?;
+    assert_eq!(reply.status, 400);
+    assert!(reply.json()?["error"]["message"].as_str().unwrap_or("").contains("b64_json"));
+
+    // A valid request still goes through
+    let request = serde_json::json!({ "prompt": "A lighthouse", "n": 2, "response_format": "b64_json", "num_inference_steps": 2 });
+    let reply = server.http_request("POST", "/v1/images/generations", Some(TOKEN), Some(&request)).await?;
+    assert_eq!(reply.status, 200, "{}", String::from_utf8_lossy(&reply.body));
+    let data = reply.json()?["data"].as_array().cloned().ok_or("no data")?;
+    assert_eq!(data.len(), 2);
+    for image in data.iter() {
+        // Base64 of the 8 byte PNG signature
+        assert!(image["b64_json"].as_str().unwrap_or("").starts_with("iVBORw0KGgo"));
+    }
+    Ok(())
+}
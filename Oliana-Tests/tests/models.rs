@@ -0,0 +1,152 @@
+// oliana_lib::models: the built in manifest, and fetching a manifest's models from a TestHttpServer laid out like huggingface.co.
+
+const CONFIG_BYTES: &[u8] = b"{\"hidden_size\": 8}\n";
+const WEIGHTS_BYTES: &[u8] = b"Oliana model bytes\n";
+const WEIGHTS_SHA256: &str = "07ae0f3e8c24277404e5c25b832b21d91ea7cc18852f019f441804fe0efb338f";
+
+fn hub_server() -> Result<oliana_tests::TestHttpServer, Box<dyn std::error::Error>> {
+    let mut routes = std::collections::HashMap::new();
+    routes.insert("/oliana/tiny-model/resolve/main/config.json".to_string(), oliana_tests::TestHttpRoute::ok(CONFIG_BYTES));
+    routes.insert("/oliana/tiny-model/resolve/main/unet/weights.bin".to_string(), oliana_tests::TestHttpRoute::ok(WEIGHTS_BYTES));
+    oliana_tests::TestHttpServer::start(routes)
+}
+
+fn tiny_manifest(huggingface_url: &str, mirrors: &[String]) -> Result<oliana_lib::models::ModelManifest, Box<dyn std::error::Error>> {
+    oliana_lib::models::ModelManifest::from_toml_str(&format!(r#"
+huggingface_url = "{huggingface_url}"
+
+[[models]]
+name = "tiny"
+repo = "oliana/tiny-model"
+cache_dir = "models/tiny"
+mirrors = {mirrors:?}
+files = [
+  {{ path = "config.json" }},
+  {{ path = "unet/weights.bin", sha256 = "{WEIGHTS_SHA256}", size_bytes = {} }},
+]
+"#, WEIGHTS_BYTES.len()))
+}
+
+#[test]
+fn builtin_manifest_lists_every_worker_model() -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = oliana_lib::models::ModelManifest::builtin()?;
+    assert_eq!(manifest.model(oliana_lib::models::TEXT_MODEL_NAME)?.repo, "microsoft/Phi-3.5-mini-instruct");
+    assert_eq!(manifest.model(oliana_lib::models::IMAGE_MODEL_NAME)?.repo, "etri-vilab/koala-lightning-1b");
+    for model in manifest.models.iter() {
+        assert!(model.files.len() > 0, "{} lists no files", model.name);
+    }
+    assert!(manifest.python.packages.iter().any(|package| package.import == "torch"));
+    assert!(manifest.model("no-such-model").is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn prefetch_downloads_from_huggingface_paths() -> Result<(), Box<dyn std::error::Error>> {
+    let server = hub_server()?;
+    let cache_root = tempfile::tempdir()?;
+    let manifest = tiny_manifest(&server.url, &[])?;
+    let model = manifest.model("tiny")?;
+
+    let prefetched = oliana_lib::models::prefetch_model(&manifest, model, cache_root.path(), &oliana_lib::files::DownloadOptions::default()).await?;
+
+    let model_dir = cache_root.path().join("models").join("tiny");
+    assert_eq!(std::fs::read(model_dir.join("config.json"))?, CONFIG_BYTES);
+    assert_eq!(std::fs::read(model_dir.join("unet").join("weights.bin"))?, WEIGHTS_BYTES);
+    assert_eq!(prefetched.iter().map(|f| f.pinned).collect::<Vec<bool>>(), vec![false, true]);
+    assert_eq!(prefetched[1].sha256, WEIGHTS_SHA256);
+    assert_eq!(prefetched[0].size_bytes, CONFIG_BYTES.len() as u64);
+
+    // Prefetching again verifies instead of downloading
+    oliana_lib::models::prefetch_model(&manifest, model, cache_root.path(), &oliana_lib::files::DownloadOptions::default()).await?;
+    assert_eq!(server.requests.lock().unwrap().len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn mirrors_are_tried_before_huggingface() -> Result<(), Box<dyn std::error::Error>> {
+    let server = hub_server()?;
+    let seed_dir = tempfile::tempdir()?; // A copy of the repository, ie on a USB stick
+    std::fs::create_dir_all(seed_dir.path().join("unet"))?;
+    std::fs::write(seed_dir.path().join("config.json"), CONFIG_BYTES)?;
+    std::fs::write(seed_dir.path().join("unet").join("weights.bin"), WEIGHTS_BYTES)?;
+    let cache_root = tempfile::tempdir()?;
+    let manifest = tiny_manifest(&server.url, &[seed_dir.path().to_string_lossy().to_string()])?;
+
+    let model_dir = oliana_lib::models::ensure_model(&manifest, "tiny", cache_root.path(), false).await?;
+
+    assert_eq!(std::fs::read(model_dir.join("unet").join("weights.bin"))?, WEIGHTS_BYTES);
+    assert_eq!(server.requests.lock().unwrap().len(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn offline_workers_never_download_and_name_missing_files() -> Result<(), Box<dyn std::error::Error>> {
+    let server = hub_server()?;
+    let cache_root = tempfile::tempdir()?;
+    let manifest = tiny_manifest(&server.url, &[])?;
+    let model_dir = cache_root.path().join("models").join("tiny");
+    std::fs::create_dir_all(model_dir.join("unet"))?;
+    std::fs::write(model_dir.join("config.json"), CONFIG_BYTES)?;
+    std::fs::write(model_dir.join("unet").join("weights.bin"), &WEIGHTS_BYTES[..4])?; // Wrong size
+
+    let e = oliana_lib::models::ensure_model(&manifest, "tiny", cache_root.path(), true).await.unwrap_err();
+    let missing = e.downcast_ref::<oliana_lib::models::MissingArtifactsError>().ok_or_else(|| format!("Expected MissingArtifactsError, got {}", e))?;
+    assert_eq!(missing.missing, vec![model_dir.join("unet").join("weights.bin").display().to_string()]);
+    assert!(e.to_string().contains(oliana_lib::models::OFFLINE_ENV_VAR));
+    assert_eq!(server.requests.lock().unwrap().len(), 0);
+
+    // Online, only what is missing gets downloaded
+    let returned_dir = oliana_lib::models::ensure_model(&manifest, "tiny", cache_root.path(), false).await?;
+    assert_eq!(returned_dir, model_dir);
+    assert_eq!(std::fs::read(model_dir.join("unet").join("weights.bin"))?, WEIGHTS_BYTES);
+    let paths: Vec<String> = server.requests.lock().unwrap().iter().map(|r| r.path.clone()).collect();
+    assert_eq!(paths, vec!["/oliana/tiny-model/resolve/main/unet/weights.bin".to_string()]);
+
+    oliana_lib::models::ensure_model(&manifest, "tiny", cache_root.path(), true).await?;
+    Ok(())
+}
+
+#[test]
+fn offline_servers_tell_their_workers() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let mut config = oliana_server_lib::config::ServerConfig::default();
+    config.track_proc_dir = Some(dir.path().to_path_buf());
+    config.models_manifest = Some("models.toml".into());
+    config.offline = true;
+    config.workers[0].env.insert(oliana_lib::models::OFFLINE_ENV_VAR.to_string(), "0".to_string()); // Overrides the server's
+
+    let mut procs = oliana_lib::launchers::TrackedProcs::new(dir.path(), dir.path());
+    config.register_workers(&mut procs)?;
+
+    let manifest_path = dir.path().join("models.toml").to_string_lossy().to_string();
+    for (i, spec) in procs.tracked_proc_specs.iter().enumerate() {
+        // Later entries win, as with Command::env()
+        let env_value = |key: &str| spec.env.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+        assert_eq!(env_value(oliana_lib::models::MODELS_MANIFEST_ENV_VAR), Some(manifest_path.clone()));
+        assert_eq!(env_value(oliana_lib::models::OFFLINE_ENV_VAR), Some(if i == 0 { "0" } else { "1" }.to_string()), "{}", spec.bin_name);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn branches_resolve_to_the_commit_they_point_to() -> Result<(), Box<dyn std::error::Error>> {
+    const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+    let mut routes = std::collections::HashMap::new();
+    routes.insert("/api/models/oliana/tiny-model/revision/main".to_string(), oliana_tests::TestHttpRoute::ok(format!(r#"{{"id": "oliana/tiny-model", "sha": "{COMMIT}"}}"#).as_bytes()));
+    let server = oliana_tests::TestHttpServer::start(routes)?;
+    let manifest = tiny_manifest(&server.url, &[])?;
+    let mut model = manifest.model("tiny")?.clone();
+    assert_eq!(model.revision, "main");
+    assert!(!oliana_lib::models::is_commit_hash(&model.revision));
+
+    assert_eq!(oliana_lib::models::resolve_revision(&manifest, &model).await?, COMMIT);
+    // A commit is already pinned and needs no request
+    model.revision = COMMIT.to_string();
+    assert!(oliana_lib::models::is_commit_hash(&model.revision));
+    assert_eq!(oliana_lib::models::resolve_revision(&manifest, &model).await?, COMMIT);
+    assert_eq!(server.requests.lock().unwrap().len(), 1);
+
+    model.revision = "no-such-branch".to_string();
+    assert!(oliana_lib::models::resolve_revision(&manifest, &model).await.is_err());
+    Ok(())
+}
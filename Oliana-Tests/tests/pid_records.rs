@@ -0,0 +1,167 @@
+
+// PID files a previous run left behind, and PIDs the OS re-used, must never make TrackedProcs mistake another process for its worker
+
+fn worker_procs(dir: &std::path::Path, orphan_policy: oliana_lib::launchers::OrphanPolicy) -> Result<oliana_lib::launchers::TrackedProcs, Box<dyn std::error::Error>> {
+    let workdir = dir.join("work");
+    std::fs::create_dir_all(&workdir)?;
+    let mut procs = oliana_tests::tracked_worker(dir, env!("CARGO_BIN_EXE_oliana_mock_worker"), oliana_lib::launchers::TrackedProcSpec::new("worker", &["--kind", "text", "--workdir", &workdir.to_string_lossy()]).with_workdir(&workdir))?;
+    procs.orphan_policy = orphan_policy;
+    Ok(procs)
+}
+
+fn worker_status(procs: &mut oliana_lib::launchers::TrackedProcs) -> oliana_lib::launchers::TrackedProcStatus {
+    procs.status().remove(0)
+}
+
+const PREVIOUS_RUN_DIR_ENV_VAR: &str = "OLIANA_TEST_PREVIOUS_RUN_DIR";
+const PREVIOUS_RUN_ADOPTS_ENV_VAR: &str = "OLIANA_TEST_PREVIOUS_RUN_ADOPTS";
+
+// Run by leave_orphan() in a process of its own, which exits without stopping its worker the way a crashed server would.
+// The OS then closes everything that process held, including its end of any pipes to the worker.
+#[test]
+#[ignore]
+fn previous_server_run() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = match std::env::var_os(PREVIOUS_RUN_DIR_ENV_VAR) {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => return Ok(()), // Only meaningful when started by leave_orphan()
+    };
+    let orphan_policy = if std::env::var_os(PREVIOUS_RUN_ADOPTS_ENV_VAR).is_some() { oliana_lib::launchers::OrphanPolicy::Adopt } else { oliana_lib::launchers::OrphanPolicy::Kill };
+    let mut previous_run = worker_procs(&dir, orphan_policy)?;
+    previous_run.ensure_registered_procs_running()?;
+    assert!(worker_status(&mut previous_run).running);
+    std::process::exit(0); // Skips TrackedProcs::drop(), which would stop the worker
+}
+
+// Starts a worker from a TrackedProcs in another process which then exits, leaving the worker behind; returns its PID
+fn leave_orphan(dir: &std::path::Path, orphan_policy: oliana_lib::launchers::OrphanPolicy) -> Result<u32, Box<dyn std::error::Error>> {
+    let mut previous_run = std::process::Command::new(std::env::current_exe()?);
+    previous_run.args(["--exact", "previous_server_run", "--ignored"]).env(PREVIOUS_RUN_DIR_ENV_VAR, dir);
+    if orphan_policy == oliana_lib::launchers::OrphanPolicy::Adopt {
+        previous_run.env(PREVIOUS_RUN_ADOPTS_ENV_VAR, "1");
+    }
+    let output = previous_run.output()?;
+    if !output.status.success() {
+        return Err(format!("The previous run failed: {}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr)).into());
+    }
+    let record = oliana_lib::launchers::read_pid_record(&dir.join("worker-pid.txt"))?.ok_or("no PID file written")?;
+    assert!(record.start_time.is_some() && record.exe.is_some(), "{:?}", record);
+    assert_eq!(record.output_to_log, orphan_policy == oliana_lib::launchers::OrphanPolicy::Adopt);
+    assert!(oliana_tests::is_alive(record.pid));
+    Ok(record.pid)
+}
+
+// Hands `workdir` a text job the way the server does and waits for the worker to finish it
+fn run_text_job(workdir: &std::path::Path, job: &str) -> Result<String, Box<dyn std::error::Error>> {
+    std::fs::write(workdir.join(format!("{}.json", job)), r#"{"system_prompt": "You are a narrator", "user_prompt": "Go on"}"#)?;
+    let start = std::time::Instant::now();
+    while !workdir.join(format!("{}.done", job)).exists() {
+        if start.elapsed() > oliana_tests::TEST_TIMEOUT {
+            return Err(format!("job {} was not done within {:?}", job, oliana_tests::TEST_TIMEOUT).into());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    if let Ok(report) = std::fs::read_to_string(workdir.join(format!("{}.{}", job, oliana_lib::launchers::FAILED_JOB_EXTENSION))) {
+        return Err(format!("job {} failed: {}", job, report).into());
+    }
+    Ok(std::fs::read_to_string(workdir.join(format!("{}.txt", job)))?)
+}
+
+#[test]
+fn orphans_are_adopted() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let orphan_pid = leave_orphan(dir.path(), oliana_lib::launchers::OrphanPolicy::Adopt)?;
+
+    let mut procs = worker_procs(dir.path(), oliana_lib::launchers::OrphanPolicy::Adopt)?;
+    procs.ensure_registered_procs_running()?;
+    let status = worker_status(&mut procs);
+    // The worker prints as it takes the job, which would kill it if its output still went to the previous run
+    let job_result = run_text_job(&dir.path().join("work"), "1");
+    let still_running = worker_status(&mut procs).running;
+    oliana_tests::kill(orphan_pid);
+    assert_eq!(status.pid, Some(orphan_pid));
+    assert!(status.running);
+    assert_eq!(status.restarts, 0);
+    assert!(job_result?.len() > 0);
+    assert!(still_running);
+    let log = procs.log_tail("worker", 50)?;
+    assert!(log.iter().any(|line| line.starts_with("Processing ")), "{:?}", log);
+    Ok(())
+}
+
+#[test]
+fn piped_orphans_are_killed_even_when_adopting() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let orphan_pid = leave_orphan(dir.path(), oliana_lib::launchers::OrphanPolicy::Kill)?;
+
+    let mut procs = worker_procs(dir.path(), oliana_lib::launchers::OrphanPolicy::Adopt)?;
+    procs.ensure_registered_procs_running()?;
+    let status = worker_status(&mut procs);
+    let orphan_alive = oliana_tests::is_alive(orphan_pid);
+    oliana_tests::kill(orphan_pid);
+    oliana_tests::kill(status.pid.ok_or("no pid")?);
+    assert!(!orphan_alive);
+    assert!(status.running);
+    assert_ne!(status.pid, Some(orphan_pid));
+    Ok(())
+}
+
+#[test]
+fn orphans_are_killed() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let orphan_pid = leave_orphan(dir.path(), oliana_lib::launchers::OrphanPolicy::Kill)?;
+
+    let mut procs = worker_procs(dir.path(), oliana_lib::launchers::OrphanPolicy::Kill)?;
+    procs.ensure_registered_procs_running()?;
+    let status = worker_status(&mut procs);
+    let orphan_alive = oliana_tests::is_alive(orphan_pid);
+    oliana_tests::kill(orphan_pid);
+    oliana_tests::kill(status.pid.ok_or("no pid")?);
+    assert!(!orphan_alive);
+    assert!(status.running);
+    assert_ne!(status.pid, Some(orphan_pid));
+    Ok(())
+}
+
+#[test]
+fn pid_files_naming_other_processes_are_ignored() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    // Our own PID stands in for whatever process the OS gave a dead worker's PID to; both the old bare-PID
+    // format and a record with the wrong start time must leave it alone.
+    for with_start_time in [false, true] {
+        let pid_file = dir.path().join("worker-pid.txt");
+        if with_start_time {
+            let bin_path = dir.path().join(oliana_lib::files::append_os_extention_to_bin("worker"));
+            oliana_lib::launchers::write_pid_record(&pid_file, &oliana_lib::launchers::PidRecord { pid: std::process::id(), start_time: Some(1), exe: Some(bin_path), output_to_log: false })?;
+        }
+        else {
+            std::fs::write(&pid_file, format!("{}", std::process::id()))?;
+        }
+        let mut procs = worker_procs(dir.path(), oliana_lib::launchers::OrphanPolicy::Kill)?;
+        procs.ensure_registered_procs_running()?;
+        let status = worker_status(&mut procs);
+        oliana_tests::kill(status.pid.ok_or("no pid")?);
+        assert_ne!(status.pid, Some(std::process::id()));
+    }
+    Ok(())
+}
+
+#[test]
+fn reused_pids_are_not_mistaken_for_the_worker() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let mut procs = worker_procs(dir.path(), oliana_lib::launchers::OrphanPolicy::Kill)?;
+    procs.ensure_registered_procs_running()?;
+    let worker_pid = worker_status(&mut procs).pid.ok_or("no pid")?;
+
+    // As if the worker had died unnoticed and our PID had been handed out since
+    let pid_file = dir.path().join("worker-pid.txt");
+    let mut record = oliana_lib::launchers::read_pid_record(&pid_file)?.ok_or("no PID file written")?;
+    record.pid = std::process::id();
+    oliana_lib::launchers::write_pid_record(&pid_file, &record)?;
+
+    let status = worker_status(&mut procs);
+    oliana_tests::kill(worker_pid);
+    assert!(!status.running);
+    assert_eq!(status.last_exit, Some(oliana_lib::launchers::ExitReason::Unknown));
+    assert!(!pid_file.exists(), "the stale PID file should have been removed");
+    Ok(())
+}
@@ -0,0 +1,33 @@
+
+// Rotation and tailing of the per-process logs in oliana_lib::proc_logs
+
+#[test]
+fn logs_rotate_and_tail_across_files() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = oliana_lib::proc_logs::log_file_path(dir.path(), "worker");
+    let mut log = oliana_lib::proc_logs::ProcLog::open(&path, oliana_lib::proc_logs::LogRotation { max_bytes: 400, max_files: 2 })?;
+    for i in 0..40 {
+        log.write_line("stdout", &format!("line {}", i))?;
+    }
+
+    // Each line is ~40 bytes, so only the newest 3 files worth survive
+    assert!(std::fs::metadata(&path)?.len() <= 400);
+    assert!(dir.path().join("worker.log.1").exists());
+    assert!(dir.path().join("worker.log.2").exists());
+    assert!(!dir.path().join("worker.log.3").exists());
+
+    let tail = oliana_lib::proc_logs::tail_log_file(&path, 15)?;
+    assert_eq!(tail.len(), 15);
+    for (line, i) in tail.iter().zip(25..40) {
+        assert!(line.ends_with(&format!(" [stdout] line {}", i)), "{:?} should be line {}", line, i);
+    }
+    assert_eq!(oliana_lib::proc_logs::tail_log_file(&dir.path().join("missing.log"), 10)?, Vec::<String>::new());
+    Ok(())
+}
+
+#[test]
+fn timestamps_are_utc_iso8601() {
+    let t = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_792_316_467_153);
+    assert_eq!(oliana_lib::misc::utc_timestamp_str(t), "2026-10-18T09:41:07.153Z");
+    assert_eq!(oliana_lib::misc::utc_timestamp_str(std::time::UNIX_EPOCH + std::time::Duration::from_secs(951_782_400)), "2000-02-29T00:00:00.000Z");
+}
@@ -0,0 +1,87 @@
+#![cfg(target_os = "linux")]
+
+// Each tracked process gets the env, working directory and limits of its own TrackedProcSpec; checked through /proc/<pid>
+
+fn spawn_worker(dir: &std::path::Path, spec: oliana_lib::launchers::TrackedProcSpec) -> Result<(oliana_lib::launchers::TrackedProcs, u32), Box<dyn std::error::Error>> {
+    let mut procs = oliana_tests::tracked_worker(dir, env!("CARGO_BIN_EXE_oliana_mock_worker"), spec)?;
+    procs.ensure_registered_procs_running()?;
+    let pid = procs.status()[0].pid.ok_or("no pid")?;
+    Ok((procs, pid))
+}
+
+#[test]
+fn workers_get_their_own_env_cwd_and_rlimits() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let workdir = dir.path().join("work");
+    std::fs::create_dir_all(&workdir)?;
+    let spec = oliana_lib::launchers::TrackedProcSpec::new("worker", &["--kind", "text", "--workdir", &workdir.to_string_lossy()])
+        .with_env("PER_PROC_MEM_FRACT", "0.55")
+        .with_cwd(&workdir)
+        .with_workdir(&workdir)
+        .with_limits(oliana_lib::proc_limits::ResourceLimits {
+            max_open_files: Some(64),
+            max_cpu_secs: Some(3600),
+            ..Default::default()
+        });
+    let (_procs, pid) = spawn_worker(dir.path(), spec)?;
+
+    let environ = std::fs::read(format!("/proc/{}/environ", pid));
+    let cwd = std::fs::read_link(format!("/proc/{}/cwd", pid));
+    let limits = std::fs::read_to_string(format!("/proc/{}/limits", pid));
+    oliana_tests::kill(pid);
+
+    assert!(environ?.split(|b| *b == 0).any(|var| var == b"PER_PROC_MEM_FRACT=0.55"));
+    assert_eq!(cwd?, workdir.canonicalize()?);
+    let limits = limits?;
+    let limit_of = |name: &str| limits.lines().find(|line| line.starts_with(name)).map(|line| line[name.len()..].split_whitespace().take(2).collect::<Vec<_>>().join(" "));
+    assert_eq!(limit_of("Max open files").as_deref(), Some("64 64"));
+    assert_eq!(limit_of("Max cpu time").as_deref(), Some("3600 3600"));
+    Ok(())
+}
+
+#[test]
+fn cgroup_limits_without_a_cgroup_parent_are_refused() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let spec = oliana_lib::launchers::TrackedProcSpec::new("worker", &["--kind", "text", "--workdir", &dir.path().to_string_lossy()])
+        .with_limits(oliana_lib::proc_limits::ResourceLimits {
+            cgroup_memory_max_bytes: Some(1024 * 1024 * 1024),
+            ..Default::default()
+        });
+    match spawn_worker(dir.path(), spec) {
+        Ok((_procs, pid)) => {
+            oliana_tests::kill(pid);
+            panic!("expected an error about the missing cgroup_parent");
+        }
+        Err(e) => assert!(format!("{}", e).contains("cgroup_parent"), "{}", e),
+    }
+    Ok(())
+}
+
+#[test]
+fn workers_get_their_workdir_from_the_config_whatever_their_args() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let mut config = oliana_server_lib::config::ServerConfig {
+        track_proc_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    for worker in config.workers.iter_mut() {
+        worker.args = vec![format!("--workdir={}", worker.args[1])]; // ie "--workdir={text_workdir}"
+    }
+    let mut custom_worker = config.workers[0].clone();
+    custom_worker.bin_name = "custom_worker".to_string();
+    custom_worker.args = vec!["--jobs-in".to_string(), "{track_proc_dir}/custom".to_string()];
+    custom_worker.workdir = Some("{track_proc_dir}/custom".to_string());
+    let mut plain_worker = custom_worker.clone();
+    plain_worker.bin_name = "plain_worker".to_string();
+    plain_worker.workdir = None;
+    config.workers.extend([custom_worker, plain_worker]);
+
+    let mut procs = oliana_lib::launchers::TrackedProcs::new(dir.path(), dir.path());
+    config.register_workers(&mut procs)?;
+    let workdir_of = |bin_name: &str| procs.tracked_proc_specs.iter().find(|spec| spec.bin_name == bin_name).and_then(|spec| spec.workdir.clone());
+    assert_eq!(workdir_of(oliana_server_lib::TEXT_WORKER_BIN_NAME), Some(config.resolve_text_workdir()?));
+    assert_eq!(workdir_of(oliana_server_lib::IMAGE_WORKER_BIN_NAME), Some(config.resolve_images_workdir()?));
+    assert_eq!(workdir_of("custom_worker"), Some(dir.path().join("custom")));
+    assert_eq!(workdir_of("plain_worker"), None);
+    Ok(())
+}
@@ -0,0 +1,132 @@
+// Ordering and slot limits of oliana_server_lib::queue::JobQueue, driven through an in-process queue whose dispatcher hands jobs to a channel
+
+use oliana_server_lib::queue::{JobQueue, QueuedJob};
+use oliana_server_lib::JobPriority;
+
+fn queue(max_concurrent_jobs: usize) -> std::sync::Arc<JobQueue> {
+    std::sync::Arc::new(JobQueue::new_in_process("test_worker", max_concurrent_jobs, std::time::Duration::from_secs(600)))
+}
+
+fn enqueue(queue: &JobQueue, nonce: u64, client_key: &str, priority: JobPriority) {
+    queue.enqueue(QueuedJob {
+        nonce: nonce,
+        client_key: client_key.to_string(),
+        priority: priority,
+        input_json: "{}".to_string(),
+    });
+}
+
+fn positions(queue: &JobQueue, nonces: &[u64]) -> Vec<Option<u32>> {
+    nonces.iter().map(|nonce| queue.position(*nonce)).collect()
+}
+
+// Runs the queue one job at a time, finishing each as soon as it is dispatched, and returns the order they went in
+async fn dispatch_order(queue: std::sync::Arc<JobQueue>, num_jobs: usize) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    let (dispatched_tx, mut dispatched_rx) = tokio::sync::mpsc::unbounded_channel();
+    let dispatcher = queue.clone().spawn_callback_dispatcher(move |job| {
+        let _ = dispatched_tx.send(job.nonce);
+    });
+    let mut order = vec![];
+    while order.len() < num_jobs {
+        let nonce = tokio::time::timeout(oliana_tests::TEST_TIMEOUT, dispatched_rx.recv()).await?.ok_or("dispatcher stopped")?;
+        order.push(nonce);
+        queue.finish(nonce);
+    }
+    dispatcher.abort();
+    Ok(order)
+}
+
+#[test]
+fn interactive_jobs_go_before_normal_and_background() {
+    let queue = queue(1);
+    enqueue(&queue, 1, "a", JobPriority::Background);
+    enqueue(&queue, 2, "a", JobPriority::Normal);
+    enqueue(&queue, 3, "b", JobPriority::Background);
+    enqueue(&queue, 4, "b", JobPriority::Interactive);
+    enqueue(&queue, 5, "a", JobPriority::Normal);
+    assert_eq!(positions(&queue, &[4, 2, 5, 1, 3]), vec![Some(1), Some(2), Some(3), Some(4), Some(5)]);
+    assert_eq!(queue.waiting_len(), 5);
+}
+
+#[test]
+fn clients_take_turns_within_a_priority() {
+    let queue = queue(1);
+    for nonce in [1, 2, 3] {
+        enqueue(&queue, nonce, "greedy", JobPriority::Normal);
+    }
+    enqueue(&queue, 4, "patient", JobPriority::Normal);
+    enqueue(&queue, 5, "patient", JobPriority::Normal);
+    enqueue(&queue, 6, "late", JobPriority::Normal);
+    assert_eq!(positions(&queue, &[1, 4, 6, 2, 5, 3]), vec![Some(1), Some(2), Some(3), Some(4), Some(5), Some(6)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn positions_match_the_dispatch_order() -> Result<(), Box<dyn std::error::Error>> {
+    let queue = queue(1);
+    let jobs = [
+        (1, "a", JobPriority::Normal), (2, "a", JobPriority::Normal), (3, "b", JobPriority::Background), (4, "c", JobPriority::Normal),
+        (5, "b", JobPriority::Interactive), (6, "a", JobPriority::Interactive), (7, "c", JobPriority::Background), (8, "b", JobPriority::Normal),
+    ];
+    for (nonce, client_key, priority) in jobs.iter() {
+        enqueue(&queue, *nonce, client_key, *priority);
+    }
+    let mut by_position: Vec<(u32, u64)> = jobs.iter().map(|(nonce, _, _)| (queue.position(*nonce).expect("every job is waiting"), *nonce)).collect();
+    by_position.sort();
+    let expected_order: Vec<u64> = by_position.iter().map(|(_, nonce)| *nonce).collect();
+    assert_eq!(by_position.iter().map(|(position, _)| *position).collect::<Vec<_>>(), (1..=jobs.len() as u32).collect::<Vec<_>>());
+
+    assert_eq!(dispatch_order(queue.clone(), jobs.len()).await?, expected_order);
+    assert_eq!(positions(&queue, &expected_order), vec![None; jobs.len()]);
+    Ok(())
+}
+
+#[test]
+fn removing_a_waiting_job_moves_the_ones_behind_it_up() {
+    let queue = queue(1);
+    for nonce in [1, 2, 3, 4] {
+        enqueue(&queue, nonce, "a", JobPriority::Normal);
+    }
+    assert_eq!(positions(&queue, &[1, 2, 3, 4]), vec![Some(1), Some(2), Some(3), Some(4)]);
+
+    assert!(queue.remove_waiting(2));
+    assert_eq!(positions(&queue, &[1, 2, 3, 4]), vec![Some(1), None, Some(2), Some(3)]);
+    assert!(!queue.remove_waiting(2), "already removed");
+    assert!(!queue.remove_waiting(99), "never queued");
+
+    // Removing a client's only job takes it out of the rotation too
+    enqueue(&queue, 5, "b", JobPriority::Normal);
+    assert_eq!(positions(&queue, &[1, 5, 3, 4]), vec![Some(1), Some(2), Some(3), Some(4)]);
+    assert!(queue.remove_waiting(5));
+    assert_eq!(positions(&queue, &[1, 3, 4]), vec![Some(1), Some(2), Some(3)]);
+    assert_eq!(queue.waiting_len(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn no_more_than_max_concurrent_jobs_run_at_once() -> Result<(), Box<dyn std::error::Error>> {
+    let queue = queue(2);
+    for nonce in 1..=5 {
+        enqueue(&queue, nonce, "a", JobPriority::Normal);
+    }
+    let (dispatched_tx, mut dispatched_rx) = tokio::sync::mpsc::unbounded_channel();
+    let dispatcher = queue.clone().spawn_callback_dispatcher(move |job| {
+        let _ = dispatched_tx.send(job.nonce);
+    });
+
+    let mut dispatched = vec![];
+    for _ in 0..2 {
+        dispatched.push(tokio::time::timeout(oliana_tests::TEST_TIMEOUT, dispatched_rx.recv()).await?.ok_or("dispatcher stopped")?);
+    }
+    assert_eq!(dispatched, vec![1, 2]);
+    // Both slots are taken, so nothing else goes out however long we wait
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(300), dispatched_rx.recv()).await.is_err());
+    assert_eq!((queue.running_len(), queue.waiting_len()), (2, 3));
+    assert_eq!(queue.position(3), Some(1));
+
+    // Each finished job lets exactly one more through
+    queue.finish(2);
+    assert_eq!(tokio::time::timeout(oliana_tests::TEST_TIMEOUT, dispatched_rx.recv()).await?, Some(3));
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(300), dispatched_rx.recv()).await.is_err());
+    assert_eq!((queue.running_len(), queue.waiting_len()), (2, 2));
+    dispatcher.abort();
+    Ok(())
+}
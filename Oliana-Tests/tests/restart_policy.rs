@@ -0,0 +1,78 @@
+
+// Drives oliana_lib::launchers::TrackedProcs directly with a worker which exits as soon as it starts, the way one does when its
+// model or python environment is broken.
+
+fn crashing_procs(dir: &std::path::Path, policy: oliana_lib::launchers::RestartPolicy) -> Result<oliana_lib::launchers::TrackedProcs, Box<dyn std::error::Error>> {
+    // oliana_mock_worker exits with code 1 on an unknown --kind
+    let mut procs = oliana_tests::tracked_worker(dir, env!("CARGO_BIN_EXE_oliana_mock_worker"), oliana_lib::launchers::TrackedProcSpec::new("crashing_worker", &["--kind", "nonsense", "--workdir", &dir.to_string_lossy()]))?;
+    procs.set_restart_policy("crashing_worker", policy);
+    Ok(procs)
+}
+
+fn poll_until<F: Fn(&oliana_lib::launchers::TrackedProcStatus) -> bool>(procs: &mut oliana_lib::launchers::TrackedProcs, done: F) -> Result<oliana_lib::launchers::TrackedProcStatus, Box<dyn std::error::Error>> {
+    let start = std::time::Instant::now();
+    loop {
+        procs.ensure_registered_procs_running()?;
+        let status = procs.status().remove(0);
+        if done(&status) {
+            return Ok(status);
+        }
+        if start.elapsed() > oliana_tests::TEST_TIMEOUT {
+            return Err(format!("Gave up waiting, last status: {:?}", status).into());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn crash_looping_worker_is_given_up_on() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let mut procs = crashing_procs(dir.path(), oliana_lib::launchers::RestartPolicy {
+        initial_backoff: std::time::Duration::from_millis(10),
+        max_backoff: std::time::Duration::from_millis(40),
+        max_restarts: 3,
+        restart_window: std::time::Duration::from_secs(60),
+        healthy_after: std::time::Duration::from_secs(60),
+    })?;
+
+    let status = poll_until(&mut procs, |s| s.failed)?;
+    assert_eq!(status.restarts, 3);
+    assert_eq!(status.last_exit, Some(oliana_lib::launchers::ExitReason::Code(1)));
+    assert!(!status.running);
+    assert_eq!(status.next_restart_in, None);
+
+    // Nothing is spawned while failed...
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    procs.ensure_registered_procs_running()?;
+    assert_eq!(procs.status()[0].restarts, 3);
+
+    // ...until someone asks for another round
+    assert!(procs.retry_failed_proc("crashing_worker"));
+    let status = poll_until(&mut procs, |s| s.restarts > 3)?;
+    assert!(!status.failed);
+    Ok(())
+}
+
+#[test]
+fn crashes_in_a_row_back_off_exponentially() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let policy = oliana_lib::launchers::RestartPolicy {
+        initial_backoff: std::time::Duration::from_secs(1),
+        max_backoff: std::time::Duration::from_secs(4),
+        max_restarts: 100,
+        restart_window: std::time::Duration::from_secs(60),
+        healthy_after: std::time::Duration::from_secs(60),
+    };
+    assert_eq!(policy.backoff(1), std::time::Duration::from_secs(1));
+    assert_eq!(policy.backoff(2), std::time::Duration::from_secs(2));
+    assert_eq!(policy.backoff(3), std::time::Duration::from_secs(4));
+    assert_eq!(policy.backoff(50), std::time::Duration::from_secs(4));
+
+    let mut procs = crashing_procs(dir.path(), policy)?;
+    let status = poll_until(&mut procs, |s| s.next_restart_in.is_some())?;
+    assert_eq!(status.restarts, 0);
+    assert!(status.next_restart_in.unwrap() <= std::time::Duration::from_secs(1));
+    let status = poll_until(&mut procs, |s| s.restarts == 1 && s.next_restart_in.is_some())?;
+    assert!(status.next_restart_in.unwrap() > std::time::Duration::from_secs(1));
+    Ok(())
+}
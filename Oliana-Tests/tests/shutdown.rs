@@ -0,0 +1,82 @@
+#![cfg(target_os = "linux")]
+
+// TrackedProcs::shutdown() (and dropping a TrackedProcs) must take down each worker along with everything it started, the way
+// oliana_images starts pip and python. /bin/sh stands in for such a worker: `sleep` children play the part of its subprocesses.
+
+fn spawn_shell_worker(dir: &std::path::Path, script: &str) -> Result<(oliana_lib::launchers::TrackedProcs, u32, Vec<u32>), Box<dyn std::error::Error>> {
+    let mut procs = oliana_tests::tracked_worker(dir, "/bin/sh", oliana_lib::launchers::TrackedProcSpec::new("shell_worker", &["-c", script]))?;
+    procs.ensure_registered_procs_running()?;
+    let pid = procs.status()[0].pid.ok_or("no pid")?;
+    let children = wait_for_children(pid, 2)?;
+    Ok((procs, pid, children))
+}
+
+fn wait_for_children(pid: u32, num_children: usize) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let start = std::time::Instant::now();
+    let mut sinfo = sysinfo::System::new();
+    loop {
+        sinfo.refresh_processes(sysinfo::ProcessesToUpdate::All, true);
+        let children: Vec<u32> = sinfo.processes().values().filter(|p| p.parent() == Some(sysinfo::Pid::from_u32(pid))).map(|p| p.pid().as_u32()).collect();
+        if children.len() >= num_children {
+            return Ok(children);
+        }
+        if start.elapsed() > oliana_tests::TEST_TIMEOUT {
+            return Err(format!("{} started {} children, expected {}", pid, children.len(), num_children).into());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn shutdown_stops_workers_and_their_children() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let (mut procs, pid, children) = spawn_shell_worker(dir.path(), "sleep 600 & sleep 600 & wait")?;
+
+    procs.shutdown();
+
+    assert!(!oliana_tests::is_alive(pid), "worker {} survived shutdown()", pid);
+    for child in children {
+        assert!(!oliana_tests::is_alive(child), "child {} of worker {} survived shutdown()", child, pid);
+    }
+    assert!(!dir.path().join("shell_worker-pid.txt").exists());
+
+    // Nothing comes back afterwards
+    procs.ensure_registered_procs_running()?;
+    let status = &procs.status()[0];
+    assert!(!status.running && status.pid.is_none(), "{:?}", status);
+    Ok(())
+}
+
+#[test]
+fn workers_ignoring_sigterm_are_killed_after_the_grace_period() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    // An ignored signal stays ignored across exec, so the sleeps ignore SIGTERM too
+    let (mut procs, pid, children) = spawn_shell_worker(dir.path(), "trap '' TERM; sleep 600 & sleep 600 & wait")?;
+    procs.shutdown_grace_period = std::time::Duration::from_millis(500);
+
+    let start = std::time::Instant::now();
+    procs.shutdown();
+
+    assert!(start.elapsed() >= procs.shutdown_grace_period);
+    assert!(!oliana_tests::is_alive(pid), "worker {} survived shutdown()", pid);
+    for child in children {
+        assert!(!oliana_tests::is_alive(child), "child {} of worker {} survived shutdown()", child, pid);
+    }
+    assert_eq!(procs.status()[0].last_exit, Some(oliana_lib::launchers::ExitReason::Signal(9)));
+    Ok(())
+}
+
+#[test]
+fn dropping_tracked_procs_stops_its_workers() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let (procs, pid, children) = spawn_shell_worker(dir.path(), "sleep 600 & sleep 600 & wait")?;
+
+    drop(procs);
+
+    assert!(!oliana_tests::is_alive(pid), "worker {} survived drop()", pid);
+    for child in children {
+        assert!(!oliana_tests::is_alive(child), "child {} of worker {} survived drop()", child, pid);
+    }
+    assert!(!dir.path().join("shell_worker-pid.txt").exists());
+    Ok(())
+}
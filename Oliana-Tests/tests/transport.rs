@@ -0,0 +1,92 @@
+// The connection steps in oliana_server_lib::transport (TLS, then the token handshake) against a real TestServer
+
+use oliana_server_lib::transport::{ClientTransportConfig, AUTH_ACCEPTED, AUTH_HELLO, AUTH_REJECTED, HANDSHAKE_TIMEOUT};
+use oliana_tests::{TestServer, TestServerOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const TOKEN: &str = "correct-horse-battery-staple";
+
+fn options_with_token() -> TestServerOptions {
+    let mut options = TestServerOptions::new(env!("CARGO_BIN_EXE_oliana_mock_worker"));
+    options.auth_tokens.insert("alice".to_string(), TOKEN.to_string());
+    options
+}
+
+// Runs the client half of the handshake over plain TCP by hand and returns the server's one-byte answer
+async fn auth_reply(server: &TestServer, token: &str) -> Result<u8, Box<dyn std::error::Error>> {
+    let mut stream = tokio::net::TcpStream::connect(server.server_url()).await?;
+    stream.write_all(AUTH_HELLO).await?;
+    stream.write_u16(token.len() as u16).await?;
+    stream.write_all(token.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_u8()).await??)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wrong_or_missing_tokens_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let server = TestServer::start(options_with_token()).await?;
+
+    assert_eq!(auth_reply(&server, "not-the-token").await?, AUTH_REJECTED);
+    assert_eq!(auth_reply(&server, "").await?, AUTH_REJECTED);
+    assert_eq!(auth_reply(&server, &TOKEN[..TOKEN.len() - 1]).await?, AUTH_REJECTED);
+
+    for token in [Some("not-the-token".to_string()), None] {
+        let transport_config = ClientTransportConfig { token: token.clone(), ..Default::default() };
+        match oliana_server_lib::transport::connect(&server.server_url(), &transport_config).await {
+            Ok(_) => panic!("connecting with token {:?} should have failed", token),
+            Err(e) => assert!(e.to_string().contains("rejected our token"), "{}", e),
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_right_token_is_accepted() -> Result<(), Box<dyn std::error::Error>> {
+    let server = TestServer::start(options_with_token()).await?;
+
+    assert_eq!(auth_reply(&server, TOKEN).await?, AUTH_ACCEPTED);
+
+    // start() already connected with it to wait for the workers; check a fresh connection can make calls too
+    let transport_config = ClientTransportConfig { token: Some(TOKEN.to_string()), ..Default::default() };
+    let client = oliana_server_lib::transport::connect(&server.server_url(), &transport_config).await?;
+    let status = client.server_status(tarpc::context::current()).await??;
+    assert!(status.workers.len() > 0);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tls_with_a_self_signed_certificate_round_trips() -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = options_with_token();
+    options.tls_self_signed = true;
+    let server = TestServer::start(options).await?;
+    assert!(server.tls_cert_file().exists());
+
+    let transport_config = ClientTransportConfig {
+        tls: true,
+        tls_ca_cert: Some(server.tls_cert_file()),
+        token: Some(TOKEN.to_string()),
+        ..Default::default()
+    };
+    let client = oliana_server_lib::transport::connect(&server.server_url(), &transport_config).await?;
+    let text = oliana_tests::generate_text(&client, "You are a test.", "Say something").await?;
+    assert!(text.len() > 0);
+
+    // Without the certificate the public roots do not vouch for the server
+    let untrusting_config = ClientTransportConfig { tls_ca_cert: None, ..transport_config };
+    assert!(oliana_server_lib::transport::connect(&server.server_url(), &untrusting_config).await.is_err());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn plaintext_clients_to_a_tls_server_fail_quickly() -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = options_with_token();
+    options.tls_self_signed = true;
+    let server = TestServer::start(options).await?;
+
+    let transport_config = ClientTransportConfig { token: Some(TOKEN.to_string()), ..Default::default() };
+    let start = std::time::Instant::now();
+    let connected = oliana_server_lib::transport::connect(&server.server_url(), &transport_config).await;
+    assert!(connected.is_err(), "a plaintext handshake should not get through TLS");
+    assert!(start.elapsed() < HANDSHAKE_TIMEOUT, "took {:?}", start.elapsed());
+    Ok(())
+}
@@ -17,10 +17,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
   Ok(())
 }
 
+#[cfg(feature = "cuda")]
 use mistralrs::{
     MemoryGpuConfig,
     IsqType, PagedAttentionMetaBuilder, TextMessageRole, TextMessages, TextModelBuilder,
 };
+#[cfg(feature = "cuda")]
 use tokio::io::AsyncWriteExt;
 
 async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
@@ -39,13 +41,38 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     return Ok(());
   }
 
+  // "mistralrs" runs Phi-3.5 on the GPU; "mock" streams seeded pseudo-text through the same workdir protocol and needs no GPU.
+  let mut backend = "mistralrs".to_string();
+  let mut seed: u64 = 0;
+  let mut mock_token_delay = oliana_lib::mock::DEFAULT_MOCK_TOKEN_DELAY;
+  if let Some(backend_i) = args.iter().position(|n| n == "--backend") {
+    if backend_i < args.len()-1 {
+      backend = args[backend_i+1].clone();
+    }
+  }
+  if let Some(seed_i) = args.iter().position(|n| n == "--seed") {
+    if seed_i < args.len()-1 {
+      seed = args[seed_i+1].parse::<u64>().map_err(oliana_lib::eloc!())?;
+    }
+  }
+  if let Some(delay_i) = args.iter().position(|n| n == "--mock-token-delay-ms") {
+    if delay_i < args.len()-1 {
+      mock_token_delay = std::time::Duration::from_millis(args[delay_i+1].parse::<u64>().map_err(oliana_lib::eloc!())?);
+    }
+  }
+
   println!("");
   println!("Using {env_var_work_dir} as a work directory.");
   println!("write files named 'NAME.json' containing objects like:");
   println!(r#" {{"system_prompt": "You are an AI agent with a specialty in cooking.", "user_prompt": "Hello! How are you? I'd like to bake a pie but do not know how, please help me!", }}"#);
-  println!("and wait for 'NAME.txt' to be written back from this process.");
+  println!("and wait for 'NAME.txt' to be written back from this process; a failed job gets 'NAME.{}' (a JSON error report) instead of the rest of its text.", oliana_lib::launchers::FAILED_JOB_EXTENSION);
+  println!("Creating 'NAME.cancel' stops generation after the current token (or skips 'NAME.json' if it has not started yet); 'NAME.done' is still written.");
   println!("'NAME.json' will remain post-generation, and if the file's mtime becomes newer it will be processed again with the new contents.");
   println!("If 'NAME.json' has an mtime older than this process's start time, it will not be processed.");
+  println!("'{}' holds this process's PID once the model is loaded and 'NAME.json' files are being picked up.", oliana_lib::launchers::WORKER_READY_FILE_NAME);
+  println!("'{}' is re-written every {:?} while idle and on every token while generating.", oliana_lib::launchers::WORKER_HEARTBEAT_FILE_NAME, oliana_lib::launchers::WORKER_HEARTBEAT_INTERVAL);
+  println!("The model comes from the '{}' entry of the models manifest (${} or the one built into oliana_lib); with {}=1 it is never downloaded.", oliana_lib::models::TEXT_MODEL_NAME, oliana_lib::models::MODELS_MANIFEST_ENV_VAR, oliana_lib::models::OFFLINE_ENV_VAR);
+  println!("Pass --backend mock to run without a GPU; output is seeded pseudo-text (--seed N, --mock-token-delay-ms N).");
   println!("");
 
   tokio::fs::create_dir_all(&env_var_work_dir[..]).await?;
@@ -54,9 +81,28 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     "WORK_DIR", env_var_work_dir.clone()
   );
 
-  let hf_home = oliana_lib::files::get_cache_file("Oliana-Text-hf_home").await.map_err(oliana_lib::eloc!())?;
+  if backend == "mock" {
+    println!("--backend mock: writing pseudo-text seeded by --seed {seed} and the prompts, one token every ~{}ms (--mock-token-delay-ms)", mock_token_delay.as_millis());
+    return oliana_lib::mock::run_mock_text_worker(&env_var_work_dir, seed, mock_token_delay).await;
+  }
+  if backend != "mistralrs" {
+    return Err(format!("Unknown --backend {:?}, expected \"mistralrs\" or \"mock\"", backend).into());
+  }
+
+  run_mistralrs_worker(env_var_work_dir).await
+}
+
+#[cfg(not(feature = "cuda"))]
+async fn run_mistralrs_worker(_env_var_work_dir: String) -> Result<(), Box<dyn std::error::Error>> {
+  Err("oliana_text was built without the \"cuda\" feature, so only --backend mock is available".into())
+}
+
+#[cfg(feature = "cuda")]
+async fn run_mistralrs_worker(env_var_work_dir: String) -> Result<(), Box<dyn std::error::Error>> {
+  let hf_home = oliana_lib::files::get_cache_file(oliana_lib::models::TEXT_HF_HOME_DIR).await.map_err(oliana_lib::eloc!())?;
+  // Held until we exit, so nothing prunes what we load from; see oliana_lib::files::prune_cache_entry
+  let _hf_home_lease = oliana_lib::files::lease_cache_entry(&hf_home)?;
   let hf_home = hf_home.to_string_lossy();
-  tokio::fs::create_dir_all(&hf_home[..]).await?;
 
   eprintln!("Storing model data at {hf_home}");
 
@@ -64,10 +110,21 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     "HF_HOME", hf_home.to_string()
   );
 
+  // Which model, and where its files come from, is up to the models manifest; see oliana_lib::models
+  let offline = oliana_lib::models::offline_mode();
+  if offline {
+    std::env::set_var("HF_HUB_OFFLINE", "1");
+  }
+  let manifest = oliana_lib::models::ModelManifest::load_default().map_err(oliana_lib::eloc!())?;
+  let cache_root = oliana_lib::files::get_cache_dir().await.map_err(oliana_lib::eloc!())?;
+  let _model_lease = oliana_lib::files::lease_cache_entry(manifest.model(oliana_lib::models::TEXT_MODEL_NAME)?.local_dir(&cache_root))?;
+  let model_dir = oliana_lib::models::ensure_model(&manifest, oliana_lib::models::TEXT_MODEL_NAME, &cache_root, offline).await?;
+  eprintln!("Loading {} from {}", manifest.model(oliana_lib::models::TEXT_MODEL_NAME)?.repo, model_dir.display());
+
   let allowed_vram_fraction: f32 = std::env::var("PER_PROC_MEM_FRACT").unwrap_or("1".to_string()).parse().unwrap_or(1.0 as f32);
   println!("PER_PROC_MEM_FRACT = {allowed_vram_fraction} (set by PER_PROC_MEM_FRACT, from 0.0 to 1.0)");
 
-  let model = TextModelBuilder::new("microsoft/Phi-3.5-mini-instruct".to_string())
+  let model = TextModelBuilder::new(model_dir.to_string_lossy().to_string())
         .with_isq(IsqType::Q8_0)
         .with_logging()
         .with_paged_attn(|| PagedAttentionMetaBuilder::default()
@@ -77,10 +134,16 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
         .build()
         .await.map_err(oliana_lib::eloc!())?;
 
+  // Subscribed before the first scan so a .json written while we scan still wakes us up afterwards
+  let workdir_watcher = oliana_lib::watch::DirWatcher::new(&env_var_work_dir).map_err(oliana_lib::eloc!())?;
+  let mut workdir_events = workdir_watcher.subscribe();
+  oliana_lib::launchers::write_worker_ready_file(&env_var_work_dir).map_err(oliana_lib::eloc!())?; // Lets oliana_server report the model as loaded
+
   let our_start_time = std::time::SystemTime::now();
   let mut last_seen_mtime = std::collections::HashMap::<std::path::PathBuf, std::time::SystemTime>::new();
   let mut allowed_errors_remaining = 100;
   loop {
+    oliana_lib::launchers::write_worker_heartbeat(&env_var_work_dir, oliana_lib::launchers::WorkerActivity::Idle, None).map_err(oliana_lib::eloc!())?;
     let mut dir_iterator = tokio::fs::read_dir(&env_var_work_dir).await?;
     while let Some(entry) = dir_iterator.next_entry().await? {
         let entry_path = entry.path();
@@ -105,10 +168,23 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
                                 tokio::fs::remove_file(&out_done_file).await?;
                             }
 
+                            let mut in_cancel_file = entry_path.clone();
+                            in_cancel_file.set_extension("cancel");
+                            let in_cancel_file = in_cancel_file;
+
                             // This has a Drop trait which creates the passed-in file when it is no longer in scope; combined with the error? returns below,
                             // this guarantees when the computation is done, out_done_file exists.
                             let out_done_writer = CreateFileOnDropped::new(out_done_file);
 
+                            if in_cancel_file.exists() {
+                                println!("Skipping {} because it was cancelled before it began", entry_path.display());
+                                continue; // out_done_writer is dropped here, which writes out_done_file
+                            }
+
+                            // Beaten on every token from here on; see oliana_lib::launchers::WORKER_HEARTBEAT_FILE_NAME
+                            let job_name = entry_path.file_stem().and_then(std::ffi::OsStr::to_str);
+                            oliana_lib::launchers::write_worker_heartbeat(&env_var_work_dir, oliana_lib::launchers::WorkerActivity::Busy, job_name).map_err(oliana_lib::eloc!())?;
+
                             let input_json_text = tokio::fs::read_to_string(&entry_path).await?;
                             let input_data: serde_json::Value = serde_json::from_str(&input_json_text)?;
                             eprintln!("Read input_data = {input_json_text}");
@@ -144,18 +220,29 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
 
                             match model.stream_chat_request(messages).await.map_err(oliana_lib::eloc!()) {
                                 Ok(mut response_stream) => {
+                                    // Failures mid-stream fail the job, so clients never mistake the error for generated text
+                                    let fail_job = |kind: oliana_lib::err::ErrorKind, message: String| {
+                                        eprintln!("{}", message);
+                                        oliana_lib::launchers::write_job_failed(std::path::Path::new(&env_var_work_dir), job_name.unwrap_or(""), &oliana_lib::err::ErrorReport::new(kind, message))
+                                    };
                                     while let Some(ref response) = response_stream.next().await {
+                                        oliana_lib::launchers::write_worker_heartbeat(&env_var_work_dir, oliana_lib::launchers::WorkerActivity::Busy, job_name).map_err(oliana_lib::eloc!())?;
+                                        if in_cancel_file.exists() {
+                                            // Breaking drops response_stream, which closes its channel; mistralrs stops the sequence when it can no longer send to it.
+                                            println!("Cancelled {}", entry_path.display());
+                                            break;
+                                        }
                                         match response {
                                             mistralrs::Response::InternalError(err) => {
-                                                out_txt_fd.write_all(format!("\n{:?}\n", err).as_bytes()).await?;
+                                                fail_job(oliana_lib::err::ErrorKind::WorkerFailed, format!("{}", err))?;
                                                 break;
                                             },
                                             mistralrs::Response::ValidationError(err) => {
-                                                out_txt_fd.write_all(format!("\n{:#?}\n", err).as_bytes()).await?;
+                                                fail_job(oliana_lib::err::ErrorKind::InvalidInput, format!("{}", err))?;
                                                 break;
                                             },
-                                            mistralrs::Response::ModelError(s, completion_response) => {
-                                                out_txt_fd.write_all(format!("\n{:#?},{:#?}\n", s, completion_response).as_bytes()).await?;
+                                            mistralrs::Response::ModelError(s, _completion_response) => {
+                                                fail_job(oliana_lib::err::ErrorKind::WorkerFailed, s.to_string())?;
                                                 break;
                                             },
                                             mistralrs::Response::Done(_completion_response) => {
@@ -168,8 +255,9 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
                                                     out_txt_fd.write_all(format!("{}", choice.delta.content ).as_bytes()).await?;
                                                 }
                                             },
-                                            mistralrs::Response::CompletionModelError(s, completion_response) => {
-                                                out_txt_fd.write_all(format!("\n{:#?},{:#?}\n", s, completion_response).as_bytes()).await?;
+                                            mistralrs::Response::CompletionModelError(s, _completion_response) => {
+                                                fail_job(oliana_lib::err::ErrorKind::WorkerFailed, s.to_string())?;
+                                                break;
                                             },
                                             mistralrs::Response::CompletionDone(_completion_response) => {
                                                 //out_txt_fd.write_all(format!("\n{:#?}\n", completion_response).as_bytes()).await?;
@@ -191,7 +279,8 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
                                 Err(e) => {
                                     allowed_errors_remaining -= 1;
                                     eprintln!("{:?}", e);
-                                    out_txt_fd.write_all(format!("\n{:#?}\n", e).as_bytes()).await?;
+                                    // Fails the job with the whole error, not just its text; out_done_writer re-writes .done below, which is harmless
+                                    oliana_lib::launchers::write_job_failed(std::path::Path::new(&env_var_work_dir), job_name.unwrap_or(""), &e.report())?;
                                 }
                             }
 
@@ -212,17 +301,20 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     if allowed_errors_remaining < 1 {
         break;
     }
-    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    // Sleep until a .json is written or it is time for the next heartbeat
+    workdir_events.wait_for_extension("json", oliana_lib::launchers::WORKER_HEARTBEAT_INTERVAL).await;
   }
 
   Ok(())
 }
 
+#[cfg(feature = "cuda")]
 #[clippy::has_significant_drop]
 pub struct CreateFileOnDropped {
     pub file_path: std::path::PathBuf,
 }
 
+#[cfg(feature = "cuda")]
 impl CreateFileOnDropped {
     pub fn new(file_path: std::path::PathBuf) -> Self {
         Self {
@@ -231,6 +323,7 @@ impl CreateFileOnDropped {
     }
 }
 
+#[cfg(feature = "cuda")]
 impl Drop for CreateFileOnDropped {
     fn drop(&mut self) {
         if let Err(e) = std::fs::write(self.file_path.as_path(), " ".as_bytes()) {